@@ -0,0 +1,144 @@
+#!/usr/bin/env python3
+"""Persistent p4 broker for p4-mcp, built on P4Python.
+
+Run the server with `--p4-broker scripts/p4_broker.py` (or `broker = [...]` in the
+config file) and it sends commands here instead of spawning `p4` for each one. The
+connection for each port/user/client/charset and environment is kept open between
+commands, so the connect, SSL handshake and ticket check happen once.
+
+The protocol is one JSON request per line on stdin and one JSON response per line on
+stdout; see src/p4/broker.rs. Anything this script doesn't handle is answered with
+{"fallback": true} and the server runs `p4` itself.
+"""
+
+import json
+import os
+import sys
+
+from P4 import P4, P4Exception
+
+# Global flags taking a value, and the P4 attribute each sets
+CONNECTION_FLAGS = {"-p": "port", "-u": "user", "-c": "client", "-C": "charset"}
+
+# Commands that are interactive or print file content, which P4Python reports
+# differently from the command line client
+FALLBACK_COMMANDS = {"login", "logout", "passwd", "print", "diff", "diff2", "describe"}
+
+connections = {}
+
+
+def fallback():
+    return {"fallback": True}
+
+
+def parse_args(args):
+    """Split p4's command line into connection settings, output flags and the command."""
+    settings = {}
+    flags = set()
+    i = 0
+    while i < len(args) and args[i].startswith("-"):
+        flag = args[i]
+        if flag == "-x" and i + 1 < len(args) and args[i + 1] == "-":
+            # Arguments on stdin, as fstat and where are run with
+            flags.add(flag)
+            i += 2
+        elif flag in CONNECTION_FLAGS and i + 1 < len(args):
+            settings[CONNECTION_FLAGS[flag]] = args[i + 1]
+            i += 2
+        elif flag in ("-s", "-ztag", "-Mj"):
+            flags.add(flag)
+            i += 1
+        else:
+            return None
+    if i == len(args):
+        return None
+    return settings, flags, args[i], args[i + 1:]
+
+
+def connection(settings, env):
+    key = json.dumps([settings, env], sort_keys=True)
+    p4 = connections.get(key)
+    if p4 is None or not p4.connected():
+        # P4Python reads P4PORT, P4CONFIG and friends from the environment
+        os.environ.clear()
+        os.environ.update(env)
+        p4 = P4()
+        for name, value in settings.items():
+            setattr(p4, name, value)
+        p4.exception_level = 0
+        p4.connect()
+        connections[key] = p4
+    return p4
+
+
+def tagged_lines(record, json_output):
+    if json_output:
+        return [json.dumps(record)]
+    lines = []
+    for key, value in record.items():
+        if isinstance(value, list):
+            lines.extend("... %s%d %s" % (key, i, v) for i, v in enumerate(value))
+        else:
+            lines.append("... %s %s" % (key, value))
+    lines.append("")
+    return lines
+
+
+def run(request):
+    parsed = parse_args(request["args"])
+    if parsed is None:
+        return fallback()
+    settings, flags, command, args = parsed
+    if command in FALLBACK_COMMANDS:
+        return fallback()
+
+    p4 = connection(settings, request.get("env") or {})
+    if request.get("cwd"):
+        p4.cwd = request["cwd"]
+    p4.tagged = "-ztag" in flags
+    if "-x" in flags:
+        args = args + (request.get("input") or "").splitlines()
+        p4.input = ""
+    else:
+        p4.input = request.get("input") or ""
+    try:
+        results = p4.run(command, *args)
+    except P4Exception:
+        results = []
+
+    stdout = []
+    stderr = []
+    for result in results:
+        if isinstance(result, dict):
+            stdout.extend(tagged_lines(result, "-Mj" in flags))
+        elif "-s" in flags:
+            stdout.extend("info: " + line for line in str(result).splitlines())
+        else:
+            stdout.extend(str(result).splitlines())
+    if "-s" in flags:
+        stdout.extend("warning: " + w for w in p4.warnings)
+        stdout.extend("error: " + e for e in p4.errors)
+        stdout.append("exit: %d" % (1 if p4.errors else 0))
+    else:
+        stderr.extend(p4.warnings)
+        stderr.extend(p4.errors)
+    return {
+        "stdout": "".join(line + "\n" for line in stdout),
+        "stderr": "".join(line + "\n" for line in stderr),
+        "status": 1 if p4.errors else 0,
+    }
+
+
+def main():
+    for line in sys.stdin:
+        try:
+            response = run(json.loads(line))
+        except Exception as e:
+            print("p4_broker: %s" % e, file=sys.stderr)
+            response = {"stdout": "", "stderr": "%s\n" % e, "status": 1}
+        sys.stdout.write(json.dumps(response) + "\n")
+        sys.stdout.flush()
+
+
+if __name__ == "__main__":
+    main()
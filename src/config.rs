@@ -0,0 +1,219 @@
+//! Configuration file
+//!
+//! `--config <FILE>` reads a TOML file with the same settings as the command line
+//! options. Options given on the command line take precedence over the file.
+//!
+//! ```toml
+//! max_processes = 8
+//! max_response_bytes = 100000
+//! max_files = 1000
+//! workspace_root = "/home/alice/ws/main"
+//! p4_bin = "/opt/perforce/bin/p4"
+//! dry_run = false
+//! format = "text"
+//! mock = false
+//! allow_backend_switch = false
+//! allow_force_sync = false
+//! audit_log = "/var/log/p4-mcp/audit.jsonl"
+//! record = "session.jsonl"
+//! broker = ["python3", "scripts/p4_broker.py"]
+//! shutdown_timeout_secs = 10
+//!
+//! [p4]
+//! port = "ssl:perforce.example.com:1666"
+//! user = "alice"
+//! client = "alice-main"
+//! charset = "utf8"
+//! p4config = ".p4config"
+//!
+//! [timeouts]
+//! default_secs = 300
+//! tools = { p4_sync = 3600 }
+//!
+//! [retry]
+//! max_attempts = 3
+//! initial_backoff_ms = 500
+//!
+//! [cache]
+//! ttl_secs = 10
+//!
+//! [tools]
+//! deny = ["p4_submit"]
+//!
+//! [aliases.nightly_sync]
+//! tool = "p4_sync"
+//! arguments = { path = "//depot/main/...#head", dry_run = true }
+//!
+//! [sandbox]
+//! depot = ["//depot/team-x/..."]
+//! local = ["/home/alice/ws/main/team-x"]
+//!
+//! [submit]
+//! min_description_length = 10
+//! require_job = true
+//! banned_files = ["*.dll"]
+//!
+//! [confirmation]
+//! tools = { p4_revert = "token" }
+//!
+//! [workspace_templates.main]
+//! root = "/home/alice/ws/{client}"
+//! view = ["//depot/main/... //{client}/..."]
+//!
+//! [rate_limits]
+//! tools = { p4_submit = { calls = 10, per_secs = 3600 } }
+//!
+//! [responses]
+//! tools = { p4_changes = { max_bytes = "16k" } }
+//!
+//! [environment]
+//! inherit = ["HTTPS_PROXY"]
+//! set = { P4IGNORE = ".p4ignore" }
+//!
+//! [login]
+//! auto = true
+//!
+//! [login.credentials]
+//! provider = "keyring"
+//! service = "p4-mcp"
+//!
+//! [mock_faults]
+//! error_rate = 0.1
+//! latency_ms = 200
+//!
+//! [[mock_users]]
+//! user = "alice"
+//! exclusive = ["//depot/main/file3.h"]
+//! ```
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+use crate::mcp::aliases::Aliases;
+use crate::mcp::file_limit::DEFAULT_MAX_FILES;
+use crate::mcp::truncation::DEFAULT_MAX_RESPONSE_BYTES;
+use crate::mcp::{ConfirmationPolicy, RateLimits, ResponseFormat, ResponseLimits, ToolFilter};
+use crate::p4::{
+    CacheSettings, EnvironmentPolicy, FaultInjection, LoginSettings, MockUser, P4Connection,
+    PathSandbox, RetryPolicy, SubmitPolicy, Timeouts, WorkspaceTemplates, DEFAULT_MAX_PROCESSES,
+};
+use crate::transport::DEFAULT_SHUTDOWN_TIMEOUT_SECS;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How many p4 processes may run at once.
+    pub max_processes: usize,
+    /// Tool results longer than this are truncated; 0 means no limit.
+    pub max_response_bytes: usize,
+    /// The most files one p4_edit, p4_add or p4_revert call may touch without
+    /// `allow_many_files`; 0 means no limit.
+    pub max_files: usize,
+    /// The p4 executable; `p4` on the `PATH` by default.
+    pub p4_bin: Option<PathBuf>,
+    /// Report the p4 commands tool calls would run instead of running them.
+    pub dry_run: bool,
+    /// How tool results are written unless a call asks otherwise.
+    pub format: ResponseFormat,
+    /// Answer commands with mock responses; also on when `P4_MOCK_MODE` is set.
+    pub mock: bool,
+    /// Whether `p4_backend` may switch between mock and real mode while running.
+    pub allow_backend_switch: bool,
+    /// Whether `p4_sync` may force a sync, overwriting files changed locally.
+    pub allow_force_sync: bool,
+    /// A JSON-lines file every p4 command run is appended to.
+    pub audit_log: Option<PathBuf>,
+    /// A JSON-lines file every p4 command run and its output is appended to.
+    pub record: Option<PathBuf>,
+    /// A file written with `record` whose responses are served instead of running p4.
+    pub replay: Option<PathBuf>,
+    /// A persistent helper to run p4 commands through; empty to spawn p4 each time.
+    pub broker: Vec<String>,
+    /// Where p4 runs when a tool call doesn't pass `cwd`.
+    pub workspace_root: Option<PathBuf>,
+    /// How long sessions finish their tool calls for when the server shuts down.
+    pub shutdown_timeout_secs: u64,
+    /// Connection settings passed to every p4 invocation.
+    pub p4: P4Connection,
+    /// Limits on how long p4 commands may run.
+    pub timeouts: Timeouts,
+    /// Retries for commands that fail on a flaky connection.
+    pub retry: RetryPolicy,
+    /// How long results of read-only commands are reused.
+    pub cache: CacheSettings,
+    /// Which tools are exposed.
+    pub tools: ToolFilter,
+    /// Tools that run a built-in tool with fixed arguments.
+    pub aliases: Aliases,
+    /// The depot and local paths commands may use.
+    pub sandbox: PathSandbox,
+    /// What changes may be submitted.
+    pub submit: SubmitPolicy,
+    /// How destructive tool calls are confirmed.
+    pub confirmation: ConfirmationPolicy,
+    /// What new client workspaces are created from.
+    pub workspace_templates: WorkspaceTemplates,
+    /// How often tools may be called.
+    pub rate_limits: RateLimits,
+    /// Response size limits for particular tools, over which results are summarized.
+    pub responses: ResponseLimits,
+    /// Which environment variables p4 runs with.
+    pub environment: EnvironmentPolicy,
+    /// Logging in again when the ticket expires.
+    pub login: LoginSettings,
+    /// Failures and latency simulated in mock mode.
+    pub mock_faults: FaultInjection,
+    /// Other users with files open in mock mode, replacing the built-in ones.
+    pub mock_users: Option<Vec<MockUser>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_processes: DEFAULT_MAX_PROCESSES,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            max_files: DEFAULT_MAX_FILES,
+            p4_bin: None,
+            dry_run: false,
+            format: ResponseFormat::default(),
+            mock: false,
+            allow_backend_switch: false,
+            allow_force_sync: false,
+            audit_log: None,
+            record: None,
+            replay: None,
+            broker: Vec::new(),
+            workspace_root: None,
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            p4: P4Connection::default(),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            cache: CacheSettings::default(),
+            tools: ToolFilter::default(),
+            aliases: Aliases::default(),
+            sandbox: PathSandbox::default(),
+            submit: SubmitPolicy::from_env(),
+            confirmation: ConfirmationPolicy::default(),
+            workspace_templates: WorkspaceTemplates::default(),
+            rate_limits: RateLimits::default(),
+            responses: ResponseLimits::default(),
+            environment: EnvironmentPolicy::default(),
+            login: LoginSettings::default(),
+            mock_faults: FaultInjection::default(),
+            mock_users: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+}
@@ -4,8 +4,16 @@
 //! to interact with Perforce version control system. It supports both real Perforce
 //! operations and mock mode for testing.
 
+pub mod config;
 pub mod mcp;
+pub mod metrics;
 pub mod p4;
+pub mod reload;
+pub mod selftest;
+pub mod swarm;
+pub mod transport;
 
-pub use mcp::{MCPMessage, MCPResponse, MCPServer};
+pub use config::Config;
+pub use mcp::{MCPMessage, MCPResponse, MCPServer, RequestId, ServerContext};
 pub use p4::{P4Command, P4Handler};
+pub use swarm::SwarmClient;
@@ -1,17 +1,33 @@
 use anyhow::Result;
-use clap::Parser;
-use std::io::{self, BufRead, BufReader, Write};
-use tokio::sync::mpsc;
-use tracing::{error, info, warn};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tracing::{info, warn};
 
-pub mod mcp;
-pub mod p4;
+use p4_mcp::mcp::ListToolsResult;
+use p4_mcp::transport::{self, framing::Framing, stdio::StdioTransport};
+use p4_mcp::{Config, MCPServer, ServerContext};
 
-use mcp::{MCPMessage, MCPServer};
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Check the setup end to end: p4, the server and ticket, then an initialize,
+    /// tools/list and p4_info call as an MCP host would make them. Exits non-zero if a
+    /// check fails
+    Selftest,
+}
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Enable debug logging
     #[arg(short, long)]
     debug: bool,
@@ -19,6 +35,190 @@ struct Args {
     /// Disable logging
     #[arg(short, long)]
     quiet: bool,
+
+    /// How log lines are written: text, or one JSON object per line, with the spans
+    /// of the request and p4 command they belong to, for log aggregation
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Serve the legacy HTTP+SSE transport on ADDR instead of stdio
+    #[arg(long, value_name = "ADDR", num_args = 0..=1, default_missing_value = "127.0.0.1:8080")]
+    sse: Option<SocketAddr>,
+
+    /// Serve on a Unix domain socket (or a named pipe on Windows) at PATH instead of stdio
+    #[arg(long, value_name = "PATH", conflicts_with = "sse")]
+    socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics (the SSE transport serves them
+    /// on its own address too)
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// How messages are framed on stdio
+    #[arg(long, value_enum, default_value_t = Framing::Ndjson)]
+    framing: Framing,
+
+    /// Print the tools the configuration exposes, with their schemas, as JSON and exit
+    #[arg(long)]
+    list_tools: bool,
+
+    /// Read settings from a TOML config file
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Perforce server to connect to, passed to p4 as -p
+    #[arg(long, value_name = "PORT")]
+    p4port: Option<String>,
+
+    /// Perforce user, passed to p4 as -u
+    #[arg(long, value_name = "USER")]
+    p4user: Option<String>,
+
+    /// Client workspace, passed to p4 as -c
+    #[arg(long, value_name = "CLIENT")]
+    p4client: Option<String>,
+
+    /// Character set for unicode servers, passed to p4 as -C (default auto: utf8 when
+    /// the server is unicode-enabled)
+    #[arg(long, value_name = "CHARSET")]
+    p4charset: Option<String>,
+
+    /// The p4 executable to run (default p4 on the PATH)
+    #[arg(long, value_name = "PATH")]
+    p4_bin: Option<PathBuf>,
+
+    /// Report the p4 commands tool calls would run instead of running them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Answer commands with mock responses instead of running p4, as P4_MOCK_MODE does
+    #[arg(long)]
+    mock: bool,
+
+    /// Run p4 commands through a persistent broker started with PROGRAM, e.g.
+    /// scripts/p4_broker.py
+    #[arg(long, value_name = "PROGRAM")]
+    p4_broker: Option<String>,
+
+    /// P4CONFIG file name to look for above the working directory (default $P4CONFIG,
+    /// or .p4config)
+    #[arg(long, value_name = "NAME")]
+    p4config: Option<String>,
+
+    /// Run p4 in DIR when a tool call doesn't pass a cwd
+    #[arg(long, value_name = "DIR")]
+    workspace_root: Option<PathBuf>,
+
+    /// Kill p4 commands that run longer than SECS (0 for no limit; default 300)
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Run p4 commands that fail on a network error up to N times (default 3)
+    #[arg(long, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Reuse results of read-only commands such as p4 info and fstat for SECS (0 to
+    /// disable; default 10)
+    #[arg(long, value_name = "SECS")]
+    cache_ttl: Option<u64>,
+
+    /// Run at most N p4 processes at once (default 8)
+    #[arg(long, value_name = "N")]
+    max_processes: Option<usize>,
+
+    /// Refuse edit, add and revert calls touching more than N files unless they pass
+    /// allow_many_files (0 for no limit; default 1000)
+    #[arg(long, value_name = "N")]
+    max_files: Option<usize>,
+
+    /// Record every p4 command run and its output in FILE
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    /// Answer p4 commands with the responses recorded in FILE instead of running p4
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+
+    /// Append a JSON line for every p4 command run to PATH
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Truncate tool results longer than BYTES (0 for no limit; default 100000)
+    #[arg(long, value_name = "BYTES")]
+    max_response_bytes: Option<usize>,
+
+    /// On shutdown, give tool calls in flight SECS to finish before cancelling them
+    /// (default 10)
+    #[arg(long, value_name = "SECS")]
+    shutdown_timeout: Option<u64>,
+}
+
+impl Args {
+    /// The config file, if any, with command line options applied on top.
+    fn config(&self) -> Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        let p4 = &mut config.p4;
+        for (value, setting) in [
+            (&self.p4port, &mut p4.port),
+            (&self.p4user, &mut p4.user),
+            (&self.p4client, &mut p4.client),
+            (&self.p4charset, &mut p4.charset),
+            (&self.p4config, &mut p4.p4config),
+        ] {
+            if value.is_some() {
+                setting.clone_from(value);
+            }
+        }
+        if self.p4_bin.is_some() {
+            config.p4_bin.clone_from(&self.p4_bin);
+        }
+        if self.dry_run {
+            config.dry_run = true;
+        }
+        if self.mock {
+            config.mock = true;
+        }
+        if let Some(program) = &self.p4_broker {
+            config.broker = vec![program.clone()];
+        }
+        if self.workspace_root.is_some() {
+            config.workspace_root.clone_from(&self.workspace_root);
+        }
+        if let Some(secs) = self.timeout {
+            config.timeouts.default_secs = secs;
+        }
+        if let Some(attempts) = self.retries {
+            config.retry.max_attempts = attempts;
+        }
+        if let Some(secs) = self.cache_ttl {
+            config.cache.ttl_secs = secs;
+        }
+        if let Some(max) = self.max_processes {
+            config.max_processes = max;
+        }
+        if let Some(max) = self.max_files {
+            config.max_files = max;
+        }
+        if self.record.is_some() {
+            config.record.clone_from(&self.record);
+        }
+        if self.replay.is_some() {
+            config.replay.clone_from(&self.replay);
+        }
+        if self.audit_log.is_some() {
+            config.audit_log.clone_from(&self.audit_log);
+        }
+        if let Some(max) = self.max_response_bytes {
+            config.max_response_bytes = max;
+        }
+        if let Some(secs) = self.shutdown_timeout {
+            config.shutdown_timeout_secs = secs;
+        }
+        Ok(config)
+    }
 }
 
 #[tokio::main]
@@ -26,71 +226,147 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     // Initialize logging - direct all logs to stderr for MCP compliance
-    if !args.quiet {
-        tracing_subscriber::fmt()
+    let selftest = matches!(args.command, Some(Command::Selftest));
+    // Anything these print goes to stdout, so only log when asked
+    if !args.quiet && !args.list_tools && (!selftest || args.debug) {
+        let logger = tracing_subscriber::fmt()
             .with_writer(std::io::stderr)
             .with_max_level(if args.debug {
                 tracing::Level::DEBUG
             } else {
                 tracing::Level::INFO
-            })
-            .init();
+            });
+        match args.log_format {
+            LogFormat::Text => logger.init(),
+            LogFormat::Json => logger.json().with_span_list(true).init(),
+        }
     }
 
-    info!("Starting p4-mcp server");
+    // Shared by every session; the socket and SSE transports serve several at once
+    let config = args.config()?;
+    let context = ServerContext::from_config(&config)?;
 
-    // Create MCP server
-    let mut server = MCPServer::new();
+    if args.list_tools {
+        let mut server = MCPServer::with_context(&context);
+        // As a client that supports sampling would see them
+        for tool in p4_mcp::mcp::sampling::SAMPLING_TOOLS {
+            server.set_tool_enabled(tool, true);
+        }
+        let tools = ListToolsResult {
+            tools: server.list_tools(),
+            next_cursor: None,
+        };
+        println!("{}", serde_json::to_string_pretty(&tools)?);
+        return Ok(());
+    }
 
-    // Set up communication channels
-    let (tx, mut rx) = mpsc::unbounded_channel::<MCPMessage>();
+    if selftest {
+        let report = p4_mcp::selftest::run(&context).await;
+        print!("{}", p4_mcp::selftest::render(&report));
+        if !report.is_healthy() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
 
-    // Spawn task to handle stdin
-    let stdin_tx = tx.clone();
-    tokio::spawn(async move {
-        let stdin = io::stdin();
-        let reader = BufReader::new(stdin);
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) => match serde_json::from_str::<MCPMessage>(&line) {
-                    Ok(message) => {
-                        if stdin_tx.send(message).is_err() {
-                            break;
-                        }
-                    }
-                    Err(parse_error) => {
-                        warn!(
-                            "Failed to parse JSON message: {} - Input: {}",
-                            parse_error, line
-                        );
-                    }
-                },
-                Err(e) => {
-                    error!("Error reading stdin: {}", e);
-                    break;
+    info!("Starting p4-mcp server");
+    // Without a working p4 every tool call would fail, so say so once up front
+    if context.is_mock() {
+        info!("Answering p4 commands with mock responses");
+    } else {
+        let report = context.check_health().await;
+        for check in &report.checks {
+            match (check.ok, &check.hint) {
+                (true, _) => info!("{} check passed: {}", check.name, check.detail),
+                (false, Some(hint)) => {
+                    warn!("{} check failed: {}. {}", check.name, check.detail, hint)
                 }
+                (false, None) => warn!("{} check failed: {}", check.name, check.detail),
             }
         }
-    });
+        // The server and ticket can come good while the server runs; a missing client can't
+        if let Some(check) = report.check("p4").filter(|check| !check.ok) {
+            anyhow::bail!("{}", check.detail);
+        }
+    }
+    match context.detect_workspace().await {
+        Ok(Some(workspace)) => match &workspace.stream {
+            Some(stream) => info!(
+                "Using client {} of stream {} at {}",
+                workspace.client, stream, workspace.root
+            ),
+            None => info!("Using client {} at {}", workspace.client, workspace.root),
+        },
+        Ok(None) => warn!("No client workspace is set; set P4CLIENT or the client in [p4]"),
+        Err(e) => warn!("Could not find the client workspace: {:#}", e),
+    }
 
-    // Main message processing loop
-    while let Some(message) = rx.recv().await {
-        match server.handle_message(message).await {
-            Ok(Some(response)) => {
-                let json = serde_json::to_string(&response)?;
-                println!("{}", json);
-                io::stdout().flush()?;
-            }
-            Ok(None) => {
-                // No response needed
+    if let Some(addr) = args.metrics_addr {
+        let metrics = context.metrics();
+        tokio::spawn(async move {
+            if let Err(e) = p4_mcp::metrics::serve(addr, metrics).await {
+                tracing::error!("Metrics endpoint failed: {:#}", e);
             }
+        });
+    }
+
+    if let Some(path) = args.config.clone() {
+        let context = context.clone();
+        let args = args.clone();
+        tokio::spawn(p4_mcp::reload::watch(path, context, config, move || {
+            args.config()
+        }));
+    }
+
+    let shutdown = context.clone();
+    tokio::spawn(async move {
+        let signal = shutdown_signal().await;
+        info!("Received {}, finishing the requests in flight", signal);
+        shutdown.shutdown();
+        let signal = shutdown_signal().await;
+        warn!("Received {} again, exiting now", signal);
+        std::process::exit(130);
+    });
+
+    if let Some(addr) = args.sse {
+        return transport::sse::serve(addr, context).await;
+    }
+    if let Some(path) = args.socket {
+        return transport::socket::serve(&path, context).await;
+    }
+
+    transport::serve(
+        MCPServer::with_context(&context),
+        StdioTransport::new(args.framing),
+    )
+    .await?;
+
+    info!("p4-mcp server shutting down");
+    // After a signal the thread reading stdin is still blocked on it, and returning
+    // would wait for it
+    std::process::exit(0);
+}
+
+/// Wait for SIGINT or, on Unix, SIGTERM, returning its name.
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
             Err(e) => {
-                error!("Error handling message: {}", e);
+                warn!("Can't handle SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
             }
         }
     }
-
-    info!("p4-mcp server shutting down");
-    Ok(())
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
 }
@@ -0,0 +1,325 @@
+//! Alias tools defined in the config file
+//!
+//! Teams encode their conventions as tools of their own, without writing Rust: an
+//! alias runs a built-in tool with fixed arguments, which can take the alias's own
+//! parameters as `{{name}}` placeholders. Aliases are listed like built-in tools, and
+//! a call runs the built-in tool with its checks, confirmations and limits, as if
+//! the built-in tool had been called.
+//!
+//! ```toml
+//! [aliases.nightly_sync]
+//! description = "Preview syncing the main branch to head"
+//! tool = "p4_sync"
+//! arguments = { path = "//depot/main/...#head", dry_run = true }
+//!
+//! [aliases.sync_release]
+//! tool = "p4_sync"
+//! arguments = { path = "//depot/release/{{version}}/..." }
+//! parameters = { version = { description = "Release version, e.g. 2024.1" } }
+//! ```
+//!
+//! A placeholder that is a whole string is replaced by the parameter's value, so an
+//! `array` parameter can fill a list argument such as `files = "{{files}}"`. An
+//! argument with a placeholder for an optional parameter the call doesn't pass is
+//! left out. Arguments the server handles itself, such as `cwd` and `dry_run`, are
+//! passed through unless the alias fixes them.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::debug;
+
+use super::registry::{Handler, ToolRegistry};
+use super::{
+    add_server_properties, validation, CallToolParams, MCPError, MCPServer, ServerContext, Tool,
+    INVALID_PARAMS,
+};
+
+/// The `[aliases]` section: alias tools by name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Aliases(pub BTreeMap<String, Alias>);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Alias {
+    /// The built-in tool the alias runs.
+    pub tool: String,
+    /// Listed as the alias's description; by default, what it runs.
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The arguments the tool runs with, with `{{name}}` placeholders for parameters.
+    #[serde(default)]
+    pub arguments: Map<String, Value>,
+    /// What a call of the alias passes.
+    #[serde(default)]
+    pub parameters: BTreeMap<String, AliasParameter>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AliasParameter {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, rename = "type")]
+    pub kind: ParameterType,
+    #[serde(default = "required_by_default")]
+    pub required: bool,
+}
+
+fn required_by_default() -> bool {
+    true
+}
+
+/// The type of a parameter's value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ParameterType {
+    #[default]
+    String,
+    Integer,
+    Boolean,
+    /// A list of strings.
+    Array,
+}
+
+impl ParameterType {
+    fn schema(self) -> Value {
+        match self {
+            ParameterType::String => json!({"type": "string"}),
+            ParameterType::Integer => json!({"type": "integer"}),
+            ParameterType::Boolean => json!({"type": "boolean"}),
+            ParameterType::Array => json!({"type": "array", "items": {"type": "string"}}),
+        }
+    }
+}
+
+impl Aliases {
+    /// Check that every alias runs a tool in `registry`, doesn't take the name of one,
+    /// and only has placeholders for its parameters.
+    pub(crate) fn check(&self, registry: &ToolRegistry) -> Result<()> {
+        for (name, alias) in &self.0 {
+            if registry.handler(name).is_some() {
+                bail!("Alias {} has the name of a built-in tool", name);
+            }
+            if registry.handler(&alias.tool).is_none() {
+                bail!(
+                    "Alias {} runs {}, which isn't a built-in tool",
+                    name,
+                    alias.tool
+                );
+            }
+            let mut placeholders = Vec::new();
+            for value in alias.arguments.values() {
+                collect_placeholders(value, &mut placeholders);
+            }
+            if let Some(unknown) = placeholders
+                .iter()
+                .find(|placeholder| !alias.parameters.contains_key(*placeholder))
+            {
+                bail!(
+                    "Alias {} has a placeholder {{{{{}}}}} for a parameter it doesn't declare",
+                    name,
+                    unknown
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Alias {
+    /// The alias's tool definition: its parameters, and the `server_properties` it
+    /// doesn't fix. `target` is the tool it runs.
+    pub(crate) fn tool(&self, name: &str, target: &Tool, server_properties: &Value) -> Tool {
+        let mut properties = Map::new();
+        let mut required = Vec::new();
+        for (parameter, spec) in &self.parameters {
+            let mut schema = spec.kind.schema();
+            if !spec.description.is_empty() {
+                schema["description"] = json!(spec.description);
+            }
+            properties.insert(parameter.clone(), schema);
+            if spec.required {
+                required.push(parameter.clone());
+            }
+        }
+        for (argument, schema) in server_properties.as_object().into_iter().flatten() {
+            if !self.arguments.contains_key(argument) {
+                properties.insert(argument.clone(), schema.clone());
+            }
+        }
+
+        let description = self.description.clone().unwrap_or_else(|| {
+            format!(
+                "Runs {} with {}",
+                self.tool,
+                Value::Object(self.arguments.clone())
+            )
+        });
+        let annotations = target.annotations.clone().map(|mut annotations| {
+            annotations.title = Some(name.to_string());
+            annotations
+        });
+        Tool {
+            name: name.to_string(),
+            description,
+            input_schema: json!({
+                "type": "object",
+                "properties": properties,
+                "required": required
+            }),
+            output_schema: target.output_schema.clone(),
+            annotations,
+        }
+    }
+
+    /// The arguments the tool runs with for a call of the alias, `tool`, passing
+    /// `arguments`.
+    fn expand(&self, tool: &Tool, arguments: &Value) -> Map<String, Value> {
+        let mut expanded: Map<String, Value> = self
+            .arguments
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), render(value, arguments)?)))
+            .collect();
+        // The server's own arguments are the alias's properties that aren't parameters
+        let declared = &tool.input_schema["properties"];
+        for (name, value) in arguments.as_object().into_iter().flatten() {
+            if declared.get(name).is_some() && !self.parameters.contains_key(name) {
+                expanded.insert(name.clone(), value.clone());
+            }
+        }
+        expanded
+    }
+}
+
+/// The parameter a string is a whole placeholder for, as in `{{files}}`.
+fn whole_placeholder(text: &str) -> Option<&str> {
+    let name = text.strip_prefix("{{")?.strip_suffix("}}")?;
+    (!name.contains("{{") && !name.contains("}}")).then_some(name)
+}
+
+/// The parameters `text` has placeholders for, in order.
+fn placeholders(text: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        names.push(&rest[start + 2..start + end]);
+        rest = &rest[start + end + 2..];
+    }
+    names
+}
+
+fn collect_placeholders(value: &Value, names: &mut Vec<String>) {
+    match value {
+        Value::String(text) => names.extend(placeholders(text).into_iter().map(String::from)),
+        Value::Array(items) => items
+            .iter()
+            .for_each(|item| collect_placeholders(item, names)),
+        Value::Object(fields) => fields
+            .values()
+            .for_each(|field| collect_placeholders(field, names)),
+        _ => {}
+    }
+}
+
+/// `value` with its placeholders filled from `arguments`, or `None` if one is for a
+/// parameter the call doesn't pass.
+fn render(value: &Value, arguments: &Value) -> Option<Value> {
+    match value {
+        Value::String(text) => {
+            if let Some(name) = whole_placeholder(text) {
+                return arguments.get(name).cloned();
+            }
+            let mut rendered = text.clone();
+            for name in placeholders(text) {
+                let text = match arguments.get(name)? {
+                    Value::String(text) => text.clone(),
+                    other => other.to_string(),
+                };
+                rendered = rendered.replacen(&format!("{{{{{}}}}}", name), &text, 1);
+            }
+            Some(Value::String(rendered))
+        }
+        Value::Array(items) => Some(Value::Array(
+            items
+                .iter()
+                .filter_map(|item| render(item, arguments))
+                .collect(),
+        )),
+        Value::Object(fields) => Some(Value::Object(
+            fields
+                .iter()
+                .filter_map(|(name, field)| Some((name.clone(), render(field, arguments)?)))
+                .collect(),
+        )),
+        other => Some(other.clone()),
+    }
+}
+
+impl ToolRegistry {
+    /// Register the aliases of `context` whose tools are registered.
+    pub(crate) fn register_aliases(&mut self, context: &ServerContext) {
+        for (name, alias) in &context.aliases.0 {
+            let Some(target) = self.get_mut(&alias.tool).map(|tool| tool.clone()) else {
+                continue;
+            };
+            // The arguments the server handles for the tool, such as cwd and dry_run
+            let mut server = Tool {
+                input_schema: json!({"type": "object", "properties": {}}),
+                ..target.clone()
+            };
+            add_server_properties(&mut server, context);
+            let tool = alias.tool(name, &target, &server.input_schema["properties"]);
+            self.insert(tool, Handler::Alias(Arc::new(alias.clone())));
+        }
+    }
+}
+
+impl MCPServer {
+    /// The call of the tool an alias runs, for a call of an alias; other calls are
+    /// returned as they are.
+    pub(crate) fn expand_alias(&self, params: CallToolParams) -> Result<CallToolParams, MCPError> {
+        let (Some(Handler::Alias(alias)), Some(tool)) = (
+            self.tools.handler(&params.name),
+            self.tools.get(&params.name),
+        ) else {
+            return Ok(params);
+        };
+        if self.tools.get(&alias.tool).is_none() {
+            return Err(MCPError {
+                code: INVALID_PARAMS,
+                message: format!("{} runs {}, which isn't available", params.name, alias.tool),
+                data: None,
+            });
+        }
+
+        // The stored output is the tool's, whatever the arguments
+        let arguments = if params.arguments.get("output_cursor").is_some() {
+            params.arguments
+        } else {
+            if let Err(e) = validation::validate(&tool.input_schema, &params.arguments) {
+                return Err(MCPError {
+                    code: INVALID_PARAMS,
+                    message: format!("Invalid arguments for {}: {}", params.name, e),
+                    data: Some(json!({"pointer": e.pointer})),
+                });
+            }
+            Value::Object(alias.expand(tool, &params.arguments))
+        };
+        debug!(
+            "Running alias {} as {} {}",
+            params.name, alias.tool, arguments
+        );
+        Ok(CallToolParams {
+            name: alias.tool.clone(),
+            arguments,
+            meta: params.meta,
+        })
+    }
+}
@@ -0,0 +1,249 @@
+//! Sessions with a custom set of tools
+//!
+//! [`MCPServer::with_context`] registers the built-in tools. A program embedding the
+//! server can start from [`MCPServer::builder`] instead to remove tools, describe them
+//! differently, run them its own way, or add tools of its own:
+//!
+//! ```no_run
+//! use p4_mcp::mcp::{MCPServer, Tool, ToolAnnotations, ToolHandler};
+//! use p4_mcp::p4::P4Handler;
+//!
+//! struct Shelve;
+//!
+//! #[async_trait::async_trait]
+//! impl ToolHandler for Shelve {
+//!     async fn call(
+//!         &self,
+//!         p4: &mut P4Handler,
+//!         arguments: serde_json::Value,
+//!     ) -> anyhow::Result<String> {
+//!         let changelist = arguments["changelist"].as_str().unwrap_or_default();
+//!         Ok(format!("Shelved {}", changelist))
+//!     }
+//! }
+//!
+//! let server = MCPServer::builder()
+//!     .remove_tool("p4_submit")
+//!     .description("p4_sync", "Sync the build workspace")
+//!     .tool(
+//!         Tool {
+//!             name: "p4_shelve".to_string(),
+//!             description: "Shelve a pending changelist".to_string(),
+//!             input_schema: serde_json::json!({"type": "object"}),
+//!             output_schema: None,
+//!             annotations: Some(ToolAnnotations::write("Shelve", false, true)),
+//!         },
+//!         Shelve,
+//!     )
+//!     .build();
+//! ```
+//!
+//! A tool can also be registered with an async closure, which gets a clone of the
+//! session's p4 handler:
+//!
+//! ```no_run
+//! # let builder = p4_mcp::mcp::MCPServer::builder();
+//! let server = builder
+//!     .register_tool(
+//!         "trigger_build",
+//!         serde_json::json!({
+//!             "type": "object",
+//!             "description": "Start a CI build of a submitted change",
+//!             "properties": {"change": {"type": "string"}},
+//!             "required": ["change"]
+//!         }),
+//!         |_p4, arguments| async move {
+//!             Ok(format!("Build queued for change {}", arguments["change"]))
+//!         },
+//!     )
+//!     .build();
+//! ```
+//!
+//! Tools added or changed this way get the same treatment as the built-in ones: the
+//! `cwd`, `dry_run` and `output_cursor` arguments, rate limits, timeouts, dry runs and
+//! the tool filter in the config file.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::future::Future;
+use std::sync::Arc;
+use tracing::warn;
+
+use super::registry::Handler;
+use super::{MCPServer, ServerContext, Tool};
+use crate::p4::P4Handler;
+
+/// Runs a tool added with [`MCPServerBuilder::tool`] or [`MCPServerBuilder::handler`].
+#[async_trait]
+pub trait ToolHandler: Send + Sync {
+    /// Run the tool with the arguments of a `tools/call`, returning its text result.
+    ///
+    /// `p4` is the session's handler, set up for the call: it runs in the call's
+    /// working directory, with its timeout, and only plans commands during a dry run.
+    async fn call(&self, p4: &mut P4Handler, arguments: serde_json::Value) -> Result<String>;
+}
+
+/// A [`ToolHandler`] that calls an async closure.
+struct ToolFn<F>(F);
+
+#[async_trait]
+impl<F, Fut> ToolHandler for ToolFn<F>
+where
+    F: Fn(P4Handler, serde_json::Value) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<String>> + Send,
+{
+    async fn call(&self, p4: &mut P4Handler, arguments: serde_json::Value) -> Result<String> {
+        (self.0)(p4.clone(), arguments).await
+    }
+}
+
+enum ToolChange {
+    Add(Tool, Arc<dyn ToolHandler>),
+    Remove(String),
+    Description(String, String),
+    InputSchema(String, serde_json::Value),
+    OutputSchema(String, Option<serde_json::Value>),
+    Handler(String, Arc<dyn ToolHandler>),
+}
+
+/// Builds an [`MCPServer`] session; see [`builder`](self).
+pub struct MCPServerBuilder {
+    context: ServerContext,
+    changes: Vec<ToolChange>,
+}
+
+impl MCPServer {
+    /// Build a session with services configured from the environment and the
+    /// built-in tools, to be customised before [`MCPServerBuilder::build`].
+    pub fn builder() -> MCPServerBuilder {
+        MCPServerBuilder {
+            context: ServerContext::from_env(),
+            changes: Vec::new(),
+        }
+    }
+}
+
+impl MCPServerBuilder {
+    /// Use the services in `context`, e.g. one read from a config file.
+    pub fn context(mut self, context: ServerContext) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Run p4 commands with `handler`, e.g. one with its own backend.
+    pub fn p4_handler(mut self, handler: P4Handler) -> Self {
+        self.context.p4_handler = handler;
+        self
+    }
+
+    /// Add a tool run by `handler`, replacing any tool with the same name.
+    pub fn tool(mut self, tool: Tool, handler: impl ToolHandler + 'static) -> Self {
+        self.changes.push(ToolChange::Add(tool, Arc::new(handler)));
+        self
+    }
+
+    /// Add a tool run by the async closure `run`, which gets a clone of the session's
+    /// p4 handler and the call's arguments. The tool is described by the `description`
+    /// of `schema`, its `inputSchema`; use [`Self::tool`] to give annotations too.
+    pub fn register_tool<F, Fut>(self, name: &str, schema: serde_json::Value, run: F) -> Self
+    where
+        F: Fn(P4Handler, serde_json::Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String>> + Send + 'static,
+    {
+        let tool = Tool {
+            name: name.to_string(),
+            description: schema["description"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            input_schema: schema,
+            output_schema: None,
+            annotations: None,
+        };
+        self.tool(tool, ToolFn(run))
+    }
+
+    /// Leave out a tool.
+    pub fn remove_tool(mut self, name: &str) -> Self {
+        self.changes.push(ToolChange::Remove(name.to_string()));
+        self
+    }
+
+    /// Describe a tool differently.
+    pub fn description(mut self, name: &str, description: impl Into<String>) -> Self {
+        self.changes.push(ToolChange::Description(
+            name.to_string(),
+            description.into(),
+        ));
+        self
+    }
+
+    /// Replace a tool's `inputSchema`. The arguments the server handles itself, such
+    /// as `cwd`, are added to it.
+    pub fn input_schema(mut self, name: &str, schema: serde_json::Value) -> Self {
+        self.changes
+            .push(ToolChange::InputSchema(name.to_string(), schema));
+        self
+    }
+
+    /// Replace a tool's `outputSchema`, or remove it with `None`.
+    pub fn output_schema(mut self, name: &str, schema: Option<serde_json::Value>) -> Self {
+        self.changes
+            .push(ToolChange::OutputSchema(name.to_string(), schema));
+        self
+    }
+
+    /// Run a built-in tool with `handler` instead. It then returns no structured
+    /// content.
+    pub fn handler(mut self, name: &str, handler: impl ToolHandler + 'static) -> Self {
+        self.changes
+            .push(ToolChange::Handler(name.to_string(), Arc::new(handler)));
+        self
+    }
+
+    pub fn build(self) -> MCPServer {
+        let mut server = MCPServer::with_context(&self.context);
+        for change in self.changes {
+            match change {
+                ToolChange::Add(mut tool, handler) => {
+                    super::add_server_properties(&mut tool, &self.context);
+                    server.tools.insert(tool, Handler::Custom(handler));
+                }
+                ToolChange::Remove(name) => server.tools.remove(&name),
+                ToolChange::Description(name, description) => {
+                    if let Some(tool) = server.tool_mut(&name) {
+                        tool.description = description;
+                    }
+                }
+                ToolChange::InputSchema(name, schema) => {
+                    if let Some(tool) = server.tool_mut(&name) {
+                        tool.input_schema = schema;
+                        super::add_server_properties(tool, &self.context);
+                    }
+                }
+                ToolChange::OutputSchema(name, schema) => {
+                    if let Some(tool) = server.tool_mut(&name) {
+                        tool.output_schema = schema;
+                    }
+                }
+                ToolChange::Handler(name, handler) => {
+                    if server.tool_mut(&name).is_some() {
+                        server.tools.set_handler(&name, Handler::Custom(handler));
+                    }
+                }
+            }
+        }
+        server
+    }
+}
+
+impl MCPServer {
+    /// A registered tool, enabled or not.
+    fn tool_mut(&mut self, name: &str) -> Option<&mut Tool> {
+        let tool = self.tools.get_mut(name);
+        if tool.is_none() {
+            warn!("Can't change unknown tool {}", name);
+        }
+        tool
+    }
+}
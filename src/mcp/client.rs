@@ -0,0 +1,114 @@
+//! Server-initiated requests to the client
+//!
+//! Sampling, roots and elicitation are requests the server sends *to* the client.
+//! They are written out through a channel, and the client's responses are routed
+//! back by the stdin reader (see [`ClientRequester::handle_response`]) without
+//! passing through the message loop, which may be waiting on them.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use super::types::*;
+
+/// How long to wait for the client (or its user) to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+type PendingRequests = HashMap<RequestId, oneshot::Sender<Result<serde_json::Value, MCPError>>>;
+
+#[derive(Clone)]
+pub struct ClientRequester {
+    tx: UnboundedSender<MCPRequest>,
+    pending: Arc<Mutex<PendingRequests>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ClientRequester {
+    /// Create a requester and the receiving end of its outgoing request channel.
+    pub fn new() -> (Self, UnboundedReceiver<MCPRequest>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Self {
+                tx,
+                pending: Arc::new(Mutex::new(HashMap::new())),
+                next_id: Arc::new(AtomicU64::new(1)),
+            },
+            rx,
+        )
+    }
+
+    /// Send a request to the client and wait for its result.
+    pub async fn request(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        // String ids keep server requests apart from any ids the client uses
+        let id = RequestId::String(format!(
+            "p4-mcp-{}",
+            self.next_id.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (done_tx, done_rx) = oneshot::channel();
+        self.pending_requests().insert(id.clone(), done_tx);
+
+        debug!("Sending {} request {} to client", method, id);
+        if self
+            .tx
+            .send(MCPRequest::new(id.clone(), method, params))
+            .is_err()
+        {
+            self.pending_requests().remove(&id);
+            return Err(anyhow::anyhow!("Client connection is closed"));
+        }
+
+        let outcome = tokio::time::timeout(REQUEST_TIMEOUT, done_rx).await;
+        self.pending_requests().remove(&id);
+        match outcome {
+            Ok(Ok(Ok(result))) => Ok(result),
+            Ok(Ok(Err(error))) => Err(anyhow::anyhow!(
+                "Client rejected {} ({}): {}",
+                method,
+                error.code,
+                error.message
+            )),
+            Ok(Err(_)) => Err(anyhow::anyhow!("{} request was dropped", method)),
+            Err(_) => Err(anyhow::anyhow!(
+                "Timed out waiting for the client to answer {}",
+                method
+            )),
+        }
+    }
+
+    /// Deliver a client response to the request waiting for it.
+    ///
+    /// Returns `false` if `line` is not a response, so the caller can handle it as a
+    /// request or notification instead.
+    pub fn handle_response(&self, line: &str) -> bool {
+        let Ok(response) = serde_json::from_str::<ClientResponse>(line) else {
+            return false;
+        };
+
+        let outcome = match (response.result, response.error) {
+            (_, Some(error)) => Err(error),
+            (Some(result), None) => Ok(result),
+            (None, None) => return false,
+        };
+
+        match self.pending_requests().remove(&response.id) {
+            Some(waiter) => {
+                let _ = waiter.send(outcome);
+            }
+            None => warn!("Ignoring response to unknown request {}", response.id),
+        }
+        true
+    }
+
+    fn pending_requests(&self) -> std::sync::MutexGuard<'_, PendingRequests> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
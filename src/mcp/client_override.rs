@@ -0,0 +1,65 @@
+//! Running a call in another client workspace
+//!
+//! One server can work in every workspace a user owns, such as a main and a release
+//! checkout of the same depot. The tools below take an optional `client` argument,
+//! passed to p4 as the global `-c` flag on every command the call runs, previews
+//! included, in place of the client from the connection settings or P4CONFIG.
+//!
+//! p4 resolves relative paths against the directory it runs in, so calls naming
+//! another client should pass depot paths, or a `cwd` inside that client's root.
+
+/// The argument naming the client a call runs in.
+pub const CLIENT_ARGUMENT: &str = "client";
+
+/// Tools that work on the files of a client workspace.
+pub const CLIENT_TOOLS: &[&str] = &[
+    "p4_status",
+    "p4_sync",
+    "p4_edit",
+    "p4_add",
+    "p4_submit",
+    "p4_revert",
+    "p4_opened",
+    "p4_diff_shelf",
+    "p4_submit_with_job",
+    "p4_workspace_health",
+    "p4_pending_work",
+    "p4_submit_preflight",
+    "p4_predict_conflicts",
+    "p4_sparse_view",
+];
+
+pub(crate) fn add_client_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            CLIENT_ARGUMENT.to_string(),
+            serde_json::json!({
+                "type": "string",
+                "description": "Client workspace to run in, instead of the server's (e.g., alice-release)"
+            }),
+        );
+    }
+}
+
+/// The client a call to `tool_name` names, or why it can't be used.
+pub(crate) fn client_override(
+    tool_name: &str,
+    arguments: &serde_json::Value,
+) -> Result<Option<String>, String> {
+    if !CLIENT_TOOLS.contains(&tool_name) {
+        return Ok(None);
+    }
+    let Some(client) = arguments.get(CLIENT_ARGUMENT).and_then(|v| v.as_str()) else {
+        return Ok(None);
+    };
+    // Also keeps the name from being read as another flag
+    let valid = !client.is_empty()
+        && !client.starts_with('-')
+        && !client.contains(|c: char| c.is_whitespace() || "/@#%*,".contains(c))
+        && !client.contains("...")
+        && !client.bytes().all(|b| b.is_ascii_digit());
+    if !valid {
+        return Err(format!("{} is not a valid client name", client));
+    }
+    Ok(Some(client.to_string()))
+}
@@ -0,0 +1,97 @@
+//! Argument completion for depot paths
+//!
+//! Prompt arguments and resource template variables that take depot paths are
+//! completed from `p4 depots`, `p4 dirs` and `p4 files` for the typed prefix.
+
+use anyhow::Result;
+
+use super::*;
+use crate::p4::P4Command;
+
+/// Most values a completion response may carry, per the MCP spec.
+const MAX_COMPLETIONS: usize = 100;
+
+/// Argument names that hold depot paths.
+const DEPOT_PATH_ARGUMENTS: &[&str] = &["path", "files", "depotPath"];
+
+impl MCPServer {
+    pub(crate) async fn complete(&mut self, params: &CompleteParams) -> Result<Completion> {
+        if !DEPOT_PATH_ARGUMENTS.contains(&params.argument.name.as_str()) {
+            return Ok(Completion::default());
+        }
+
+        // `p4://{depotPath}` templates take the path without its leading `//`
+        let value = &params.argument.value;
+        let bare = matches!(params.reference, CompletionReference::Resource { .. })
+            && !value.starts_with("//");
+        let typed = format!("//{}", value.trim_start_matches('/'));
+
+        let mut values = self.depot_path_completions(&typed).await;
+        if bare {
+            values = values
+                .into_iter()
+                .map(|v| v.trim_start_matches('/').to_string())
+                .collect();
+        }
+
+        let total = values.len();
+        values.truncate(MAX_COMPLETIONS);
+        Ok(Completion {
+            values,
+            total: Some(total),
+            has_more: Some(total > MAX_COMPLETIONS),
+        })
+    }
+
+    /// Depots, directories and files starting with `typed`. Directories end with `/`.
+    async fn depot_path_completions(&mut self, typed: &str) -> Vec<String> {
+        let Some((dir, partial)) = typed[2..].rsplit_once('/') else {
+            let depots = self
+                .p4_handler
+                .execute(P4Command::Depots)
+                .await
+                .unwrap_or_default();
+            return depots
+                .lines()
+                .filter_map(|line| line.strip_prefix("Depot "))
+                .filter_map(|rest| rest.split_whitespace().next())
+                .filter(|name| name.starts_with(&typed[2..]))
+                .map(|name| format!("//{}/", name))
+                .collect();
+        };
+
+        // `p4 dirs` and `p4 files` fail when nothing matches, which just means no completions
+        let pattern = format!("//{}/{}*", dir, partial);
+        let dirs = self
+            .p4_handler
+            .execute(P4Command::Dirs {
+                path: pattern.clone(),
+            })
+            .await
+            .unwrap_or_default();
+        let files = self
+            .p4_handler
+            .execute(P4Command::Files {
+                path: pattern,
+                max: Some(MAX_COMPLETIONS as u32 + 1),
+            })
+            .await
+            .unwrap_or_default();
+
+        let mut values: Vec<String> = dirs
+            .lines()
+            .filter(|line| line.starts_with("//"))
+            .map(|line| format!("{}/", line.trim()))
+            .chain(
+                files
+                    .lines()
+                    .filter_map(|line| line.split_once('#'))
+                    .map(|(file, _)| file.to_string()),
+            )
+            .filter(|value| value.starts_with(typed))
+            .collect();
+        values.sort();
+        values.dedup();
+        values
+    }
+}
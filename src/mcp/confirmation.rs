@@ -0,0 +1,262 @@
+//! Confirmation tokens for destructive tool calls
+//!
+//! Elicitation only helps when the client can prompt the user. Agents running
+//! unattended can instead be made to call a destructive tool twice: the first call
+//! changes nothing and returns a summary of what would happen along with a
+//! `confirmation_token`, and only a second call with the same arguments and that token
+//! runs the command. How each tool is confirmed is set in the `[confirmation]` section
+//! of the config file:
+//!
+//! ```toml
+//! [confirmation]
+//! default = "elicitation"
+//! tools = { p4_revert = "token", p4_sync = "token" }
+//! ```
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/// The argument carrying the token on the second call.
+pub const TOKEN_ARGUMENT: &str = "confirmation_token";
+
+/// How long a token can be used after it was issued.
+const TOKEN_TTL: Duration = Duration::from_secs(300);
+
+/// Tools that can need confirmation; see [`elicitation::confirmation_message`].
+pub const DESTRUCTIVE_TOOLS: &[&str] = &["p4_revert", "p4_sync", "p4_sparse_view", "p4_populate"];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfirmationMode {
+    /// Ask the user through elicitation when the client supports it.
+    #[default]
+    Elicitation,
+    /// Require a second call with a confirmation token.
+    Token,
+    /// Run without confirming.
+    None,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfirmationPolicy {
+    /// How destructive calls are confirmed unless their tool is listed in `tools`.
+    pub default: ConfirmationMode,
+    /// Per-tool overrides.
+    pub tools: HashMap<String, ConfirmationMode>,
+}
+
+impl ConfirmationPolicy {
+    pub fn mode(&self, tool_name: &str) -> ConfirmationMode {
+        self.tools.get(tool_name).copied().unwrap_or(self.default)
+    }
+}
+
+struct IssuedToken {
+    tool: String,
+    arguments: serde_json::Value,
+    issued: Instant,
+}
+
+/// Tokens a session has issued and that haven't been used yet.
+#[derive(Clone, Default)]
+pub(crate) struct TokenStore(Arc<Mutex<HashMap<String, IssuedToken>>>);
+
+impl TokenStore {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, IssuedToken>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A new token for calling `tool` with `arguments`.
+    fn issue(&self, tool: &str, arguments: &serde_json::Value) -> String {
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let mut tokens = self.lock();
+        tokens.retain(|_, issued| issued.issued.elapsed() < TOKEN_TTL);
+        tokens.insert(
+            token.clone(),
+            IssuedToken {
+                tool: tool.to_string(),
+                arguments: bound_arguments(arguments),
+                issued: Instant::now(),
+            },
+        );
+        token
+    }
+
+    /// Use up `token`. Returns `false` unless it was issued for this exact call and
+    /// hasn't expired.
+    fn redeem(&self, token: &str, tool: &str, arguments: &serde_json::Value) -> bool {
+        self.lock().remove(token).is_some_and(|issued| {
+            issued.tool == tool
+                && issued.arguments == bound_arguments(arguments)
+                && issued.issued.elapsed() < TOKEN_TTL
+        })
+    }
+}
+
+/// The arguments a token is bound to: everything but the token itself.
+fn bound_arguments(arguments: &serde_json::Value) -> serde_json::Value {
+    let mut arguments = arguments.clone();
+    if let Some(arguments) = arguments.as_object_mut() {
+        arguments.remove(TOKEN_ARGUMENT);
+    }
+    arguments
+}
+
+pub(crate) fn add_token_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            TOKEN_ARGUMENT.to_string(),
+            serde_json::json!({
+                "type": "string",
+                "description": "Token from a previous call with the same arguments, confirming the command should run"
+            }),
+        );
+    }
+}
+
+impl MCPServer {
+    /// Check the token on a call to a tool confirmed with tokens. Returns the response
+    /// for a call that must not run yet: the summary and a new token when there's no
+    /// token, or an error when the token doesn't match.
+    pub(crate) async fn check_confirmation_token(
+        &mut self,
+        id: RequestId,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+        message: &str,
+        cwd: Option<PathBuf>,
+    ) -> Option<MCPResponse> {
+        if let Some(token) = arguments.get(TOKEN_ARGUMENT) {
+            let token = token.as_str().unwrap_or_default();
+            if self.tokens.redeem(token, tool_name, arguments) {
+                info!("Confirmation token accepted for {}", tool_name);
+                return None;
+            }
+            return Some(MCPResponse::error(
+                id,
+                INVALID_PARAMS,
+                format!(
+                    "Invalid or expired {}; call {} without it for a new one",
+                    TOKEN_ARGUMENT, tool_name
+                ),
+            ));
+        }
+
+        let previous_cwd = self.p4_handler.set_working_dir(cwd);
+        let previous_call = self
+            .p4_handler
+            .set_call_info(Some(call_info(&id, tool_name)));
+        let impact = self.impact_preview(tool_name, arguments).await;
+        self.p4_handler.set_working_dir(previous_cwd);
+        self.p4_handler.set_call_info(previous_call);
+
+        let token = self.tokens.issue(tool_name, arguments);
+        let mut text = format!("Confirmation required: {}\n", message);
+        match &impact {
+            Some((_, Ok(preview))) if preview.trim().is_empty() => {
+                text.push_str("\nNo files would be affected.\n")
+            }
+            Some((heading, Ok(preview))) => {
+                text.push_str(&format!("\n{}:\n", heading));
+                text.push_str(preview);
+                if !preview.ends_with('\n') {
+                    text.push('\n');
+                }
+            }
+            Some((_, Err(e))) => {
+                text.push_str(&format!("\nCould not preview the changes: {}\n", e))
+            }
+            None => {}
+        }
+        text.push_str(&format!(
+            "\nNothing was changed. To proceed, call {} again with the same arguments and \"{}\": \"{}\" within {} minutes.",
+            tool_name,
+            TOKEN_ARGUMENT,
+            token,
+            TOKEN_TTL.as_secs() / 60
+        ));
+
+        Some(MCPResponse::CallToolResult {
+            id,
+            result: CallToolResult {
+                content: vec![ToolContent::Text { text }],
+                structured_content: Some(serde_json::json!({
+                    "confirmationRequired": true,
+                    "summary": message,
+                    "affected": impact.and_then(|(_, preview)| preview.ok()),
+                    "confirmationToken": token,
+                    "expiresInSecs": TOKEN_TTL.as_secs(),
+                })),
+                meta: None,
+            },
+        })
+    }
+
+    /// What the tool call would change, under a heading: the files from p4's `-n`
+    /// preview of the command, or for a new client view, the view it replaces.
+    async fn impact_preview(
+        &mut self,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+    ) -> Option<(&'static str, anyhow::Result<String>)> {
+        let command = match tool_name {
+            "p4_sparse_view" => {
+                let paths: Vec<String> = arguments
+                    .get("paths")
+                    .and_then(|v| v.as_array())
+                    .map(|arr| {
+                        arr.iter()
+                            .filter_map(|v| v.as_str())
+                            .map(|s| s.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let preview = self.p4_handler.sparse_view_preview(&paths).await;
+                return Some(("Client view", preview));
+            }
+            "p4_revert" => crate::p4::P4Command::RevertPreview {
+                files: arguments
+                    .get("files")
+                    .and_then(|v| v.as_array())
+                    .map(|arr| {
+                        arr.iter()
+                            .filter_map(|v| v.as_str())
+                            .map(|s| s.to_string())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+                wipe: arguments
+                    .get("wipe")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false),
+            },
+            "p4_sync" => crate::p4::P4Command::ForceSyncPreview {
+                path: arguments
+                    .get("path")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("...")
+                    .to_string(),
+            },
+            "p4_populate" => crate::p4::P4Command::PopulatePreview {
+                from: arguments
+                    .get("from")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                to: arguments
+                    .get("to")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+            },
+            _ => return None,
+        };
+        Some(("Affected files", self.p4_handler.execute(command).await))
+    }
+}
@@ -0,0 +1,99 @@
+//! Dry runs of tool calls
+//!
+//! With `--dry-run`, or `dry_run: true` on a single call, a tool reports the p4
+//! commands it would run instead of changing anything: each command line, the
+//! directory it runs in and the paths it names. Read-only commands still run, so a
+//! composite tool can work out its later steps; see [`crate::p4::plan`].
+
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use super::*;
+use crate::p4::CommandPlan;
+
+pub(crate) fn add_dry_run_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            "dry_run".to_string(),
+            serde_json::json!({
+                "type": "boolean",
+                "description": "Return the p4 commands this call would run instead of running them",
+                "default": false
+            }),
+        );
+    }
+}
+
+impl MCPServer {
+    /// Whether a call with `arguments` should only be planned.
+    pub(crate) fn is_dry_run(&self, arguments: &serde_json::Value) -> bool {
+        self.dry_run
+            || arguments
+                .get("dry_run")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false)
+    }
+
+    /// Run a tool with its changes recorded rather than made, and report the plan.
+    pub(crate) async fn dry_run_tool(
+        &mut self,
+        id: &RequestId,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+        cwd: Option<PathBuf>,
+    ) -> String {
+        let plan = CommandPlan::new();
+        let dir = cwd
+            .clone()
+            .or_else(|| self.p4_handler.workspace_root().map(Path::to_path_buf));
+
+        let result = if tool_name.starts_with("swarm_") && !self.is_read_only(tool_name) {
+            Err(anyhow::anyhow!(
+                "{} calls the Swarm API, which dry runs don't cover; nothing was sent",
+                tool_name
+            ))
+        } else {
+            let previous_cwd = self.p4_handler.set_working_dir(cwd);
+            let previous_timeout = self
+                .p4_handler
+                .set_timeout(self.p4_handler.timeout_for(tool_name).into());
+            let previous_plan = self.p4_handler.set_command_plan(Some(plan.clone()));
+            let previous_call = self
+                .p4_handler
+                .set_call_info(Some(call_info(id, tool_name)));
+            let result = self.run_tool(tool_name, arguments).await;
+            self.p4_handler.set_working_dir(previous_cwd);
+            self.p4_handler.set_timeout(previous_timeout);
+            self.p4_handler.set_command_plan(previous_plan);
+            self.p4_handler.set_call_info(previous_call);
+            result
+        };
+
+        let commands = plan.commands();
+        info!(
+            "Dry run of {}: {} command(s), {} not run",
+            tool_name,
+            commands.len(),
+            commands.iter().filter(|command| !command.executed).count()
+        );
+        let mut report = serde_json::json!({
+            "dryRun": true,
+            "tool": tool_name,
+            "cwd": dir,
+            "commands": commands,
+        });
+        if let Err(e) = result {
+            // Typically a later step needing the output of a command that didn't run
+            report["stoppedEarly"] = format!("{:#}", e).into();
+        }
+        serde_json::to_string_pretty(&report).unwrap_or_default()
+    }
+
+    fn is_read_only(&self, tool_name: &str) -> bool {
+        self.tools
+            .get(tool_name)
+            .and_then(|tool| tool.annotations.as_ref())
+            .and_then(|annotations| annotations.read_only_hint)
+            .unwrap_or(false)
+    }
+}
@@ -0,0 +1,112 @@
+//! Confirmation of destructive tool calls through MCP elicitation
+//!
+//! When the client supports elicitation, tool calls that discard workspace state or
+//! submit files on the server ask the user to confirm with `elicitation/create` before
+//! the p4 command runs.
+
+use anyhow::Result;
+use tracing::info;
+
+use super::*;
+
+fn string_list(arguments: &serde_json::Value, key: &str) -> String {
+    arguments
+        .get(key)
+        .and_then(|v| v.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|v| v.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default()
+}
+
+fn flag(arguments: &serde_json::Value, key: &str) -> bool {
+    arguments
+        .get(key)
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// What a tool call is about to destroy, or `None` if it needs no confirmation.
+pub fn confirmation_message(tool_name: &str, arguments: &serde_json::Value) -> Option<String> {
+    match tool_name {
+        "p4_revert" if flag(arguments, "wipe") => Some(format!(
+            "Revert {} and delete any files opened for add from disk?",
+            string_list(arguments, "files")
+        )),
+        "p4_sync" if flag(arguments, "force") => Some(format!(
+            "Force sync {}? Writable files in the workspace will be overwritten.",
+            arguments
+                .get("path")
+                .and_then(|v| v.as_str())
+                .unwrap_or("...")
+        )),
+        "p4_sparse_view" if flag(arguments, "apply") => Some(format!(
+            "Replace the current client view with a view of {}? Files outside it are removed on the next sync.",
+            string_list(arguments, "paths")
+        )),
+        "p4_populate" => Some(format!(
+            "Branch {} into {} and submit the new files on the server?",
+            arguments
+                .get("from")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default(),
+            arguments
+                .get("to")
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+        )),
+        _ => None,
+    }
+}
+
+impl MCPServer {
+    /// Ask the user to confirm `message`. Returns `true` only on an explicit confirmation.
+    ///
+    /// Clients without elicitation aren't asked; they rely on the tool annotations instead.
+    pub(crate) async fn confirm(&self, message: &str) -> Result<bool> {
+        let Some(client) = self.client.as_ref().filter(|_| self.elicitation_supported) else {
+            return Ok(true);
+        };
+
+        let result = client
+            .request(
+                "elicitation/create",
+                serde_json::json!({
+                    "message": message,
+                    "requestedSchema": {
+                        "type": "object",
+                        "properties": {
+                            "confirm": {
+                                "type": "boolean",
+                                "title": "Run this command",
+                                "description": message
+                            }
+                        },
+                        "required": ["confirm"]
+                    }
+                }),
+            )
+            .await?;
+
+        let action = result
+            .get("action")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let confirmed = action == "accept"
+            && result
+                .get("content")
+                .and_then(|content| content.get("confirm"))
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+        info!(
+            "Confirmation {}: {}",
+            if confirmed { "given" } else { "refused" },
+            message
+        );
+        Ok(confirmed)
+    }
+}
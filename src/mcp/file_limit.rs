@@ -0,0 +1,126 @@
+//! Guarding against calls that touch too many files
+//!
+//! One stray `//...` can open or revert a whole depot. Calls to the tools below that
+//! name more than `max_files` files are refused unless they pass `allow_many_files`.
+//! Wildcard paths are expanded with p4's `-n` preview of the command first, so
+//! `//depot/main/...` counts as every file under it.
+
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+use super::*;
+
+/// The argument that lets a call touch more files than the limit.
+pub const OVERRIDE_ARGUMENT: &str = "allow_many_files";
+
+/// How many files a call may touch unless configured otherwise.
+pub const DEFAULT_MAX_FILES: usize = 1000;
+
+/// Tools whose calls are limited.
+pub const FILE_LIMITED_TOOLS: &[&str] = &["p4_edit", "p4_add", "p4_revert"];
+
+pub(crate) fn add_override_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            OVERRIDE_ARGUMENT.to_string(),
+            serde_json::json!({
+                "type": "boolean",
+                "description": "Allow the call to touch more files than the server's limit",
+                "default": false
+            }),
+        );
+    }
+}
+
+fn has_wildcard(path: &str) -> bool {
+    path.contains("...") || path.contains('*')
+}
+
+/// The number of files in `-n` preview output, whose lines name a revision, e.g.
+/// `//depot/a.c#3 - opened for edit`. Warnings such as `//depot/x/... - file(s) not
+/// on client.` don't.
+fn count_files(preview: &str) -> usize {
+    preview
+        .lines()
+        .filter(|line| {
+            line.split_once(" - ")
+                .is_some_and(|(file, _)| file.contains('#'))
+        })
+        .count()
+}
+
+impl MCPServer {
+    /// Refuse a call that would touch more files than the limit. Returns the error for
+    /// a call that must not run.
+    pub(crate) async fn check_file_count(
+        &mut self,
+        id: RequestId,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+        cwd: Option<PathBuf>,
+    ) -> Option<MCPResponse> {
+        if self.max_files == 0
+            || !FILE_LIMITED_TOOLS.contains(&tool_name)
+            || arguments
+                .get(OVERRIDE_ARGUMENT)
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false)
+        {
+            return None;
+        }
+        let files: Vec<String> = arguments
+            .get("files")
+            .and_then(|v| v.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|v| v.as_str())
+                    .map(|s| s.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut count = files.len();
+        if count <= self.max_files && files.iter().any(|file| has_wildcard(file)) {
+            let command = match tool_name {
+                "p4_edit" => P4Command::EditPreview { files },
+                "p4_add" => P4Command::AddPreview { files },
+                _ => P4Command::RevertPreview { files, wipe: false },
+            };
+            let previous_cwd = self.p4_handler.set_working_dir(cwd);
+            let previous_call = self
+                .p4_handler
+                .set_call_info(Some(call_info(&id, tool_name)));
+            let preview = self.p4_handler.execute(command).await;
+            self.p4_handler.set_working_dir(previous_cwd);
+            self.p4_handler.set_call_info(previous_call);
+            match preview {
+                Ok(preview) => count = count_files(&preview),
+                // The call itself will report the problem
+                Err(e) => debug!("Could not count the files {} would touch: {}", tool_name, e),
+            }
+        }
+        if count <= self.max_files {
+            return None;
+        }
+
+        warn!(
+            "Refused {} call touching {} files, over the limit of {}",
+            tool_name, count, self.max_files
+        );
+        Some(MCPResponse::Error {
+            id,
+            error: MCPError {
+                code: INVALID_PARAMS,
+                message: format!(
+                    "{} would touch {} files, more than the limit of {}; narrow the paths, or pass \"{}\": true if that many is intended",
+                    tool_name, count, self.max_files, OVERRIDE_ARGUMENT
+                ),
+                data: Some(serde_json::json!({
+                    "tooManyFiles": true,
+                    "files": count,
+                    "maxFiles": self.max_files,
+                })),
+            },
+        })
+    }
+}
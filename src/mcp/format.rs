@@ -0,0 +1,266 @@
+//! Markdown tool results
+//!
+//! Tool results are p4's own text by default, which chat clients show as one run-on
+//! paragraph with stray emphasis wherever p4 prints `*pending*`. With
+//! `format = "markdown"` in the config file, or `format: "markdown"` on a single call,
+//! results are rendered as Markdown instead:
+//!
+//! - `Change ... by user@client 'description'` lines become a table of changes
+//! - `Field: value` lines, as `p4 info` prints them, become a table of fields
+//! - file lines (`//depot/a.c#3 - edit default change (text)`) become a bulleted list
+//! - each file of a diff gets a heading with its revision and language, and a fenced
+//!   `diff` block; see [`diff_sections`]
+//! - JSON results go in a fenced `json` block
+//!
+//! Anything else is kept as text, escaped so Markdown leaves it alone.
+
+use regex::Regex;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+use super::*;
+use crate::p4::diff::{self, FileDiff};
+
+/// How tool results are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResponseFormat {
+    /// p4's output as it prints it.
+    #[default]
+    Text,
+    Markdown,
+}
+
+static CHANGE_LINE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^Change (\d+) on (\S+(?: \d\d:\d\d:\d\d)?) by ([^@\s]+)@(\S+)(?: \*(\w+)\*)?(?: '(.*?)'?)?$")
+        .unwrap()
+});
+
+static FIELD_LINE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([A-Z][A-Za-z0-9 ]{0,30}): (.+)$").unwrap());
+
+pub(crate) fn add_format_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            "format".to_string(),
+            serde_json::json!({
+                "type": "string",
+                "enum": ["text", "markdown"],
+                "description": "Return the result as p4 prints it, or rendered as Markdown (defaults to the server's setting)"
+            }),
+        );
+    }
+}
+
+impl MCPServer {
+    /// The format a call with `arguments` asks for, or the server's.
+    pub(crate) fn response_format(&self, arguments: &serde_json::Value) -> ResponseFormat {
+        arguments
+            .get("format")
+            .and_then(|v| ResponseFormat::deserialize(v).ok())
+            .unwrap_or(self.format)
+    }
+
+    /// `text` in the format a call with `arguments` asks for.
+    pub(crate) fn format_result(&self, arguments: &serde_json::Value, text: String) -> String {
+        match self.response_format(arguments) {
+            ResponseFormat::Text => text,
+            ResponseFormat::Markdown => markdown(&text),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum Kind {
+    Blank,
+    Change,
+    Field,
+    File,
+    Text,
+}
+
+fn kind(line: &str) -> Kind {
+    if line.trim().is_empty() {
+        Kind::Blank
+    } else if CHANGE_LINE.is_match(line) {
+        Kind::Change
+    } else if line.starts_with("//") {
+        Kind::File
+    } else if FIELD_LINE.is_match(line) {
+        Kind::Field
+    } else {
+        Kind::Text
+    }
+}
+
+/// Render p4 output as Markdown; see [`format`](self).
+pub fn markdown(text: &str) -> String {
+    if text.trim().is_empty() {
+        return text.to_string();
+    }
+    if serde_json::from_str::<serde_json::Value>(text.trim())
+        .is_ok_and(|value| value.is_object() || value.is_array())
+    {
+        return fenced(text.trim_end(), "json") + "\n";
+    }
+
+    let diff = diff::split(text);
+    let mut blocks = text_blocks(&diff.preamble);
+    blocks.extend(diff.files.iter().map(file_section));
+    blocks.join("\n\n") + "\n"
+}
+
+/// Diff output with a heading for each file, naming its revision, what it's compared
+/// with and its language, and its changes in a fenced `diff` block. What comes
+/// before the first file is kept as it is.
+pub fn diff_sections(text: &str) -> String {
+    let diff = diff::split(text);
+    if diff.files.is_empty() {
+        return fenced(text.trim_end(), "diff");
+    }
+    let mut blocks: Vec<String> = (!diff.preamble.is_empty())
+        .then(|| diff.preamble.clone())
+        .into_iter()
+        .collect();
+    blocks.extend(diff.files.iter().map(file_section));
+    blocks.join("\n\n")
+}
+
+/// Markdown blocks for output other than diffs.
+fn text_blocks(text: &str) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut lines = text.lines().peekable();
+    while let Some(line) = lines.next() {
+        let kind = kind(line);
+        if kind == Kind::Blank {
+            continue;
+        }
+        let mut group = vec![line];
+        while let Some(line) = lines.next_if(|line| self::kind(line) == kind) {
+            group.push(line);
+        }
+        blocks.push(match kind {
+            Kind::Change => changes_table(&group),
+            Kind::Field if group.len() > 1 => fields_table(&group),
+            Kind::File => group
+                .iter()
+                .map(|line| file_item(line))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            _ => group
+                .iter()
+                .map(|line| escape(line))
+                .collect::<Vec<_>>()
+                .join("  \n"),
+        });
+    }
+    blocks
+}
+
+fn file_section(file: &FileDiff) -> String {
+    let mut details = Vec::new();
+    if let Some(revision) = &file.revision {
+        details.push(format!("revision {}", revision));
+    }
+    if let Some(against) = &file.against {
+        details.push(format!("against `{}`", against));
+    }
+    if let Some(file_type) = &file.file_type {
+        details.push(format!("file type {}", escape(file_type)));
+    }
+    if let Some(status) = &file.status {
+        details.push(escape(status));
+    }
+    if let Some(language) = file.language() {
+        details.push(format!("language {}", language));
+    }
+
+    let mut section = format!("#### `{}`", file.path);
+    if !details.is_empty() {
+        section.push_str("\n\n");
+        section.push_str(&capitalize(&details.join(", ")));
+    }
+    if !file.body.is_empty() {
+        section.push_str("\n\n");
+        section.push_str(&fenced(&file.body, "diff"));
+    }
+    section
+}
+
+fn capitalize(text: &str) -> String {
+    let mut chars = text.chars();
+    chars.next().map_or_else(String::new, |first| {
+        first.to_uppercase().chain(chars).collect()
+    })
+}
+
+fn file_item(line: &str) -> String {
+    match line.split_once(" - ") {
+        Some((path, rest)) => format!("- `{}` {}", path, escape(rest)),
+        None => format!("- `{}`", line),
+    }
+}
+
+fn changes_table(lines: &[&str]) -> String {
+    let rows: Vec<_> = lines
+        .iter()
+        .filter_map(|line| CHANGE_LINE.captures(line))
+        .collect();
+    let status = rows.iter().any(|row| row.get(5).is_some());
+    let mut table = String::from("| Change | Date | User | Client |");
+    table.push_str(if status { " Status |" } else { "" });
+    table.push_str(" Description |\n|---|---|---|---|");
+    table.push_str(if status { "---|" } else { "" });
+    table.push_str("---|");
+    for row in rows {
+        let cell = |i: usize| row.get(i).map_or("", |m| m.as_str());
+        table.push_str(&format!(
+            "\n| {} | {} | {} | {} |",
+            cell(1),
+            cell(2),
+            escape(cell(3)),
+            escape(cell(4))
+        ));
+        if status {
+            table.push_str(&format!(" {} |", cell(5)));
+        }
+        table.push_str(&format!(" {} |", escape(cell(6))));
+    }
+    table
+}
+
+fn fields_table(lines: &[&str]) -> String {
+    let mut table = String::from("| Field | Value |\n|---|---|");
+    for captures in lines.iter().filter_map(|line| FIELD_LINE.captures(line)) {
+        table.push_str(&format!(
+            "\n| {} | {} |",
+            escape(&captures[1]),
+            escape(&captures[2])
+        ));
+    }
+    table
+}
+
+/// `body` in a fenced code block, with a fence longer than any run of backticks in it.
+fn fenced(body: &str, language: &str) -> String {
+    let mut fence = "```".to_string();
+    while body.contains(&fence) {
+        fence.push('`');
+    }
+    format!("{fence}{language}\n{body}\n{fence}")
+}
+
+/// `text` with the characters Markdown would act on escaped.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '|' | '<' | '>' | '[' | ']' | '#'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,232 @@
+//! Expanding glob patterns in file arguments
+//!
+//! Models often pass shell globs such as `src/**/*.cpp`, which p4 doesn't understand:
+//! its wildcards are `*` within a directory and `...` across directories. Before a
+//! call runs, each local entry of its `files` or `paths` argument that uses glob
+//! syntax p4 lacks (`**`, `?`, `[...]`) is replaced by the files matching it, found
+//! in the directory the call runs in. `p4_add` takes names literally, so a `*` in its
+//! files is expanded too.
+//!
+//! A pattern that matches nothing, or more than [`MAX_GLOB_MATCHES`] files, fails the
+//! call. A path that names an existing file is never treated as a pattern.
+//!
+//! Only the directory a pattern's wildcards start in is searched, so it has to be
+//! within the client's roots and the sandbox, like any other path; `/**/*.h` or
+//! `../../**` is refused before anything is read. A search that looks through more
+//! than [`MAX_GLOB_ENTRIES`] files and directories fails too, however few match.
+
+use std::path::{Path, PathBuf};
+
+use super::roots::{is_within, normalize, outside_roots};
+use super::MCPServer;
+
+/// The most files one pattern may expand to.
+pub const MAX_GLOB_MATCHES: usize = 1000;
+
+/// The most files and directories one pattern may look through.
+pub const MAX_GLOB_ENTRIES: usize = 100_000;
+
+/// Whether `path` is a pattern to expand before calling `tool`.
+pub(crate) fn is_glob(tool: &str, path: &str) -> bool {
+    // Checked first, as calls can name thousands of paths without any
+    if !path.bytes().any(|b| matches!(b, b'*' | b'?' | b'[')) {
+        return false;
+    }
+    if path.starts_with("//") || path.contains("...") {
+        return false;
+    }
+    path.contains("**") || path.contains(['?', '[']) || tool == "p4_add"
+}
+
+impl MCPServer {
+    /// Expand the patterns in a call's file arguments against `cwd`, or the workspace
+    /// root, or describe why one can't be.
+    pub(crate) async fn expand_globs(
+        &self,
+        tool: &str,
+        arguments: &mut serde_json::Value,
+        cwd: Option<&Path>,
+    ) -> Result<(), String> {
+        let has_glob = ["files", "paths"].iter().any(|key| {
+            arguments[key].as_array().is_some_and(|values| {
+                values
+                    .iter()
+                    .any(|v| v.as_str().is_some_and(|path| is_glob(tool, path)))
+            })
+        });
+        if !has_glob {
+            return Ok(());
+        }
+        let dir = match cwd {
+            Some(cwd) => cwd.to_path_buf(),
+            None => self.base_dir().await.map_err(|e| e.to_string())?,
+        };
+        let roots = self.client_roots.read().await.clone();
+        let sandbox = self.p4_handler.path_sandbox();
+        let searchable = |base: &Path| -> Result<(), String> {
+            if let Some(roots) = roots.as_deref() {
+                if !is_within(base, roots) {
+                    return Err(outside_roots(&base.to_string_lossy(), roots));
+                }
+            }
+            sandbox
+                .check(&base.to_string_lossy(), &dir)
+                .map_err(|e| e.to_string())
+        };
+        expand_arguments(tool, arguments, &dir, &searchable)
+    }
+}
+
+/// Replace the patterns in the `files` and `paths` arguments of a call to `tool` with
+/// the files they match under `dir`, or describe why one can't be. `searchable` is
+/// asked about the directory each pattern would search first.
+pub(crate) fn expand_arguments(
+    tool: &str,
+    arguments: &mut serde_json::Value,
+    dir: &Path,
+    searchable: &dyn Fn(&Path) -> Result<(), String>,
+) -> Result<(), String> {
+    for key in ["files", "paths"] {
+        let Some(values) = arguments.get_mut(key).and_then(|v| v.as_array_mut()) else {
+            continue;
+        };
+        let mut expanded = Vec::with_capacity(values.len());
+        for value in values.drain(..) {
+            match value.as_str() {
+                Some(path) if is_glob(tool, path) && !dir.join(path).exists() => {
+                    expanded.extend(expand(path, dir, searchable)?.into_iter().map(Into::into));
+                }
+                _ => expanded.push(value),
+            }
+        }
+        *values = expanded;
+    }
+    Ok(())
+}
+
+/// The files matching `pattern`, relative to `dir` if the pattern is.
+fn expand(
+    pattern: &str,
+    dir: &Path,
+    searchable: &dyn Fn(&Path) -> Result<(), String>,
+) -> Result<Vec<String>, String> {
+    let pattern = if cfg!(windows) {
+        pattern.to_string()
+    } else {
+        pattern.replace('\\', "/")
+    };
+
+    // Split off the directories before the first wildcard, which are searched from
+    let components: Vec<&str> = pattern.split(['/', '\\']).collect();
+    let literal = components
+        .iter()
+        .position(|component| component.contains(['*', '?', '[']))
+        .unwrap_or(components.len());
+    let prefix = components[..literal].join("/");
+    let rest = components[literal..].join("/");
+    let base = dir.join(if prefix.is_empty() && literal > 0 {
+        "/"
+    } else {
+        &prefix
+    });
+    searchable(&normalize(&base))
+        .map_err(|reason| format!("Can't expand {}: {}", pattern, reason))?;
+
+    let matcher =
+        glob::Pattern::new(&rest).map_err(|e| format!("Invalid pattern {}: {}", pattern, e))?;
+    let mut search = Search {
+        matcher,
+        options: glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        },
+        max_depth: if rest.contains("**") {
+            usize::MAX
+        } else {
+            components.len() - literal
+        },
+        visited: 0,
+        found: Vec::new(),
+    };
+    search
+        .walk(&base, &mut PathBuf::new(), 1)
+        .map_err(|limit| match limit {
+            Limit::Entries => format!(
+                "{} looks through more than {} files and directories; use a narrower \
+             pattern, or a p4 wildcard such as src/... to let p4 find them",
+                pattern, MAX_GLOB_ENTRIES
+            ),
+            Limit::Matches => format!(
+                "{} matches more than {} files; use a narrower pattern, or a p4 \
+             wildcard such as src/... to let p4 find them",
+                pattern, MAX_GLOB_MATCHES
+            ),
+        })?;
+
+    if search.found.is_empty() {
+        return Err(format!("No files match {}", pattern));
+    }
+    let start = if Path::new(&pattern).is_relative() {
+        PathBuf::from(&prefix)
+    } else {
+        base
+    };
+    Ok(search
+        .found
+        .into_iter()
+        .map(|relative| start.join(relative).to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Which limit a search ran into.
+enum Limit {
+    Entries,
+    Matches,
+}
+
+/// A walk of the directory a pattern's wildcards start in.
+struct Search {
+    /// The part of the pattern from the first wildcard on.
+    matcher: glob::Pattern,
+    options: glob::MatchOptions,
+    /// How many directories deep matches can be, counting the files themselves.
+    max_depth: usize,
+    visited: usize,
+    /// Matching files, relative to the directory searched.
+    found: Vec<PathBuf>,
+}
+
+impl Search {
+    /// Look through `dir`, which is `relative` below the directory searched and
+    /// `depth` levels down, in name order.
+    fn walk(&mut self, dir: &Path, relative: &mut PathBuf, depth: usize) -> Result<(), Limit> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Ok(());
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        self.visited += entries.len();
+        if self.visited > MAX_GLOB_ENTRIES {
+            return Err(Limit::Entries);
+        }
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            relative.push(entry.file_name());
+            let path = entry.path();
+            // Symlinked directories aren't followed, so links can't lead the search
+            // out of the directory or round in circles
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                if depth < self.max_depth {
+                    self.walk(&path, relative, depth + 1)?;
+                }
+            } else if path.is_file() && self.matcher.matches_path_with(relative, self.options) {
+                if self.found.len() == MAX_GLOB_MATCHES {
+                    return Err(Limit::Matches);
+                }
+                self.found.push(relative.clone());
+            }
+            relative.pop();
+        }
+        Ok(())
+    }
+}
@@ -1,200 +1,798 @@
 use anyhow::Result;
 use std::collections::HashMap;
-use tracing::{debug, info};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, Instrument};
 
-use crate::p4::P4Command;
+use crate::metrics::Metrics;
+use crate::p4::{CallInfo, CommandTimeout, ExecutionLog, P4Command};
+use crate::reload::Live;
 
+pub mod aliases;
+pub mod builder;
+pub mod client;
+pub mod client_override;
+pub mod completion;
+pub mod confirmation;
+pub mod dry_run;
+pub mod elicitation;
+pub mod file_limit;
+pub mod format;
+pub mod globs;
+pub mod pagination;
+pub mod progress;
+pub mod prompts;
+pub mod rate_limit;
+pub mod registry;
+pub mod resources;
+pub mod roots;
+pub mod sampling;
+pub mod stats;
+pub mod structured;
+pub mod subscriptions;
+pub mod tool_filter;
+pub mod truncation;
 pub mod types;
+pub mod validation;
 
+pub use builder::{MCPServerBuilder, ToolHandler};
+pub use client::ClientRequester;
+pub use confirmation::{ConfirmationMode, ConfirmationPolicy};
+pub use format::ResponseFormat;
+pub use rate_limit::{RateLimit, RateLimiter, RateLimits};
+pub use tool_filter::ToolFilter;
+pub use truncation::{ResponseLimits, ResponsePolicy};
 pub use types::*;
 
+/// Progress of the MCP initialization handshake with the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeState {
+    /// No `initialize` request has been received yet.
+    Uninitialized,
+    /// `initialize` was answered; waiting for `notifications/initialized`.
+    Initializing,
+    Ready,
+}
+
+/// The error for a call refused by a rate limit.
+fn rate_limited_response(id: RequestId, limited: rate_limit::RateLimited) -> MCPResponse {
+    warn!("{}", limited);
+    MCPResponse::Error {
+        id,
+        error: MCPError {
+            code: RATE_LIMITED,
+            message: limited.to_string(),
+            data: Some(serde_json::json!({
+                "rateLimited": true,
+                "tool": limited.tool,
+                "calls": limited.limit.calls,
+                "perSecs": limited.limit.per_secs,
+                "retryAfterSecs": limited.retry_after.as_secs_f64().ceil() as u64,
+            })),
+        },
+    }
+}
+
+/// Who p4 commands run for, as recorded in the audit log.
+fn call_info(id: &RequestId, tool_name: &str) -> CallInfo {
+    CallInfo {
+        request_id: id.to_string(),
+        tool: tool_name.to_string(),
+    }
+}
+
+/// Add the arguments the server handles itself to `tool`'s input schema.
+fn add_server_properties(tool: &mut Tool, context: &ServerContext) {
+    // p4 resolves relative paths and finds P4CONFIG files from where it runs
+    roots::add_cwd_property(&mut tool.input_schema);
+    if client_override::CLIENT_TOOLS.contains(&tool.name.as_str()) {
+        client_override::add_client_property(&mut tool.input_schema);
+    }
+    truncation::add_output_cursor_property(&mut tool.input_schema);
+    dry_run::add_dry_run_property(&mut tool.input_schema);
+    format::add_format_property(&mut tool.input_schema);
+    if confirmation::DESTRUCTIVE_TOOLS.contains(&tool.name.as_str())
+        && context.confirmation.mode(&tool.name) == ConfirmationMode::Token
+    {
+        confirmation::add_token_property(&mut tool.input_schema);
+    }
+    if context.max_files > 0 && file_limit::FILE_LIMITED_TOOLS.contains(&tool.name.as_str()) {
+        file_limit::add_override_property(&mut tool.input_schema);
+    }
+}
+
+/// Services shared by every session a process serves: the p4 execution layer and the
+/// Swarm client.
+///
+/// Cloning is cheap. Each [`MCPServer`] session works on its own clone, so per-session
+/// settings (like where p4 activity is logged) stay with the session.
+#[derive(Clone)]
+pub struct ServerContext {
+    p4_handler: crate::p4::P4Handler,
+    swarm: Option<Arc<crate::swarm::SwarmClient>>,
+    max_response_bytes: usize,
+    /// Limits for particular tools; see [`truncation`].
+    response_limits: Arc<ResponseLimits>,
+    /// Which tools sessions expose; see [`crate::reload`].
+    tool_filter: Live<ToolFilter>,
+    /// Tools defined in the config file that run other tools; see [`aliases`].
+    aliases: Arc<aliases::Aliases>,
+    /// Tells sessions the config was reloaded, so they can check their tools.
+    reloads: Arc<tokio::sync::watch::Sender<()>>,
+    /// How destructive tool calls are confirmed.
+    confirmation: Arc<ConfirmationPolicy>,
+    /// Plan every tool call instead of running it; see [`dry_run`].
+    dry_run: bool,
+    /// The most files one call may touch; see [`file_limit`].
+    max_files: usize,
+    /// How tool results are written unless a call asks otherwise; see [`format`].
+    format: ResponseFormat,
+    /// How often tools may run, counted across every session.
+    rate_limiter: Arc<RateLimiter>,
+    /// Recorded by every session; see [`crate::metrics`].
+    metrics: Arc<Metrics>,
+    /// Set once the server is shutting down; see [`ServerContext::shutdown`].
+    shutdown: Arc<tokio::sync::watch::Sender<bool>>,
+    /// How long sessions finish their tool calls for when shutting down.
+    shutdown_timeout: Duration,
+}
+
+impl ServerContext {
+    /// Configure the shared services from the environment (`P4_MOCK_MODE`, `SWARM_URL`, ...).
+    pub fn from_env() -> Self {
+        let mut p4_handler = crate::p4::P4Handler::new();
+        let metrics = Arc::new(Metrics::new());
+        p4_handler.set_metrics(Some(metrics.clone()));
+        Self {
+            swarm: crate::swarm::SwarmClient::from_env(p4_handler.mock_mode()).map(Arc::new),
+            p4_handler,
+            max_response_bytes: truncation::DEFAULT_MAX_RESPONSE_BYTES,
+            response_limits: Arc::default(),
+            tool_filter: Live::default(),
+            aliases: Arc::default(),
+            reloads: Arc::new(tokio::sync::watch::Sender::new(())),
+            shutdown: Arc::new(tokio::sync::watch::Sender::new(false)),
+            shutdown_timeout: Duration::from_secs(crate::transport::DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+            confirmation: Arc::default(),
+            dry_run: false,
+            max_files: file_limit::DEFAULT_MAX_FILES,
+            format: ResponseFormat::default(),
+            rate_limiter: Arc::default(),
+            metrics,
+        }
+    }
+
+    /// Configure the shared services from the environment, with `config` applied on top.
+    /// Fails if the audit log or a recording can't be opened.
+    pub fn from_config(config: &crate::config::Config) -> Result<Self> {
+        let mut context = Self::from_env();
+        if config.mock && !context.p4_handler.mock_mode().set(true) {
+            // Mock mode brings a placeholder Swarm client
+            context.swarm =
+                crate::swarm::SwarmClient::from_env(context.p4_handler.mock_mode()).map(Arc::new);
+        }
+        context
+            .p4_handler
+            .set_allow_backend_switch(config.allow_backend_switch);
+        if let Some(p4_bin) = &config.p4_bin {
+            context.p4_handler.set_p4_bin(p4_bin.clone());
+        }
+        context.p4_handler.set_broker(config.broker.clone());
+        context.p4_handler.set_connection(config.p4.clone());
+        context.p4_handler.set_submit_policy(config.submit.clone());
+        context
+            .p4_handler
+            .set_workspace_templates(config.workspace_templates.clone());
+        context
+            .p4_handler
+            .set_allow_force_sync(config.allow_force_sync);
+        context
+            .p4_handler
+            .set_environment_policy(config.environment.clone());
+        context.p4_handler.set_path_sandbox(config.sandbox.clone());
+        context
+            .p4_handler
+            .set_workspace_root(config.workspace_root.clone());
+        context.p4_handler.set_timeouts(config.timeouts.clone());
+        context.p4_handler.set_retry_policy(config.retry.clone());
+        context.p4_handler.set_cache_settings(&config.cache);
+        context.p4_handler.set_login_settings(config.login.clone());
+        context
+            .p4_handler
+            .set_mock_faults(config.mock_faults.clone());
+        if let Some(users) = &config.mock_users {
+            context.p4_handler.set_mock_users(users.clone());
+        }
+        context.p4_handler.set_max_processes(config.max_processes);
+        if let Some(path) = &config.audit_log {
+            let log = crate::p4::AuditLog::open(path)?;
+            context.p4_handler.set_audit_log(Some(Arc::new(log)));
+        }
+        match (&config.record, &config.replay) {
+            (Some(_), Some(_)) => anyhow::bail!("A session can't be recorded and replayed at once"),
+            (Some(path), None) => {
+                let recorder = crate::p4::Recorder::create(path)?;
+                context.p4_handler.set_recorder(Some(Arc::new(recorder)));
+            }
+            (None, Some(path)) => {
+                let replayer = crate::p4::Replayer::load(path)?;
+                context.p4_handler.set_backend(Some(Arc::new(replayer)));
+            }
+            (None, None) => {}
+        }
+        context.max_response_bytes = config.max_response_bytes;
+        context.response_limits = Arc::new(config.responses.clone());
+        context.tool_filter = Live::new(config.tools.clone());
+        config
+            .aliases
+            .check(&registry::ToolRegistry::built_in(&context))?;
+        context.aliases = Arc::new(config.aliases.clone());
+        context.confirmation = Arc::new(config.confirmation.clone());
+        context.dry_run = config.dry_run;
+        context.max_files = config.max_files;
+        context.format = config.format;
+        context.rate_limiter = Arc::new(RateLimiter::new(config.rate_limits.clone()));
+        context.shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
+        Ok(context)
+    }
+}
+
+impl ServerContext {
+    /// Check that the p4 client can be run, returning its version.
+    pub async fn check_p4(&self) -> anyhow::Result<String> {
+        self.p4_handler.check_p4_bin().await
+    }
+
+    /// Whether p4 commands get mock responses.
+    pub fn is_mock(&self) -> bool {
+        self.p4_handler.mock_mode().is_enabled()
+    }
+
+    /// Check that p4 runs, reaches the server and has a valid ticket.
+    pub async fn check_health(&self) -> crate::p4::HealthReport {
+        self.p4_handler.health().await
+    }
+
+    /// Find the client workspace from `p4 info`, which relative paths are then
+    /// resolved against when a call doesn't say where to run.
+    pub async fn detect_workspace(&self) -> anyhow::Result<Option<crate::p4::Workspace>> {
+        self.p4_handler.detect_workspace().await
+    }
+
+    /// Apply the `[tools]`, `[sandbox]` and `[timeouts]` sections of `config` to every
+    /// session; see [`crate::reload`].
+    pub fn reload(&self, config: &crate::config::Config) {
+        self.tool_filter.set(config.tools.clone());
+        self.p4_handler
+            .reload(config.sandbox.clone(), config.timeouts.clone());
+        self.reloads.send_replace(());
+    }
+
+    /// Shut the server down gracefully: sessions stop reading requests, and the
+    /// transports stop accepting connections once their sessions have finished the
+    /// tool calls in flight.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Wait until [`shutdown`](Self::shutdown) is called.
+    pub async fn shutdown_requested(&self) {
+        let _ = self
+            .shutdown
+            .subscribe()
+            .wait_for(|shutdown| *shutdown)
+            .await;
+    }
+
+    /// The metrics every session records into.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+}
+
+impl Default for ServerContext {
+    fn default() -> Self {
+        Self::from_env()
+    }
+}
+
+/// One client session: the handshake, negotiated capabilities, log level, roots and
+/// subscriptions of a single connection.
 pub struct MCPServer {
-    tools: HashMap<String, Tool>,
+    handshake: HandshakeState,
+    /// Every tool registered, enabled or not; see [`registry`].
+    tools: registry::ToolRegistry,
     p4_handler: crate::p4::P4Handler,
+    swarm: Option<Arc<crate::swarm::SwarmClient>>,
+    resource_roots: Vec<String>,
+    subscriptions: HashMap<String, JoinHandle<()>>,
+    poll_interval: Duration,
+    notification_tx: Option<UnboundedSender<MCPNotification>>,
+    client: Option<ClientRequester>,
+    /// Whether the client advertised `roots`; see [`roots`].
+    roots_supported: bool,
+    client_roots: roots::ClientRoots,
+    /// Whether destructive tool calls can be confirmed with `elicitation/create`.
+    elicitation_supported: bool,
+    /// Minimum severity forwarded to the client as `notifications/message`.
+    log_level: Arc<RwLock<LoggingLevel>>,
+    /// Text results longer than this are truncated; 0 means no limit.
+    max_response_bytes: usize,
+    response_limits: Arc<ResponseLimits>,
+    /// Full outputs of truncated results; see [`truncation`].
+    outputs: truncation::OutputStore,
+    /// Images the running tool call returns after its text, such as a printed PNG.
+    images: Vec<ToolContent>,
+    /// Structured content the running tool call returns, for tools with typed results.
+    structured: Option<serde_json::Value>,
+    confirmation: Arc<ConfirmationPolicy>,
+    /// Confirmation tokens issued and not yet used; see [`confirmation`].
+    tokens: confirmation::TokenStore,
+    dry_run: bool,
+    max_files: usize,
+    format: ResponseFormat,
+    rate_limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    /// Tells this session's requests apart from other sessions' in the logs.
+    session_id: String,
+    /// Shared with forks, which are part of the same session; see [`stats`].
+    stats: Arc<stats::SessionStats>,
+    /// Changes when the config is reloaded; see [`ServerContext::reload`].
+    reloads: tokio::sync::watch::Receiver<()>,
+    /// Set once the server is shutting down; see [`ServerContext::shutdown`].
+    shutdown: tokio::sync::watch::Receiver<bool>,
+    shutdown_timeout: Duration,
 }
 
 impl MCPServer {
+    /// A session with its own services configured from the environment.
     pub fn new() -> Self {
-        let mut tools = HashMap::new();
-
-        // Register P4 tools
-        tools.insert(
-            "p4_status".to_string(),
-            Tool {
-                name: "p4_status".to_string(),
-                description: "Get Perforce workspace status".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "path": {
-                            "type": "string",
-                            "description": "Optional path to check status for"
-                        }
-                    }
-                }),
-            },
-        );
+        Self::with_context(&ServerContext::from_env())
+    }
 
-        tools.insert(
-            "p4_sync".to_string(),
-            Tool {
-                name: "p4_sync".to_string(),
-                description: "Sync files from Perforce depot".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "path": {
-                            "type": "string",
-                            "description": "Path to sync (e.g., //depot/main/...)"
-                        },
-                        "force": {
-                            "type": "boolean",
-                            "description": "Force sync (overwrite local changes)"
+    /// A new session using the services in `context`.
+    pub fn with_context(context: &ServerContext) -> Self {
+        Self {
+            handshake: HandshakeState::Uninitialized,
+            tools: registry::ToolRegistry::built_in(context),
+            p4_handler: context.p4_handler.clone(),
+            swarm: context.swarm.clone(),
+            resource_roots: resources::depot_roots_from_env(),
+            subscriptions: HashMap::new(),
+            poll_interval: subscriptions::poll_interval_from_env(),
+            notification_tx: None,
+            client: None,
+            roots_supported: false,
+            client_roots: Default::default(),
+            elicitation_supported: false,
+            log_level: Arc::new(RwLock::new(LoggingLevel::Warning)),
+            max_response_bytes: context.max_response_bytes,
+            response_limits: context.response_limits.clone(),
+            outputs: Default::default(),
+            images: Vec::new(),
+            structured: None,
+            confirmation: context.confirmation.clone(),
+            tokens: Default::default(),
+            dry_run: context.dry_run,
+            max_files: context.max_files,
+            format: context.format,
+            rate_limiter: context.rate_limiter.clone(),
+            metrics: context.metrics.clone(),
+            session_id: uuid::Uuid::new_v4().simple().to_string()[..8].to_string(),
+            stats: Arc::default(),
+            reloads: context.reloads.subscribe(),
+            shutdown: context.shutdown.subscribe(),
+            shutdown_timeout: context.shutdown_timeout,
+        }
+    }
+
+    /// A handle on this session for running a request concurrently with others.
+    ///
+    /// The fork shares the session's services, client channels, roots and log level, and
+    /// starts from a snapshot of its handshake state and tools. It has no subscriptions
+    /// of its own, so it is only suitable for messages where
+    /// [`MCPMessage::runs_concurrently`] is true. Its p4 commands have a
+    /// [`CancelToken`](crate::p4::CancelToken) of their own, so cancelling the request
+    /// leaves the session's other requests running.
+    pub fn fork(&self) -> Self {
+        let mut p4_handler = self.p4_handler.clone();
+        p4_handler.set_cancel_token(crate::p4::CancelToken::new());
+        Self {
+            handshake: self.handshake,
+            tools: self.tools.clone(),
+            p4_handler,
+            swarm: self.swarm.clone(),
+            resource_roots: self.resource_roots.clone(),
+            subscriptions: HashMap::new(),
+            poll_interval: self.poll_interval,
+            notification_tx: self.notification_tx.clone(),
+            client: self.client.clone(),
+            roots_supported: self.roots_supported,
+            client_roots: self.client_roots.clone(),
+            elicitation_supported: self.elicitation_supported,
+            log_level: self.log_level.clone(),
+            max_response_bytes: self.max_response_bytes,
+            response_limits: self.response_limits.clone(),
+            outputs: self.outputs.clone(),
+            images: Vec::new(),
+            structured: None,
+            confirmation: self.confirmation.clone(),
+            tokens: self.tokens.clone(),
+            dry_run: self.dry_run,
+            max_files: self.max_files,
+            format: self.format,
+            rate_limiter: self.rate_limiter.clone(),
+            metrics: self.metrics.clone(),
+            session_id: self.session_id.clone(),
+            stats: self.stats.clone(),
+            reloads: self.reloads.clone(),
+            shutdown: self.shutdown.clone(),
+            shutdown_timeout: self.shutdown_timeout,
+        }
+    }
+
+    /// Cancels the p4 commands this server runs; see [`Self::fork`].
+    pub fn cancel_token(&self) -> crate::p4::CancelToken {
+        self.p4_handler.cancel_token()
+    }
+
+    /// Name the session in the spans of its requests, e.g. after the transport's own
+    /// session id.
+    pub fn set_session_id(&mut self, id: impl Into<String>) {
+        self.session_id = id.into();
+    }
+
+    /// Set the channel used to deliver server-initiated notifications to the client.
+    pub fn set_notification_sender(&mut self, tx: UnboundedSender<MCPNotification>) {
+        let log_level = self.log_level.clone();
+        let log_tx = tx.clone();
+        self.p4_handler
+            .set_activity_logger(Arc::new(move |level, message| {
+                let level = LoggingLevel::from(level);
+                if log_level.read().is_ok_and(|min| level >= *min) {
+                    let _ = log_tx.send(MCPNotification::log_message(level, "p4", message));
+                }
+            }));
+
+        // A reloaded tool filter can change the exposed tools of a session at any time
+        let tools = self.tools.clone();
+        let mut reloads = self.reloads.clone();
+        let reload_tx = tx.clone();
+        let mut allowed = tools.allowed_names();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    changed = reloads.changed() => {
+                        if changed.is_err() {
+                            return;
                         }
                     }
-                }),
-            },
-        );
+                    _ = reload_tx.closed() => return,
+                }
+                let now_allowed = tools.allowed_names();
+                if now_allowed != allowed {
+                    allowed = now_allowed;
+                    let _ = reload_tx.send(MCPNotification::tools_list_changed());
+                }
+            }
+        });
+        self.notification_tx = Some(tx);
+    }
 
-        tools.insert(
-            "p4_edit".to_string(),
-            Tool {
-                name: "p4_edit".to_string(),
-                description: "Open file(s) for edit in Perforce".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "files": {
-                            "type": "array",
-                            "items": {"type": "string"},
-                            "description": "Files to open for edit"
-                        }
-                    },
-                    "required": ["files"]
-                }),
-            },
-        );
+    /// Set the requester used to send sampling, roots and elicitation requests to the client.
+    pub fn set_client_requester(&mut self, client: ClientRequester) {
+        self.client = Some(client);
+    }
 
-        tools.insert(
-            "p4_add".to_string(),
-            Tool {
-                name: "p4_add".to_string(),
-                description: "Add new file(s) to Perforce".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "files": {
-                            "type": "array",
-                            "items": {"type": "string"},
-                            "description": "Files to add"
-                        }
-                    },
-                    "required": ["files"]
-                }),
-            },
-        );
+    /// Changes to `true` when the server starts shutting down.
+    pub(crate) fn shutdown_signal(&self) -> tokio::sync::watch::Receiver<bool> {
+        self.shutdown.clone()
+    }
 
-        tools.insert(
-            "p4_submit".to_string(),
-            Tool {
-                name: "p4_submit".to_string(),
-                description: "Submit changes to Perforce".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "description": {
-                            "type": "string",
-                            "description": "Change description"
-                        },
-                        "files": {
-                            "type": "array",
-                            "items": {"type": "string"},
-                            "description": "Optional specific files to submit"
-                        }
+    /// How long the tool calls in flight may run once the session is over.
+    pub(crate) fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    /// The tools `tools/list` offers now, by name.
+    pub fn list_tools(&self) -> Vec<Tool> {
+        self.tools.list()
+    }
+
+    /// Enable or disable a registered tool at runtime.
+    ///
+    /// Disabled tools are hidden from `tools/list` and rejected by `tools/call`. When the
+    /// visible tool set changes, clients are sent `notifications/tools/list_changed`.
+    /// Returns `false` if no tool with that name is registered.
+    pub fn set_tool_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        match self.tools.set_enabled(name, enabled) {
+            Some(true) => {
+                info!(
+                    "Tool {} {}",
+                    name,
+                    if enabled { "enabled" } else { "disabled" }
+                );
+                self.notify(MCPNotification::tools_list_changed());
+                true
+            }
+            Some(false) => true,
+            None => false,
+        }
+    }
+
+    fn notify(&self, notification: MCPNotification) {
+        if let Some(tx) = &self.notification_tx {
+            let _ = tx.send(notification);
+        }
+    }
+
+    /// Handle a `tools/call` request.
+    async fn call_tool(
+        &mut self,
+        id: RequestId,
+        params: CallToolParams,
+    ) -> Result<Option<MCPResponse>> {
+        if self.handshake == HandshakeState::Uninitialized {
+            return Ok(Some(MCPResponse::error(
+                id,
+                INVALID_REQUEST,
+                "Server not initialized: send initialize before calling tools",
+            )));
+        }
+
+        let params = match self.expand_alias(params) {
+            Ok(params) => params,
+            Err(error) => return Ok(Some(MCPResponse::Error { id, error })),
+        };
+        let tool_name = &params.name;
+
+        if self.tools.get(tool_name).is_none() {
+            return Ok(Some(MCPResponse::Error {
+                id,
+                error: MCPError {
+                    code: INVALID_PARAMS,
+                    message: format!("Unknown tool: {}", tool_name),
+                    data: None,
+                },
+            }));
+        }
+
+        if let Some(cursor) = params
+            .arguments
+            .get("output_cursor")
+            .and_then(|v| v.as_str())
+        {
+            return Ok(Some(match self.continue_output(tool_name, cursor) {
+                Ok(text) => MCPResponse::CallToolResult {
+                    id,
+                    result: CallToolResult {
+                        content: vec![ToolContent::Text { text }],
+                        structured_content: None,
+                        meta: None,
                     },
-                    "required": ["description"]
-                }),
-            },
-        );
+                },
+                Err(message) => MCPResponse::error(id, INVALID_PARAMS, message),
+            }));
+        }
 
-        tools.insert(
-            "p4_revert".to_string(),
-            Tool {
-                name: "p4_revert".to_string(),
-                description: "Revert files in Perforce".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "files": {
-                            "type": "array",
-                            "items": {"type": "string"},
-                            "description": "Files to revert"
-                        }
+        let schema = self.tools.get(tool_name).map(|tool| &tool.input_schema);
+        if let Some(Err(e)) = schema.map(|schema| validation::validate(schema, &params.arguments)) {
+            warn!("Rejected {} call: {}", tool_name, e);
+            return Ok(Some(MCPResponse::Error {
+                id,
+                error: MCPError {
+                    code: INVALID_PARAMS,
+                    message: format!("Invalid arguments for {}: {}", tool_name, e),
+                    data: Some(serde_json::json!({"pointer": e.pointer})),
+                },
+            }));
+        }
+
+        let cwd = match self.working_dir(&params.arguments).await {
+            Ok(cwd) => cwd,
+            Err(message) => {
+                warn!("Rejected {} call: {}", tool_name, message);
+                return Ok(Some(MCPResponse::error(id, INVALID_PARAMS, message)));
+            }
+        };
+        let client = match client_override::client_override(tool_name, &params.arguments) {
+            Ok(client) => client,
+            Err(message) => {
+                warn!("Rejected {} call: {}", tool_name, message);
+                return Ok(Some(MCPResponse::error(id, INVALID_PARAMS, message)));
+            }
+        };
+
+        // Every command the call runs uses the client it names, previews included
+        let previous_client = self.p4_handler.set_client(client);
+        let response = self.call_tool_in(id, params, cwd).await;
+        self.p4_handler.set_client(previous_client);
+        response
+    }
+
+    /// Handle a `tools/call` request whose arguments were checked, running p4 in `cwd`.
+    async fn call_tool_in(
+        &mut self,
+        id: RequestId,
+        mut params: CallToolParams,
+        cwd: Option<std::path::PathBuf>,
+    ) -> Result<Option<MCPResponse>> {
+        let tool_name = &params.name;
+
+        if let Err(message) = self
+            .expand_globs(tool_name, &mut params.arguments, cwd.as_deref())
+            .await
+        {
+            warn!("Rejected {} call: {}", tool_name, message);
+            return Ok(Some(MCPResponse::error(id, INVALID_PARAMS, message)));
+        }
+
+        if let Some(message) = self.check_roots(&params.arguments, cwd.as_deref()).await {
+            warn!("Rejected {} call: {}", tool_name, message);
+            return Ok(Some(MCPResponse::error(id, INVALID_PARAMS, message)));
+        }
+
+        // Nothing is changed, so there's nothing to confirm
+        if self.is_dry_run(&params.arguments) {
+            let text = self
+                .dry_run_tool(&id, tool_name, &params.arguments, cwd)
+                .await;
+            let text = self.format_result(&params.arguments, text);
+            return Ok(Some(MCPResponse::CallToolResult {
+                id,
+                result: CallToolResult {
+                    content: vec![ToolContent::Text {
+                        text: self.limit_output(tool_name, text),
+                    }],
+                    structured_content: None,
+                    meta: None,
+                },
+            }));
+        }
+
+        if let Some(response) = self
+            .check_file_count(id.clone(), tool_name, &params.arguments, cwd.clone())
+            .await
+        {
+            return Ok(Some(response));
+        }
+
+        // Checked before confirming, so the user isn't asked about a call that
+        // can't run, and counted once the call runs
+        if let Err(limited) = self.rate_limiter.check(tool_name) {
+            return Ok(Some(rate_limited_response(id, limited)));
+        }
+
+        // Refused before the user is asked to confirm it
+        if tool_name == "p4_sync"
+            && params.arguments.get("force").and_then(|v| v.as_bool()) == Some(true)
+        {
+            if let Err(e) = self.p4_handler.check_force_sync_allowed() {
+                warn!("Rejected {} call: {}", tool_name, e);
+                return Ok(Some(MCPResponse::error(id, INVALID_PARAMS, e.to_string())));
+            }
+        }
+
+        if let Some(message) = elicitation::confirmation_message(tool_name, &params.arguments) {
+            let mode = self.confirmation.mode(tool_name);
+            if mode == ConfirmationMode::Token {
+                if let Some(response) = self
+                    .check_confirmation_token(
+                        id.clone(),
+                        tool_name,
+                        &params.arguments,
+                        &message,
+                        cwd.clone(),
+                    )
+                    .await
+                {
+                    return Ok(Some(response));
+                }
+            } else if mode == ConfirmationMode::Elicitation && !self.confirm(&message).await? {
+                return Ok(Some(MCPResponse::CallToolResult {
+                    id,
+                    result: CallToolResult {
+                        content: vec![ToolContent::Text {
+                            text: format!("Cancelled: {} was not confirmed", tool_name),
+                        }],
+                        structured_content: None,
+                        meta: None,
                     },
-                    "required": ["files"]
-                }),
-            },
-        );
+                }));
+            }
+        }
 
-        tools.insert(
-            "p4_opened".to_string(),
-            Tool {
-                name: "p4_opened".to_string(),
-                description: "List files opened for edit".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "changelist": {
-                            "type": "string",
-                            "description": "Optional changelist number"
-                        }
-                    }
-                }),
-            },
-        );
+        if let Err(limited) = self.rate_limiter.acquire(tool_name) {
+            return Ok(Some(rate_limited_response(id, limited)));
+        }
 
-        tools.insert(
-            "p4_changes".to_string(),
-            Tool {
-                name: "p4_changes".to_string(),
-                description: "List recent changes".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {
-                        "max": {
-                            "type": "integer",
-                            "description": "Maximum number of changes to return",
-                            "default": 10
+        let previous_cwd = self.p4_handler.set_working_dir(cwd);
+        let previous_timeout = self
+            .p4_handler
+            .set_timeout(self.p4_handler.timeout_for(tool_name).into());
+        let previous_listener = self
+            .p4_handler
+            .set_output_listener(self.progress_listener(params.meta.as_ref()));
+        let previous_call = self
+            .p4_handler
+            .set_call_info(Some(call_info(&id, tool_name)));
+        let executions = ExecutionLog::new();
+        let previous_executions = self.p4_handler.set_execution_log(Some(executions.clone()));
+        let started = std::time::Instant::now();
+        let result = self.run_tool(tool_name, &params.arguments).await;
+        let duration_ms = started.elapsed().as_millis() as u64;
+        self.p4_handler.set_working_dir(previous_cwd);
+        self.p4_handler.set_timeout(previous_timeout);
+        self.p4_handler.set_output_listener(previous_listener);
+        self.p4_handler.set_call_info(previous_call);
+        self.p4_handler.set_execution_log(previous_executions);
+        self.stats
+            .record_commands(tool_name, &executions.commands());
+
+        let (result, structured_content) = match result {
+            Ok(result) => result,
+            Err(e) => match e.downcast_ref::<CommandTimeout>() {
+                Some(timeout) => {
+                    warn!("{} call: {}", tool_name, timeout);
+                    return Ok(Some(MCPResponse::Error {
+                        id,
+                        error: MCPError {
+                            code: INTERNAL_ERROR,
+                            message: timeout.to_string(),
+                            data: Some(serde_json::json!({
+                                "timedOut": true,
+                                "command": format!("p4 {}", timeout.command),
+                                "timeoutSecs": timeout.timeout.as_secs(),
+                            })),
                         },
-                        "path": {
-                            "type": "string",
-                            "description": "Optional path to filter changes"
-                        }
-                    }
-                }),
+                    }));
+                }
+                None => return Err(e),
             },
-        );
+        };
 
-        tools.insert(
-            "p4_info".to_string(),
-            Tool {
-                name: "p4_info".to_string(),
-                description: "Get Perforce client and server information".to_string(),
-                input_schema: serde_json::json!({
-                    "type": "object",
-                    "properties": {}
-                }),
+        let result = self.format_result(&params.arguments, result);
+        let meta = ToolResultMeta {
+            commands: executions.commands(),
+            duration_ms,
+            truncated: self.exceeds_response_limit(tool_name, &result),
+        };
+        let mut content = vec![ToolContent::Text {
+            text: self.limit_output(tool_name, result),
+        }];
+        content.append(&mut self.images);
+        Ok(Some(MCPResponse::CallToolResult {
+            id,
+            result: CallToolResult {
+                content,
+                structured_content,
+                meta: Some(meta),
             },
-        );
-
-        Self {
-            tools,
-            p4_handler: crate::p4::P4Handler::new(),
-        }
+        }))
     }
 
+    /// Handle one message, returning the response to send, if any.
+    ///
+    /// Everything logged while handling it, including the p4 commands run, is in a
+    /// `request` span naming the session, the request id, the method and, for
+    /// `tools/call`, the tool.
     pub async fn handle_message(&mut self, message: MCPMessage) -> Result<Option<MCPResponse>> {
+        let span = tracing::info_span!(
+            "request",
+            session = %self.session_id,
+            id = message.id().map(tracing::field::display),
+            method = message.method(),
+            tool = tracing::field::Empty,
+        );
+        self.dispatch(message).instrument(span).await
+    }
+
+    async fn dispatch(&mut self, message: MCPMessage) -> Result<Option<MCPResponse>> {
         debug!("Handling message: {:?}", message);
+        let metrics = self.metrics.clone();
+        let _in_flight = metrics.request_started();
 
         match message {
             MCPMessage::Initialize { id, params } => {
@@ -202,17 +800,31 @@ impl MCPServer {
                     "Received initialize request with client info: {:?}",
                     params.client_info
                 );
+                self.handshake = HandshakeState::Initializing;
+                self.roots_supported = params.capabilities.roots.is_some();
+                self.elicitation_supported = params.capabilities.elicitation.is_some();
+
+                if params.capabilities.sampling.is_some() {
+                    for tool in sampling::SAMPLING_TOOLS {
+                        self.set_tool_enabled(tool, true);
+                    }
+                }
 
                 Ok(Some(MCPResponse::InitializeResult {
-                    jsonrpc: "2.0".to_string(),
                     id,
                     result: InitializeResult {
                         protocol_version: "2024-11-05".to_string(),
                         capabilities: ServerCapabilities {
-                            tools: Some(ToolsCapability {
+                            completions: Some(CompletionsCapability {}),
+                            logging: Some(LoggingCapability {}),
+                            tools: Some(ToolsCapability { list_changed: true }),
+                            prompts: Some(PromptsCapability {
+                                list_changed: false,
+                            }),
+                            resources: Some(ResourcesCapability {
+                                subscribe: true,
                                 list_changed: false,
                             }),
-                            ..Default::default()
                         },
                         server_info: ServerInfo {
                             name: "P4Server".to_string(),
@@ -223,160 +835,248 @@ impl MCPServer {
                 }))
             }
 
-            MCPMessage::ListTools { id } => {
-                let tools: Vec<Tool> = self.tools.values().cloned().collect();
+            MCPMessage::ListTools { id, params } => {
+                let tools = self.tools.list();
 
-                Ok(Some(MCPResponse::ListToolsResult {
-                    id,
-                    result: ListToolsResult { tools },
-                }))
+                let cursor = params.and_then(|p| p.cursor);
+                match pagination::paginate(tools, cursor.as_deref(), pagination::TOOLS_PAGE_SIZE) {
+                    Some((tools, next_cursor)) => Ok(Some(MCPResponse::ListToolsResult {
+                        id,
+                        result: ListToolsResult { tools, next_cursor },
+                    })),
+                    None => Ok(Some(MCPResponse::error(
+                        id,
+                        INVALID_PARAMS,
+                        format!("Invalid cursor: {}", cursor.unwrap_or_default()),
+                    ))),
+                }
+            }
+
+            MCPMessage::Initialized {} => {
+                if self.handshake == HandshakeState::Uninitialized {
+                    warn!("Received notifications/initialized before initialize");
+                } else {
+                    info!("Client initialization complete");
+                }
+                self.handshake = HandshakeState::Ready;
+                if self.roots_supported {
+                    self.refresh_roots().await;
+                }
+                Ok(None)
+            }
+
+            // Requests in flight are cancelled by the session before they get here
+            MCPMessage::Cancelled { params } => {
+                debug!("No request {} in flight to cancel", params.request_id);
+                Ok(None)
+            }
+
+            MCPMessage::RootsListChanged {} => {
+                if self.roots_supported {
+                    info!("Client roots changed");
+                    self.refresh_roots().await;
+                }
+                Ok(None)
             }
 
             MCPMessage::CallTool { id, params } => {
-                let tool_name = &params.name;
+                // Any name a client sends would otherwise become a metric label
+                tracing::Span::current().record("tool", params.name.as_str());
+                let tool_name = match self.tools.get(&params.name) {
+                    Some(_) => params.name.clone(),
+                    None => "unknown".to_string(),
+                };
+                let response = self.call_tool(id, params).await;
+                let outcome = crate::metrics::tool_call_outcome(&response);
+                self.metrics.record_tool_call(&tool_name, outcome);
+                self.stats.record_call(&tool_name, &response, outcome);
+                response
+            }
 
-                if !self.tools.contains_key(tool_name) {
-                    return Ok(Some(MCPResponse::Error {
+            MCPMessage::Ping { id } => Ok(Some(MCPResponse::Pong { id })),
+
+            MCPMessage::ListPrompts { id } => Ok(Some(MCPResponse::ListPromptsResult {
+                id,
+                result: ListPromptsResult {
+                    prompts: prompts::prompt_definitions(),
+                },
+            })),
+
+            MCPMessage::GetPrompt { id, params } => {
+                match self.get_prompt(&params.name, &params.arguments).await? {
+                    Some(result) => Ok(Some(MCPResponse::GetPromptResult { id, result })),
+                    None => Ok(Some(MCPResponse::Error {
                         id,
                         error: MCPError {
-                            code: -32602,
-                            message: format!("Unknown tool: {}", tool_name),
+                            code: INVALID_PARAMS,
+                            message: format!("Unknown prompt: {}", params.name),
                             data: None,
                         },
-                    }));
+                    })),
                 }
+            }
 
-                let result = self.execute_tool(tool_name, params.arguments).await?;
+            MCPMessage::SetLevel { id, params } => {
+                info!("Client log level set to {:?}", params.level);
+                if let Ok(mut level) = self.log_level.write() {
+                    *level = params.level;
+                }
 
-                Ok(Some(MCPResponse::CallToolResult {
+                Ok(Some(MCPResponse::Empty {
                     id,
-                    result: CallToolResult {
-                        content: vec![ToolContent::Text { text: result }],
-                    },
+                    result: EmptyResult {},
                 }))
             }
 
-            MCPMessage::Ping { id } => Ok(Some(MCPResponse::Pong { id })),
-        }
-    }
-
-    async fn execute_tool(
-        &mut self,
-        tool_name: &str,
-        arguments: serde_json::Value,
-    ) -> Result<String> {
-        debug!("Executing tool: {} with args: {}", tool_name, arguments);
+            MCPMessage::Complete { id, params } => {
+                let completion = self.complete(&params).await?;
 
-        match tool_name {
-            "p4_status" => {
-                let path = arguments
-                    .get("path")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string());
-                self.p4_handler.execute(P4Command::Status { path }).await
+                Ok(Some(MCPResponse::CompleteResult {
+                    id,
+                    result: CompleteResult { completion },
+                }))
             }
 
-            "p4_sync" => {
-                let path = arguments
-                    .get("path")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string())
-                    .unwrap_or("...".to_string());
-                let force = arguments
-                    .get("force")
-                    .and_then(|v| v.as_bool())
-                    .unwrap_or(false);
-                self.p4_handler
-                    .execute(P4Command::Sync { path, force })
-                    .await
-            }
+            MCPMessage::ListResources { id } => {
+                let resources = self.list_resources().await?;
 
-            "p4_edit" => {
-                let files: Vec<String> = arguments
-                    .get("files")
-                    .and_then(|v| v.as_array())
-                    .map(|arr| {
-                        arr.iter()
-                            .filter_map(|v| v.as_str())
-                            .map(|s| s.to_string())
-                            .collect()
-                    })
-                    .unwrap_or_default();
-                self.p4_handler.execute(P4Command::Edit { files }).await
+                Ok(Some(MCPResponse::ListResourcesResult {
+                    id,
+                    result: ListResourcesResult { resources },
+                }))
             }
 
-            "p4_add" => {
-                let files: Vec<String> = arguments
-                    .get("files")
-                    .and_then(|v| v.as_array())
-                    .map(|arr| {
-                        arr.iter()
-                            .filter_map(|v| v.as_str())
-                            .map(|s| s.to_string())
-                            .collect()
-                    })
-                    .unwrap_or_default();
-                self.p4_handler.execute(P4Command::Add { files }).await
+            MCPMessage::ListResourceTemplates { id } => {
+                Ok(Some(MCPResponse::ListResourceTemplatesResult {
+                    id,
+                    result: ListResourceTemplatesResult {
+                        resource_templates: resources::resource_templates(),
+                    },
+                }))
             }
 
-            "p4_submit" => {
-                let description = arguments
-                    .get("description")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string())
-                    .unwrap_or_default();
-                let files: Option<Vec<String>> = arguments
-                    .get("files")
-                    .and_then(|v| v.as_array())
-                    .map(|arr| {
-                        arr.iter()
-                            .filter_map(|v| v.as_str())
-                            .map(|s| s.to_string())
-                            .collect()
-                    });
-                self.p4_handler
-                    .execute(P4Command::Submit { description, files })
-                    .await
+            MCPMessage::Subscribe { id, params } => {
+                let Some(depot_path) = resources::uri_to_depot_path(&params.uri) else {
+                    return Ok(Some(MCPResponse::Error {
+                        id,
+                        error: MCPError {
+                            code: -32002,
+                            message: format!("Resource not found: {}", params.uri),
+                            data: None,
+                        },
+                    }));
+                };
+
+                if let Some(tx) = &self.notification_tx {
+                    info!("Subscribing to {}", params.uri);
+                    let poller = subscriptions::spawn_change_poller(
+                        self.p4_handler.clone(),
+                        params.uri.clone(),
+                        depot_path,
+                        self.poll_interval,
+                        tx.clone(),
+                    );
+                    if let Some(previous) = self.subscriptions.insert(params.uri, poller) {
+                        previous.abort();
+                    }
+                } else {
+                    debug!("No notification channel; not polling {}", params.uri);
+                }
+
+                Ok(Some(MCPResponse::Empty {
+                    id,
+                    result: EmptyResult {},
+                }))
             }
 
-            "p4_revert" => {
-                let files: Vec<String> = arguments
-                    .get("files")
-                    .and_then(|v| v.as_array())
-                    .map(|arr| {
-                        arr.iter()
-                            .filter_map(|v| v.as_str())
-                            .map(|s| s.to_string())
-                            .collect()
-                    })
-                    .unwrap_or_default();
-                self.p4_handler.execute(P4Command::Revert { files }).await
+            MCPMessage::Unsubscribe { id, params } => {
+                if let Some(poller) = self.subscriptions.remove(&params.uri) {
+                    info!("Unsubscribing from {}", params.uri);
+                    poller.abort();
+                }
+
+                Ok(Some(MCPResponse::Empty {
+                    id,
+                    result: EmptyResult {},
+                }))
             }
 
-            "p4_opened" => {
-                let changelist = arguments
-                    .get("changelist")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string());
-                self.p4_handler
-                    .execute(P4Command::Opened { changelist })
-                    .await
+            MCPMessage::ReadResource { id, params } => {
+                if let Some(changelist) = resources::uri_to_change(&params.uri) {
+                    let contents = self.read_change_resource(changelist).await?;
+
+                    return Ok(Some(MCPResponse::ReadResourceResult {
+                        id,
+                        result: ReadResourceResult {
+                            contents: vec![contents],
+                        },
+                    }));
+                }
+
+                let Some(depot_path) = resources::uri_to_depot_path(&params.uri) else {
+                    return Ok(Some(MCPResponse::Error {
+                        id,
+                        error: MCPError {
+                            code: -32002,
+                            message: format!("Resource not found: {}", params.uri),
+                            data: None,
+                        },
+                    }));
+                };
+
+                let contents = self.read_resource(&depot_path).await?;
+
+                Ok(Some(MCPResponse::ReadResourceResult {
+                    id,
+                    result: ReadResourceResult {
+                        contents: vec![contents],
+                    },
+                }))
             }
+        }
+    }
 
-            "p4_changes" => {
-                let max = arguments.get("max").and_then(|v| v.as_u64()).unwrap_or(10) as u32;
-                let path = arguments
-                    .get("path")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string());
-                self.p4_handler
-                    .execute(P4Command::Changes { max, path })
-                    .await
+    /// Run a tool for its text result and, if it has one, its structured output.
+    async fn run_tool(
+        &mut self,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<(String, Option<serde_json::Value>)> {
+        debug!("Executing tool: {} with args: {}", tool_name, arguments);
+        self.images.clear();
+        self.structured = None;
+        let run = match self.tools.handler(tool_name) {
+            Some(registry::Handler::BuiltIn(run)) => run,
+            Some(registry::Handler::Custom(handler)) => {
+                let result = handler
+                    .call(&mut self.p4_handler, arguments.clone())
+                    .await?;
+                return Ok((result, None));
             }
+            Some(registry::Handler::Alias(_)) => {
+                unreachable!(
+                    "{} is an alias, which call_tool expands before dispatch",
+                    tool_name
+                )
+            }
+            None => return Err(anyhow::anyhow!("Unknown tool: {}", tool_name)),
+        };
+        let result = run(self, registry::Arguments(arguments.clone())).await?;
 
-            "p4_info" => self.p4_handler.execute(P4Command::Info).await,
+        Ok((result, self.structured.take()))
+    }
+}
 
-            _ => Err(anyhow::anyhow!("Unknown tool: {}", tool_name)),
+impl Drop for MCPServer {
+    fn drop(&mut self) {
+        for poller in self.subscriptions.values() {
+            poller.abort();
         }
     }
 }
+
+impl Default for MCPServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
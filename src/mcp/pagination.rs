@@ -0,0 +1,26 @@
+//! Cursor-based pagination for MCP list results
+//!
+//! Cursors are opaque to clients; the server encodes the offset of the next page.
+
+/// Number of tools returned per `tools/list` page.
+pub const TOOLS_PAGE_SIZE: usize = 50;
+
+/// Return the page of `items` starting at `cursor`, and the cursor for the page after it.
+///
+/// Returns `None` if the cursor is not one this server handed out.
+pub fn paginate<T>(
+    items: Vec<T>,
+    cursor: Option<&str>,
+    page_size: usize,
+) -> Option<(Vec<T>, Option<String>)> {
+    let offset = match cursor {
+        Some(cursor) => cursor.parse::<usize>().ok().filter(|&o| o <= items.len())?,
+        None => 0,
+    };
+
+    let end = offset.saturating_add(page_size).min(items.len());
+    let next_cursor = (end < items.len()).then(|| end.to_string());
+    let page = items.into_iter().skip(offset).take(end - offset).collect();
+
+    Some((page, next_cursor))
+}
@@ -0,0 +1,49 @@
+//! Progress from long-running tool calls
+//!
+//! Lines a p4 command prints are forwarded while it runs. A call whose `_meta` has a
+//! `progressToken` gets `notifications/progress` with the number of lines so far and
+//! the latest one, at most every [`PROGRESS_INTERVAL`]; other calls send the lines as
+//! debug-level log messages, which clients only see if they asked for them.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::*;
+use crate::p4::{OutputChunk, OutputListener};
+
+/// Minimum time between progress notifications for one call.
+pub const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+impl MCPServer {
+    /// An output listener reporting progress for a tool call to the client.
+    pub(crate) fn progress_listener(&self, meta: Option<&RequestMeta>) -> Option<OutputListener> {
+        let tx = self.notification_tx.clone()?;
+
+        if let Some(token) = meta.and_then(|meta| meta.progress_token.clone()) {
+            let state = Mutex::new((0u64, None::<Instant>));
+            return Some(Arc::new(move |chunk: &OutputChunk| {
+                let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+                let (lines, last_sent) = &mut *state;
+                *lines += 1;
+                if last_sent.is_none_or(|sent| sent.elapsed() >= PROGRESS_INTERVAL) {
+                    *last_sent = Some(Instant::now());
+                    let _ = tx.send(MCPNotification::progress(&token, *lines, chunk.text()));
+                }
+            }));
+        }
+
+        let log_level = self.log_level.clone();
+        Some(Arc::new(move |chunk: &OutputChunk| {
+            if log_level
+                .read()
+                .is_ok_and(|min| LoggingLevel::Debug >= *min)
+            {
+                let _ = tx.send(MCPNotification::log_message(
+                    LoggingLevel::Debug,
+                    "p4",
+                    chunk.text(),
+                ));
+            }
+        }))
+    }
+}
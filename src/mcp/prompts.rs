@@ -0,0 +1,152 @@
+//! Built-in MCP prompts that pre-fill Perforce context
+
+use anyhow::Result;
+use std::collections::HashMap;
+
+use super::*;
+use crate::p4::P4Command;
+
+pub fn prompt_definitions() -> Vec<Prompt> {
+    vec![
+        Prompt {
+            name: "write_change_description".to_string(),
+            description: Some(
+                "Write a changelist description for the currently opened files".to_string(),
+            ),
+            arguments: vec![PromptArgument {
+                name: "changelist".to_string(),
+                description: Some(
+                    "Pending changelist to describe (default: all opened files)".to_string(),
+                ),
+                required: false,
+            }],
+        },
+        Prompt {
+            name: "review_shelved_change".to_string(),
+            description: Some("Review the files shelved in a changelist".to_string()),
+            arguments: vec![PromptArgument {
+                name: "changelist".to_string(),
+                description: Some("Shelved changelist number".to_string()),
+                required: true,
+            }],
+        },
+        Prompt {
+            name: "summarize_recent_changes".to_string(),
+            description: Some("Summarize the recent submitted changes under a path".to_string()),
+            arguments: vec![
+                PromptArgument {
+                    name: "path".to_string(),
+                    description: Some("Depot path (e.g., //depot/main/...)".to_string()),
+                    required: true,
+                },
+                PromptArgument {
+                    name: "max".to_string(),
+                    description: Some("Number of changes to include (default: 20)".to_string()),
+                    required: false,
+                },
+            ],
+        },
+    ]
+}
+
+impl MCPServer {
+    /// Render a prompt, or `None` if no prompt has that name.
+    pub(crate) async fn get_prompt(
+        &mut self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Option<GetPromptResult>> {
+        let (description, text) = match name {
+            "write_change_description" => (
+                "Write a changelist description for the opened files",
+                self.change_description_request(arguments.get("changelist").cloned())
+                    .await?,
+            ),
+
+            "review_shelved_change" => {
+                let Some(changelist) = arguments.get("changelist") else {
+                    return Err(anyhow::anyhow!("Missing required argument: changelist"));
+                };
+                let described = self
+                    .p4_handler
+                    .execute(P4Command::DescribeShelved {
+                        changelist: changelist.clone(),
+                    })
+                    .await?;
+
+                (
+                    "Review a shelved change",
+                    format!(
+                        "Review shelved change {}. Point out bugs, risky changes, and missing tests, \
+                         referencing files and lines where possible.\n\n{}",
+                        changelist,
+                        format::diff_sections(&described)
+                    ),
+                )
+            }
+
+            "summarize_recent_changes" => {
+                let Some(path) = arguments.get("path") else {
+                    return Err(anyhow::anyhow!("Missing required argument: path"));
+                };
+                let max = arguments
+                    .get("max")
+                    .and_then(|m| m.parse().ok())
+                    .unwrap_or(20);
+                let changes = self
+                    .p4_handler
+                    .execute(P4Command::ChangesLong {
+                        path: path.clone(),
+                        max: Some(max),
+                    })
+                    .await?;
+
+                (
+                    "Summarize recent changes",
+                    format!(
+                        "Summarize the recent changes submitted under {}. Group related changes \
+                         and call out anything notable.\n\n{}",
+                        path,
+                        changes.trim_end()
+                    ),
+                )
+            }
+
+            _ => return Ok(None),
+        };
+
+        Ok(Some(GetPromptResult {
+            description: Some(description.to_string()),
+            messages: vec![PromptMessage {
+                role: "user".to_string(),
+                content: ToolContent::Text { text },
+            }],
+        }))
+    }
+
+    /// Ask for a changelist description, with the opened files and their diffs as context.
+    pub(crate) async fn change_description_request(
+        &mut self,
+        changelist: Option<String>,
+    ) -> Result<String> {
+        let opened = self
+            .p4_handler
+            .execute(P4Command::Opened { changelist })
+            .await?;
+        let files: Vec<String> = opened
+            .lines()
+            .filter_map(|line| line.split_once('#'))
+            .map(|(file, _)| file.trim().to_string())
+            .filter(|file| file.starts_with("//"))
+            .collect();
+        let diff = self.p4_handler.execute(P4Command::Diff { files }).await?;
+
+        Ok(format!(
+            "Write a concise Perforce changelist description for the following work. \
+             Start with a one-line summary, then a short explanation of what changed and why.\n\n\
+             Opened files:\n{}\n\nDiff:\n\n{}",
+            opened.trim_end(),
+            format::diff_sections(&diff)
+        ))
+    }
+}
@@ -0,0 +1,137 @@
+//! Per-tool rate limits
+//!
+//! An agent stuck in a loop can submit or sync far more often than any person would,
+//! loading the Perforce server for everyone. Limits set in the `[rate_limits]` section
+//! of the config file cap how often a tool may run across all sessions:
+//!
+//! ```toml
+//! [rate_limits]
+//! tools = { p4_submit = { calls = 10, per_secs = 3600 }, p4_sync = { calls = 5, per_secs = 60 } }
+//! ```
+//!
+//! A call over the limit fails without running, and the error says when the tool can
+//! be called again. Calls that aren't run, such as dry runs and calls waiting for a
+//! confirmation token, don't count.
+
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// At most `calls` calls in any `per_secs` seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    #[serde(deserialize_with = "deserialize_positive")]
+    pub calls: usize,
+    #[serde(deserialize_with = "deserialize_positive")]
+    pub per_secs: u64,
+}
+
+/// A count that must be at least 1: no calls at all, or a window of no time, would
+/// make the tool fail or leave it unlimited rather than limit it.
+fn deserialize_positive<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(serde::de::Error::custom("must be at least 1"));
+    }
+    Ok(value)
+}
+
+impl RateLimit {
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.per_secs)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    /// Limits by tool name; tools not listed aren't limited.
+    pub tools: HashMap<String, RateLimit>,
+}
+
+/// A call refused for going over its tool's limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimited {
+    pub tool: String,
+    pub limit: RateLimit,
+    /// How long until the tool can be called again.
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Rate limited: {} may be called {} time(s) per {}s; retry after {}s",
+            self.tool,
+            self.limit.calls,
+            self.limit.per_secs,
+            self.retry_after.as_secs_f64().ceil()
+        )
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+/// The times limited tools were run, shared by every session.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    calls: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            calls: Mutex::default(),
+        }
+    }
+
+    /// Check that `tool` may run now, without counting a call.
+    pub fn check(&self, tool: &str) -> Result<(), RateLimited> {
+        self.admit(tool, false)
+    }
+
+    /// Count a call to `tool`, unless it's over the limit.
+    pub fn acquire(&self, tool: &str) -> Result<(), RateLimited> {
+        self.admit(tool, true)
+    }
+
+    fn admit(&self, tool: &str, record: bool) -> Result<(), RateLimited> {
+        let Some(limit) = self.limits.tools.get(tool) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        let times = calls.entry(tool.to_string()).or_default();
+        while times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= limit.window())
+        {
+            times.pop_front();
+        }
+        if times.len() >= limit.calls {
+            // The oldest call in the window is the next to leave it
+            let retry_after = times
+                .front()
+                .map(|oldest| limit.window() - now.duration_since(*oldest))
+                .unwrap_or(limit.window());
+            return Err(RateLimited {
+                tool: tool.to_string(),
+                limit: *limit,
+                retry_after,
+            });
+        }
+        if record {
+            times.push_back(now);
+        }
+        Ok(())
+    }
+}
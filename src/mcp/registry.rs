@@ -0,0 +1,1223 @@
+//! The tools a session offers and how each one runs
+//!
+//! Every built-in tool is registered here in one place: the [`Tool`] listed by
+//! `tools/list`, how the arguments of a `tools/call` are read, and what it runs. A
+//! new tool only needs a `register` call below, plus an entry in
+//! [`structured::output_schema`] if it returns structured content with `typed_result`. Tools added with
+//! the [`builder`](super::builder) are held in the same [`ToolRegistry`], run by their
+//! [`ToolHandler`].
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use super::{
+    add_server_properties, structured, MCPServer, ServerContext, Tool, ToolAnnotations,
+    ToolContent, ToolFilter, ToolHandler,
+};
+use crate::p4::{FileContents, Integration, OpenedFile, P4Command};
+use crate::reload::Live;
+
+/// The text result of a tool call, still running.
+pub(crate) type ToolFuture<'a> = Pin<Box<dyn Future<Output = Result<String>> + Send + 'a>>;
+
+/// Runs a built-in tool on a session with the call's arguments.
+pub(crate) type RunTool = for<'a> fn(&'a mut MCPServer, Arguments) -> ToolFuture<'a>;
+
+/// How a registered tool runs.
+#[derive(Clone)]
+pub(crate) enum Handler {
+    BuiltIn(RunTool),
+    /// Added or replaced with the [`builder`](super::builder).
+    Custom(Arc<dyn ToolHandler>),
+    /// Runs another tool; see [`aliases`](super::aliases).
+    Alias(Arc<super::aliases::Alias>),
+}
+
+#[derive(Clone)]
+struct Entry {
+    tool: Tool,
+    handler: Handler,
+    /// Disabled tools are hidden from `tools/list` and rejected by `tools/call`.
+    enabled: bool,
+}
+
+/// The arguments of a `tools/call`. Arguments that are missing or of the wrong type
+/// read as not given.
+pub(crate) struct Arguments(pub serde_json::Value);
+
+impl Arguments {
+    fn str(&self, name: &str) -> Option<&str> {
+        self.0.get(name).and_then(|v| v.as_str())
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        self.str(name).map(|s| s.to_string())
+    }
+
+    fn strings(&self, name: &str) -> Option<Vec<String>> {
+        self.0.get(name).and_then(|v| v.as_array()).map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str())
+                .map(|s| s.to_string())
+                .collect()
+        })
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.0.get(name).and_then(|v| v.as_bool()).unwrap_or(false)
+    }
+
+    fn count(&self, name: &str, default: u32) -> u32 {
+        self.0
+            .get(name)
+            .and_then(|v| v.as_u64())
+            .map_or(default, |n| n as u32)
+    }
+}
+
+/// A built-in tool's definition, with the output schema [`structured`] declares for it.
+fn tool(
+    name: &str,
+    description: &str,
+    input_schema: serde_json::Value,
+    annotations: ToolAnnotations,
+) -> Tool {
+    Tool {
+        name: name.to_string(),
+        description: description.to_string(),
+        input_schema,
+        output_schema: structured::output_schema(name),
+        annotations: Some(annotations),
+    }
+}
+
+/// Return `result` as the call's structured content, with `text` as its text.
+fn typed_result<T: Serialize>(server: &mut MCPServer, result: &T, text: String) -> Result<String> {
+    server.structured = Some(serde_json::to_value(result)?);
+    Ok(text)
+}
+
+/// The registered tools of a session, enabled or not.
+#[derive(Clone, Default)]
+pub(crate) struct ToolRegistry {
+    entries: HashMap<String, Entry>,
+    /// Tools it withholds are hidden like disabled ones, whatever their state; it can
+    /// change while the server runs, see [`crate::reload`].
+    filter: Live<ToolFilter>,
+}
+
+impl ToolRegistry {
+    /// The built-in tools for a session using `context`, hiding those its tool filter
+    /// withholds.
+    pub(crate) fn built_in(context: &ServerContext) -> Self {
+        let mut registry = Self {
+            filter: context.tool_filter.clone(),
+            ..Self::default()
+        };
+        registry.register_p4_tools();
+        for entry in registry.entries.values_mut() {
+            add_server_properties(&mut entry.tool, context);
+        }
+        if context.swarm.is_some() {
+            registry.register_swarm_tools();
+        }
+        registry.register_aliases(context);
+        registry
+    }
+
+    fn register(&mut self, tool: Tool, run: RunTool) {
+        self.insert(tool, Handler::BuiltIn(run));
+    }
+
+    /// Register `tool`, enabled, replacing any tool with the same name.
+    pub(crate) fn insert(&mut self, tool: Tool, handler: Handler) {
+        self.entries.insert(
+            tool.name.clone(),
+            Entry {
+                tool,
+                handler,
+                enabled: true,
+            },
+        );
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) {
+        self.entries.remove(name);
+    }
+
+    /// An enabled tool the filter allows.
+    pub(crate) fn get(&self, name: &str) -> Option<&Tool> {
+        self.entries
+            .get(name)
+            .filter(|entry| entry.enabled && self.filter.get().allows(name))
+            .map(|entry| &entry.tool)
+    }
+
+    /// A registered tool, enabled or not.
+    pub(crate) fn get_mut(&mut self, name: &str) -> Option<&mut Tool> {
+        self.entries.get_mut(name).map(|entry| &mut entry.tool)
+    }
+
+    /// The enabled tools the filter allows, by name.
+    pub(crate) fn list(&self) -> Vec<Tool> {
+        let filter = self.filter.get();
+        let mut tools: Vec<Tool> = self
+            .entries
+            .values()
+            .filter(|entry| entry.enabled && filter.allows(&entry.tool.name))
+            .map(|entry| entry.tool.clone())
+            .collect();
+        tools.sort_by(|a, b| a.name.cmp(&b.name));
+        tools
+    }
+
+    /// The names of the registered tools the filter allows, enabled or not.
+    pub(crate) fn allowed_names(&self) -> Vec<String> {
+        let filter = self.filter.get();
+        let mut names: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|name| filter.allows(name))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// How a registered tool runs.
+    pub(crate) fn handler(&self, name: &str) -> Option<Handler> {
+        self.entries.get(name).map(|entry| entry.handler.clone())
+    }
+
+    /// Run a registered tool with `handler` instead.
+    pub(crate) fn set_handler(&mut self, name: &str, handler: Handler) {
+        if let Some(entry) = self.entries.get_mut(name) {
+            entry.handler = handler;
+        }
+    }
+
+    /// Enable or disable a registered tool, returning whether that changed it, or
+    /// `None` if no tool with that name is registered or the filter withholds it.
+    pub(crate) fn set_enabled(&mut self, name: &str, enabled: bool) -> Option<bool> {
+        if !self.filter.get().allows(name) {
+            return None;
+        }
+        let entry = self.entries.get_mut(name)?;
+        let changed = entry.enabled != enabled;
+        entry.enabled = enabled;
+        Some(changed)
+    }
+
+    fn register_p4_tools(&mut self) {
+        self.register(
+            tool(
+                "p4_status",
+                "Get Perforce workspace status",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Optional path to check status for"
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Workspace Status"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path");
+                    let files: Vec<OpenedFile> = server
+                        .p4_handler
+                        .execute_typed(P4Command::Status { path })
+                        .await?;
+                    let text = structured::opened_text(&files, "No file(s) to reconcile.");
+                    typed_result(server, &json!({ "files": files }), text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_sync",
+                "Sync files from Perforce depot",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path to sync (e.g., //depot/main/...)"
+                        },
+                        "force": {
+                            "type": "boolean",
+                            "description": "Force sync (overwrite local changes)"
+                        }
+                    }
+                }),
+                ToolAnnotations::write("Sync Files", true, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path").unwrap_or("...".to_string());
+                    let synced = if args.flag("force") {
+                        server.p4_handler.force_sync(path).await?
+                    } else {
+                        server.p4_handler.sync(&path).await?
+                    };
+                    typed_result(server, &synced, structured::sync_text(&synced))
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_edit",
+                "Open file(s) for edit in Perforce",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Files to open for edit"
+                        }
+                    },
+                    "required": ["files"]
+                }),
+                ToolAnnotations::write("Open Files for Edit", false, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let files = args.strings("files").unwrap_or_default();
+                    server.p4_handler.execute(P4Command::Edit { files }).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_add",
+                "Add new file(s) to Perforce",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Files to add"
+                        }
+                    },
+                    "required": ["files"]
+                }),
+                ToolAnnotations::write("Open Files for Add", false, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let files = args.strings("files").unwrap_or_default();
+                    server.p4_handler.execute(P4Command::Add { files }).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_submit",
+                "Submit opened files to Perforce with a new description. The files are moved into a new numbered changelist, which is submitted; if the submit fails, the files stay in that pending changelist. Returns the submitted change number",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "description": {
+                            "type": "string",
+                            "description": "Change description"
+                        },
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Opened files to submit, from any pending changelist; defaults to every file in the default changelist"
+                        },
+                        "jobs": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Jobs the change fixes (e.g., job000123)"
+                        }
+                    },
+                    "required": ["description"]
+                }),
+                ToolAnnotations::write("Submit Changelist", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let description = args.str("description").unwrap_or_default();
+                    let jobs = args.strings("jobs").unwrap_or_default();
+                    server
+                        .p4_handler
+                        .submit(description, args.strings("files"), jobs)
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_revert",
+                "Revert files in Perforce",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Files to revert"
+                        },
+                        "wipe": {
+                            "type": "boolean",
+                            "description": "Also delete files opened for add from disk (p4 revert -w)",
+                            "default": false
+                        }
+                    },
+                    "required": ["files"]
+                }),
+                ToolAnnotations::write("Revert Files", true, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let files = args.strings("files").unwrap_or_default();
+                    let wipe = args.flag("wipe");
+                    server
+                        .p4_handler
+                        .execute(P4Command::Revert { files, wipe })
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_opened",
+                "List files opened for edit",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Optional changelist number"
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Opened Files"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let files = server.p4_handler.opened(args.string("changelist")).await?;
+                    let text =
+                        structured::opened_text(&files, "File(s) not opened on this client.");
+                    typed_result(server, &json!({ "files": files }), text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_changes",
+                "List recent changes",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "max": {
+                            "type": "integer",
+                            "description": "Maximum number of changes to return",
+                            "default": 10
+                        },
+                        "path": {
+                            "type": "string",
+                            "description": "Optional path to filter changes"
+                        },
+                        "cursor": {
+                            "type": "string",
+                            "description": "Continuation cursor from a previous page of results"
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Recent Changes"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let max = args.count("max", 10);
+                    let page = server
+                        .p4_handler
+                        .changes_page(args.string("path"), max, args.str("cursor"))
+                        .await?;
+                    let text = structured::changes_text(&page);
+                    typed_result(server, &page, text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_files",
+                "List depot files matching a path, one page at a time",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Depot path or wildcard (e.g., //depot/main/...)"
+                        },
+                        "max": {
+                            "type": "integer",
+                            "description": "Maximum number of files per page",
+                            "default": 100
+                        },
+                        "cursor": {
+                            "type": "string",
+                            "description": "Continuation cursor from a previous page of results"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+                ToolAnnotations::read_only("Depot Files"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.str("path").unwrap_or("//...");
+                    let max = args.count("max", 100);
+                    server
+                        .p4_handler
+                        .files_page(path, max, args.str("cursor"))
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_describe",
+                "Describe a submitted change: its description, jobs and affected files, and optionally the diffs of its files",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Changelist number"
+                        },
+                        "diffs": {
+                            "type": "boolean",
+                            "description": "Include the diffs of the change's files",
+                            "default": false
+                        },
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Only include the diffs of these depot files, or of the files under paths ending in /..."
+                        },
+                        "max_diff_kb": {
+                            "type": "integer",
+                            "description": "Largest diff to include per file, in KB; larger ones are replaced by a note saying how to fetch them with p4_diff2",
+                            "default": 32
+                        }
+                    },
+                    "required": ["changelist"]
+                }),
+                ToolAnnotations::read_only("Describe Change"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let changelist = args.string("changelist").unwrap_or_default();
+                    if !args.flag("diffs") {
+                        let described = server.p4_handler.describe(&changelist).await?;
+                        let text = structured::describe_text(&described);
+                        return typed_result(server, &described, text);
+                    }
+                    let files = args.strings("files").unwrap_or_default();
+                    let max_diff_bytes = args.count("max_diff_kb", 32) as usize * 1024;
+                    server
+                        .p4_handler
+                        .describe_diffs(&changelist, &files, max_diff_bytes)
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_diff2",
+                "Show the differences between two depot file revisions",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "left": {
+                            "type": "string",
+                            "description": "Depot file revision to compare from (e.g., //depot/main/a.c#3)"
+                        },
+                        "right": {
+                            "type": "string",
+                            "description": "Depot file revision to compare to (e.g., //depot/main/a.c#4)"
+                        }
+                    },
+                    "required": ["left", "right"]
+                }),
+                ToolAnnotations::read_only("Compare Revisions"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let left = args.string("left").unwrap_or_default();
+                    let right = args.string("right").unwrap_or_default();
+                    server
+                        .p4_handler
+                        .execute(P4Command::Diff2 { left, right })
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_integrated",
+                "Show the integration history of files: which revisions of which files were copied, merged or branched into them, and by which change. Use it to check whether a change has already been merged to another branch",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "File or path to show integrations for (e.g., //depot/release/1.0/...)"
+                        },
+                        "reverse": {
+                            "type": "boolean",
+                            "description": "Show where the files were integrated into instead of from",
+                            "default": false
+                        }
+                    },
+                    "required": ["path"]
+                }),
+                ToolAnnotations::read_only("Integration History"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path").unwrap_or_default();
+                    let reverse = args.flag("reverse");
+                    let integrations: Vec<Integration> = server
+                        .p4_handler
+                        .execute_typed(P4Command::Integrated { path, reverse })
+                        .await?;
+                    let text = structured::integrated_text(&integrations);
+                    typed_result(server, &json!({ "integrations": integrations }), text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_diff_shelf",
+                "Diff the files shelved in a pending change against the workspace files, or against their head revisions, to review a shelf as it's reworked",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Pending changelist with shelved files"
+                        },
+                        "files": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Files to compare (default: every file in the shelf)"
+                        },
+                        "against": {
+                            "type": "string",
+                            "enum": ["workspace", "head"],
+                            "description": "Compare the shelved files with the workspace files or with their head revisions",
+                            "default": "workspace"
+                        }
+                    },
+                    "required": ["changelist"]
+                }),
+                ToolAnnotations::read_only("Diff Against Shelf"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let changelist = args.string("changelist").unwrap_or_default();
+                    let files = args.strings("files").unwrap_or_default();
+                    let against_head = args.str("against") == Some("head");
+                    server
+                        .p4_handler
+                        .diff_shelf(&changelist, files, against_head)
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_print",
+                "Print the contents of a depot file. Images are returned as images, and other binary files are described rather than printed",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Depot path of a single file, optionally with a revision (e.g., //depot/main/README.md#3)"
+                        }
+                    },
+                    "required": ["path"]
+                }),
+                ToolAnnotations::read_only("Print File"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path").unwrap_or_default();
+                    match server.p4_handler.print(&path).await? {
+                        FileContents::Text(text) => Ok(text),
+                        FileContents::Image { mime_type, data } => {
+                            let summary = format!("{}: {}, {} bytes", path, mime_type, data.len());
+                            if let Some(limit) = server.blob_over_limit(Some("p4_print"), data.len())
+                            {
+                                return Ok(format!(
+                                    "{}, over the response limit of {} bytes; not shown",
+                                    summary, limit
+                                ));
+                            }
+                            server.images.push(ToolContent::Image {
+                                data: BASE64.encode(data),
+                                mime_type: mime_type.to_string(),
+                            });
+                            Ok(summary)
+                        }
+                        FileContents::Binary(data) => Ok(format!(
+                            "{}: binary file of {} bytes, not shown",
+                            path,
+                            data.len()
+                        )),
+                    }
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_info",
+                "Get the user, client workspace (name, host, root and stream) and server as JSON",
+                json!({
+                    "type": "object",
+                    "properties": {}
+                }),
+                ToolAnnotations::read_only("Server Info"),
+            ),
+            |server, _| {
+                Box::pin(async move {
+                    let info = server.p4_handler.info().await?;
+                    let text = serde_json::to_string_pretty(&info)?;
+                    typed_result(server, &info, text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_client",
+                "Get a client workspace's spec as JSON: its owner, host, root, options, stream and view",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "name": {
+                            "type": "string",
+                            "description": "Client workspace name (default: the current client)"
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Client Spec"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let spec = server.p4_handler.client_spec(args.str("name")).await?;
+                    let text = serde_json::to_string_pretty(&spec)?;
+                    typed_result(server, &spec, text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_submit_with_job",
+                "Verify a job exists, link it to a pending changelist with p4 fix, and submit the changelist",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "job": {
+                            "type": "string",
+                            "description": "Job ID the change fixes (e.g., job000123)"
+                        },
+                        "changelist": {
+                            "type": "string",
+                            "description": "Pending changelist number to submit"
+                        }
+                    },
+                    "required": ["job", "changelist"]
+                }),
+                ToolAnnotations::write("Submit with Job", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let job = args.str("job").unwrap_or_default();
+                    let changelist = args.str("changelist").unwrap_or_default();
+                    server.p4_handler.submit_with_job(job, changelist).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_workspace_health",
+                "Report opened files, files needing resolve, out-of-date files, shelved changes, and ticket status in one structured response",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Optional path to limit the resolve and out-of-date checks to"
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Workspace Health"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path");
+                    server.p4_handler.workspace_health(path).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_pending_work",
+                "Summarize your pending work in this workspace by changelist: each change's description, the files open in it, and whether it's shelved. Files in the default changelist come first",
+                json!({
+                    "type": "object",
+                    "properties": {}
+                }),
+                ToolAnnotations::read_only("Pending Work"),
+            ),
+            |server, _args| {
+                Box::pin(async move {
+                    let work = server.p4_handler.pending_work().await?;
+                    let text = serde_json::to_string_pretty(&work)?;
+                    typed_result(server, &work, text)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_health",
+                "Check the connection to Perforce: that the p4 client runs, that p4 info reaches the server, and that the login ticket is valid. Use it when tool calls keep failing",
+                json!({
+                    "type": "object",
+                    "properties": {}
+                }),
+                ToolAnnotations::read_only("Connection Health"),
+            ),
+            |server, _args| {
+                Box::pin(async move {
+                    let report = server.p4_handler.health().await;
+                    Ok(serde_json::to_string_pretty(&report)?)
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_submit_preflight",
+                "Check whether a pending changelist is ready to submit: files resolved and up to date, description meets policy, and no exclusive locks held by other users",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Pending changelist number to check"
+                        }
+                    },
+                    "required": ["changelist"]
+                }),
+                ToolAnnotations::read_only("Submit Preflight"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let changelist = args.str("changelist").unwrap_or_default();
+                    server.p4_handler.submit_preflight(changelist).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_predict_conflicts",
+                "Predict whether integrating one path into another will conflict, using a scratch changelist that is cleaned up afterwards",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "from": {
+                            "type": "string",
+                            "description": "Source path to integrate from (e.g., //depot/main/...)"
+                        },
+                        "to": {
+                            "type": "string",
+                            "description": "Target path to integrate into (e.g., //depot/rel/...)"
+                        }
+                    },
+                    "required": ["from", "to"]
+                }),
+                ToolAnnotations::write("Predict Conflicts", false, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let from = args.str("from").unwrap_or_default();
+                    let to = args.str("to").unwrap_or_default();
+                    server.p4_handler.predict_conflicts(from, to).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_populate",
+                "Branch files into a new path on the server with p4 populate, without opening or syncing them in the workspace. The branched files are submitted in a single new change, so it's the quickest way to create a branch of a large tree. The target must not already have files",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "from": {
+                            "type": "string",
+                            "description": "Source path to branch from (e.g., //depot/main/...)"
+                        },
+                        "to": {
+                            "type": "string",
+                            "description": "New path to branch into (e.g., //depot/rel/2.0/...)"
+                        },
+                        "description": {
+                            "type": "string",
+                            "description": "Description of the change the branched files are submitted in"
+                        }
+                    },
+                    "required": ["from", "to", "description"]
+                }),
+                ToolAnnotations::write("Populate Branch", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let from = args.str("from").unwrap_or_default();
+                    let to = args.str("to").unwrap_or_default();
+                    let description = args.str("description").unwrap_or_default();
+                    server.p4_handler.populate(from, to, description).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_sparse_view",
+                "Generate a minimal client View mapping for a set of depot paths, and optionally apply it to the current workspace",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "paths": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Depot paths of interest (e.g., //depot/main/src/...); directories unless given with a revision, like //depot/main/app.ini#head"
+                        },
+                        "apply": {
+                            "type": "boolean",
+                            "description": "Save the generated view to the current client spec",
+                            "default": false
+                        }
+                    },
+                    "required": ["paths"]
+                }),
+                ToolAnnotations::write("Sparse Client View", true, true),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let paths = args.strings("paths").unwrap_or_default();
+                    let apply = args.flag("apply");
+                    server.p4_handler.sparse_client_view(&paths, apply).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_workspace_create",
+                "Create a client workspace from a template in the server's config, which sets its root, stream or view, and options, and optionally sync it to head. Returns the new workspace's spec",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "template": {
+                            "type": "string",
+                            "description": "Name of the workspace template to use"
+                        },
+                        "name": {
+                            "type": "string",
+                            "description": "Name of the new client workspace (e.g., alice-main)"
+                        },
+                        "root": {
+                            "type": "string",
+                            "description": "Directory for the workspace's files, instead of the template's root"
+                        },
+                        "sync": {
+                            "type": "boolean",
+                            "description": "Sync the new workspace to head once it's created",
+                            "default": false
+                        }
+                    },
+                    "required": ["template", "name"]
+                }),
+                ToolAnnotations::write("Create Workspace", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let template = args.str("template").unwrap_or_default();
+                    let name = args.str("name").unwrap_or_default();
+                    let sync = args.flag("sync");
+                    let check_root = server.roots_check().await;
+                    server
+                        .p4_handler
+                        .create_workspace(template, name, args.str("root"), sync, &check_root)
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_stream_graph",
+                "Show the stream hierarchy as a parent/child tree, optionally with a Mermaid diagram",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Optional stream path filter (e.g., //streams/...)"
+                        },
+                        "mermaid": {
+                            "type": "boolean",
+                            "description": "Include a Mermaid diagram of the hierarchy",
+                            "default": false
+                        }
+                    }
+                }),
+                ToolAnnotations::read_only("Stream Graph"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let path = args.string("path");
+                    let mermaid = args.flag("mermaid");
+                    server.p4_handler.stream_graph(path, mermaid).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_release_notes",
+                "Collect the changes between two labels or changelists with descriptions, authors, and linked jobs as structured data for release notes",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "from": {
+                            "type": "string",
+                            "description": "Label or changelist of the previous release (exclusive)"
+                        },
+                        "to": {
+                            "type": "string",
+                            "description": "Label or changelist of the new release (inclusive)"
+                        },
+                        "path": {
+                            "type": "string",
+                            "description": "Depot path to collect changes for (default: //...)"
+                        }
+                    },
+                    "required": ["from", "to"]
+                }),
+                ToolAnnotations::read_only("Release Notes"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let from = args.str("from").unwrap_or_default();
+                    let to = args.str("to").unwrap_or_default();
+                    let path = args.str("path").unwrap_or("//...");
+                    server.p4_handler.release_notes(path, from, to).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "p4_backend",
+                "Report whether the server answers with mock responses or runs p4 against the real server, or switch between the two for every session (needs allow_backend_switch)",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "mode": {
+                            "type": "string",
+                            "enum": ["mock", "real"],
+                            "description": "Backend to switch to; omit to report the current one"
+                        }
+                    }
+                }),
+                ToolAnnotations::write("Switch Backend", false, true),
+            ),
+            |server, args| {
+                Box::pin(async move { server.p4_handler.switch_backend(args.str("mode")).await })
+            },
+        );
+
+        self.register(
+            tool(
+                "server_stats",
+                "Report this session's usage so far: tool calls, errors, bytes returned, and p4 commands run and the time they took, by tool",
+                json!({
+                    "type": "object",
+                    "properties": {}
+                }),
+                ToolAnnotations::read_only("Session Statistics"),
+            ),
+            |server, _args| {
+                Box::pin(async move {
+                    let report = server.stats.report(&server.session_id);
+                    Ok(serde_json::to_string_pretty(&report)?)
+                })
+            },
+        );
+
+        // Offered once the client advertises sampling; see `sampling::SAMPLING_TOOLS`
+        self.register(
+            tool(
+                "p4_suggest_description",
+                "Generate a changelist description for the opened files with the client's model",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Pending changelist to describe (default: all opened files)"
+                        },
+                        "apply": {
+                            "type": "boolean",
+                            "description": "Save the description to the changelist",
+                            "default": false
+                        }
+                    }
+                }),
+                ToolAnnotations::write("Suggest Description", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let changelist = args.string("changelist");
+                    let apply = args.flag("apply");
+                    server.suggest_description(changelist, apply).await
+                })
+            },
+        );
+        self.set_enabled("p4_suggest_description", false);
+    }
+
+    fn register_swarm_tools(&mut self) {
+        self.register(
+            tool(
+                "swarm_create_review",
+                "Create a Helix Swarm review from a shelved changelist",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "changelist": {
+                            "type": "string",
+                            "description": "Shelved changelist number to review"
+                        },
+                        "description": {
+                            "type": "string",
+                            "description": "Optional review description (defaults to the change description)"
+                        },
+                        "reviewers": {
+                            "type": "array",
+                            "items": {"type": "string"},
+                            "description": "Optional reviewers to add to the review"
+                        }
+                    },
+                    "required": ["changelist"]
+                }),
+                ToolAnnotations::write("Create Swarm Review", false, false),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let changelist = args.str("changelist").unwrap_or_default();
+                    let reviewers = args.strings("reviewers").unwrap_or_default();
+                    server
+                        .swarm()?
+                        .create_review(changelist, args.str("description"), &reviewers)
+                        .await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "swarm_review_status",
+                "Get the state and reviewer votes of a Helix Swarm review",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "review": {
+                            "type": "string",
+                            "description": "Swarm review ID"
+                        }
+                    },
+                    "required": ["review"]
+                }),
+                ToolAnnotations::read_only("Swarm Review Status"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let review = args.str("review").unwrap_or_default();
+                    server.swarm()?.review_status(review).await
+                })
+            },
+        );
+
+        self.register(
+            tool(
+                "swarm_review_comments",
+                "List reviewer comments on a Helix Swarm review",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "review": {
+                            "type": "string",
+                            "description": "Swarm review ID"
+                        }
+                    },
+                    "required": ["review"]
+                }),
+                ToolAnnotations::read_only("Swarm Review Comments"),
+            ),
+            |server, args| {
+                Box::pin(async move {
+                    let review = args.str("review").unwrap_or_default();
+                    server.swarm()?.review_comments(review).await
+                })
+            },
+        );
+    }
+}
+
+impl MCPServer {
+    fn swarm(&self) -> Result<&crate::swarm::SwarmClient> {
+        self.swarm
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("Swarm integration is not configured"))
+    }
+}
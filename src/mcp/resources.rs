@@ -0,0 +1,209 @@
+//! MCP resources backed by depot files
+//!
+//! Depot paths are exposed with the `p4:` URI scheme, so `//depot/main/README.md`
+//! becomes `p4://depot/main/README.md`. Revision specifiers are passed through to
+//! `p4 print`, so `p4://depot/main/README.md@12345` reads the file at change 12345.
+//! Images and other binary files are read as base64 `blob`s, with the image's MIME
+//! type or `application/octet-stream`.
+//!
+//! Changelists are exposed as `p4://change/<number>` and read as structured
+//! `p4 describe` output.
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use super::*;
+use crate::p4::{FileContents, P4Command};
+
+const URI_SCHEME: &str = "p4:";
+const CHANGE_URI_PREFIX: &str = "p4://change/";
+
+/// Maximum number of files listed when a directory resource is read.
+const MAX_LISTED_FILES: u32 = 1000;
+
+/// Depot roots exposed by `resources/list`, from the comma-separated `P4_DEPOT_ROOTS`.
+///
+/// When unset, the roots are discovered from `p4 depots` on each listing.
+pub(crate) fn depot_roots_from_env() -> Vec<String> {
+    std::env::var("P4_DEPOT_ROOTS")
+        .map(|roots| {
+            roots
+                .split(',')
+                .map(|root| root.trim().to_string())
+                .filter(|root| root.starts_with("//"))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn depot_path_to_uri(depot_path: &str) -> String {
+    format!("{}{}", URI_SCHEME, depot_path)
+}
+
+pub fn change_to_uri(changelist: &str) -> String {
+    format!("{}{}", CHANGE_URI_PREFIX, changelist)
+}
+
+/// Changelist number addressed by a `p4://change/<number>` URI.
+pub fn uri_to_change(uri: &str) -> Option<&str> {
+    uri.strip_prefix(CHANGE_URI_PREFIX)
+        .filter(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Map a `p4:` URI back to a depot path.
+///
+/// Clients expanding the URI templates with simple string expansion encode the
+/// slashes in `{depotPath}` as `%2F`; those are decoded. Other escapes are left
+/// alone since Perforce uses `%40`, `%23`, `%25` and `%2A` in file names.
+pub fn uri_to_depot_path(uri: &str) -> Option<String> {
+    let path = uri.strip_prefix(URI_SCHEME)?;
+    let path = path.replace("%2F", "/").replace("%2f", "/");
+    (path.starts_with("//") && path.len() > 2).then_some(path)
+}
+
+/// URI templates clients can expand to address arbitrary depot files and revisions.
+pub fn resource_templates() -> Vec<ResourceTemplate> {
+    vec![
+        ResourceTemplate {
+            uri_template: "p4://change/{changelist}".to_string(),
+            name: "Changelist".to_string(),
+            description: Some(
+                "Changelist metadata, description, affected files and fixed jobs".to_string(),
+            ),
+            mime_type: Some("application/json".to_string()),
+        },
+        ResourceTemplate {
+            uri_template: "p4://{depotPath}".to_string(),
+            name: "Depot file".to_string(),
+            description: Some(
+                "Head revision of a depot file, e.g. depotPath = depot/main/README.md".to_string(),
+            ),
+            mime_type: None,
+        },
+        ResourceTemplate {
+            uri_template: "p4://{depotPath}@{revision}".to_string(),
+            name: "Depot file at revision".to_string(),
+            description: Some(
+                "Depot file at a changelist number, label, or date (any @ revision specifier)"
+                    .to_string(),
+            ),
+            mime_type: None,
+        },
+        ResourceTemplate {
+            uri_template: "p4://{depotPath}#{rev}".to_string(),
+            name: "Depot file revision".to_string(),
+            description: Some("Specific file revision number, or #head / #have".to_string()),
+            mime_type: None,
+        },
+    ]
+}
+
+/// Best-effort MIME type from a depot path's file extension.
+pub fn mime_type_for(depot_path: &str) -> &'static str {
+    let file = depot_path.rsplit('/').next().unwrap_or(depot_path);
+    let file = file.split(['#', '@']).next().unwrap_or(file);
+    match file
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase())
+    {
+        Some(ext) => match ext.as_str() {
+            "md" => "text/markdown",
+            "json" => "application/json",
+            "xml" => "application/xml",
+            "html" | "htm" => "text/html",
+            "css" => "text/css",
+            "js" => "text/javascript",
+            "c" | "h" => "text/x-c",
+            "cpp" | "cc" | "cxx" | "hpp" | "hh" => "text/x-c++",
+            "rs" => "text/x-rust",
+            "py" => "text/x-python",
+            _ => "text/plain",
+        },
+        None => "text/plain",
+    }
+}
+
+impl MCPServer {
+    pub(crate) async fn list_resources(&mut self) -> Result<Vec<Resource>> {
+        let roots = if self.resource_roots.is_empty() {
+            let depots = self.p4_handler.execute(P4Command::Depots).await?;
+            // Depot <name> <date> <type> <map> '<description>'
+            depots
+                .lines()
+                .filter_map(|line| line.strip_prefix("Depot "))
+                .filter_map(|rest| rest.split_whitespace().next())
+                .map(|name| format!("//{}/...", name))
+                .collect()
+        } else {
+            self.resource_roots.clone()
+        };
+
+        Ok(roots
+            .into_iter()
+            .map(|root| Resource {
+                uri: depot_path_to_uri(&root),
+                description: Some(format!("Files under {}", root)),
+                name: root,
+                mime_type: Some("text/plain".to_string()),
+            })
+            .collect())
+    }
+
+    pub(crate) async fn read_change_resource(
+        &mut self,
+        changelist: &str,
+    ) -> Result<ResourceContents> {
+        let described = self.p4_handler.describe(changelist).await?;
+
+        Ok(ResourceContents {
+            uri: change_to_uri(changelist),
+            mime_type: Some("application/json".to_string()),
+            text: Some(serde_json::to_string_pretty(&described)?),
+            blob: None,
+        })
+    }
+
+    pub(crate) async fn read_resource(&mut self, depot_path: &str) -> Result<ResourceContents> {
+        let is_directory = depot_path.contains("...") || depot_path.contains('*');
+
+        let (text, blob, mime_type) = if is_directory {
+            let files = self
+                .p4_handler
+                .execute(P4Command::Files {
+                    path: depot_path.to_string(),
+                    max: Some(MAX_LISTED_FILES),
+                })
+                .await?;
+            (Some(files), None, "text/plain")
+        } else {
+            match self.p4_handler.print(depot_path).await? {
+                FileContents::Text(text) => (Some(text), None, mime_type_for(depot_path)),
+                FileContents::Image { data, .. } | FileContents::Binary(data)
+                    if self.blob_over_limit(None, data.len()).is_some() =>
+                {
+                    let note = format!(
+                        "{}: {} bytes, over the response limit of {} bytes; not shown",
+                        depot_path,
+                        data.len(),
+                        self.max_response_bytes
+                    );
+                    (Some(note), None, "text/plain")
+                }
+                FileContents::Image { mime_type, data } => {
+                    (None, Some(BASE64.encode(data)), mime_type)
+                }
+                FileContents::Binary(data) => {
+                    (None, Some(BASE64.encode(data)), "application/octet-stream")
+                }
+            }
+        };
+
+        Ok(ResourceContents {
+            uri: depot_path_to_uri(depot_path),
+            mime_type: Some(mime_type.to_string()),
+            text,
+            blob,
+        })
+    }
+}
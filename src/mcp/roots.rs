@@ -0,0 +1,258 @@
+//! Client roots
+//!
+//! Clients advertising the `roots` capability are asked for `roots/list` once the
+//! handshake completes, and again on `notifications/roots/list_changed`. Tool calls
+//! naming local paths outside those roots are rejected, so the server only touches
+//! workspace files the client has exposed. Depot paths (`//depot/...`) are not local
+//! and are always allowed.
+//!
+//! p4 tools also take a `cwd` argument to run p4 in another directory; it has to be
+//! an existing directory within the roots, and relative paths are resolved against it.
+
+use anyhow::Result;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+use super::*;
+
+/// Local directories the client exposes, or `None` when the client has not reported any.
+pub(crate) type ClientRoots = Arc<tokio::sync::RwLock<Option<Vec<PathBuf>>>>;
+
+/// Map a `file://` root URI to a local path.
+pub fn root_uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    // file://localhost/path and file:///path name the same directory
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    let path = percent_decode(path);
+    // file:///C:/work -> C:/work
+    let path = match path.as_bytes() {
+        [b'/', drive, b':', ..] if drive.is_ascii_alphabetic() => path[1..].to_string(),
+        _ => path,
+    };
+    Path::new(&path)
+        .is_absolute()
+        .then(|| normalize(Path::new(&path)))
+}
+
+/// The absolute local path named by a tool argument, or `None` for depot paths.
+///
+/// Relative paths are resolved against `cwd` like p4 does. Revision specifiers and
+/// the components from the first wildcard onwards are dropped, so `src/...@123`
+/// checks `src`.
+pub fn local_path(arg: &str, cwd: &Path) -> Option<PathBuf> {
+    if arg.starts_with("//") {
+        return None;
+    }
+    let path = arg.split(['#', '@']).next().unwrap_or(arg);
+    let wildcard = path
+        .split(['/', '\\'])
+        .position(|component| component.contains("...") || component.contains('*'));
+    let path: Vec<&str> = match wildcard {
+        Some(position) => path.split(['/', '\\']).take(position).collect(),
+        None => vec![path],
+    };
+    Some(normalize(&cwd.join(path.join("/"))))
+}
+
+/// Whether `path` is one of `roots` or inside one.
+pub fn is_within(path: &Path, roots: &[PathBuf]) -> bool {
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+/// Resolve `.` and `..` without touching the filesystem, since the file may not exist yet.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escape {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub(crate) fn outside_roots(arg: &str, roots: &[PathBuf]) -> String {
+    format!(
+        "{} is outside the client's roots ({})",
+        arg,
+        roots
+            .iter()
+            .map(|root| root.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// Arguments of the tools that name files or directories, each a path or a list of
+/// them: paths to act on, the two sides of an integration (`from`, `to`) or a diff
+/// (`left`, `right`), and the root of a new workspace.
+const PATH_ARGUMENTS: &[&str] = &[
+    "path", "files", "paths", "from", "to", "left", "right", "root",
+];
+
+/// Local path arguments of a tool call.
+fn path_arguments(arguments: &serde_json::Value) -> Vec<&str> {
+    let mut paths = Vec::new();
+    for key in PATH_ARGUMENTS {
+        match arguments.get(key) {
+            Some(serde_json::Value::String(path)) => paths.push(path.as_str()),
+            Some(serde_json::Value::Array(values)) => {
+                paths.extend(values.iter().filter_map(|v| v.as_str()))
+            }
+            _ => {}
+        }
+    }
+    paths
+}
+
+/// Add the optional `cwd` argument to a p4 tool's input schema.
+pub(crate) fn add_cwd_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            "cwd".to_string(),
+            serde_json::json!({
+                "type": "string",
+                "description": "Local directory to run p4 in (default: the server's working directory)"
+            }),
+        );
+    }
+}
+
+async fn fetch_roots(client: &ClientRequester) -> Result<Vec<PathBuf>> {
+    let result = client.request("roots/list", serde_json::json!({})).await?;
+    let roots = result
+        .get("roots")
+        .and_then(|roots| roots.as_array())
+        .ok_or_else(|| anyhow::anyhow!("roots/list result has no roots"))?;
+
+    Ok(roots
+        .iter()
+        .filter_map(|root| root.get("uri").and_then(|uri| uri.as_str()))
+        .filter_map(|uri| {
+            let path = root_uri_to_path(uri);
+            if path.is_none() {
+                warn!(
+                    "Ignoring client root that is not a local directory: {}",
+                    uri
+                );
+            }
+            path
+        })
+        .collect())
+}
+
+impl MCPServer {
+    /// Ask the client for its roots in the background.
+    ///
+    /// Tool calls wait for the answer, so none run against stale roots.
+    pub(crate) async fn refresh_roots(&mut self) {
+        let Some(client) = self.client.clone() else {
+            return;
+        };
+
+        let mut roots = self.client_roots.clone().write_owned().await;
+        tokio::spawn(async move {
+            match fetch_roots(&client).await {
+                Ok(fetched) => {
+                    info!("Client roots: {:?}", fetched);
+                    *roots = Some(fetched);
+                }
+                Err(e) => warn!("Could not list client roots: {}", e),
+            }
+        });
+    }
+
+    /// Where relative paths are resolved when a call doesn't pass `cwd`: the configured
+    /// workspace root, the client root, or the server's working directory.
+    pub(crate) async fn base_dir(&self) -> std::io::Result<PathBuf> {
+        match self.p4_handler.default_dir().await {
+            Some(dir) => Ok(dir),
+            None => std::env::current_dir(),
+        }
+    }
+
+    /// The directory named by a tool call's `cwd` argument, or an error message if it
+    /// isn't a directory inside the client's roots.
+    pub(crate) async fn working_dir(
+        &self,
+        arguments: &serde_json::Value,
+    ) -> Result<Option<PathBuf>, String> {
+        let Some(arg) = arguments.get("cwd").and_then(|v| v.as_str()) else {
+            return Ok(None);
+        };
+        let base = self.base_dir().await.map_err(|e| e.to_string())?;
+        let dir = local_path(arg, &base)
+            .filter(|_| !arg.contains(['#', '@', '*']) && !arg.contains("..."))
+            .ok_or_else(|| format!("cwd must be a local directory, not {}", arg))?;
+        if !dir.is_dir() {
+            return Err(format!("cwd {} is not a directory", arg));
+        }
+        if let Some(roots) = self.client_roots.read().await.as_ref() {
+            if !is_within(&dir, roots) {
+                return Err(outside_roots(arg, roots));
+            }
+        }
+        Ok(Some(dir))
+    }
+
+    /// Checks a local directory that a tool writes into without naming it in an
+    /// argument, such as the root a template gives a new workspace, against the
+    /// client's roots as they are now.
+    pub(crate) async fn roots_check(&self) -> impl Fn(&str) -> Result<()> + Send + Sync {
+        let roots = self.client_roots.read().await.clone();
+        let base = self.base_dir().await.unwrap_or_default();
+        move |dir| match &roots {
+            Some(roots) if local_path(dir, &base).is_none_or(|path| !is_within(&path, roots)) => {
+                Err(anyhow::anyhow!(outside_roots(dir, roots)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Describe the first local path argument that falls outside the client's roots.
+    /// Relative paths are resolved against `cwd`, or the workspace root.
+    pub(crate) async fn check_roots(
+        &self,
+        arguments: &serde_json::Value,
+        cwd: Option<&Path>,
+    ) -> Option<String> {
+        let roots = self.client_roots.read().await;
+        let roots = roots.as_ref()?;
+        let cwd = match cwd {
+            Some(cwd) => cwd.to_path_buf(),
+            None => self.base_dir().await.ok()?,
+        };
+
+        path_arguments(arguments).into_iter().find_map(|arg| {
+            let path = local_path(arg, &cwd)?;
+            (!is_within(&path, roots)).then(|| outside_roots(arg, roots))
+        })
+    }
+}
@@ -0,0 +1,69 @@
+//! Changelist descriptions written by the client's model through MCP sampling
+
+use anyhow::Result;
+
+use super::*;
+
+/// Tools that are only offered when the client supports sampling.
+pub const SAMPLING_TOOLS: &[&str] = &["p4_suggest_description"];
+
+const SYSTEM_PROMPT: &str =
+    "You write Perforce changelist descriptions. Reply with the description only, without quotes or commentary.";
+
+const MAX_DESCRIPTION_TOKENS: u32 = 500;
+
+impl MCPServer {
+    /// Generate a description for the opened files with the client's model, and optionally
+    /// save it to the pending changelist.
+    pub(crate) async fn suggest_description(
+        &mut self,
+        changelist: Option<String>,
+        apply: bool,
+    ) -> Result<String> {
+        let Some(client) = self.client.clone() else {
+            return Err(anyhow::anyhow!("The client does not support sampling"));
+        };
+
+        let changelist = changelist.filter(|c| c != "default");
+        if apply && changelist.is_none() {
+            return Err(anyhow::anyhow!(
+                "A numbered pending changelist is required to apply the description"
+            ));
+        }
+
+        let request = self.change_description_request(changelist.clone()).await?;
+        let result = client
+            .request(
+                "sampling/createMessage",
+                serde_json::json!({
+                    "messages": [{
+                        "role": "user",
+                        "content": {"type": "text", "text": request}
+                    }],
+                    "systemPrompt": SYSTEM_PROMPT,
+                    "includeContext": "none",
+                    "maxTokens": MAX_DESCRIPTION_TOKENS
+                }),
+            )
+            .await?;
+
+        let description = result
+            .get("content")
+            .and_then(|content| content.get("text"))
+            .and_then(|text| text.as_str())
+            .map(str::trim)
+            .filter(|text| !text.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("The client's model returned no text"))?;
+
+        match changelist {
+            Some(changelist) if apply => {
+                let saved = self
+                    .p4_handler
+                    .set_change_description(&changelist, description)
+                    .await?;
+                Ok(format!("{}\n\n{}", description, saved.trim()))
+            }
+            _ => Ok(description.to_string()),
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Usage statistics for a session
+//!
+//! Each session counts its tool calls by tool: how many there were, how many failed,
+//! how much text they returned, and the p4 commands they ran and how long those took.
+//! The `server_stats` tool reports them, which helps when debugging a session and when
+//! looking at how an agent uses Perforce. Unlike the [`metrics`](crate::metrics),
+//! which add up every session of the process, these start from zero for each session.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::{MCPResponse, ToolContent};
+use crate::p4::ExecutedCommand;
+
+/// What calls to one tool, or to every tool, added up to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ToolStats {
+    pub calls: u64,
+    pub errors: u64,
+    /// Bytes of content in the results, after any truncation.
+    pub bytes_returned: u64,
+    pub p4_commands: u64,
+    /// How long the p4 commands run took, in milliseconds.
+    pub p4_time_ms: u64,
+}
+
+impl ToolStats {
+    fn add(&mut self, other: &ToolStats) {
+        self.calls += other.calls;
+        self.errors += other.errors;
+        self.bytes_returned += other.bytes_returned;
+        self.p4_commands += other.p4_commands;
+        self.p4_time_ms += other.p4_time_ms;
+    }
+}
+
+/// The counters of one session, shared by its forks.
+#[derive(Debug)]
+pub struct SessionStats {
+    started: Instant,
+    tools: Mutex<BTreeMap<String, ToolStats>>,
+    /// Failed calls by the category [`crate::metrics::tool_call_outcome`] gives them.
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// A snapshot of [`SessionStats`], as `server_stats` returns it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsReport {
+    pub session: String,
+    pub uptime_secs: u64,
+    pub totals: ToolStats,
+    pub errors: BTreeMap<&'static str, u64>,
+    pub tools: BTreeMap<String, ToolStats>,
+}
+
+impl Default for SessionStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            tools: Mutex::default(),
+            errors: Mutex::default(),
+        }
+    }
+}
+
+impl SessionStats {
+    fn tools(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, ToolStats>> {
+        self.tools.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count a finished call to `tool`, which got `response`.
+    pub fn record_call(
+        &self,
+        tool: &str,
+        response: &anyhow::Result<Option<MCPResponse>>,
+        outcome: Result<(), &'static str>,
+    ) {
+        let bytes = match response {
+            Ok(Some(MCPResponse::CallToolResult { result, .. })) => result
+                .content
+                .iter()
+                .map(|content| match content {
+                    ToolContent::Text { text } => text.len() as u64,
+                    ToolContent::Image { data, .. } => data.len() as u64,
+                })
+                .sum(),
+            _ => 0,
+        };
+        let mut tools = self.tools();
+        let stats = tools.entry(tool.to_string()).or_default();
+        stats.calls += 1;
+        stats.bytes_returned += bytes;
+        if let Err(category) = outcome {
+            stats.errors += 1;
+            *self
+                .errors
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .entry(category)
+                .or_default() += 1;
+        }
+    }
+
+    /// Count the p4 commands a call to `tool` ran, whether or not it succeeded.
+    pub fn record_commands(&self, tool: &str, commands: &[ExecutedCommand]) {
+        let mut tools = self.tools();
+        let stats = tools.entry(tool.to_string()).or_default();
+        stats.p4_commands += commands.len() as u64;
+        stats.p4_time_ms += commands.iter().map(|c| c.duration_ms).sum::<u64>();
+    }
+
+    /// Everything counted so far in the session called `session`.
+    pub fn report(&self, session: &str) -> StatsReport {
+        let tools = self.tools().clone();
+        let mut totals = ToolStats::default();
+        for stats in tools.values() {
+            totals.add(stats);
+        }
+        StatsReport {
+            session: session.to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            totals,
+            errors: self
+                .errors
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+            tools,
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Structured tool output
+//!
+//! Read-only tools that map onto a single p4 command declare an `outputSchema` and
+//! return the command's typed `-ztag` records as `structuredContent`. Tools with typed
+//! results from [`P4Handler`](crate::p4::P4Handler), such as `p4_sync` and
+//! `p4_describe`, return those. Either way the command runs once: the text output is
+//! rendered from the same result, laid out as p4 prints it for files and changes, and
+//! as JSON for `p4_info`, `p4_client` and `p4_pending_work`.
+
+use serde_json::json;
+
+use crate::p4::ztag::{DescribedChange, Integration, OpenedFile};
+use crate::p4::{ChangesPage, SyncResult};
+
+fn string_properties(fields: &[&str]) -> serde_json::Value {
+    fields
+        .iter()
+        .map(|field| (field.to_string(), json!({"type": "string"})))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn opened_files_schema() -> serde_json::Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "files": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": string_properties(&[
+                        "depotFile", "clientFile", "rev", "action", "change", "type", "user",
+                        "client",
+                    ]),
+                    "required": ["depotFile", "action"]
+                }
+            }
+        },
+        "required": ["files"]
+    })
+}
+
+/// The `outputSchema` declared for a tool, if it returns structured content.
+pub fn output_schema(tool_name: &str) -> Option<serde_json::Value> {
+    match tool_name {
+        "p4_info" => Some(json!({
+            "type": "object",
+            "properties": string_properties(&[
+                "userName", "clientName", "clientHost", "clientRoot", "clientStream", "serverAddress",
+                "serverRoot", "serverVersion", "serverID", "caseHandling",
+            ]),
+            "required": ["userName", "serverAddress"]
+        })),
+        "p4_status" | "p4_opened" => Some(opened_files_schema()),
+        "p4_changes" => Some(json!({
+            "type": "object",
+            "properties": {
+                "changes": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": string_properties(&[
+                            "change", "time", "user", "client", "status", "changeType", "path",
+                            "desc",
+                        ]),
+                        "required": ["change"]
+                    }
+                },
+                "nextCursor": {"type": "string"}
+            },
+            "required": ["changes"]
+        })),
+        "p4_integrated" => Some(json!({
+            "type": "object",
+            "properties": {
+                "integrations": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": string_properties(&[
+                            "toFile", "startToRev", "endToRev", "fromFile", "startFromRev",
+                            "endFromRev", "how", "change",
+                        ]),
+                        "required": ["toFile", "fromFile", "how", "change"]
+                    }
+                }
+            },
+            "required": ["integrations"]
+        })),
+        "p4_pending_work" => Some(json!({
+            "type": "object",
+            "properties": {
+                "user": {"type": "string"},
+                "client": {"type": ["string", "null"]},
+                "changelists": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "change": {"type": "string"},
+                            "description": {"type": ["string", "null"]},
+                            "shelved": {"type": "boolean"},
+                            "files": {
+                                "type": "array",
+                                "items": {
+                                    "type": "object",
+                                    "properties": {
+                                        "depotFile": {"type": "string"},
+                                        "rev": {"type": "string"},
+                                        "action": {"type": "string"},
+                                        "type": {"type": "string"},
+                                        "locked": {"type": "boolean"}
+                                    },
+                                    "required": ["depotFile", "action"]
+                                }
+                            }
+                        },
+                        "required": ["change", "shelved", "files"]
+                    }
+                }
+            },
+            "required": ["user", "changelists"]
+        })),
+        "p4_sync" => Some(json!({
+            "type": "object",
+            "properties": {
+                "files": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "depotFile": {"type": "string"},
+                            "rev": {"type": ["integer", "null"]},
+                            "action": {"type": "string"},
+                            "clientFile": {"type": "string"}
+                        },
+                        "required": ["depotFile", "action", "clientFile"]
+                    }
+                },
+                "messages": {"type": "array", "items": {"type": "string"}}
+            },
+            "required": ["files", "messages"]
+        })),
+        "p4_client" => Some(json!({
+            "type": "object",
+            "properties": {
+                "client": {"type": "string"},
+                "owner": {"type": "string"},
+                "host": {"type": "string"},
+                "root": {"type": "string"},
+                "options": {"type": "array", "items": {"type": "string"}},
+                "lineEnd": {"type": "string"},
+                "stream": {"type": ["string", "null"]},
+                "view": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": string_properties(&["depotPath", "clientPath"]),
+                        "required": ["depotPath", "clientPath"]
+                    }
+                }
+            },
+            "required": ["client", "root", "view"]
+        })),
+        _ => None,
+    }
+}
+
+/// A sync as `p4 sync` reports it: a line per file synced, then p4's other messages.
+pub(crate) fn sync_text(synced: &SyncResult) -> String {
+    let mut text = String::new();
+    for file in &synced.files {
+        let report = match file.action.as_str() {
+            "added" => "added as",
+            "deleted" => "deleted as",
+            "refreshed" => "refreshing",
+            "replaced" => "replacing",
+            _ => "updating",
+        };
+        let rev = file.rev.map(|rev| format!("#{}", rev)).unwrap_or_default();
+        text.push_str(&format!(
+            "{}{} - {} {}\n",
+            file.depot_file, rev, report, file.client_file
+        ));
+    }
+    for message in &synced.messages {
+        text.push_str(message);
+        text.push('\n');
+    }
+    text
+}
+
+/// Opened files as `p4 opened` prints them, or `none` if there are none.
+pub(crate) fn opened_text(files: &[OpenedFile], none: &str) -> String {
+    if files.is_empty() {
+        return format!("{}\n", none);
+    }
+    files
+        .iter()
+        .map(|file| {
+            let change = match file.change.as_str() {
+                "default" => "default change".to_string(),
+                change => format!("change {}", change),
+            };
+            let lock = if file.our_lock.is_some() {
+                " *locked*"
+            } else {
+                ""
+            };
+            format!(
+                "{}#{} - {} {} ({}){}\n",
+                file.depot_file, file.rev, file.action, change, file.file_type, lock
+            )
+        })
+        .collect()
+}
+
+/// A page of changes as `p4 changes` prints them, followed by the cursor of the next.
+pub(crate) fn changes_text(page: &ChangesPage) -> String {
+    let mut text: String = page
+        .changes
+        .iter()
+        .map(|change| {
+            let pending = if change.status == "pending" {
+                " *pending*"
+            } else {
+                ""
+            };
+            format!(
+                "Change {} on {} by {}@{}{} '{}'\n",
+                change.change,
+                p4_time(&change.time, "%Y/%m/%d"),
+                change.user,
+                change.client,
+                pending,
+                change.desc.lines().next().unwrap_or_default().trim()
+            )
+        })
+        .collect();
+    if let Some(cursor) = &page.next_cursor {
+        text.push_str(&format!(
+            "\nMore changes available; pass cursor \"{}\" to continue.\n",
+            cursor
+        ));
+    }
+    text
+}
+
+/// Integration records as `p4 integrated` prints them.
+pub(crate) fn integrated_text(integrations: &[Integration]) -> String {
+    if integrations.is_empty() {
+        return "No file(s) integrated.\n".to_string();
+    }
+    integrations
+        .iter()
+        .map(|integration| {
+            format!(
+                "{}{} - {} {}{}\n",
+                integration.to_file,
+                rev_range(&integration.start_to_rev, &integration.end_to_rev),
+                integration.how,
+                integration.from_file,
+                rev_range(&integration.start_from_rev, &integration.end_from_rev)
+            )
+        })
+        .collect()
+}
+
+/// A `-ztag integrated` revision range, which starts at the revision before its first,
+/// as p4 prints it: `#3,#5`, or `#5` for a single revision.
+fn rev_range(start: &str, end: &str) -> String {
+    let rev = |rev: &str| rev.strip_prefix('#')?.parse::<u32>().ok();
+    match (rev(start), rev(end)) {
+        (Some(start), Some(end)) if start + 1 < end => format!("#{},#{}", start + 1, end),
+        _ => end.to_string(),
+    }
+}
+
+/// A change as `p4 describe -s` prints it.
+pub(crate) fn describe_text(change: &DescribedChange) -> String {
+    let mut text = format!(
+        "Change {} by {}@{} on {}",
+        change.change,
+        change.user,
+        change.client,
+        p4_time(&change.time, "%Y/%m/%d %H:%M:%S")
+    );
+    if change.status != "submitted" {
+        text.push_str(&format!(" *{}*", change.status));
+    }
+    text.push_str("\n\n");
+    for line in change.description.trim_end().lines() {
+        text.push_str(&format!("\t{}\n", line));
+    }
+    if !change.jobs.is_empty() {
+        text.push_str("\nJobs fixed ...\n\n");
+        for job in &change.jobs {
+            text.push_str(&format!("{}\n", job));
+        }
+    }
+    text.push_str("\nAffected files ...\n\n");
+    for file in &change.files {
+        let rev = file.rev.map(|rev| format!("#{}", rev)).unwrap_or_default();
+        text.push_str(&format!("... {}{} {}\n", file.depot_file, rev, file.action));
+    }
+    text
+}
+
+/// A `-ztag` time, in seconds since the epoch, in `format`.
+fn p4_time(time: &str, format: &str) -> String {
+    time.parse()
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map_or_else(|| time.to_string(), |time| time.format(format).to_string())
+}
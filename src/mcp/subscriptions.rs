@@ -0,0 +1,76 @@
+//! Resource subscriptions backed by polling `p4 changes`
+
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::MCPNotification;
+use crate::p4::{P4Command, P4Handler};
+
+/// Polling interval for subscribed resources, from `P4_SUBSCRIPTION_POLL_SECS`.
+pub(crate) fn poll_interval_from_env() -> Duration {
+    let secs = std::env::var("P4_SUBSCRIPTION_POLL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+/// Newest change number in `p4 changes` output.
+pub fn latest_change(output: &str) -> Option<u32> {
+    output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Change "))
+        .filter_map(|rest| rest.split_whitespace().next())
+        .filter_map(|n| n.parse().ok())
+        .max()
+}
+
+/// Poll `p4 changes -m 1 <path>` and send `notifications/resources/updated` for `uri`
+/// whenever a newer change lands. The first poll only records the current change.
+pub(crate) fn spawn_change_poller(
+    mut handler: P4Handler,
+    uri: String,
+    depot_path: String,
+    interval: Duration,
+    notifications: UnboundedSender<MCPNotification>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last_seen: Option<u32> = None;
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            ticker.tick().await;
+
+            let output = match handler
+                .execute(P4Command::Changes {
+                    max: 1,
+                    path: Some(depot_path.clone()),
+                })
+                .await
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    warn!("Failed to poll changes for {}: {}", uri, e);
+                    continue;
+                }
+            };
+
+            let latest = latest_change(&output);
+            if last_seen.is_some() && latest > last_seen {
+                debug!("Resource {} updated by change {:?}", uri, latest);
+                if notifications
+                    .send(MCPNotification::resource_updated(&uri))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            if latest.is_some() {
+                last_seen = latest;
+            }
+        }
+    })
+}
@@ -0,0 +1,38 @@
+//! Choosing which tools a server exposes
+//!
+//! A team may want an assistant that can read the depot but never submit, or one
+//! limited to a handful of workflows. The `[tools]` section of the config file names
+//! the tools to offer (`allow`) and the tools to withhold (`deny`); withheld tools are
+//! left out of `tools/list` and rejected by `tools/call` like unknown tools.
+//!
+//! ```toml
+//! [tools]
+//! deny = ["p4_submit", "swarm_*"]
+//! ```
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolFilter {
+    /// Tools to offer; empty offers every tool.
+    pub allow: Vec<String>,
+    /// Tools to withhold, even if allowed.
+    pub deny: Vec<String>,
+}
+
+impl ToolFilter {
+    /// Whether the tool `name` is exposed.
+    pub fn allows(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|pattern| matches(pattern, name)))
+            && !self.deny.iter().any(|pattern| matches(pattern, name))
+    }
+}
+
+/// A tool name, or a prefix ending in `*` such as `swarm_*`.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => pattern == name,
+    }
+}
@@ -0,0 +1,277 @@
+//! Size limits on tool output
+//!
+//! Text results larger than the configured limit are cut at a line boundary and end
+//! with a marker naming an `output_cursor`. Calling the same tool with that argument
+//! returns the next chunk from the stored output, without running p4 again — which
+//! matters for tools like `p4_sync` that shouldn't be repeated.
+//!
+//! Tools can have limits of their own in the `[responses]` section of the config file:
+//!
+//! ```toml
+//! [responses]
+//! tools = { p4_changes = { max_bytes = "16k", summary_lines = 5 } }
+//! ```
+//!
+//! A result over a tool's own limit is summarized instead of cut: how many lines and
+//! bytes it has, its first and last `summary_lines` lines, and an `output_cursor` to
+//! page through the whole of it.
+
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::*;
+
+/// Response size limit unless configured otherwise.
+pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 100_000;
+
+/// How many truncated outputs a session keeps for continuation.
+const KEPT_OUTPUTS: usize = 16;
+
+/// Lines quoted in a summary are cut to this many characters.
+const SUMMARY_LINE_CHARS: usize = 200;
+
+/// Per-tool response size limits.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResponseLimits {
+    /// Policies by tool name; other tools use `max_response_bytes`.
+    pub tools: HashMap<String, ResponsePolicy>,
+}
+
+/// How large a tool's results may get, and how they're summarized when they're larger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResponsePolicy {
+    /// A number of bytes, or a size such as `"16k"` or `"1m"`; 0 means no limit.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub max_bytes: usize,
+    /// How many lines from the start and from the end a summary quotes.
+    #[serde(default = "default_summary_lines")]
+    pub summary_lines: usize,
+}
+
+fn default_summary_lines() -> usize {
+    5
+}
+
+/// A size in bytes, with an optional `k` or `m` suffix (binary multiples).
+pub fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim().to_ascii_lowercase();
+    let size = size.strip_suffix('b').unwrap_or(&size);
+    let (number, multiplier) = match size.strip_suffix('k') {
+        Some(number) => (number, 1024),
+        None => match size.strip_suffix('m') {
+            Some(number) => (number, 1024 * 1024),
+            None => (size, 1),
+        },
+    };
+    number.trim().parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(usize),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => parse_size(&text).ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "invalid size {:?}: expected a number of bytes, or e.g. \"16k\" or \"1m\"",
+                text
+            ))
+        }),
+    }
+}
+
+struct StoredOutput {
+    id: String,
+    tool: String,
+    text: String,
+}
+
+/// Truncated outputs of a session, oldest first.
+#[derive(Clone, Default)]
+pub(crate) struct OutputStore(Arc<Mutex<VecDeque<StoredOutput>>>);
+
+impl OutputStore {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<StoredOutput>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Split `text[start..]` into a chunk of at most `max_bytes` and the offset of the
+/// rest, if any.
+///
+/// Chunks end after a newline when there is one in the second half of the chunk, and
+/// never split a UTF-8 character.
+pub fn chunk(text: &str, start: usize, max_bytes: usize) -> (&str, Option<usize>) {
+    let rest = &text[start..];
+    if max_bytes == 0 || rest.len() <= max_bytes {
+        return (rest, None);
+    }
+
+    let mut end = max_bytes;
+    while !rest.is_char_boundary(end) {
+        end -= 1;
+    }
+    if let Some(newline) = rest[..end].rfind('\n').filter(|&n| n >= end / 2) {
+        end = newline + 1;
+    }
+    // A limit smaller than one character still has to make progress
+    if end == 0 {
+        end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+    }
+    (&rest[..end], (end < rest.len()).then_some(start + end))
+}
+
+/// Add the optional `output_cursor` argument to a p4 tool's input schema.
+pub(crate) fn add_output_cursor_property(schema: &mut serde_json::Value) {
+    if let Some(properties) = schema.get_mut("properties").and_then(|p| p.as_object_mut()) {
+        properties.insert(
+            "output_cursor".to_string(),
+            serde_json::json!({
+                "type": "string",
+                "description": "Continue a truncated result from the cursor given at its end"
+            }),
+        );
+    }
+}
+
+/// A summary of `text`, a result of `tool_name` over the policy's limit, whose whole
+/// output can be read from `cursor` on.
+fn summary(tool_name: &str, text: &str, policy: &ResponsePolicy, cursor: &str) -> String {
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    let quote = |lines: &[&str]| -> String {
+        lines
+            .iter()
+            .map(|line| match line.char_indices().nth(SUMMARY_LINE_CHARS) {
+                Some((end, _)) => format!("{}...\n", &line[..end]),
+                None => format!("{}\n", line),
+            })
+            .collect()
+    };
+
+    let mut summary = format!(
+        "[Output summarized: {} lines, {} bytes, over the {}-byte limit for {}.]\n",
+        lines.len(),
+        text.len(),
+        policy.max_bytes,
+        tool_name
+    );
+    let n = policy.summary_lines;
+    if lines.len() <= 2 * n {
+        summary.push_str(&quote(&lines));
+    } else {
+        summary.push_str(&format!("First {} lines:\n", n));
+        summary.push_str(&quote(&lines[..n]));
+        summary.push_str(&format!(
+            "... {} more lines ...\nLast {} lines:\n",
+            lines.len() - 2 * n,
+            n
+        ));
+        summary.push_str(&quote(&lines[lines.len() - n..]));
+    }
+    summary.push_str(&format!(
+        "[Call again with \"output_cursor\": \"{}\" to read the whole output {} bytes at a time, or narrow the call, e.g. to fewer changes or a more specific path.]\n",
+        cursor, policy.max_bytes
+    ));
+    summary
+}
+
+fn marker(start: usize, end: usize, total: usize, cursor: &str) -> String {
+    format!(
+        "\n[Output truncated: showing bytes {}-{} of {}. Call again with \"output_cursor\": \"{}\" for more.]\n",
+        start, end, total, cursor
+    )
+}
+
+impl MCPServer {
+    /// Cap a tool's text output at the response size limit, keeping the full output
+    /// for continuation when it is cut.
+    pub(crate) fn limit_output(&self, tool_name: &str, text: String) -> String {
+        let (first, next) = chunk(&text, 0, self.response_limit(tool_name));
+        let Some(next) = next else {
+            return text;
+        };
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let limited = match self.response_limits.tools.get(tool_name) {
+            Some(policy) => summary(tool_name, &text, policy, &format!("{}:0", id)),
+            None => format!(
+                "{}{}",
+                first,
+                marker(0, next, text.len(), &format!("{}:{}", id, next))
+            ),
+        };
+        info!(
+            "Truncated {} output to {} of {} bytes",
+            tool_name,
+            next,
+            text.len()
+        );
+
+        let mut outputs = self.outputs.lock();
+        if outputs.len() == KEPT_OUTPUTS {
+            outputs.pop_front();
+        }
+        outputs.push_back(StoredOutput {
+            id,
+            tool: tool_name.to_string(),
+            text,
+        });
+        limited
+    }
+
+    /// The most bytes a result of `tool_name` may have.
+    fn response_limit(&self, tool_name: &str) -> usize {
+        self.response_limits
+            .tools
+            .get(tool_name)
+            .map_or(self.max_response_bytes, |policy| policy.max_bytes)
+    }
+
+    /// The response limit of `tool_name`, or of resources without one, if `len` bytes
+    /// of binary data are over it once base64-encoded. Images count against the same
+    /// limit as text, but can't be cut, so they're left out instead.
+    pub(crate) fn blob_over_limit(&self, tool_name: Option<&str>, len: usize) -> Option<usize> {
+        let limit = tool_name.map_or(self.max_response_bytes, |name| self.response_limit(name));
+        (limit > 0 && len.div_ceil(3) * 4 > limit).then_some(limit)
+    }
+
+    /// Whether [`Self::limit_output`] would cut or summarize `text`.
+    pub(crate) fn exceeds_response_limit(&self, tool_name: &str, text: &str) -> bool {
+        chunk(text, 0, self.response_limit(tool_name)).1.is_some()
+    }
+
+    /// The chunk of a stored output that `cursor` points at, or an error message if the
+    /// cursor is unknown or belongs to another tool.
+    pub(crate) fn continue_output(&self, tool_name: &str, cursor: &str) -> Result<String, String> {
+        let invalid = || format!("Unknown or expired output_cursor: {}", cursor);
+        let (id, start) = cursor.split_once(':').ok_or_else(invalid)?;
+        let start: usize = start.parse().map_err(|_| invalid())?;
+
+        let outputs = self.outputs.lock();
+        let stored = outputs
+            .iter()
+            .find(|stored| stored.id == id && stored.tool == tool_name)
+            .filter(|stored| start < stored.text.len() && stored.text.is_char_boundary(start))
+            .ok_or_else(invalid)?;
+
+        let (text, next) = chunk(&stored.text, start, self.response_limit(tool_name));
+        Ok(match next {
+            Some(next) => format!(
+                "{}{}",
+                text,
+                marker(start, next, stored.text.len(), &format!("{}:{}", id, next))
+            ),
+            None => text.to_string(),
+        })
+    }
+}
@@ -1,43 +1,529 @@
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeStruct;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub const JSONRPC_VERSION: &str = "2.0";
+
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+/// A server-defined code for calls refused by a rate limit; see [`super::rate_limit`].
+pub const RATE_LIMITED: i32 = -32029;
+
+/// A JSON-RPC request id, which clients may send as a number or a string.
+///
+/// `Null` is only used for error responses to requests whose id could not be read.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+    Null,
+}
+
+impl From<i64> for RequestId {
+    fn from(id: i64) -> Self {
+        RequestId::Number(id)
+    }
+}
+
+impl From<&str> for RequestId {
+    fn from(id: &str) -> Self {
+        RequestId::String(id.to_string())
+    }
+}
+
+impl PartialEq<i32> for RequestId {
+    fn eq(&self, other: &i32) -> bool {
+        matches!(self, RequestId::Number(id) if *id == i64::from(*other))
+    }
+}
+
+impl PartialEq<&str> for RequestId {
+    fn eq(&self, other: &&str) -> bool {
+        matches!(self, RequestId::String(id) if id == other)
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestId::Number(id) => write!(f, "{}", id),
+            RequestId::String(id) => write!(f, "{}", id),
+            RequestId::Null => write!(f, "null"),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "method")]
 pub enum MCPMessage {
     #[serde(rename = "initialize")]
-    Initialize { id: i32, params: InitializeParams },
+    Initialize {
+        id: RequestId,
+        params: InitializeParams,
+    },
+    #[serde(rename = "notifications/initialized")]
+    Initialized {},
+    #[serde(rename = "notifications/roots/list_changed")]
+    RootsListChanged {},
+    #[serde(rename = "notifications/cancelled")]
+    Cancelled { params: CancelledParams },
     #[serde(rename = "tools/list")]
-    ListTools { id: i32 },
+    ListTools {
+        id: RequestId,
+        params: Option<PaginatedParams>,
+    },
     #[serde(rename = "tools/call")]
-    CallTool { id: i32, params: CallToolParams },
+    CallTool {
+        id: RequestId,
+        params: CallToolParams,
+    },
     #[serde(rename = "ping")]
-    Ping { id: i32 },
+    Ping { id: RequestId },
+    #[serde(rename = "prompts/list")]
+    ListPrompts { id: RequestId },
+    #[serde(rename = "prompts/get")]
+    GetPrompt {
+        id: RequestId,
+        params: GetPromptParams,
+    },
+    #[serde(rename = "logging/setLevel")]
+    SetLevel {
+        id: RequestId,
+        params: SetLevelParams,
+    },
+    #[serde(rename = "completion/complete")]
+    Complete {
+        id: RequestId,
+        params: CompleteParams,
+    },
+    #[serde(rename = "resources/list")]
+    ListResources { id: RequestId },
+    #[serde(rename = "resources/templates/list")]
+    ListResourceTemplates { id: RequestId },
+    #[serde(rename = "resources/subscribe")]
+    Subscribe {
+        id: RequestId,
+        params: SubscribeParams,
+    },
+    #[serde(rename = "resources/unsubscribe")]
+    Unsubscribe {
+        id: RequestId,
+        params: SubscribeParams,
+    },
+    #[serde(rename = "resources/read")]
+    ReadResource {
+        id: RequestId,
+        params: ReadResourceParams,
+    },
 }
 
-#[derive(Debug, Serialize)]
-#[serde(untagged)]
+impl MCPMessage {
+    /// Parse one line of JSON-RPC input.
+    ///
+    /// Returns `Ok(None)` for notifications (messages without an `id`) the server doesn't
+    /// handle, and `Err` with the error response to send for malformed or unknown requests.
+    pub fn parse(line: &str) -> Result<Option<MCPMessage>, Box<MCPResponse>> {
+        let reject = |id: RequestId, code: i32, message: String| {
+            Box::new(MCPResponse::error(id, code, message))
+        };
+
+        let value: serde_json::Value = serde_json::from_str(line)
+            .map_err(|e| reject(RequestId::Null, PARSE_ERROR, format!("Parse error: {}", e)))?;
+
+        let id = match value.get("id") {
+            Some(id) => serde_json::from_value::<RequestId>(id.clone()).map_err(|_| {
+                reject(
+                    RequestId::Null,
+                    INVALID_REQUEST,
+                    "Invalid request id".to_string(),
+                )
+            })?,
+            None => RequestId::Null,
+        };
+
+        let Some(method) = value.get("method").and_then(|m| m.as_str()) else {
+            return Err(reject(id, INVALID_REQUEST, "Invalid request".to_string()));
+        };
+        if value
+            .get("jsonrpc")
+            .is_some_and(|version| version != JSONRPC_VERSION)
+        {
+            return Err(reject(
+                id,
+                INVALID_REQUEST,
+                "Unsupported jsonrpc version".to_string(),
+            ));
+        }
+
+        if value.get("id").is_none() {
+            return Ok(serde_json::from_value(value).ok());
+        }
+
+        serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| {
+                if e.to_string().starts_with("unknown variant") {
+                    reject(
+                        id,
+                        METHOD_NOT_FOUND,
+                        format!("Method not found: {}", method),
+                    )
+                } else {
+                    reject(id, INVALID_PARAMS, format!("Invalid params: {}", e))
+                }
+            })
+    }
+
+    /// Whether handling the message may take a while but leaves the session's state
+    /// alone, so it can run alongside other requests.
+    pub fn runs_concurrently(&self) -> bool {
+        matches!(
+            self,
+            MCPMessage::CallTool { .. }
+                | MCPMessage::GetPrompt { .. }
+                | MCPMessage::Complete { .. }
+                | MCPMessage::ListResources { .. }
+                | MCPMessage::ReadResource { .. }
+        )
+    }
+
+    /// The request id, or `None` for notifications.
+    pub fn id(&self) -> Option<&RequestId> {
+        let id = match self {
+            MCPMessage::Initialized {}
+            | MCPMessage::RootsListChanged {}
+            | MCPMessage::Cancelled { .. } => return None,
+            MCPMessage::Initialize { id, .. }
+            | MCPMessage::ListTools { id, .. }
+            | MCPMessage::CallTool { id, .. }
+            | MCPMessage::Ping { id }
+            | MCPMessage::ListPrompts { id }
+            | MCPMessage::GetPrompt { id, .. }
+            | MCPMessage::SetLevel { id, .. }
+            | MCPMessage::Complete { id, .. }
+            | MCPMessage::ListResources { id }
+            | MCPMessage::ListResourceTemplates { id }
+            | MCPMessage::Subscribe { id, .. }
+            | MCPMessage::Unsubscribe { id, .. }
+            | MCPMessage::ReadResource { id, .. } => id,
+        };
+        Some(id)
+    }
+
+    /// The JSON-RPC method, e.g. `tools/call`.
+    pub fn method(&self) -> &'static str {
+        match self {
+            MCPMessage::Initialize { .. } => "initialize",
+            MCPMessage::Initialized {} => "notifications/initialized",
+            MCPMessage::RootsListChanged {} => "notifications/roots/list_changed",
+            MCPMessage::Cancelled { .. } => "notifications/cancelled",
+            MCPMessage::ListTools { .. } => "tools/list",
+            MCPMessage::CallTool { .. } => "tools/call",
+            MCPMessage::Ping { .. } => "ping",
+            MCPMessage::ListPrompts { .. } => "prompts/list",
+            MCPMessage::GetPrompt { .. } => "prompts/get",
+            MCPMessage::SetLevel { .. } => "logging/setLevel",
+            MCPMessage::Complete { .. } => "completion/complete",
+            MCPMessage::ListResources { .. } => "resources/list",
+            MCPMessage::ListResourceTemplates { .. } => "resources/templates/list",
+            MCPMessage::Subscribe { .. } => "resources/subscribe",
+            MCPMessage::Unsubscribe { .. } => "resources/unsubscribe",
+            MCPMessage::ReadResource { .. } => "resources/read",
+        }
+    }
+}
+
+/// A JSON-RPC response. Every variant serializes as
+/// `{"jsonrpc": "2.0", "id": ..., "result": ...}`, or with `error` in place of `result`.
+///
+/// Responses deserialize by the shape of their result, so a client can read what the
+/// server sent back. `Pong` and `Empty` both send `{}`, which reads back as `Empty`.
+#[derive(Debug, Clone, PartialEq)]
 pub enum MCPResponse {
     InitializeResult {
-        jsonrpc: String,
-        id: i32,
+        id: RequestId,
         result: InitializeResult,
     },
     ListToolsResult {
-        id: i32,
+        id: RequestId,
         result: ListToolsResult,
     },
     CallToolResult {
-        id: i32,
+        id: RequestId,
         result: CallToolResult,
     },
     Pong {
-        id: i32,
+        id: RequestId,
+    },
+    ListResourcesResult {
+        id: RequestId,
+        result: ListResourcesResult,
+    },
+    ListResourceTemplatesResult {
+        id: RequestId,
+        result: ListResourceTemplatesResult,
+    },
+    ReadResourceResult {
+        id: RequestId,
+        result: ReadResourceResult,
+    },
+    ListPromptsResult {
+        id: RequestId,
+        result: ListPromptsResult,
+    },
+    GetPromptResult {
+        id: RequestId,
+        result: GetPromptResult,
+    },
+    CompleteResult {
+        id: RequestId,
+        result: CompleteResult,
+    },
+    Empty {
+        id: RequestId,
+        result: EmptyResult,
     },
     Error {
-        id: i32,
+        id: RequestId,
         error: MCPError,
     },
 }
 
+impl MCPResponse {
+    pub fn error(id: RequestId, code: i32, message: impl Into<String>) -> Self {
+        MCPResponse::Error {
+            id,
+            error: MCPError {
+                code,
+                message: message.into(),
+                data: None,
+            },
+        }
+    }
+
+    pub fn id(&self) -> &RequestId {
+        match self {
+            MCPResponse::InitializeResult { id, .. }
+            | MCPResponse::ListToolsResult { id, .. }
+            | MCPResponse::CallToolResult { id, .. }
+            | MCPResponse::Pong { id }
+            | MCPResponse::ListResourcesResult { id, .. }
+            | MCPResponse::ListResourceTemplatesResult { id, .. }
+            | MCPResponse::ReadResourceResult { id, .. }
+            | MCPResponse::ListPromptsResult { id, .. }
+            | MCPResponse::GetPromptResult { id, .. }
+            | MCPResponse::CompleteResult { id, .. }
+            | MCPResponse::Empty { id, .. }
+            | MCPResponse::Error { id, .. } => id,
+        }
+    }
+}
+
+impl Serialize for MCPResponse {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut response = serializer.serialize_struct("MCPResponse", 3)?;
+        response.serialize_field("jsonrpc", JSONRPC_VERSION)?;
+        response.serialize_field("id", self.id())?;
+        match self {
+            MCPResponse::InitializeResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::ListToolsResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::CallToolResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::Pong { .. } => response.serialize_field("result", &EmptyResult {})?,
+            MCPResponse::ListResourcesResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::ListResourceTemplatesResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::ReadResourceResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::ListPromptsResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::GetPromptResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::CompleteResult { result, .. } => {
+                response.serialize_field("result", result)?
+            }
+            MCPResponse::Empty { result, .. } => response.serialize_field("result", result)?,
+            MCPResponse::Error { error, .. } => response.serialize_field("error", error)?,
+        }
+        response.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for MCPResponse {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Raw {
+            id: RequestId,
+            result: Option<serde_json::Value>,
+            error: Option<MCPError>,
+        }
+
+        fn result<T: serde::de::DeserializeOwned, E: de::Error>(
+            value: serde_json::Value,
+        ) -> Result<T, E> {
+            serde_json::from_value(value).map_err(E::custom)
+        }
+
+        let Raw {
+            id,
+            result: value,
+            error,
+        } = Raw::deserialize(deserializer)?;
+        if let Some(error) = error {
+            return Ok(MCPResponse::Error { id, error });
+        }
+        let Some(value) = value else {
+            return Err(de::Error::missing_field("result"));
+        };
+        let has = |field: &str| value.get(field).is_some();
+        let response = if has("protocolVersion") {
+            MCPResponse::InitializeResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("tools") {
+            MCPResponse::ListToolsResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("content") {
+            MCPResponse::CallToolResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("resources") {
+            MCPResponse::ListResourcesResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("resourceTemplates") {
+            MCPResponse::ListResourceTemplatesResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("contents") {
+            MCPResponse::ReadResourceResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("prompts") {
+            MCPResponse::ListPromptsResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("messages") {
+            MCPResponse::GetPromptResult {
+                id,
+                result: result(value)?,
+            }
+        } else if has("completion") {
+            MCPResponse::CompleteResult {
+                id,
+                result: result(value)?,
+            }
+        } else if value.as_object().is_some_and(|fields| fields.is_empty()) {
+            MCPResponse::Empty {
+                id,
+                result: EmptyResult {},
+            }
+        } else {
+            return Err(de::Error::custom(format!("unrecognized result: {}", value)));
+        };
+        Ok(response)
+    }
+}
+
+/// A server-initiated JSON-RPC notification.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MCPNotification {
+    pub jsonrpc: String,
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+impl MCPNotification {
+    pub fn new(method: &str, params: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            method: method.to_string(),
+            params,
+        }
+    }
+
+    pub fn resource_updated(uri: &str) -> Self {
+        Self::new(
+            "notifications/resources/updated",
+            serde_json::json!({ "uri": uri }),
+        )
+    }
+
+    pub fn log_message(level: LoggingLevel, logger: &str, data: &str) -> Self {
+        Self::new(
+            "notifications/message",
+            serde_json::json!({ "level": level, "logger": logger, "data": data }),
+        )
+    }
+
+    pub fn progress(token: &serde_json::Value, progress: u64, message: &str) -> Self {
+        Self::new(
+            "notifications/progress",
+            serde_json::json!({ "progressToken": token, "progress": progress, "message": message }),
+        )
+    }
+
+    pub fn tools_list_changed() -> Self {
+        Self::new("notifications/tools/list_changed", serde_json::json!({}))
+    }
+}
+
+/// A server-initiated JSON-RPC request to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MCPRequest {
+    pub jsonrpc: String,
+    pub id: RequestId,
+    pub method: String,
+    pub params: serde_json::Value,
+}
+
+impl MCPRequest {
+    pub fn new(id: RequestId, method: &str, params: serde_json::Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+/// The client's response to an [`MCPRequest`].
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientResponse {
+    #[serde(default)]
+    pub jsonrpc: Option<String>,
+    pub id: RequestId,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<MCPError>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmptyResult {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InitializeParams {
     #[serde(rename = "protocolVersion")]
@@ -51,24 +537,28 @@ pub struct InitializeParams {
 pub struct ClientCapabilities {
     pub roots: Option<RootsCapability>,
     pub sampling: Option<SamplingCapability>,
+    pub elicitation: Option<ElicitationCapability>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RootsCapability {
-    #[serde(rename = "listChanged")]
+    #[serde(rename = "listChanged", default)]
     pub list_changed: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SamplingCapability {}
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ElicitationCapability {}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ClientInfo {
     pub name: String,
     pub version: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct InitializeResult {
     #[serde(rename = "protocolVersion")]
     pub protocol_version: String,
@@ -78,68 +568,178 @@ pub struct InitializeResult {
 }
 
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerCapabilities {
+    pub completions: Option<CompletionsCapability>,
     pub logging: Option<LoggingCapability>,
     pub prompts: Option<PromptsCapability>,
     pub resources: Option<ResourcesCapability>,
     pub tools: Option<ToolsCapability>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompletionsCapability {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoggingCapability {}
 
-#[derive(Debug, Serialize)]
+/// Syslog severities used by the MCP logging capability, from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoggingLevel {
+    Debug,
+    Info,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl From<tracing::Level> for LoggingLevel {
+    fn from(level: tracing::Level) -> Self {
+        match level {
+            tracing::Level::ERROR => LoggingLevel::Error,
+            tracing::Level::WARN => LoggingLevel::Warning,
+            tracing::Level::INFO => LoggingLevel::Info,
+            _ => LoggingLevel::Debug,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetLevelParams {
+    pub level: LoggingLevel,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct PromptsCapability {
     #[serde(rename = "listChanged")]
     pub list_changed: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ResourcesCapability {
     pub subscribe: bool,
     #[serde(rename = "listChanged")]
     pub list_changed: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ToolsCapability {
     #[serde(rename = "listChanged")]
     pub list_changed: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerInfo {
     pub name: String,
     pub title: String,
     pub version: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PaginatedParams {
+    pub cursor: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ListToolsResult {
     pub tools: Vec<Tool>,
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tool {
     pub name: String,
     pub description: String,
     #[serde(rename = "inputSchema")]
     pub input_schema: serde_json::Value,
+    #[serde(rename = "outputSchema")]
+    pub output_schema: Option<serde_json::Value>,
+    pub annotations: Option<ToolAnnotations>,
+}
+
+/// Hints about a tool's behavior that clients can use for confirmation policies.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolAnnotations {
+    pub title: Option<String>,
+    #[serde(rename = "readOnlyHint")]
+    pub read_only_hint: Option<bool>,
+    #[serde(rename = "destructiveHint")]
+    pub destructive_hint: Option<bool>,
+    #[serde(rename = "idempotentHint")]
+    pub idempotent_hint: Option<bool>,
+}
+
+impl ToolAnnotations {
+    pub fn read_only(title: &str) -> Self {
+        Self {
+            title: Some(title.to_string()),
+            read_only_hint: Some(true),
+            destructive_hint: None,
+            idempotent_hint: None,
+        }
+    }
+
+    /// A tool that changes workspace or server state.
+    pub fn write(title: &str, destructive: bool, idempotent: bool) -> Self {
+        Self {
+            title: Some(title.to_string()),
+            read_only_hint: Some(false),
+            destructive_hint: Some(destructive),
+            idempotent_hint: Some(idempotent),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CallToolParams {
     pub name: String,
     pub arguments: serde_json::Value,
+    #[serde(rename = "_meta", default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<RequestMeta>,
 }
 
-#[derive(Debug, Serialize)]
+/// The `_meta` object of a request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestMeta {
+    /// Token to send `notifications/progress` for while the request runs.
+    #[serde(rename = "progressToken", skip_serializing_if = "Option::is_none")]
+    pub progress_token: Option<serde_json::Value>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CallToolResult {
     pub content: Vec<ToolContent>,
+    /// JSON matching the tool's `outputSchema`, for tools that declare one.
+    #[serde(rename = "structuredContent")]
+    pub structured_content: Option<serde_json::Value>,
+    /// How the call ran, for calls that ran the tool.
+    #[serde(rename = "_meta")]
+    pub meta: Option<ToolResultMeta>,
+}
+
+/// The `_meta` object of a tool result: what the call actually ran.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolResultMeta {
+    /// Every p4 process the call started, in order; results answered from the cache
+    /// aren't listed.
+    pub commands: Vec<crate::p4::ExecutedCommand>,
+    /// How long the whole call took.
+    pub duration_ms: u64,
+    /// Whether the text was cut at the response size limit.
+    pub truncated: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ToolContent {
     #[serde(rename = "text")]
@@ -152,9 +752,154 @@ pub enum ToolContent {
     },
 }
 
-#[derive(Debug, Serialize)]
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MCPError {
     pub code: i32,
     pub message: String,
     pub data: Option<serde_json::Value>,
 }
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Resource {
+    pub uri: String,
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListResourcesResult {
+    pub resources: Vec<Resource>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceTemplate {
+    #[serde(rename = "uriTemplate")]
+    pub uri_template: String,
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListResourceTemplatesResult {
+    #[serde(rename = "resourceTemplates")]
+    pub resource_templates: Vec<ResourceTemplate>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReadResourceParams {
+    pub uri: String,
+}
+
+/// The client no longer wants the answer to a request it sent.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelledParams {
+    pub request_id: RequestId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SubscribeParams {
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReadResourceResult {
+    pub contents: Vec<ResourceContents>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResourceContents {
+    pub uri: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+    pub text: Option<String>,
+    pub blob: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Prompt {
+    pub name: String,
+    pub description: Option<String>,
+    pub arguments: Vec<PromptArgument>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptArgument {
+    pub name: String,
+    pub description: Option<String>,
+    pub required: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ListPromptsResult {
+    pub prompts: Vec<Prompt>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPromptParams {
+    pub name: String,
+    #[serde(default)]
+    pub arguments: std::collections::HashMap<String, String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GetPromptResult {
+    pub description: Option<String>,
+    pub messages: Vec<PromptMessage>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptMessage {
+    pub role: String,
+    pub content: ToolContent,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompleteParams {
+    #[serde(rename = "ref")]
+    pub reference: CompletionReference,
+    pub argument: CompletionArgument,
+}
+
+/// The prompt or resource template whose argument is being completed.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CompletionReference {
+    #[serde(rename = "ref/prompt")]
+    Prompt { name: String },
+    #[serde(rename = "ref/resource")]
+    Resource { uri: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompletionArgument {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompleteResult {
+    pub completion: Completion,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Completion {
+    pub values: Vec<String>,
+    pub total: Option<usize>,
+    #[serde(rename = "hasMore")]
+    pub has_more: Option<bool>,
+}
@@ -0,0 +1,138 @@
+//! Checking tool arguments against the tools' input schemas
+//!
+//! Tools read their arguments leniently, so a call missing `files` used to run with
+//! an empty list instead of failing. Every call's arguments are now checked against
+//! the tool's `inputSchema` before it runs, and a call that doesn't match is refused
+//! with `-32602` and the [JSON pointer](https://www.rfc-editor.org/rfc/rfc6901) of
+//! the offending field in the error's `data`, as in `{"pointer": "/files/1"}`.
+//!
+//! The keywords the tools' schemas use are checked: `type`, `properties`, `required`,
+//! `items` and `enum`. Others are ignored, and so are arguments the schema doesn't
+//! declare.
+
+use serde_json::Value;
+
+/// Why some arguments don't match a schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+    /// Where in the arguments, as a JSON pointer; empty for the arguments themselves.
+    pub pointer: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.pointer.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.pointer, self.message)
+        }
+    }
+}
+
+/// Check `value` against `schema`, returning the first mismatch.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), SchemaError> {
+    check(schema, value, &mut String::new())
+}
+
+fn check(schema: &Value, value: &Value, pointer: &mut String) -> Result<(), SchemaError> {
+    let fail = |pointer: &str, message: String| {
+        Err(SchemaError {
+            pointer: pointer.to_string(),
+            message,
+        })
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            return fail(
+                pointer,
+                format!("expected {}, got {}", types.join(" or "), type_name(value)),
+            );
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            let allowed: Vec<String> = allowed.iter().map(Value::to_string).collect();
+            return fail(
+                pointer,
+                format!("expected one of {}, got {}", allowed.join(", "), value),
+            );
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        for name in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(name) {
+                return fail(
+                    &format!("{}/{}", pointer, escape(name)),
+                    "is required".to_string(),
+                );
+            }
+        }
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, property) in object {
+                if let Some(property_schema) = properties.get(name) {
+                    let len = pointer.len();
+                    pointer.push('/');
+                    pointer.push_str(&escape(name));
+                    check(property_schema, property, pointer)?;
+                    pointer.truncate(len);
+                }
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            let len = pointer.len();
+            pointer.push_str(&format!("/{}", index));
+            check(items, item, pointer)?;
+            pointer.truncate(len);
+        }
+    }
+
+    Ok(())
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// `name` escaped for a JSON pointer.
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
@@ -0,0 +1,274 @@
+//! Prometheus metrics
+//!
+//! Every session of a process records into the same [`Metrics`]: tool calls by tool
+//! and outcome, errors by category, p4 command latency by command, and the requests
+//! and p4 commands in flight. They're served in the Prometheus text format at
+//! `/metrics`, on the HTTP+SSE transport's address or on a dedicated `--metrics-addr`:
+//!
+//! ```text
+//! p4mcp_tool_calls_total{tool="p4_sync",outcome="ok"} 12
+//! p4mcp_errors_total{category="timeout"} 1
+//! p4mcp_p4_command_duration_seconds_bucket{command="sync",le="1"} 9
+//! p4mcp_requests_in_flight 2
+//! ```
+
+use anyhow::Result;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+use crate::mcp::{MCPResponse, INVALID_PARAMS, INVALID_REQUEST, RATE_LIMITED};
+
+/// Upper bounds of the p4 command latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 60.0,
+];
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// Observations per bucket of [`LATENCY_BUCKETS`], not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    tool_calls: BTreeMap<(String, &'static str), u64>,
+    errors: BTreeMap<&'static str, u64>,
+    p4_commands: BTreeMap<(String, &'static str), u64>,
+    p4_latency: BTreeMap<String, Histogram>,
+}
+
+/// Metrics shared by every session of a process.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    recorded: Mutex<Recorded>,
+    requests_in_flight: AtomicI64,
+    p4_commands_in_flight: AtomicI64,
+}
+
+/// Counts something as in flight until it's dropped.
+pub struct InFlight<'a>(&'a AtomicI64);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn recorded(&self) -> std::sync::MutexGuard<'_, Recorded> {
+        self.recorded.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Count an MCP request as in flight while the guard lives.
+    pub fn request_started(&self) -> InFlight<'_> {
+        self.requests_in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(&self.requests_in_flight)
+    }
+
+    /// Count a p4 command as in flight while the guard lives.
+    pub fn p4_command_started(&self) -> InFlight<'_> {
+        self.p4_commands_in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(&self.p4_commands_in_flight)
+    }
+
+    /// Record a finished tool call, with the error category of `outcome` if it failed.
+    pub fn record_tool_call(&self, tool: &str, outcome: Result<(), &'static str>) {
+        let mut recorded = self.recorded();
+        let label = match outcome {
+            Ok(()) => "ok",
+            Err(category) => {
+                *recorded.errors.entry(category).or_default() += 1;
+                "error"
+            }
+        };
+        *recorded
+            .tool_calls
+            .entry((tool.to_string(), label))
+            .or_default() += 1;
+    }
+
+    /// Record a p4 run of `command`, e.g. `sync`, that took `duration`.
+    pub fn record_p4_command(&self, command: &str, succeeded: bool, duration: Duration) {
+        let mut recorded = self.recorded();
+        let result = if succeeded { "ok" } else { "error" };
+        *recorded
+            .p4_commands
+            .entry((command.to_string(), result))
+            .or_default() += 1;
+        recorded
+            .p4_latency
+            .entry(command.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+        if !succeeded {
+            *recorded.errors.entry("p4_command").or_default() += 1;
+        }
+    }
+
+    /// Everything recorded so far, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let recorded = self.recorded();
+        let mut out = String::new();
+
+        out.push_str("# HELP p4mcp_tool_calls_total Tool calls by tool and outcome.\n");
+        out.push_str("# TYPE p4mcp_tool_calls_total counter\n");
+        for ((tool, outcome), count) in &recorded.tool_calls {
+            let _ = writeln!(
+                out,
+                "p4mcp_tool_calls_total{{tool=\"{}\",outcome=\"{}\"}} {}",
+                escape(tool),
+                outcome,
+                count
+            );
+        }
+
+        out.push_str("# HELP p4mcp_errors_total Failed tool calls and p4 commands by category.\n");
+        out.push_str("# TYPE p4mcp_errors_total counter\n");
+        for (category, count) in &recorded.errors {
+            let _ = writeln!(
+                out,
+                "p4mcp_errors_total{{category=\"{}\"}} {}",
+                category, count
+            );
+        }
+
+        out.push_str("# HELP p4mcp_p4_commands_total p4 commands run, by command and result.\n");
+        out.push_str("# TYPE p4mcp_p4_commands_total counter\n");
+        for ((command, result), count) in &recorded.p4_commands {
+            let _ = writeln!(
+                out,
+                "p4mcp_p4_commands_total{{command=\"{}\",result=\"{}\"}} {}",
+                escape(command),
+                result,
+                count
+            );
+        }
+
+        out.push_str("# HELP p4mcp_p4_command_duration_seconds How long p4 commands took.\n");
+        out.push_str("# TYPE p4mcp_p4_command_duration_seconds histogram\n");
+        for (command, histogram) in &recorded.p4_latency {
+            let command = escape(command);
+            let mut cumulative = 0;
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "p4mcp_p4_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "p4mcp_p4_command_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}",
+                command, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "p4mcp_p4_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                command, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "p4mcp_p4_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, histogram.count
+            );
+        }
+
+        for (name, help, gauge) in [
+            (
+                "p4mcp_requests_in_flight",
+                "MCP requests being handled.",
+                &self.requests_in_flight,
+            ),
+            (
+                "p4mcp_p4_commands_in_flight",
+                "p4 commands running.",
+                &self.p4_commands_in_flight,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, gauge.load(Ordering::SeqCst));
+        }
+        out
+    }
+}
+
+/// `value` escaped for a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The outcome of a `tools/call` request for [`Metrics::record_tool_call`].
+pub fn tool_call_outcome(response: &Result<Option<MCPResponse>>) -> Result<(), &'static str> {
+    match response {
+        Ok(Some(MCPResponse::Error { error, .. })) => Err(match error.code {
+            _ if error
+                .data
+                .as_ref()
+                .is_some_and(|data| data["timedOut"] == true) =>
+            {
+                "timeout"
+            }
+            RATE_LIMITED => "rate_limited",
+            INVALID_PARAMS => "invalid_params",
+            INVALID_REQUEST => "invalid_request",
+            _ => "internal",
+        }),
+        Ok(_) => Ok(()),
+        Err(_) => Err("tool_failed"),
+    }
+}
+
+/// Routes serving `metrics` at `/metrics`.
+pub fn router(metrics: Arc<Metrics>) -> Router {
+    Router::new().route(
+        "/metrics",
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                metrics.render(),
+            )
+                .into_response()
+        }),
+    )
+}
+
+/// Serve `metrics` at `http://{addr}/metrics` until the process exits.
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(
+        "Serving metrics at http://{}/metrics",
+        listener.local_addr()?
+    );
+    axum::serve(listener, router(metrics)).await?;
+    Ok(())
+}
@@ -0,0 +1,127 @@
+//! Audit log of executed p4 commands
+//!
+//! Teams letting an assistant work in their depot want to review what it did. With
+//! `audit_log` configured, every p4 process the server runs appends a JSON line to
+//! the file: when it ran, the MCP request and tool it ran for, its exact arguments,
+//! how it exited and how much it read and wrote. Input isn't recorded, as it may be a
+//! password for `p4 login`. Mock mode runs nothing, so it logs nothing.
+//!
+//! The commands a single tool call runs are also collected in an [`ExecutionLog`], and
+//! reported with the call's result.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// The MCP call a command runs for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallInfo {
+    pub request_id: String,
+    pub tool: String,
+}
+
+/// One line of the audit log.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry<'a> {
+    pub timestamp: String,
+    pub request_id: Option<&'a str>,
+    pub tool: Option<&'a str>,
+    pub program: String,
+    pub args: &'a [String],
+    pub cwd: Option<&'a Path>,
+    /// The exit code; `None` when p4 was killed or couldn't be started.
+    pub exit_status: Option<i32>,
+    /// Why the command didn't finish, e.g. a timeout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub stdin_bytes: usize,
+    pub stdout_bytes: usize,
+    pub stderr_bytes: usize,
+    pub duration_ms: u128,
+}
+
+/// A p4 command as it was run for a tool call.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutedCommand {
+    /// The whole command line, quoted for a shell.
+    pub command_line: String,
+    /// Where p4 ran; the server's working directory when `None`.
+    pub cwd: Option<PathBuf>,
+    /// The `exit:` code of `p4 -s`, or the process's exit status; `None` when p4 was
+    /// killed or couldn't be started.
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// The commands run for one tool call, shared by clones of the handler.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionLog(Arc<Mutex<Vec<ExecutedCommand>>>);
+
+impl ExecutionLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, command: ExecutedCommand) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(command);
+    }
+
+    pub fn commands(&self) -> Vec<ExecutedCommand> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// An append-only JSON-lines file.
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open audit log {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &AuditEntry) {
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Could not serialize audit log entry: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        // One write per line, so lines from concurrent commands don't interleave
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!(
+                "Could not write to audit log {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The current time in RFC 3339 form, in UTC.
+pub fn timestamp() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
@@ -0,0 +1,169 @@
+//! Backends that run p4 commands
+//!
+//! [`P4Handler`](super::P4Handler) builds each command line, then hands it to a
+//! [`P4Backend`] to run: [`CliBackend`](super::CliBackend) runs the p4 command-line
+//! client, [`MockBackend`](super::MockBackend) answers with canned responses and a
+//! [`Replayer`](super::Replayer) with recorded ones. Library users can plug in their
+//! own with [`P4Handler::set_backend`](super::P4Handler::set_backend), e.g. one that
+//! calls the Helix C++ API or forwards commands to a remote proxy. The handler deals
+//! with everything else the same way for every backend: timeouts, cancellation,
+//! severity, logging in again, retries, the audit log and recordings.
+//!
+//! Mock mode is turned on with `P4_MOCK_MODE`, `--mock` or `mock = true` in the config
+//! file. With `allow_backend_switch = true` the `p4_backend` tool can also switch it
+//! while the server runs, so the same configuration can flip between a sandbox and
+//! the real server.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::P4Command;
+
+/// One run of p4.
+#[derive(Debug, Clone, Copy)]
+pub struct Invocation<'a> {
+    /// The command being run, or `None` for those the handler runs itself, such as
+    /// `p4 login`.
+    pub command: Option<&'a P4Command>,
+    /// Everything after `p4`: global flags such as `-s` or `-ztag`, the command and its
+    /// arguments.
+    pub args: &'a [String],
+    /// What p4 reads on stdin, already encoded in `charset`.
+    pub input: Option<&'a [u8]>,
+    /// Where p4 runs, or the server's working directory when `None`.
+    pub cwd: Option<&'a Path>,
+    /// The environment p4 runs with, already filtered by the environment policy.
+    pub env: &'a [(OsString, OsString)],
+    /// The character set p4 runs with, if it isn't the server's default.
+    pub charset: Option<&'a str>,
+}
+
+/// Receives each line of stdout, with its line ending, as a command prints it.
+pub type LineSink<'a> = dyn Fn(&[u8]) + Send + Sync + 'a;
+
+/// Runs p4 commands.
+///
+/// A backend answers as p4 would: the exit status, and stdout and stderr in the
+/// format the global flags ask for. Failures p4 reports are output like any other;
+/// an `Err` means the command couldn't be run at all.
+///
+/// A command that times out or whose call is cancelled is dropped, which should stop
+/// it, as dropping the p4 process of [`CliBackend`](super::CliBackend) kills it.
+#[async_trait]
+pub trait P4Backend: Send + Sync {
+    /// Run a command and wait for everything it prints.
+    async fn execute(&self, invocation: &Invocation<'_>) -> Result<Output>;
+
+    /// Run a command, passing each line of stdout to `on_line` as it's printed.
+    /// Backends that can't stream pass every line once the command finishes.
+    async fn stream(&self, invocation: &Invocation<'_>, on_line: &LineSink<'_>) -> Result<Output> {
+        let output = self.execute(invocation).await?;
+        output
+            .stdout
+            .split_inclusive(|&byte| byte == b'\n')
+            .for_each(on_line);
+        Ok(output)
+    }
+
+    /// What the audit log names as the program that ran.
+    fn program(&self) -> String;
+}
+
+/// Whether commands get mock responses, shared by every clone of a handler and by the
+/// Swarm client so the whole server switches at once.
+#[derive(Debug, Clone, Default)]
+pub struct MockMode(Arc<AtomicBool>);
+
+impl MockMode {
+    pub fn new(enabled: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    /// Enabled when `P4_MOCK_MODE` is set.
+    pub fn from_env() -> Self {
+        Self::new(std::env::var("P4_MOCK_MODE").is_ok())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Turn mock mode on or off everywhere it's shared, returning whether it was on.
+    pub fn set(&self, enabled: bool) -> bool {
+        self.0.swap(enabled, Ordering::SeqCst)
+    }
+
+    /// The name of the backend in use.
+    pub fn name(&self) -> &'static str {
+        if self.is_enabled() {
+            "mock"
+        } else {
+            "real"
+        }
+    }
+}
+
+/// The status of a process that exited with `code`, for backends that don't run one.
+pub fn exit_status(code: i32) -> ExitStatus {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw((code & 0xff) << 8)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+}
+
+/// Cancels the p4 commands of one tool call: those running when it's cancelled, which
+/// fail with an error, and any the call starts afterwards.
+///
+/// Clones of a handler share its token, so cancelling one call leaves the calls of
+/// other handlers, and other sessions, running.
+#[derive(Debug, Clone)]
+pub struct CancelToken(Arc<tokio::sync::watch::Sender<bool>>);
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self(Arc::new(tokio::sync::watch::Sender::new(false)))
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait for `run` unless the token is cancelled first, in which case `run` is
+    /// dropped, killing the p4 it started.
+    pub(crate) async fn run<T>(
+        &self,
+        args: &[String],
+        run: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let mut cancelled = self.0.subscribe();
+        // A command started once the call was cancelled never runs
+        tokio::select! {
+            biased;
+            _ = cancelled.wait_for(|cancelled| *cancelled) => {
+                Err(anyhow::anyhow!("p4 {} was cancelled", args.join(" ")))
+            }
+            result = run => result,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Running p4 commands through a persistent broker
+//!
+//! Spawning `p4` for every command pays for process start-up, the TCP connect, the
+//! SSL handshake and authentication each time. With a broker configured, commands go
+//! to a long-lived helper that keeps its server connection open between commands,
+//! such as `scripts/p4_broker.py`, which uses P4Python.
+//!
+//! The helper reads one JSON request per line on stdin and answers each with one JSON
+//! line on stdout:
+//!
+//! ```text
+//! {"args": ["-p", "perforce:1666", "-s", "opened"], "input": null, "cwd": "/ws", "env": {"P4CLIENT": "ws"}}
+//! {"stdout": "info: //depot/a.txt#1 - edit default change (text)\nexit: 0\n", "stderr": "", "status": 0}
+//! ```
+//!
+//! `args` are exactly what `p4` would have been run with, and the response is what it
+//! would have printed. A helper that can't serve a request (say, `p4 -V`) answers
+//! `{"fallback": true}` and the command is run with `p4` as usual. Several helpers run
+//! at once when commands overlap, up to the process limit.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{Output, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::backend::exit_status;
+
+#[derive(Debug, Serialize)]
+pub struct Request<'a> {
+    pub args: &'a [String],
+    pub input: Option<String>,
+    pub cwd: Option<&'a Path>,
+    pub env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Response {
+    fallback: bool,
+    stdout: String,
+    stderr: String,
+    status: i32,
+}
+
+struct Helper {
+    // Killed when dropped, e.g. when a command times out mid-request
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// A pool of broker helpers started from the same command line.
+pub struct Broker {
+    command: Vec<String>,
+    idle: Mutex<Vec<Helper>>,
+}
+
+impl Broker {
+    pub fn new(command: Vec<String>) -> Self {
+        Self {
+            command,
+            idle: Mutex::default(),
+        }
+    }
+
+    /// Run a command through an idle helper, starting one if none is free. Returns
+    /// `None` when the helper asks for the command to be run with `p4` instead, or
+    /// can't be started.
+    ///
+    /// A helper that fails mid-request is discarded. The command isn't run again with
+    /// `p4` then, since the helper may already have run it.
+    pub async fn run(&self, request: &Request<'_>) -> Result<Option<Output>> {
+        let helper = self.idle.lock().await.pop();
+        let mut helper = match helper {
+            Some(helper) => helper,
+            None => match self.spawn() {
+                Ok(helper) => helper,
+                Err(e) => {
+                    warn!("{:#}; running p4 directly", e);
+                    return Ok(None);
+                }
+            },
+        };
+
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        helper.stdin.write_all(line.as_bytes()).await?;
+        helper.stdin.flush().await?;
+
+        let mut answer = String::new();
+        if helper.stdout.read_line(&mut answer).await? == 0 {
+            return Err(anyhow::anyhow!("The p4 broker exited"));
+        }
+        let response: Response =
+            serde_json::from_str(&answer).context("Invalid response from the p4 broker")?;
+        self.idle.lock().await.push(helper);
+
+        if response.fallback {
+            return Ok(None);
+        }
+        Ok(Some(Output {
+            status: exit_status(response.status),
+            stdout: response.stdout.into_bytes(),
+            stderr: response.stderr.into_bytes(),
+        }))
+    }
+
+    fn spawn(&self) -> Result<Helper> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("The p4 broker command is empty"))?;
+        debug!("Starting p4 broker {:?}", self.command);
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Could not start the p4 broker {}", program))?;
+        let stdin = child.stdin.take().context("The p4 broker has no stdin")?;
+        let stdout = child.stdout.take().context("The p4 broker has no stdout")?;
+        Ok(Helper {
+            _child: child,
+            stdin,
+            stdout: BufReader::new(stdout),
+        })
+    }
+}
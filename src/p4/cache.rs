@@ -0,0 +1,85 @@
+//! Caching the results of read-only commands
+//!
+//! Models often repeat a query within a few turns: `p4 info` before every workflow,
+//! `fstat` on the same files while planning and again while acting. Results of
+//! idempotent reads (see [`P4Command::is_cacheable`]) are kept for a short time and
+//! thrown away as soon as a command that changes anything runs through the handler.
+//!
+//! [`P4Command::is_cacheable`]: super::P4Command::is_cacheable
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSettings {
+    /// Seconds a cached result stays valid; 0 disables the cache.
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        Self { ttl_secs: 10 }
+    }
+}
+
+/// What a result depends on: where p4 ran, its whole command line and its input.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub dir: Option<PathBuf>,
+    pub args: Vec<String>,
+    pub input: Option<String>,
+}
+
+/// Command output keyed by [`CacheKey`], each entry valid for the TTL.
+#[derive(Debug, Default)]
+pub struct ResultCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<CacheKey, (Instant, String)>>,
+}
+
+impl ResultCache {
+    pub fn new(settings: &CacheSettings) -> Self {
+        Self {
+            ttl: Duration::from_secs(settings.ttl_secs),
+            entries: Mutex::default(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// The cached output for `key`, if it hasn't expired.
+    pub fn get(&self, key: &CacheKey) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        match entries.get(key) {
+            Some((stored, output)) if stored.elapsed() < self.ttl => Some(output.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, output: String) {
+        if !self.is_enabled() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        entries.insert(key, (Instant::now(), output));
+    }
+
+    /// Forget every cached result, e.g. after a command that changed files.
+    pub fn invalidate(&self) {
+        self.entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
@@ -0,0 +1,70 @@
+//! Character sets for p4 input and output
+//!
+//! Unicode-enabled servers require clients to name a character set with `-C`; with
+//! `utf8` every file name and description comes back as UTF-8. Other servers send
+//! whatever bytes the files were submitted with, which are decoded with the configured
+//! `P4CHARSET` instead of being mangled into replacement characters.
+
+use encoding_rs::Encoding;
+
+/// Detect the character set: `utf8` for unicode servers, otherwise none.
+pub const AUTO: &str = "auto";
+
+/// Never pass `-C`.
+pub const NONE: &str = "none";
+
+/// The character set used for unicode servers unless another is configured.
+pub const UTF8: &str = "utf8";
+
+pub fn is_auto(charset: &str) -> bool {
+    charset.eq_ignore_ascii_case(AUTO)
+}
+
+/// The encoding for a p4 character set name, e.g. `winansi` or `shiftjis`.
+///
+/// Unknown names fall back to Windows-1252, the most common code page for files on
+/// non-unicode servers.
+pub fn encoding(charset: &str) -> &'static Encoding {
+    let charset = charset.to_ascii_lowercase();
+    let label = match charset.as_str() {
+        "utf8" | "utf8-bom" | "utf8unchecked" | "utf8unchecked-bom" => "utf-8",
+        "utf16" | "utf16-nobom" | "utf16le" | "utf16le-bom" => "utf-16le",
+        "utf16be" | "utf16be-bom" => "utf-16be",
+        "winansi" | "iso8859-1" => "windows-1252",
+        "iso8859-15" => "iso-8859-15",
+        "iso8859-5" => "iso-8859-5",
+        "iso8859-7" => "iso-8859-7",
+        "cp1251" => "windows-1251",
+        "cp1253" => "windows-1253",
+        "koi8-r" => "koi8-r",
+        "macosroman" => "macintosh",
+        "shiftjis" | "cp932" => "shift_jis",
+        "eucjp" => "euc-jp",
+        "cp936" => "gbk",
+        "cp949" => "euc-kr",
+        "cp950" => "big5",
+        other => other,
+    };
+    Encoding::for_label(label.as_bytes()).unwrap_or(encoding_rs::WINDOWS_1252)
+}
+
+/// Decode p4 output. Valid UTF-8 is taken as is, which covers ASCII and unicode
+/// servers; anything else is decoded with `charset`.
+pub fn decode(bytes: &[u8], charset: Option<&str>) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => {
+            let (text, _, _) = encoding(charset.unwrap_or("winansi")).decode(bytes);
+            text.into_owned()
+        }
+    }
+}
+
+/// Encode text sent to p4 on stdin, such as a changelist spec.
+///
+/// Without a charset, or with a UTF-16 one that encoding_rs can't produce, the text
+/// is sent as UTF-8.
+pub fn encode(text: &str, charset: Option<&str>) -> Vec<u8> {
+    let encoding = encoding(charset.unwrap_or(UTF8));
+    encoding.encode(text).0.into_owned()
+}
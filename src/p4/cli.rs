@@ -0,0 +1,206 @@
+//! Running the p4 command-line client
+//!
+//! The default backend spawns p4 for every command, or sends it to a persistent
+//! broker when one is configured, falling back to spawning p4 if the broker can't be
+//! started.
+//!
+//! Commands given more than [`ARGFILE_THRESHOLD`] files read them from an argfile
+//! (`p4 -x <file>`), since a command line with thousands of paths can go over the OS
+//! limit on its length, which is about 32 KB on Windows.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Output, Stdio};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tracing::debug;
+
+use super::backend::{Invocation, LineSink, P4Backend};
+use super::{broker, charset};
+
+/// The most files a command is given on its command line.
+pub const ARGFILE_THRESHOLD: usize = 100;
+
+/// The args of `invocation` with its files moved to an argfile, and the argfile, which
+/// is deleted when dropped. `None` when the files fit on the command line.
+fn with_argfile(invocation: &Invocation<'_>) -> Result<Option<(Vec<String>, NamedTempFile)>> {
+    let Some(command) = invocation.command else {
+        return Ok(None);
+    };
+    let Some(files) = command
+        .file_list()
+        .filter(|files| files.len() > ARGFILE_THRESHOLD)
+    else {
+        return Ok(None);
+    };
+    let args = invocation.args;
+    let command_args = command.to_command_args().1.len();
+    if command_args > args.len() || !args.ends_with(files) {
+        return Ok(None);
+    }
+
+    let mut argfile = NamedTempFile::new()?;
+    let mut list = files.join("\n");
+    list.push('\n');
+    argfile.write_all(&charset::encode(&list, invocation.charset))?;
+    argfile.flush()?;
+
+    // -x is a global flag, so it goes before the command
+    let start = args.len() - command_args;
+    let mut rewritten = args[..start].to_vec();
+    rewritten.push("-x".to_string());
+    rewritten.push(argfile.path().to_string_lossy().into_owned());
+    rewritten.extend_from_slice(&args[start..args.len() - files.len()]);
+    Ok(Some((rewritten, argfile)))
+}
+
+pub struct CliBackend {
+    /// The p4 executable, `p4` on the `PATH` unless configured.
+    p4_bin: PathBuf,
+    /// Runs commands over a persistent connection instead of spawning p4 for each.
+    broker: Option<Arc<broker::Broker>>,
+}
+
+impl CliBackend {
+    pub fn new(p4_bin: PathBuf, broker: Option<Arc<broker::Broker>>) -> Self {
+        Self { p4_bin, broker }
+    }
+
+    pub fn p4_bin(&self) -> &Path {
+        &self.p4_bin
+    }
+
+    pub fn broker(&self) -> Option<&Arc<broker::Broker>> {
+        self.broker.as_ref()
+    }
+
+    /// Explain a failure to start p4 in `dir`. A missing executable and a missing
+    /// working directory both surface as `NotFound`.
+    fn spawn_error(&self, e: std::io::Error, dir: Option<&Path>) -> anyhow::Error {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return anyhow::anyhow!("Could not run {}: {}", self.p4_bin.display(), e);
+        }
+        match dir.filter(|dir| !dir.is_dir()) {
+            Some(dir) => anyhow::anyhow!("Working directory {} does not exist", dir.display()),
+            None => anyhow::anyhow!(
+                "The p4 command-line client was not found at {}. Install it, or point \
+                 --p4-bin (p4_bin in the config file) at the p4 executable",
+                self.p4_bin.display()
+            ),
+        }
+    }
+
+    async fn run(&self, invocation: &Invocation<'_>, on_line: &LineSink<'_>) -> Result<Output> {
+        let argfile = with_argfile(invocation)?;
+        let args = match &argfile {
+            Some((args, file)) => {
+                debug!("Passing the files to p4 in {}", file.path().display());
+                args.as_slice()
+            }
+            None => invocation.args,
+        };
+
+        if let Some(broker) = &self.broker {
+            let request = broker::Request {
+                args,
+                input: invocation
+                    .input
+                    .map(|input| charset::decode(input, invocation.charset)),
+                cwd: invocation.cwd,
+                env: invocation
+                    .env
+                    .iter()
+                    .map(|(name, value)| {
+                        (
+                            name.to_string_lossy().into_owned(),
+                            value.to_string_lossy().into_owned(),
+                        )
+                    })
+                    .collect(),
+            };
+            if let Some(output) = broker.run(&request).await? {
+                output
+                    .stdout
+                    .split_inclusive(|&byte| byte == b'\n')
+                    .for_each(on_line);
+                return Ok(output);
+            }
+        }
+
+        let mut process = Command::new(&self.p4_bin);
+        process
+            .env_clear()
+            .envs(invocation.env.iter().map(|(name, value)| (name, value)));
+        if let Some(dir) = invocation.cwd {
+            process.current_dir(dir);
+        }
+        let mut child = process
+            .args(args)
+            .stdin(if invocation.input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // Dropping the child when the command times out or is cancelled kills it
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| self.spawn_error(e, invocation.cwd))?;
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+        let write_input = async {
+            if let (Some(input), Some(mut stdin)) = (invocation.input, stdin) {
+                stdin.write_all(input).await?;
+            }
+            Ok::<_, std::io::Error>(())
+        };
+        // stdout is read line by line so long commands can report progress
+        let read_stdout = async {
+            let mut output = Vec::new();
+            if let Some(stdout) = stdout {
+                let mut reader = BufReader::new(stdout);
+                let mut line = Vec::new();
+                while reader.read_until(b'\n', &mut line).await? > 0 {
+                    on_line(&line);
+                    output.append(&mut line);
+                }
+            }
+            Ok(output)
+        };
+        let read_stderr = async {
+            let mut output = Vec::new();
+            if let Some(mut stderr) = stderr {
+                stderr.read_to_end(&mut output).await?;
+            }
+            Ok(output)
+        };
+        let ((), stdout, stderr) = tokio::try_join!(write_input, read_stdout, read_stderr)?;
+        Ok(Output {
+            status: child.wait().await?,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+#[async_trait]
+impl P4Backend for CliBackend {
+    async fn execute(&self, invocation: &Invocation<'_>) -> Result<Output> {
+        self.stream(invocation, &|_| {}).await
+    }
+
+    async fn stream(&self, invocation: &Invocation<'_>, on_line: &LineSink<'_>) -> Result<Output> {
+        self.run(invocation, on_line).await
+    }
+
+    fn program(&self) -> String {
+        self.p4_bin.display().to_string()
+    }
+}
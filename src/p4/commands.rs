@@ -1,3 +1,7 @@
+use anyhow::Result;
+
+use super::spec::{Spec, SpecKind};
+
 #[derive(Debug, Clone)]
 pub enum P4Command {
     Status {
@@ -13,12 +17,26 @@ pub enum P4Command {
     Add {
         files: Vec<String>,
     },
-    Submit {
-        description: String,
-        files: Option<Vec<String>>,
+    Reopen {
+        changelist: String,
+        files: Vec<String>,
     },
     Revert {
         files: Vec<String>,
+        wipe: bool,
+    },
+    /// `p4 revert -n`: what a revert would do
+    RevertPreview {
+        files: Vec<String>,
+        wipe: bool,
+    },
+    /// `p4 edit -n`: the files an edit would open
+    EditPreview {
+        files: Vec<String>,
+    },
+    /// `p4 add -n`: the files an add would open
+    AddPreview {
+        files: Vec<String>,
     },
     Opened {
         changelist: Option<String>,
@@ -28,6 +46,148 @@ pub enum P4Command {
         path: Option<String>,
     },
     Info,
+    Jobs {
+        filter: Option<String>,
+        max: Option<u32>,
+    },
+    Fix {
+        changelist: String,
+        jobs: Vec<String>,
+    },
+    SubmitChangelist {
+        changelist: String,
+    },
+    ResolvePreview {
+        path: Option<String>,
+        changelist: Option<String>,
+    },
+    SyncPreview {
+        paths: Vec<String>,
+    },
+    /// `p4 sync -f -n`: what a forced sync would overwrite
+    ForceSyncPreview {
+        path: String,
+    },
+    /// `p4 diff -se`: files changed locally without being opened
+    DiffUnopened {
+        path: String,
+    },
+    ShelvedChanges {
+        user: Option<String>,
+        client: Option<String>,
+    },
+    /// `p4 changes -l -s pending`, with whole descriptions
+    PendingChanges {
+        user: Option<String>,
+        client: Option<String>,
+    },
+    LoginStatus,
+    Version,
+    OpenedAll {
+        files: Vec<String>,
+    },
+    CreateChange {
+        description: String,
+    },
+    /// `p4 <kind> -o [name]`
+    SpecOutput {
+        kind: SpecKind,
+        name: Option<String>,
+    },
+    /// `p4 <kind> -i`, with the form on stdin
+    SpecInput {
+        kind: SpecKind,
+        spec: String,
+    },
+    DeleteChange {
+        changelist: String,
+    },
+    IntegratePreview {
+        from: String,
+        to: String,
+    },
+    Integrate {
+        from: String,
+        to: String,
+        changelist: String,
+    },
+    PopulatePreview {
+        from: String,
+        to: String,
+    },
+    Populate {
+        from: String,
+        to: String,
+        description: String,
+    },
+    RevertChange {
+        changelist: String,
+    },
+    AutoResolvePreview {
+        changelist: String,
+    },
+    Streams {
+        path: Option<String>,
+    },
+    ChangesLong {
+        path: String,
+        max: Option<u32>,
+    },
+    Fixes {
+        path: String,
+    },
+    /// `p4 integrated`: how the files under `path` were integrated from other files, or
+    /// with `reverse` (`-r`) into them
+    Integrated {
+        path: String,
+        reverse: bool,
+    },
+    Depots,
+    Dirs {
+        path: String,
+    },
+    Files {
+        path: String,
+        max: Option<u32>,
+    },
+    Print {
+        path: String,
+    },
+    /// `p4 -x - fstat`, with the paths on stdin
+    Fstat {
+        paths: Vec<String>,
+    },
+    /// `p4 -x - where`, with the paths on stdin
+    Where {
+        paths: Vec<String>,
+    },
+    Describe {
+        changelist: String,
+    },
+    DescribeShelved {
+        changelist: String,
+    },
+    /// `p4 describe -S -s`: the files shelved in a change, without their diffs
+    DescribeShelvedFiles {
+        changelist: String,
+    },
+    /// `p4 diff -du -f`: workspace files against their shelved revisions in `changelist`
+    DiffShelved {
+        files: Vec<String>,
+        changelist: String,
+    },
+    /// `p4 describe -du`: a submitted change with the diffs of its files
+    DescribeDiffs {
+        changelist: String,
+    },
+    /// `p4 diff2 -du`: the differences between two file revisions
+    Diff2 {
+        left: String,
+        right: String,
+    },
+    Diff {
+        files: Vec<String>,
+    },
 }
 
 impl P4Command {
@@ -58,20 +218,55 @@ impl P4Command {
 
             P4Command::Add { files } => {
                 let mut args = vec!["add".to_string()];
+                // Names are taken literally; p4 wants -f to accept ones like a@2x.png
+                if files
+                    .iter()
+                    .any(|file| super::paths::has_special_characters(file))
+                {
+                    args.push("-f".to_string());
+                }
                 args.extend(files.clone());
                 ("p4".to_string(), args)
             }
 
-            P4Command::Submit { description, files } => {
-                let mut args = vec!["submit".to_string(), "-d".to_string(), description.clone()];
-                if let Some(f) = files {
-                    args.extend(f.clone());
-                }
+            P4Command::Reopen { changelist, files } => {
+                let mut args = vec!["reopen".to_string(), "-c".to_string(), changelist.clone()];
+                args.extend(files.clone());
                 ("p4".to_string(), args)
             }
 
-            P4Command::Revert { files } => {
+            P4Command::Revert { files, wipe } => {
                 let mut args = vec!["revert".to_string()];
+                if *wipe {
+                    args.push("-w".to_string());
+                }
+                args.extend(files.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::RevertPreview { files, wipe } => {
+                let mut args = vec!["revert".to_string(), "-n".to_string()];
+                if *wipe {
+                    args.push("-w".to_string());
+                }
+                args.extend(files.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::EditPreview { files } => {
+                let mut args = vec!["edit".to_string(), "-n".to_string()];
+                args.extend(files.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::AddPreview { files } => {
+                let mut args = vec!["add".to_string(), "-n".to_string()];
+                if files
+                    .iter()
+                    .any(|file| super::paths::has_special_characters(file))
+                {
+                    args.push("-f".to_string());
+                }
                 args.extend(files.clone());
                 ("p4".to_string(), args)
             }
@@ -94,6 +289,596 @@ impl P4Command {
             }
 
             P4Command::Info => ("p4".to_string(), vec!["info".to_string()]),
+
+            P4Command::Jobs { filter, max } => {
+                let mut args = vec!["jobs".to_string()];
+                if let Some(f) = filter {
+                    args.push("-e".to_string());
+                    args.push(f.clone());
+                }
+                if let Some(m) = max {
+                    args.push("-m".to_string());
+                    args.push(m.to_string());
+                }
+                ("p4".to_string(), args)
+            }
+
+            P4Command::Fix { changelist, jobs } => {
+                let mut args = vec!["fix".to_string(), "-c".to_string(), changelist.clone()];
+                args.extend(jobs.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::SubmitChangelist { changelist } => (
+                "p4".to_string(),
+                vec!["submit".to_string(), "-c".to_string(), changelist.clone()],
+            ),
+
+            P4Command::ResolvePreview { path, changelist } => {
+                let mut args = vec!["resolve".to_string(), "-n".to_string()];
+                if let Some(cl) = changelist {
+                    args.push("-c".to_string());
+                    args.push(cl.clone());
+                }
+                if let Some(p) = path {
+                    args.push(p.clone());
+                }
+                ("p4".to_string(), args)
+            }
+
+            P4Command::SyncPreview { paths } => {
+                let mut args = vec!["sync".to_string(), "-n".to_string()];
+                args.extend(paths.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::DiffUnopened { path } => (
+                "p4".to_string(),
+                vec!["diff".to_string(), "-se".to_string(), path.clone()],
+            ),
+
+            P4Command::ForceSyncPreview { path } => (
+                "p4".to_string(),
+                vec![
+                    "sync".to_string(),
+                    "-f".to_string(),
+                    "-n".to_string(),
+                    path.clone(),
+                ],
+            ),
+
+            P4Command::ShelvedChanges { user, client } => {
+                let mut args = vec![
+                    "changes".to_string(),
+                    "-s".to_string(),
+                    "shelved".to_string(),
+                ];
+                if let Some(u) = user {
+                    args.push("-u".to_string());
+                    args.push(u.clone());
+                }
+                if let Some(c) = client {
+                    args.push("-c".to_string());
+                    args.push(c.clone());
+                }
+                ("p4".to_string(), args)
+            }
+
+            P4Command::PendingChanges { user, client } => {
+                let mut args = vec![
+                    "changes".to_string(),
+                    "-l".to_string(),
+                    "-s".to_string(),
+                    "pending".to_string(),
+                ];
+                if let Some(u) = user {
+                    args.push("-u".to_string());
+                    args.push(u.clone());
+                }
+                if let Some(c) = client {
+                    args.push("-c".to_string());
+                    args.push(c.clone());
+                }
+                ("p4".to_string(), args)
+            }
+
+            P4Command::OpenedAll { files } => {
+                let mut args = vec!["opened".to_string(), "-a".to_string()];
+                args.extend(files.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::CreateChange { .. } => (
+                "p4".to_string(),
+                vec!["change".to_string(), "-i".to_string()],
+            ),
+
+            P4Command::SpecOutput { kind, name } => {
+                let mut args = vec![kind.command().to_string(), "-o".to_string()];
+                args.extend(name.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::SpecInput { kind, .. } => (
+                "p4".to_string(),
+                vec![kind.command().to_string(), "-i".to_string()],
+            ),
+
+            P4Command::DeleteChange { changelist } => (
+                "p4".to_string(),
+                vec!["change".to_string(), "-d".to_string(), changelist.clone()],
+            ),
+
+            P4Command::IntegratePreview { from, to } => (
+                "p4".to_string(),
+                vec![
+                    "integrate".to_string(),
+                    "-n".to_string(),
+                    from.clone(),
+                    to.clone(),
+                ],
+            ),
+
+            P4Command::Integrate {
+                from,
+                to,
+                changelist,
+            } => (
+                "p4".to_string(),
+                vec![
+                    "integrate".to_string(),
+                    "-c".to_string(),
+                    changelist.clone(),
+                    from.clone(),
+                    to.clone(),
+                ],
+            ),
+
+            P4Command::PopulatePreview { from, to } => (
+                "p4".to_string(),
+                vec![
+                    "populate".to_string(),
+                    "-n".to_string(),
+                    "-o".to_string(),
+                    from.clone(),
+                    to.clone(),
+                ],
+            ),
+
+            P4Command::Populate {
+                from,
+                to,
+                description,
+            } => (
+                "p4".to_string(),
+                vec![
+                    "populate".to_string(),
+                    "-d".to_string(),
+                    description.clone(),
+                    from.clone(),
+                    to.clone(),
+                ],
+            ),
+
+            P4Command::AutoResolvePreview { changelist } => (
+                "p4".to_string(),
+                vec![
+                    "resolve".to_string(),
+                    "-am".to_string(),
+                    "-n".to_string(),
+                    "-c".to_string(),
+                    changelist.clone(),
+                ],
+            ),
+
+            P4Command::Streams { path } => {
+                let mut args = vec!["streams".to_string()];
+                if let Some(p) = path {
+                    args.push(p.clone());
+                }
+                ("p4".to_string(), args)
+            }
+
+            P4Command::ChangesLong { path, max } => {
+                let mut args = vec![
+                    "changes".to_string(),
+                    "-l".to_string(),
+                    "-s".to_string(),
+                    "submitted".to_string(),
+                ];
+                if let Some(m) = max {
+                    args.push("-m".to_string());
+                    args.push(m.to_string());
+                }
+                args.push(path.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::Fixes { path } => {
+                ("p4".to_string(), vec!["fixes".to_string(), path.clone()])
+            }
+
+            P4Command::Integrated { path, reverse } => {
+                let mut args = vec!["integrated".to_string()];
+                if *reverse {
+                    args.push("-r".to_string());
+                }
+                args.push(path.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::Depots => ("p4".to_string(), vec!["depots".to_string()]),
+
+            P4Command::Dirs { path } => ("p4".to_string(), vec!["dirs".to_string(), path.clone()]),
+
+            P4Command::Files { path, max } => {
+                let mut args = vec!["files".to_string()];
+                if let Some(m) = max {
+                    args.push("-m".to_string());
+                    args.push(m.to_string());
+                }
+                args.push(path.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::Print { path } => (
+                "p4".to_string(),
+                vec!["print".to_string(), "-q".to_string(), path.clone()],
+            ),
+
+            // The paths go on stdin, however many there are, rather than over the
+            // OS limit on the length of a command line
+            P4Command::Fstat { .. } => (
+                "p4".to_string(),
+                vec!["-x".to_string(), "-".to_string(), "fstat".to_string()],
+            ),
+
+            P4Command::Where { .. } => (
+                "p4".to_string(),
+                vec!["-x".to_string(), "-".to_string(), "where".to_string()],
+            ),
+
+            P4Command::Describe { changelist } => (
+                "p4".to_string(),
+                vec!["describe".to_string(), "-s".to_string(), changelist.clone()],
+            ),
+
+            P4Command::DescribeShelved { changelist } => (
+                "p4".to_string(),
+                vec![
+                    "describe".to_string(),
+                    "-S".to_string(),
+                    "-du".to_string(),
+                    changelist.clone(),
+                ],
+            ),
+
+            P4Command::DescribeShelvedFiles { changelist } => (
+                "p4".to_string(),
+                vec![
+                    "describe".to_string(),
+                    "-S".to_string(),
+                    "-s".to_string(),
+                    changelist.clone(),
+                ],
+            ),
+
+            P4Command::DiffShelved { files, changelist } => {
+                let mut args = vec!["diff".to_string(), "-du".to_string(), "-f".to_string()];
+                args.extend(files.iter().map(|file| format!("{}@={}", file, changelist)));
+                ("p4".to_string(), args)
+            }
+
+            P4Command::DescribeDiffs { changelist } => (
+                "p4".to_string(),
+                vec![
+                    "describe".to_string(),
+                    "-du".to_string(),
+                    changelist.clone(),
+                ],
+            ),
+
+            P4Command::Diff2 { left, right } => (
+                "p4".to_string(),
+                vec![
+                    "diff2".to_string(),
+                    "-du".to_string(),
+                    left.clone(),
+                    right.clone(),
+                ],
+            ),
+
+            P4Command::Diff { files } => {
+                let mut args = vec!["diff".to_string(), "-du".to_string()];
+                args.extend(files.clone());
+                ("p4".to_string(), args)
+            }
+
+            P4Command::RevertChange { changelist } => (
+                "p4".to_string(),
+                vec![
+                    "revert".to_string(),
+                    "-c".to_string(),
+                    changelist.clone(),
+                    "//...".to_string(),
+                ],
+            ),
+
+            P4Command::LoginStatus => (
+                "p4".to_string(),
+                vec!["login".to_string(), "-s".to_string()],
+            ),
+
+            P4Command::Version => ("p4".to_string(), vec!["-V".to_string()]),
+        }
+    }
+
+    /// The depot or local paths the command reads or writes, including the files and
+    /// depot sides of the view in a spec it saves. Revision specifiers are left on.
+    pub fn paths(&self) -> Vec<String> {
+        match self {
+            P4Command::Status { path }
+            | P4Command::Changes { path, .. }
+            | P4Command::ResolvePreview { path, .. }
+            | P4Command::Streams { path } => path.iter().cloned().collect(),
+            P4Command::Sync { path, .. }
+            | P4Command::ChangesLong { path, .. }
+            | P4Command::Fixes { path }
+            | P4Command::Integrated { path, .. }
+            | P4Command::ForceSyncPreview { path }
+            | P4Command::DiffUnopened { path }
+            | P4Command::Dirs { path }
+            | P4Command::Files { path, .. }
+            | P4Command::Print { path } => vec![path.clone()],
+            P4Command::Edit { files }
+            | P4Command::Add { files }
+            | P4Command::Reopen { files, .. }
+            | P4Command::Revert { files, .. }
+            | P4Command::RevertPreview { files, .. }
+            | P4Command::EditPreview { files }
+            | P4Command::AddPreview { files }
+            | P4Command::OpenedAll { files }
+            | P4Command::Diff { files }
+            | P4Command::DiffShelved { files, .. } => files.clone(),
+            P4Command::SyncPreview { paths }
+            | P4Command::Fstat { paths }
+            | P4Command::Where { paths } => paths.clone(),
+            P4Command::IntegratePreview { from, to }
+            | P4Command::Integrate { from, to, .. }
+            | P4Command::PopulatePreview { from, to }
+            | P4Command::Populate { from, to, .. } => vec![from.clone(), to.clone()],
+            P4Command::Diff2 { left, right } => vec![left.clone(), right.clone()],
+            P4Command::SpecInput { kind, spec } => {
+                let spec = Spec::parse(spec);
+                let field = match kind {
+                    SpecKind::Change => "Files",
+                    SpecKind::Client | SpecKind::Label => "View",
+                    SpecKind::Job | SpecKind::Stream => return Vec::new(),
+                };
+                spec.lines(field)
+                    .into_iter()
+                    .filter_map(|line| {
+                        // `//depot/a.txt\t# edit` or `-"//depot/a b/..." //ws/a b/...`
+                        let line = line.split('#').next()?.trim();
+                        let line = line.trim_start_matches(['-', '+']);
+                        let path = match line.strip_prefix('"') {
+                            Some(quoted) => quoted.split('"').next()?,
+                            None => line.split_whitespace().next()?,
+                        };
+                        (!path.is_empty()).then(|| path.to_string())
+                    })
+                    .collect()
+            }
+            P4Command::Opened { .. }
+            | P4Command::Info
+            | P4Command::Jobs { .. }
+            | P4Command::Fix { .. }
+            | P4Command::SubmitChangelist { .. }
+            | P4Command::ShelvedChanges { .. }
+            | P4Command::PendingChanges { .. }
+            | P4Command::LoginStatus
+            | P4Command::Version
+            | P4Command::CreateChange { .. }
+            | P4Command::SpecOutput { .. }
+            | P4Command::DeleteChange { .. }
+            | P4Command::RevertChange { .. }
+            | P4Command::AutoResolvePreview { .. }
+            | P4Command::Depots
+            | P4Command::Describe { .. }
+            | P4Command::DescribeShelved { .. }
+            | P4Command::DescribeShelvedFiles { .. }
+            | P4Command::DescribeDiffs { .. } => Vec::new(),
+        }
+    }
+
+    /// Replace each path the command is given with `f` of it. Paths in spec forms are
+    /// left alone.
+    pub fn map_paths(&mut self, mut f: impl FnMut(&str) -> Result<String>) -> Result<()> {
+        let mut map = |path: &mut String| -> Result<()> {
+            *path = f(path)?;
+            Ok(())
+        };
+        match self {
+            P4Command::Status { path }
+            | P4Command::Changes { path, .. }
+            | P4Command::ResolvePreview { path, .. }
+            | P4Command::Streams { path } => path.iter_mut().try_for_each(map),
+            P4Command::Sync { path, .. }
+            | P4Command::ChangesLong { path, .. }
+            | P4Command::Fixes { path }
+            | P4Command::Integrated { path, .. }
+            | P4Command::ForceSyncPreview { path }
+            | P4Command::DiffUnopened { path }
+            | P4Command::Dirs { path }
+            | P4Command::Files { path, .. }
+            | P4Command::Print { path } => map(path),
+            P4Command::Edit { files }
+            | P4Command::Add { files }
+            | P4Command::Reopen { files, .. }
+            | P4Command::Revert { files, .. }
+            | P4Command::RevertPreview { files, .. }
+            | P4Command::EditPreview { files }
+            | P4Command::AddPreview { files }
+            | P4Command::OpenedAll { files }
+            | P4Command::Diff { files }
+            | P4Command::DiffShelved { files, .. } => files.iter_mut().try_for_each(map),
+            P4Command::SyncPreview { paths }
+            | P4Command::Fstat { paths }
+            | P4Command::Where { paths } => paths.iter_mut().try_for_each(map),
+            P4Command::IntegratePreview { from, to }
+            | P4Command::Integrate { from, to, .. }
+            | P4Command::PopulatePreview { from, to }
+            | P4Command::Populate { from, to, .. } => {
+                map(from)?;
+                map(to)
+            }
+            P4Command::Diff2 { left, right } => {
+                map(left)?;
+                map(right)
+            }
+            P4Command::SpecInput { .. }
+            | P4Command::Opened { .. }
+            | P4Command::Info
+            | P4Command::Jobs { .. }
+            | P4Command::Fix { .. }
+            | P4Command::SubmitChangelist { .. }
+            | P4Command::ShelvedChanges { .. }
+            | P4Command::PendingChanges { .. }
+            | P4Command::LoginStatus
+            | P4Command::Version
+            | P4Command::CreateChange { .. }
+            | P4Command::SpecOutput { .. }
+            | P4Command::DeleteChange { .. }
+            | P4Command::RevertChange { .. }
+            | P4Command::AutoResolvePreview { .. }
+            | P4Command::Depots
+            | P4Command::Describe { .. }
+            | P4Command::DescribeShelved { .. }
+            | P4Command::DescribeShelvedFiles { .. }
+            | P4Command::DescribeDiffs { .. } => Ok(()),
+        }
+    }
+
+    /// The files that end the command line, for commands that take a list of them,
+    /// which p4 can read from an argfile (`p4 -x`) instead.
+    pub fn file_list(&self) -> Option<&[String]> {
+        match self {
+            P4Command::Edit { files }
+            | P4Command::Add { files }
+            | P4Command::Reopen { files, .. }
+            | P4Command::Revert { files, .. }
+            | P4Command::RevertPreview { files, .. }
+            | P4Command::EditPreview { files }
+            | P4Command::AddPreview { files }
+            | P4Command::OpenedAll { files }
+            | P4Command::Diff { files } => Some(files),
+            _ => None,
+        }
+    }
+
+    /// Whether p4 handles each file on its own, so errors for some files can come with
+    /// the rest opened, reverted or listed. Any other command that printed output
+    /// before failing still failed, and must not be reported as having run.
+    pub fn reports_per_file(&self) -> bool {
+        matches!(
+            self,
+            P4Command::Edit { .. }
+                | P4Command::Add { .. }
+                | P4Command::Revert { .. }
+                | P4Command::EditPreview { .. }
+                | P4Command::AddPreview { .. }
+                | P4Command::RevertPreview { .. }
+                | P4Command::Fstat { .. }
+                | P4Command::Files { .. }
+        )
+    }
+
+    /// Whether the result only depends on server and workspace state that commands
+    /// run through the handler change, so it can be cached until one of them runs.
+    pub fn is_cacheable(&self) -> bool {
+        matches!(
+            self,
+            P4Command::Info
+                | P4Command::Where { .. }
+                | P4Command::Dirs { .. }
+                | P4Command::Fstat { .. }
+        )
+    }
+
+    /// Whether the command changes files, changelists or specs, making cached results
+    /// stale.
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            P4Command::Sync { .. }
+            | P4Command::Edit { .. }
+            | P4Command::Add { .. }
+            | P4Command::Reopen { .. }
+            | P4Command::Revert { .. }
+            | P4Command::Fix { .. }
+            | P4Command::SubmitChangelist { .. }
+            | P4Command::CreateChange { .. }
+            | P4Command::SpecInput { .. }
+            | P4Command::DeleteChange { .. }
+            | P4Command::Integrate { .. }
+            | P4Command::Populate { .. }
+            | P4Command::RevertChange { .. } => true,
+            P4Command::Status { .. }
+            | P4Command::Opened { .. }
+            | P4Command::Changes { .. }
+            | P4Command::Info
+            | P4Command::Jobs { .. }
+            | P4Command::ResolvePreview { .. }
+            | P4Command::SyncPreview { .. }
+            | P4Command::RevertPreview { .. }
+            | P4Command::EditPreview { .. }
+            | P4Command::AddPreview { .. }
+            | P4Command::ForceSyncPreview { .. }
+            | P4Command::DiffUnopened { .. }
+            | P4Command::ShelvedChanges { .. }
+            | P4Command::PendingChanges { .. }
+            | P4Command::LoginStatus
+            | P4Command::Version
+            | P4Command::OpenedAll { .. }
+            | P4Command::SpecOutput { .. }
+            | P4Command::IntegratePreview { .. }
+            | P4Command::PopulatePreview { .. }
+            | P4Command::AutoResolvePreview { .. }
+            | P4Command::Streams { .. }
+            | P4Command::ChangesLong { .. }
+            | P4Command::Fixes { .. }
+            | P4Command::Integrated { .. }
+            | P4Command::Depots
+            | P4Command::Dirs { .. }
+            | P4Command::Files { .. }
+            | P4Command::Print { .. }
+            | P4Command::Fstat { .. }
+            | P4Command::Where { .. }
+            | P4Command::Describe { .. }
+            | P4Command::DescribeShelved { .. }
+            | P4Command::DescribeShelvedFiles { .. }
+            | P4Command::DescribeDiffs { .. }
+            | P4Command::DiffShelved { .. }
+            | P4Command::Diff2 { .. }
+            | P4Command::Diff { .. } => false,
+        }
+    }
+
+    /// What to feed to the command's stdin: a spec form for commands that read one
+    /// (`-i`), or the file arguments for commands run with `-x -`.
+    pub fn stdin_input(&self) -> Option<String> {
+        match self {
+            P4Command::Fstat { paths } | P4Command::Where { paths } => {
+                Some(paths.iter().map(|path| format!("{}\n", path)).collect())
+            }
+            P4Command::CreateChange { description } => {
+                let description: String = description
+                    .lines()
+                    .map(|line| format!("\t{}\n", line))
+                    .collect();
+                Some(format!("Change: new\n\nDescription:\n{}", description))
+            }
+            P4Command::SpecInput { spec, .. } => Some(spec.clone()),
+            _ => None,
         }
     }
 }
@@ -0,0 +1,148 @@
+//! Where the password for `p4 login` comes from
+//!
+//! Passwords are read when a login is needed, never taken from tool arguments, and
+//! only ever handed to `p4 login` on stdin. A provider reads them from an environment
+//! variable, a file, or the OS keyring (Keychain, Windows Credential Manager, or the
+//! Linux kernel keyring).
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::warn;
+
+use super::P4Connection;
+
+/// A source of the password for `p4 login`.
+pub trait CredentialProvider: Send + Sync {
+    /// Where the password comes from, for logs. Never includes the password.
+    fn describe(&self) -> String;
+
+    /// The password, or `None` if this provider has none.
+    fn password(&self) -> Result<Option<String>>;
+}
+
+/// Reads the password from an environment variable.
+pub struct EnvCredentials {
+    pub var: String,
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn describe(&self) -> String {
+        format!("environment variable {}", self.var)
+    }
+
+    fn password(&self) -> Result<Option<String>> {
+        Ok(std::env::var(&self.var)
+            .ok()
+            .filter(|password| !password.is_empty()))
+    }
+}
+
+/// Reads the password from the first line of a file.
+pub struct FileCredentials {
+    pub path: PathBuf,
+}
+
+impl CredentialProvider for FileCredentials {
+    fn describe(&self) -> String {
+        format!("password file {}", self.path.display())
+    }
+
+    fn password(&self) -> Result<Option<String>> {
+        let text = std::fs::read_to_string(&self.path)
+            .with_context(|| format!("Could not read {}", self.describe()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&self.path)?.permissions().mode();
+            if mode & 0o077 != 0 {
+                warn!(
+                    "{} is readable by other users; restrict it with chmod 600",
+                    self.describe()
+                );
+            }
+        }
+        Ok(text
+            .lines()
+            .next()
+            .map(str::to_string)
+            .filter(|password| !password.is_empty()))
+    }
+}
+
+/// Reads the password from the OS keyring.
+pub struct KeyringCredentials {
+    pub service: String,
+    pub user: String,
+}
+
+impl CredentialProvider for KeyringCredentials {
+    fn describe(&self) -> String {
+        format!("keyring entry {}/{}", self.service, self.user)
+    }
+
+    fn password(&self) -> Result<Option<String>> {
+        let entry = keyring::Entry::new(&self.service, &self.user)?;
+        match entry.get_password() {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => {
+                Err(anyhow::Error::new(e).context(format!("Could not read {}", self.describe())))
+            }
+        }
+    }
+}
+
+/// The `[login.credentials]` config table.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Credentials {
+    Env {
+        #[serde(default = "default_env_var")]
+        var: String,
+    },
+    File {
+        path: PathBuf,
+    },
+    Keyring {
+        #[serde(default = "default_keyring_service")]
+        service: String,
+        /// Defaults to the p4 user.
+        user: Option<String>,
+    },
+}
+
+fn default_env_var() -> String {
+    "P4PASSWD".to_string()
+}
+
+fn default_keyring_service() -> String {
+    "p4-mcp".to_string()
+}
+
+impl Default for Credentials {
+    fn default() -> Self {
+        Self::Env {
+            var: default_env_var(),
+        }
+    }
+}
+
+impl Credentials {
+    /// The provider for these settings. Keyring entries without a user are looked up
+    /// under the connection's user, or else `P4USER`.
+    pub fn provider(&self, connection: &P4Connection) -> Arc<dyn CredentialProvider> {
+        match self.clone() {
+            Self::Env { var } => Arc::new(EnvCredentials { var }),
+            Self::File { path } => Arc::new(FileCredentials { path }),
+            Self::Keyring { service, user } => Arc::new(KeyringCredentials {
+                service,
+                user: user
+                    .or_else(|| connection.user.clone())
+                    .or_else(|| std::env::var("P4USER").ok())
+                    .unwrap_or_default(),
+            }),
+        }
+    }
+}
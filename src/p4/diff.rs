@@ -0,0 +1,227 @@
+//! Splitting diff output by file
+//!
+//! `p4 diff`, `p4 describe -du` and `p4 diff2` print each file's differences after a
+//! header naming it:
+//!
+//! ```text
+//! ==== //depot/main/a.c#3 (text) ====
+//! ==== //depot/main/a.c#3 - /ws/main/a.c ====
+//! ==== //depot/main/a.c#3 (text) - //depot/main/a.c#4 (text) ==== content
+//! ```
+//!
+//! or, when the diff program prints unified headers of its own:
+//!
+//! ```text
+//! --- //depot/main/a.c    2024/01/15 12:30:45
+//! +++ /ws/main/a.c        2024/01/15 12:45:10
+//! @@ -1,2 +1,2 @@
+//! ```
+//!
+//! [`split`] cuts the output into a [`FileDiff`] per file, keeping what comes before
+//! the first file, such as `p4 describe`'s change header, as the preamble.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// One side of a `====` header: a path, its revision and its file type.
+static SIDE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(.+?)(#\d+|#none|#have|@=?\d+)?(?: \(([\w+]+)\))?$").unwrap());
+
+static HEADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^==== (.+?)(?: - (.+?))? ====(?: (\w+))?$").unwrap());
+
+/// Diff output split by file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diff {
+    /// Everything before the first file.
+    pub preamble: String,
+    pub files: Vec<FileDiff>,
+}
+
+/// The differences in one file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileDiff {
+    /// The path without its revision, usually a depot path.
+    pub path: String,
+    /// E.g. `#3`, or `@=12345` for a shelved file.
+    pub revision: Option<String>,
+    /// What the file is compared with: the workspace file or another revision.
+    pub against: Option<String>,
+    /// The p4 file type, e.g. `text` or `binary+l`.
+    pub file_type: Option<String>,
+    /// How `p4 diff2` says the two revisions differ, e.g. `content` or `identical`.
+    pub status: Option<String>,
+    /// The diff lines after the header, without surrounding blank lines.
+    pub body: String,
+}
+
+impl FileDiff {
+    /// The language of the file, going by its name; see [`language`].
+    pub fn language(&self) -> Option<&'static str> {
+        language(&self.path)
+    }
+
+    /// The file's `====` header, as p4 prints it.
+    pub fn header(&self) -> String {
+        let mut left = format!("{}{}", self.path, self.revision.as_deref().unwrap_or(""));
+        if let Some(file_type) = &self.file_type {
+            left.push_str(&format!(" ({})", file_type));
+        }
+        let mut header = match &self.against {
+            Some(against) => format!("==== {} - {} ====", left, against),
+            None => format!("==== {} ====", left),
+        };
+        if let Some(status) = &self.status {
+            header.push_str(&format!(" {}", status));
+        }
+        header
+    }
+}
+
+/// Split diff output by file. Output without any file headers is all preamble.
+pub fn split(output: &str) -> Diff {
+    let lines: Vec<&str> = output.lines().collect();
+    let mut diff = Diff::default();
+    let mut preamble = Vec::new();
+    let mut body: Vec<&str> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let header = match unified_header(&lines[i..]) {
+            // The unified header of a file that already had a `====` one
+            Some(_) if !diff.files.is_empty() && trim_blank_lines(&body).is_empty() => {
+                i += 2;
+                continue;
+            }
+            Some(file) => Some((file, 2)),
+            None => equals_header(lines[i]).map(|file| (file, 1)),
+        };
+        match header {
+            Some((file, header_lines)) => {
+                finish(&mut diff.files, &mut body);
+                diff.files.push(file);
+                i += header_lines;
+            }
+            None => {
+                if diff.files.is_empty() {
+                    preamble.push(lines[i]);
+                } else {
+                    body.push(lines[i]);
+                }
+                i += 1;
+            }
+        }
+    }
+    finish(&mut diff.files, &mut body);
+    diff.preamble = trim_blank_lines(&preamble).join("\n");
+    diff
+}
+
+/// Give the last file the body collected since its header.
+fn finish(files: &mut [FileDiff], body: &mut Vec<&str>) {
+    if let Some(file) = files.last_mut() {
+        file.body = trim_blank_lines(body).join("\n");
+    }
+    body.clear();
+}
+
+fn trim_blank_lines<'a, 'b>(lines: &'a [&'b str]) -> &'a [&'b str] {
+    let start = lines
+        .iter()
+        .position(|line| !line.trim().is_empty())
+        .unwrap_or(lines.len());
+    let end = lines
+        .iter()
+        .rposition(|line| !line.trim().is_empty())
+        .map_or(start, |end| end + 1);
+    &lines[start..end]
+}
+
+fn equals_header(line: &str) -> Option<FileDiff> {
+    let captures = HEADER.captures(line)?;
+    let left = SIDE.captures(&captures[1])?;
+    let against = captures.get(2).map(|right| right.as_str().to_string());
+    Some(FileDiff {
+        path: left[1].to_string(),
+        revision: left.get(2).map(|m| m.as_str().to_string()),
+        against,
+        file_type: left.get(3).map(|m| m.as_str().to_string()),
+        status: captures.get(3).map(|m| m.as_str().to_string()),
+        body: String::new(),
+    })
+}
+
+/// A `---`/`+++` header pair, which only counts when a hunk follows it: a removed
+/// line starting with `--` followed by an added one starting with `++` looks the same.
+fn unified_header(lines: &[&str]) -> Option<FileDiff> {
+    let [old, new, hunk, ..] = lines else {
+        return None;
+    };
+    let old = old.strip_prefix("--- ")?;
+    let new = new.strip_prefix("+++ ")?;
+    if !hunk.starts_with("@@") {
+        return None;
+    }
+    let name = |side: &str| side.split('\t').next().unwrap_or(side).trim().to_string();
+    let (old, new) = (name(old), name(new));
+    let left = SIDE.captures(&old)?;
+    Some(FileDiff {
+        path: left[1].to_string(),
+        revision: left.get(2).map(|m| m.as_str().to_string()),
+        against: (new != old).then_some(new),
+        file_type: None,
+        status: None,
+        body: String::new(),
+    })
+}
+
+/// The language of a file going by its name, as code block info strings name it, or
+/// `None` when it isn't recognized.
+pub fn language(path: &str) -> Option<&'static str> {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let language = match name {
+        "Makefile" | "makefile" | "GNUmakefile" => "makefile",
+        "CMakeLists.txt" => "cmake",
+        "Dockerfile" => "dockerfile",
+        "Jenkinsfile" => "groovy",
+        _ => match name.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+            "c" => "c",
+            "h" | "hh" | "hpp" | "hxx" | "cc" | "cpp" | "cxx" | "inl" => "cpp",
+            "cs" => "csharp",
+            "java" => "java",
+            "kt" | "kts" => "kotlin",
+            "scala" => "scala",
+            "groovy" | "gradle" => "groovy",
+            "go" => "go",
+            "rs" => "rust",
+            "swift" => "swift",
+            "m" | "mm" => "objectivec",
+            "py" => "python",
+            "rb" => "ruby",
+            "php" => "php",
+            "pl" | "pm" => "perl",
+            "lua" => "lua",
+            "js" | "mjs" | "cjs" => "javascript",
+            "jsx" => "jsx",
+            "ts" => "typescript",
+            "tsx" => "tsx",
+            "html" | "htm" => "html",
+            "css" => "css",
+            "scss" => "scss",
+            "xml" | "xaml" | "csproj" | "vcxproj" => "xml",
+            "json" => "json",
+            "yaml" | "yml" => "yaml",
+            "toml" => "toml",
+            "ini" | "cfg" => "ini",
+            "md" => "markdown",
+            "sql" => "sql",
+            "sh" | "bash" => "bash",
+            "ps1" => "powershell",
+            "bat" | "cmd" => "batch",
+            "cmake" => "cmake",
+            "hlsl" | "fx" | "usf" | "ush" => "hlsl",
+            "glsl" | "vert" | "frag" => "glsl",
+            _ => return None,
+        },
+    };
+    Some(language)
+}
@@ -0,0 +1,87 @@
+//! The environment p4 runs in
+//!
+//! The server may be launched by any desktop app, with whatever that app happened to
+//! have in its environment. p4 only sees the variables it needs: the basics for
+//! finding files and locales, and the `P4*` settings. `P4PASSWD` is held back unless
+//! configured, since a password set for some other tool shouldn't silently
+//! authenticate the assistant's commands.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+/// Variables p4 needs from the system to find its files, temp space and locale.
+const SYSTEM_VARIABLES: &[&str] = &[
+    "PATH",
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "LANG",
+    "LC_ALL",
+    "LC_CTYPE",
+    "TZ",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+    "SSL_CERT_FILE",
+    "SSL_CERT_DIR",
+    "USERNAME",
+    "USERPROFILE",
+    "HOMEDRIVE",
+    "HOMEPATH",
+    "APPDATA",
+    "LOCALAPPDATA",
+    "PROGRAMDATA",
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "WINDIR",
+    "COMSPEC",
+    "PATHEXT",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvironmentPolicy {
+    /// Pass the server's whole environment to p4.
+    pub inherit_all: bool,
+    /// More variables to pass through.
+    pub inherit: Vec<String>,
+    /// Pass `P4PASSWD` through.
+    pub inherit_p4passwd: bool,
+    /// Variables to set for every p4 command, e.g. `P4IGNORE`.
+    pub set: BTreeMap<String, String>,
+}
+
+impl EnvironmentPolicy {
+    /// Whether the variable `name` is passed through to p4.
+    pub fn allows(&self, name: &str) -> bool {
+        if name.eq_ignore_ascii_case("P4PASSWD") {
+            return self.inherit_all || self.inherit_p4passwd;
+        }
+        self.inherit_all
+            || name.to_ascii_uppercase().starts_with("P4")
+            || SYSTEM_VARIABLES
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+            || self
+                .inherit
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+    }
+
+    /// The environment for a p4 process, from the server's environment `vars`.
+    pub fn apply(
+        &self,
+        vars: impl IntoIterator<Item = (OsString, OsString)>,
+    ) -> Vec<(OsString, OsString)> {
+        let mut env: Vec<(OsString, OsString)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.to_str().is_some_and(|name| self.allows(name)))
+            .collect();
+        for (name, value) in &self.set {
+            env.retain(|(existing, _)| existing != name.as_str());
+            env.push((name.into(), value.into()));
+        }
+        env
+    }
+}
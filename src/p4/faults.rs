@@ -0,0 +1,173 @@
+//! Simulated failures in mock mode
+//!
+//! Mock mode answers every command at once and successfully, which leaves a client's
+//! error handling untested. The `[mock_faults]` section of the config file makes mock
+//! commands fail some of the time with errors p4 really prints, and take a while to
+//! answer:
+//!
+//! ```toml
+//! [mock_faults]
+//! error_rate = 0.1
+//! errors = ["expired_ticket", "connection_reset"]
+//! latency_ms = 200
+//! seed = 42
+//!
+//! [mock_faults.commands.submit]
+//! error_rate = 1.0
+//! errors = ["locked_file"]
+//! ```
+//!
+//! Settings under `commands` apply to one p4 command, e.g. `sync` or `submit`, in
+//! place of the ones above. Nothing is injected outside mock mode.
+
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// An error p4 can fail with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    /// The ticket has expired and the user must log in again.
+    ExpiredTicket,
+    /// The file is locked by another user.
+    LockedFile,
+    /// The connection to the server dropped mid-command.
+    ConnectionReset,
+}
+
+impl Fault {
+    pub const ALL: &'static [Fault] = &[
+        Fault::ExpiredTicket,
+        Fault::LockedFile,
+        Fault::ConnectionReset,
+    ];
+
+    /// What p4 prints for this error, for a command on `path`.
+    pub fn message(&self, path: Option<&str>) -> String {
+        match self {
+            Fault::ExpiredTicket => "Your session has expired, please login again.".to_string(),
+            Fault::LockedFile => format!(
+                "{} - already locked by mock-user@mock-client",
+                path.unwrap_or("//depot/main/file1.txt")
+            ),
+            Fault::ConnectionReset => "Perforce client error:\n\tTCP receive failed.\n\tread: \
+                                       socket: Connection reset by peer"
+                .to_string(),
+        }
+    }
+}
+
+/// Settings for one p4 command; those left out are taken from [`FaultInjection`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommandFaults {
+    #[serde(deserialize_with = "deserialize_optional_rate")]
+    pub error_rate: Option<f64>,
+    pub errors: Option<Vec<Fault>>,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultInjection {
+    /// The chance, from 0 to 1, that a command fails.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub error_rate: f64,
+    /// The errors failing commands report, picked at random; every kind if empty.
+    pub errors: Vec<Fault>,
+    /// How long each command takes to answer.
+    pub latency_ms: u64,
+    /// Seeds the random choices, so a run can be repeated exactly.
+    pub seed: Option<u64>,
+    /// Settings for individual p4 commands, by command name.
+    pub commands: HashMap<String, CommandFaults>,
+}
+
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    let rate = f64::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&rate) {
+        return Err(serde::de::Error::custom(format!(
+            "error_rate {} is not between 0 and 1",
+            rate
+        )));
+    }
+    Ok(rate)
+}
+
+fn deserialize_optional_rate<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<f64>, D::Error> {
+    deserialize_rate(deserializer).map(Some)
+}
+
+impl FaultInjection {
+    pub fn is_enabled(&self) -> bool {
+        self.error_rate > 0.0
+            || self.latency_ms > 0
+            || self.commands.values().any(|c| {
+                c.error_rate.is_some_and(|r| r > 0.0) || c.latency_ms.is_some_and(|l| l > 0)
+            })
+    }
+
+    fn error_rate(&self, command: &str) -> f64 {
+        self.commands
+            .get(command)
+            .and_then(|c| c.error_rate)
+            .unwrap_or(self.error_rate)
+    }
+
+    fn errors(&self, command: &str) -> &[Fault] {
+        let errors = self
+            .commands
+            .get(command)
+            .and_then(|c| c.errors.as_deref())
+            .unwrap_or(&self.errors);
+        if errors.is_empty() {
+            Fault::ALL
+        } else {
+            errors
+        }
+    }
+
+    fn latency(&self, command: &str) -> Duration {
+        Duration::from_millis(
+            self.commands
+                .get(command)
+                .and_then(|c| c.latency_ms)
+                .unwrap_or(self.latency_ms),
+        )
+    }
+}
+
+/// Decides how each mock command behaves, shared between handler clones so a seeded
+/// run makes the same choices in the same order.
+#[derive(Debug)]
+pub struct FaultInjector {
+    settings: FaultInjection,
+    rng: Mutex<fastrand::Rng>,
+}
+
+impl FaultInjector {
+    pub fn new(settings: FaultInjection) -> Self {
+        let rng = match settings.seed {
+            Some(seed) => fastrand::Rng::with_seed(seed),
+            None => fastrand::Rng::new(),
+        };
+        Self {
+            settings,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    /// How long a run of `command` takes, and the error it fails with, if any.
+    pub fn next(&self, command: &str) -> (Duration, Option<Fault>) {
+        let mut rng = self.rng.lock().unwrap_or_else(|e| e.into_inner());
+        let fault = (rng.f64() < self.settings.error_rate(command)).then(|| {
+            let errors = self.settings.errors(command);
+            errors[rng.usize(..errors.len())]
+        });
+        (self.settings.latency(command), fault)
+    }
+}
@@ -0,0 +1,125 @@
+//! Checking that p4 can reach the server
+//!
+//! When every tool call fails, the cause is almost always one of three things: the p4
+//! client can't be run, the server can't be reached, or the ticket is missing or
+//! expired. [`P4Handler::health`] checks each in turn; the server logs the report at
+//! startup and returns it from the `p4_health` tool.
+
+use serde::Serialize;
+
+use super::{CacheSettings, P4Command, P4Handler};
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthCheck {
+    /// `p4`, `server` or `ticket`.
+    pub name: &'static str,
+    pub ok: bool,
+    /// What was found, or what went wrong.
+    pub detail: String,
+    /// What to do about a failed check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+/// The checks run by [`P4Handler::health`], in order. A check isn't run when an
+/// earlier one it depends on failed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// The backend the commands ran on: the p4 executable, `mock` or a replay.
+    pub backend: String,
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    /// Whether every check passed.
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|check| check.ok)
+    }
+
+    /// The check called `name`, if it was run.
+    pub fn check(&self, name: &str) -> Option<&HealthCheck> {
+        self.checks.iter().find(|check| check.name == name)
+    }
+}
+
+impl HealthCheck {
+    pub(crate) fn passed(name: &'static str, detail: String) -> Self {
+        Self {
+            name,
+            ok: true,
+            detail,
+            hint: None,
+        }
+    }
+
+    pub(crate) fn failed(name: &'static str, error: anyhow::Error, hint: Option<&str>) -> Self {
+        Self {
+            name,
+            ok: false,
+            detail: format!("{:#}", error).trim().to_string(),
+            hint: hint.map(str::to_string),
+        }
+    }
+}
+
+impl P4Handler {
+    /// Check that the p4 client runs, that `p4 info` reaches the server and that the
+    /// ticket is valid.
+    pub async fn health(&self) -> HealthReport {
+        let mut handler = self.clone();
+        // A cached answer would only say the server was up when it was last asked
+        handler.set_cache_settings(&CacheSettings { ttl_secs: 0 });
+        let mut report = HealthReport {
+            backend: handler.backend().program(),
+            checks: Vec::new(),
+        };
+
+        match handler.check_p4_bin().await {
+            Ok(version) => report.checks.push(HealthCheck::passed("p4", version)),
+            Err(e) => {
+                report.checks.push(HealthCheck::failed("p4", e, None));
+                return report;
+            }
+        }
+
+        match handler.info().await {
+            Ok(info) => report.checks.push(HealthCheck::passed(
+                "server",
+                format!(
+                    "{} ({}) as {}, client {}",
+                    info.server_address,
+                    info.server_version,
+                    info.user_name,
+                    info.client().unwrap_or("unknown")
+                ),
+            )),
+            Err(e) => {
+                report.checks.push(HealthCheck::failed(
+                    "server",
+                    e,
+                    Some(
+                        "Check P4PORT (--p4port, or [p4] port in the config file), and that \
+                         the server is running and reachable from this machine",
+                    ),
+                ));
+                return report;
+            }
+        }
+
+        report
+            .checks
+            .push(match handler.execute(P4Command::LoginStatus).await {
+                Ok(status) => HealthCheck::passed("ticket", status.trim().to_string()),
+                Err(e) => HealthCheck::failed(
+                    "ticket",
+                    e,
+                    Some(
+                        "Run p4 login as the server's user, or give the server a password for \
+                     p4 login with [login.credentials] in the config file",
+                    ),
+                ),
+            });
+        report
+    }
+}
@@ -0,0 +1,42 @@
+//! Logging in again when a ticket expires
+//!
+//! Tickets last 12 hours by default, so a long assistant session eventually sees every
+//! command fail with "Your session has expired". When the credential provider has a
+//! password, the handler runs `p4 login` with it on stdin and retries the command once.
+
+use serde::Deserialize;
+
+use super::credentials::Credentials;
+
+/// Fragments of p4 error messages meaning the ticket is missing or no longer valid.
+const SESSION_EXPIRED_ERRORS: &[&str] = &[
+    "your session has expired",
+    "your session was logged out",
+    "perforce password (p4passwd) invalid or unset",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginSettings {
+    /// Log in again and retry when a command fails because the ticket expired.
+    pub auto: bool,
+    /// Where the password for `p4 login` comes from.
+    pub credentials: Credentials,
+}
+
+impl Default for LoginSettings {
+    fn default() -> Self {
+        Self {
+            auto: true,
+            credentials: Credentials::default(),
+        }
+    }
+}
+
+/// Whether p4's errors say the command needs a fresh login.
+pub fn is_session_expired(errors: &str) -> bool {
+    let errors = errors.to_lowercase();
+    SESSION_EXPIRED_ERRORS
+        .iter()
+        .any(|fragment| errors.contains(fragment))
+}
@@ -0,0 +1,1001 @@
+//! Mock responses
+//!
+//! The backend used in mock mode answers every command with made-up but plausible
+//! output, as p4 would print it for the flags given, so the server can be tried out
+//! and tested without a Perforce server. Other users are modelled as
+//! [`mock_users`](super::mock_users) says, and failures are simulated as
+//! [`faults`](super::faults) says.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::process::Output;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+use super::backend::{exit_status, Invocation, P4Backend};
+use super::faults::{FaultInjection, FaultInjector};
+use super::mock_users::{self, MockUser};
+use super::severity::{self, Report};
+use super::{P4Command, Spec, SpecKind};
+
+#[derive(Debug, Clone)]
+pub struct MockBackend {
+    /// Other users with files open; see [`mock_users`].
+    users: Arc<Vec<MockUser>>,
+    /// Failures and latency to simulate; see [`super::faults`].
+    faults: Option<Arc<FaultInjector>>,
+}
+
+impl Default for MockBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockBackend {
+    /// A backend with the [default users](mock_users::default_users) and no faults.
+    pub fn new() -> Self {
+        Self {
+            users: Arc::new(mock_users::default_users()),
+            faults: None,
+        }
+    }
+
+    /// Model `users` as the other users with files open.
+    pub fn with_users(mut self, users: Vec<MockUser>) -> Self {
+        self.users = Arc::new(users);
+        self
+    }
+
+    /// Make commands fail and take time as `faults` says.
+    pub fn with_faults(mut self, faults: FaultInjection) -> Self {
+        self.faults = faults
+            .is_enabled()
+            .then(|| Arc::new(FaultInjector::new(faults)));
+        self
+    }
+
+    /// The response to a command run with `args`, which `command` was built into.
+    fn respond(&self, command: Option<&P4Command>, args: &[String]) -> Report {
+        let response = match command {
+            Some(command) if has_global_flag(command, args, "-ztag") => {
+                Self::tagged_response(command.clone()).map(|output| Report {
+                    output,
+                    ..Default::default()
+                })
+            }
+            Some(command) => self.response(command.clone()),
+            None if args.iter().any(|arg| arg == "login") => Ok(Report {
+                output: "User testuser logged in.\n".to_string(),
+                ..Default::default()
+            }),
+            None => Err(anyhow::anyhow!(
+                "Mock mode has no response to p4 {}",
+                args.join(" ")
+            )),
+        };
+        response.unwrap_or_else(|e| Report {
+            errors: vec![e.to_string()],
+            ..Default::default()
+        })
+    }
+
+    /// `p4 edit` of `files`, which fails for files another user has open exclusively
+    /// and warns about those they have open otherwise.
+    fn edit(&self, files: &[String]) -> Report {
+        let (errors, warnings) = mock_users::edit_conflicts(&self.users, files);
+        // Files another user has open exclusively stay closed
+        let opened: Vec<&str> = files
+            .iter()
+            .filter(|path| !mock_users::is_held_exclusively(&self.users, path))
+            .map(String::as_str)
+            .collect();
+        let output = if opened.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "Mock P4 Edit:\n\
+                 Files opened for edit:\n\
+                 {}\n\
+                 ... {} file(s) opened for edit\n",
+                opened.join(", "),
+                opened.len()
+            )
+        };
+        Report {
+            output,
+            warnings,
+            errors,
+            exit_code: None,
+        }
+    }
+
+    /// `p4 edit -n` or `p4 add -n` output, with a directory wildcard standing for
+    /// three files.
+    fn open_preview(files: &[String], action: &str) -> String {
+        files
+            .iter()
+            .flat_map(|file| match file.strip_suffix("...") {
+                Some(dir) => ["a.txt", "b.cpp", "c.h"]
+                    .iter()
+                    .map(|name| format!("{}{}", dir, name))
+                    .collect(),
+                None => vec![file.clone()],
+            })
+            .map(|file| format!("{}#1 - opened for {}\n", file, action))
+            .collect()
+    }
+
+    fn spec(kind: SpecKind, name: Option<String>) -> String {
+        match kind {
+            SpecKind::Change => format!(
+                "Change: {}\n\
+                 \n\
+                 Client: test-client\n\
+                 \n\
+                 User: testuser\n\
+                 \n\
+                 Status: {}\n\
+                 \n\
+                 Description:\n\
+                 \t{}\n\
+                 \n\
+                 Files:\n\
+                 \t//depot/main/file1.txt\t# edit\n\
+                 \t//depot/main/file3.h\t# edit\n",
+                name.as_deref().unwrap_or("new"),
+                if name.is_some() { "pending" } else { "new" },
+                if name.is_some() {
+                    "Fix crash on startup when config is missing"
+                } else {
+                    "<enter description here>"
+                },
+            ),
+            SpecKind::Client => {
+                let name = name.unwrap_or_else(|| "test-client".to_string());
+                // Only clients that exist report when they were last used
+                let access = if name == "test-client" {
+                    "Update:\t2024/01/10 09:00:00\n\nAccess:\t2024/01/15 10:30:00\n\n"
+                } else {
+                    ""
+                };
+                format!(
+                    "Client:\t{name}\n\
+                     \n\
+                     {access}\
+                     Owner:\ttestuser\n\
+                     \n\
+                     Host:\ttest-host\n\
+                     \n\
+                     Root:\tC:\\workspace\\p4\\{name}\n\
+                     \n\
+                     Options:\tnoallwrite noclobber nocompress unlocked nomodtime normdir\n\
+                     \n\
+                     LineEnd:\tlocal\n\
+                     \n\
+                     View:\n\
+                     \t//depot/... //{name}/...\n"
+                )
+            }
+            SpecKind::Job => format!(
+                "# A Perforce Job Specification.\n\
+                 \n\
+                 Job:\t{}\n\
+                 \n\
+                 Status:\topen\n\
+                 \n\
+                 User:\ttestuser\n\
+                 \n\
+                 Description:\n\
+                 \t<enter description here>\n",
+                name.unwrap_or_else(|| "new".to_string())
+            ),
+            SpecKind::Label => format!(
+                "Label:\t{}\n\
+                 \n\
+                 Owner:\ttestuser\n\
+                 \n\
+                 Description:\n\
+                 \tCreated by testuser.\n\
+                 \n\
+                 Options:\tunlocked noautoreload\n\
+                 \n\
+                 View:\n\
+                 \t//depot/...\n",
+                name.unwrap_or_else(|| "test-label".to_string())
+            ),
+            SpecKind::Stream => {
+                let stream = name.unwrap_or_else(|| "//streams/main".to_string());
+                let title = stream.rsplit('/').next().unwrap_or(&stream).to_string();
+                format!(
+                    "Stream:\t{}\n\
+                     \n\
+                     Owner:\ttestuser\n\
+                     \n\
+                     Name:\t{}\n\
+                     \n\
+                     Parent:\tnone\n\
+                     \n\
+                     Type:\tmainline\n\
+                     \n\
+                     Paths:\n\
+                     \tshare ...\n",
+                    stream, title
+                )
+            }
+        }
+    }
+
+    fn tagged_response(command: P4Command) -> Result<String> {
+        debug!("Mock executing tagged p4 command: {:?}", command);
+
+        match command {
+            P4Command::Info => Ok("... userName testuser\n\
+                 ... clientName test-client\n\
+                 ... clientHost test-host\n\
+                 ... clientRoot C:\\workspace\\p4\\test-client\n\
+                 ... serverAddress perforce.example.com:1666\n\
+                 ... serverRoot /opt/perforce/depot\n\
+                 ... serverVersion P4D/LINUX26X86_64/2023.1/2553040 (2023/06/15)\n\
+                 ... serverID perforce-server\n\
+                 ... caseHandling insensitive\n"
+                .to_string()),
+
+            P4Command::Status { .. } | P4Command::Opened { .. } => Ok([
+                ("//depot/main/file1.txt", "1", "edit", "default"),
+                ("//depot/main/file2.cpp", "2", "add", "default"),
+                ("//depot/main/file3.h", "1", "edit", "12346"),
+            ]
+            .iter()
+            .map(|(file, rev, action, change)| {
+                format!(
+                    "... depotFile {}\n... rev {}\n... action {}\n... change {}\n\
+                     ... type text\n... user testuser\n... client test-client\n\n",
+                    file, rev, action, change
+                )
+            })
+            .collect()),
+
+            P4Command::Changes { max, path } => {
+                let newest = path
+                    .as_deref()
+                    .and_then(|p| p.rsplit_once(",@"))
+                    .and_then(|(_, n)| n.parse::<u32>().ok())
+                    .unwrap_or(12350);
+                Ok((0..std::cmp::min(max, 5))
+                    .map(|i| {
+                        format!(
+                            "... change {}\n... time 17053{}0000\n... user user\n\
+                             ... client workspace\n... status submitted\n\
+                             ... changeType public\n... path //depot/main/...\n\
+                             ... desc Sample change description {}\n\n",
+                            newest - i,
+                            i,
+                            i + 1
+                        )
+                    })
+                    .collect())
+            }
+
+            P4Command::ShelvedChanges { .. } => Ok(pending_change(
+                "12347",
+                "1705234800",
+                "Shelved work in progress",
+            )),
+
+            P4Command::PendingChanges { .. } => {
+                Ok(
+                    pending_change("12346", "1705321200", "Refactor the config loader")
+                        + &pending_change("12347", "1705234800", "Shelved work in progress"),
+                )
+            }
+
+            P4Command::Describe { changelist } => Ok(format!(
+                "... change {}\n... user testuser\n... client test-client\n\
+                 ... time 1705321845\n... desc Fix crash on startup when config is missing\n\
+                 \n... status submitted\n... changeType public\n\
+                 ... job0 job000123\n... jobstat0 closed\n\
+                 ... depotFile0 //depot/main/file1.txt\n... action0 edit\n\
+                 ... type0 text\n... rev0 3\n\
+                 ... depotFile1 //depot/main/file2.cpp\n... action1 add\n\
+                 ... type1 text\n... rev1 1\n",
+                changelist
+            )),
+
+            P4Command::Integrated { .. } => Ok([
+                ("file1.txt", "#0", "#1", "#0", "#2", "branch from", "12355"),
+                ("file1.txt", "#1", "#2", "#2", "#3", "copy from", "12361"),
+                ("file2.cpp", "#1", "#2", "#1", "#4", "merge from", "12361"),
+            ]
+            .iter()
+            .map(
+                |(file, start_to, end_to, start_from, end_from, how, change)| {
+                    format!(
+                        "... toFile //depot/release/1.0/{file}\n... startToRev {start_to}\n\
+                     ... endToRev {end_to}\n... fromFile //depot/main/{file}\n\
+                     ... startFromRev {start_from}\n... endFromRev {end_from}\n\
+                     ... how {how}\n... change {change}\n\n"
+                    )
+                },
+            )
+            .collect()),
+
+            P4Command::DescribeShelvedFiles { changelist } => Ok(format!(
+                "... change {}\n... user testuser\n... client test-client\n\
+                 ... time 1705234800\n... desc Shelved work in progress\n\
+                 \n... status pending\n... changeType public\n... shelved \n\
+                 ... depotFile0 //depot/main/file1.txt\n... action0 edit\n\
+                 ... type0 text\n... rev0 3\n\
+                 ... depotFile1 //depot/main/file3.h\n... action1 edit\n\
+                 ... type1 text\n... rev1 1\n",
+                changelist
+            )),
+
+            P4Command::Fstat { paths } => Ok(paths
+                .iter()
+                .map(|path| {
+                    format!(
+                        "... depotFile {}\n... clientFile /local/workspace/{}\n\
+                         ... headAction edit\n... headType text\n... headTime 1705321845\n\
+                         ... headRev 3\n... headChange 12345\n... haveRev 3\n\n",
+                        path,
+                        path.rsplit('/').next().unwrap_or_default()
+                    )
+                })
+                .collect()),
+
+            P4Command::Where { paths } => Ok(paths
+                .iter()
+                .map(|path| {
+                    let relative = path
+                        .trim_start_matches("//")
+                        .split_once('/')
+                        .map_or("", |(_, rest)| rest);
+                    format!(
+                        "... depotFile {}\n... clientFile //test-client/{}\n\
+                         ... path /local/workspace/{}\n\n",
+                        path, relative, relative
+                    )
+                })
+                .collect()),
+
+            other => Err(anyhow::anyhow!("No tagged mock output for {:?}", other)),
+        }
+    }
+
+    /// The response to `command`, as `p4 -s` would report it.
+    fn response(&self, command: P4Command) -> Result<Report> {
+        debug!("Mock executing p4 command: {:?}", command);
+
+        let output: Result<String> = match command {
+            P4Command::Status { path } => {
+                let path_info = path.unwrap_or("current directory".to_string());
+                Ok(format!(
+                    "Mock P4 Status for {}:\n\
+                     //depot/main/file1.txt#1 - edit default change (text)\n\
+                     //depot/main/file2.cpp#2 - add default change (text)\n\
+                     ... (mock data)",
+                    path_info
+                ))
+            }
+
+            P4Command::Sync { path, force } => {
+                let force_flag = if force { " (forced)" } else { "" };
+                Ok(format!(
+                    "Mock P4 Sync{}:\n\
+                     //depot/main/{}#1 - updating /local/workspace/file1.txt\n\
+                     //depot/main/{}#2 - updating /local/workspace/file2.cpp\n\
+                     ... synced 15 files",
+                    force_flag, path, path
+                ))
+            }
+
+            P4Command::Edit { files } => return Ok(self.edit(&files)),
+
+            P4Command::Add { files } => {
+                let file_list = files.join(", ");
+                Ok(format!(
+                    "Mock P4 Add:\n\
+                     Files opened for add:\n\
+                     {}\n\
+                     ... {} file(s) opened for add",
+                    file_list,
+                    files.len()
+                ))
+            }
+
+            P4Command::Reopen { changelist, files } => Ok(files
+                .iter()
+                .map(|file| format!("{}#1 - reopened; change {}\n", file, changelist))
+                .collect()),
+
+            P4Command::Revert { files, .. } => {
+                let file_list = files.join(", ");
+                Ok(format!(
+                    "Mock P4 Revert:\n\
+                     Files reverted:\n\
+                     {}\n\
+                     ... {} file(s) reverted",
+                    file_list,
+                    files.len()
+                ))
+            }
+
+            P4Command::Opened { changelist } => {
+                let cl_info = if let Some(cl) = changelist {
+                    format!(" in changelist {}", cl)
+                } else {
+                    String::new()
+                };
+                Ok(format!(
+                    "Mock P4 Opened{}:\n\
+                     //depot/main/file1.txt#1 - edit default change (text)\n\
+                     //depot/main/file2.cpp#2 - add default change (text)\n\
+                     //depot/main/file3.h#1 - edit change 12346 (text)",
+                    cl_info
+                ))
+            }
+
+            P4Command::Changes { max, path } => {
+                let path_info = if let Some(path) = &path {
+                    format!(" for path {}", path)
+                } else {
+                    String::new()
+                };
+
+                let mut result = format!("Mock P4 Changes (max: {}){}:\n", max, path_info);
+
+                // Honor an upper bound from a `@1,@N` revision range
+                let newest = path
+                    .as_deref()
+                    .and_then(|p| p.rsplit_once(",@"))
+                    .and_then(|(_, n)| n.parse::<u32>().ok())
+                    .unwrap_or(12350);
+
+                for i in 0..std::cmp::min(max, 5) {
+                    let change_num = newest - i;
+                    result.push_str(&format!(
+                        "Change {} on 2024/01/1{} by user@workspace 'Sample change description {}'\n",
+                        change_num,
+                        15 + i,
+                        i + 1
+                    ));
+                }
+
+                Ok(result)
+            }
+
+            P4Command::Info => Ok("Mock P4 Info:\n\
+                     User name: testuser\n\
+                     Client name: test-client\n\
+                     Client host: test-host\n\
+                     Client root: C:\\workspace\\p4\\test-client\n\
+                     Current directory: C:\\workspace\\p4\\test-client\\main\n\
+                     Peer address: ssl:perforce.example.com:1666\n\
+                     Client address: 192.168.1.100\n\
+                     Server address: perforce.example.com:1666\n\
+                     Server root: /opt/perforce/depot\n\
+                     Server date: 2024/01/15 12:30:45 -0800 PST\n\
+                     Server uptime: 15:32:18\n\
+                     Server version: P4D/LINUX26X86_64/2023.1/2553040 (2023/06/15)\n\
+                     ServerID: perforce-server\n\
+                     Case Handling: insensitive"
+                .to_string()),
+
+            P4Command::Jobs { filter, max } => {
+                let job = filter
+                    .as_deref()
+                    .and_then(|f| f.split('=').nth(1))
+                    .unwrap_or("job000123");
+                let mut result = format!(
+                    "{} on 2024/01/15 by testuser *open* 'Fix crash on startup'\n",
+                    job
+                );
+                if filter.is_none() && max.unwrap_or(2) > 1 {
+                    result.push_str(
+                        "job000122 on 2024/01/14 by alice *closed* 'Update build scripts'\n",
+                    );
+                }
+                Ok(result)
+            }
+
+            P4Command::Fix { changelist, jobs } => Ok(jobs
+                .iter()
+                .map(|job| format!("{} fixed by change {}.\n", job, changelist))
+                .collect()),
+
+            P4Command::SubmitChangelist { changelist } => Ok(format!(
+                "Submitting change {}.\n\
+                 Locking 2 files ...\n\
+                 edit //depot/main/file1.txt#2\n\
+                 add //depot/main/file2.cpp#1\n\
+                 Change {} submitted.",
+                changelist, changelist
+            )),
+
+            P4Command::ResolvePreview { .. } => Ok(
+                "C:\\workspace\\p4\\test-client\\main\\file1.txt - merging //depot/main/file1.txt#3\n\
+                 C:\\workspace\\p4\\test-client\\main\\file3.h - merging //depot/main/file3.h#2\n"
+                    .to_string(),
+            ),
+
+            P4Command::AutoResolvePreview { .. } => Ok(
+                "C:\\workspace\\p4\\test-client\\rel\\file1.txt - merging //depot/main/file1.txt#3\n\
+                 Diff chunks: 0 yours + 1 theirs + 0 both + 1 conflicting\n\
+                 C:\\workspace\\p4\\test-client\\rel\\file3.h - merging //depot/main/file3.h#2\n\
+                 Diff chunks: 1 yours + 2 theirs + 0 both + 0 conflicting\n"
+                    .to_string(),
+            ),
+
+            P4Command::SyncPreview { .. } => Ok(
+                "//depot/main/file4.cpp#5 - updating C:\\workspace\\p4\\test-client\\main\\file4.cpp\n\
+                 //depot/main/file5.h#2 - added as C:\\workspace\\p4\\test-client\\main\\file5.h\n"
+                    .to_string(),
+            ),
+
+            P4Command::RevertPreview { files, wipe } => Ok(files
+                .iter()
+                .map(|file| {
+                    format!(
+                        "{}#1 - was edit, reverted{}\n",
+                        file,
+                        if wipe { " (wiped)" } else { "" }
+                    )
+                })
+                .collect()),
+
+            P4Command::EditPreview { files } => Ok(Self::open_preview(&files, "edit")),
+
+            P4Command::AddPreview { files } => Ok(Self::open_preview(&files, "add")),
+
+            P4Command::DiffUnopened { .. } => {
+                Ok("C:\\workspace\\p4\\test-client\\main\\file2.cpp\n".to_string())
+            }
+
+            P4Command::ForceSyncPreview { path } => Ok(format!(
+                "{}file1.txt#3 - refreshing C:\\workspace\\p4\\test-client\\main\\file1.txt\n\
+                 {}file2.cpp#2 - refreshing C:\\workspace\\p4\\test-client\\main\\file2.cpp\n",
+                path.trim_end_matches("..."),
+                path.trim_end_matches("...")
+            )),
+
+            P4Command::ShelvedChanges { .. } => Ok(
+                "Change 12347 on 2024/01/14 by testuser@test-client *pending* 'Shelved work in progress'\n"
+                    .to_string(),
+            ),
+
+            P4Command::PendingChanges { .. } => Ok(
+                "Change 12346 on 2024/01/15 by testuser@test-client *pending*\n\n\
+                 \tRefactor the config loader\n\n\
+                 Change 12347 on 2024/01/14 by testuser@test-client *pending*\n\n\
+                 \tShelved work in progress\n\n"
+                    .to_string(),
+            ),
+
+            P4Command::OpenedAll { files } => {
+                let own = "//depot/main/file1.txt";
+                let mut lines = Vec::new();
+                if files.is_empty() || files.iter().any(|path| mock_users::covers(path, own)) {
+                    lines.push(format!(
+                        "{}#1 - edit change 12345 (text) by testuser@test-client",
+                        own
+                    ));
+                }
+                for user in self.users.iter() {
+                    lines.extend(user.opened_lines(&files));
+                }
+                Ok(lines.iter().map(|line| format!("{}\n", line)).collect())
+            }
+
+            P4Command::CreateChange { .. } => Ok("Change 12348 created.".to_string()),
+
+            P4Command::DeleteChange { changelist } => {
+                Ok(format!("Change {} deleted.", changelist))
+            }
+
+            P4Command::IntegratePreview { from, to } => {
+                let from = from.trim_end_matches("...");
+                let to = to.trim_end_matches("...");
+                Ok(format!(
+                    "{to}file1.txt#1 - integrate from {from}file1.txt#3\n\
+                     {to}file3.h#1 - integrate from {from}file3.h#2\n\
+                     {to}file6.cpp#1 - branch/sync from {from}file6.cpp#1\n"
+                ))
+            }
+
+            P4Command::Integrate {
+                from, changelist, ..
+            } => Ok(format!(
+                "... integrated 3 file(s) from {} into change {}",
+                from, changelist
+            )),
+
+            P4Command::PopulatePreview { from, to } => {
+                let from = from.trim_end_matches("...");
+                let to = to.trim_end_matches("...");
+                Ok(format!(
+                    "{to}file1.txt#1 - branch/sync from {from}file1.txt#1,#3\n\
+                     {to}file3.h#1 - branch/sync from {from}file3.h#1,#2\n\
+                     {to}file6.cpp#1 - branch/sync from {from}file6.cpp#1\n"
+                ))
+            }
+
+            P4Command::Populate { .. } => Ok("3 files branched (change 12362).".to_string()),
+
+            P4Command::RevertChange { changelist } => Ok(format!(
+                "... reverted all files in change {}",
+                changelist
+            )),
+
+            P4Command::Streams { .. } => Ok(
+                "Stream //streams/main mainline none 'Main'\n\
+                 Stream //streams/dev development //streams/main 'Development'\n\
+                 Stream //streams/rel1.0 release //streams/main 'Release 1.0'\n\
+                 Stream //streams/feature-x development //streams/dev 'Feature X'\n"
+                    .to_string(),
+            ),
+
+            P4Command::SpecOutput { kind, name } => Ok(Self::spec(kind, name)),
+
+            P4Command::SpecInput { kind, spec } => {
+                let spec = Spec::parse(&spec);
+                Ok(match (kind, spec.field(kind.name_field())) {
+                    (SpecKind::Change, "new") => "Change 12348 created.".to_string(),
+                    (SpecKind::Change, change) => format!("Change {} updated.", change),
+                    (SpecKind::Job, "new") => "Job job000124 saved.".to_string(),
+                    (kind, name) => format!("{} {} saved.", kind.name_field(), name),
+                })
+            }
+
+            P4Command::ChangesLong { .. } => Ok(
+                "Change 12353 on 2024/01/18 by alice@alice-ws\n\
+                 \n\
+                 \tAdd retry support to the network layer\n\
+                 \tConnections are retried up to three times.\n\
+                 \n\
+                 Change 12352 on 2024/01/17 by bob@bob-ws\n\
+                 \n\
+                 \tFix crash on startup when config is missing\n\
+                 \n\
+                 Change 12351 on 2024/01/16 by testuser@test-client\n\
+                 \n\
+                 \tUpdate build scripts\n"
+                    .to_string(),
+            ),
+
+            P4Command::Integrated { reverse: false, .. } => Ok(
+                "//depot/release/1.0/file1.txt#1 - branch from //depot/main/file1.txt#1,#2\n\
+                 //depot/release/1.0/file1.txt#2 - copy from //depot/main/file1.txt#3\n\
+                 //depot/release/1.0/file2.cpp#2 - merge from //depot/main/file2.cpp#2,#4\n"
+                    .to_string(),
+            ),
+
+            P4Command::Integrated { reverse: true, .. } => Ok(
+                "//depot/main/file1.txt#1,#2 - branch into //depot/release/1.0/file1.txt#1\n\
+                 //depot/main/file1.txt#3 - copy into //depot/release/1.0/file1.txt#2\n\
+                 //depot/main/file2.cpp#2,#4 - merge into //depot/release/1.0/file2.cpp#2\n"
+                    .to_string(),
+            ),
+
+            P4Command::Fixes { .. } => Ok(
+                "job000123 fixed by change 12352 on 2024/01/17 by bob@bob-ws (closed)\n\
+                 job000125 fixed by change 12353 on 2024/01/18 by alice@alice-ws (closed)\n"
+                    .to_string(),
+            ),
+
+            P4Command::Depots => Ok(
+                "Depot depot 2023/06/15 local depot/... 'Default depot'\n\
+                 Depot streams 2023/06/15 stream streams/... 'Stream depot'\n"
+                    .to_string(),
+            ),
+
+            P4Command::Dirs { path } => {
+                let (parent, prefix) = path
+                    .trim_end_matches('*')
+                    .rsplit_once('/')
+                    .unwrap_or_default();
+                Ok(["dev", "main", "release"]
+                    .iter()
+                    .filter(|dir| dir.starts_with(prefix))
+                    .map(|dir| format!("{}/{}\n", parent, dir))
+                    .collect())
+            }
+
+            P4Command::Files { path, max } => {
+                let base = path.trim_end_matches("...").trim_end_matches('/');
+                let (base, prefix) = match base.strip_suffix('*') {
+                    Some(pattern) => pattern.rsplit_once('/').unwrap_or_default(),
+                    None => (base, ""),
+                };
+                let files = [
+                    "file1.txt#3 - edit change 12352 (text)",
+                    "file2.cpp#2 - add change 12340 (text)",
+                    "file3.h#1 - add change 12340 (text)",
+                ];
+                Ok(files
+                    .iter()
+                    .filter(|f| f.starts_with(prefix))
+                    .take(max.unwrap_or(u32::MAX) as usize)
+                    .map(|f| format!("{}/{}\n", base, f))
+                    .collect())
+            }
+
+            P4Command::Print { path } => Ok(String::from_utf8_lossy(&printed(&path)).into_owned()),
+
+            P4Command::Fstat { paths } => Ok(paths
+                .iter()
+                .map(|path| {
+                    format!(
+                        "... depotFile {}\n... headAction edit\n... headType text\n\
+                         ... headRev 3\n... headChange 12345\n... haveRev 3\n\n",
+                        path
+                    )
+                })
+                .collect()),
+
+            P4Command::Where { paths } => Ok(paths
+                .iter()
+                .map(|path| {
+                    let relative = path
+                        .trim_start_matches("//")
+                        .split_once('/')
+                        .map_or("", |(_, rest)| rest);
+                    format!(
+                        "{} //test-client/{} /local/workspace/{}\n",
+                        path, relative, relative
+                    )
+                })
+                .collect()),
+
+            P4Command::Describe { changelist } => Ok(format!(
+                "Change {} by testuser@test-client on 2024/01/15 12:30:45\n\
+                 \n\
+                 \tFix crash on startup when config is missing\n\
+                 \n\
+                 Jobs fixed ...\n\
+                 \n\
+                 job000123 on 2024/01/15 by testuser *closed*\n\
+                 \n\
+                 \tFix crash on startup\n\
+                 \n\
+                 Affected files ...\n\
+                 \n\
+                 ... //depot/main/file1.txt#3 edit\n\
+                 ... //depot/main/file2.cpp#1 add\n",
+                changelist
+            )),
+
+            P4Command::DescribeShelved { changelist } => Ok(format!(
+                "Change {} by testuser@test-client on 2024/01/14 09:12:01 *pending*\n\
+                 \n\
+                 \tShelved work in progress\n\
+                 \n\
+                 Shelved files ...\n\
+                 \n\
+                 ... //depot/main/file1.txt#3 edit\n\
+                 \n\
+                 Differences ...\n\
+                 \n\
+                 ==== //depot/main/file1.txt#3 (text) ====\n\
+                 \n\
+                 @@ -1,3 +1,3 @@\n\
+                 \x20first line\n\
+                 -second line\n\
+                 +second line, revised\n\
+                 \x20third line\n",
+                changelist
+            )),
+
+            P4Command::Diff { files } => {
+                let files = if files.is_empty() {
+                    vec!["//depot/main/file1.txt".to_string()]
+                } else {
+                    files
+                };
+                Ok(files
+                    .iter()
+                    .map(|file| {
+                        format!(
+                            "--- {file}\t2024/01/15 12:30:45\n\
+                             +++ {file}\t2024/01/15 12:45:10\n\
+                             @@ -1,2 +1,2 @@\n\
+                             -old line\n\
+                             +new line\n\
+                             \x20unchanged line\n"
+                        )
+                    })
+                    .collect())
+            }
+
+            P4Command::DescribeShelvedFiles { changelist } => Ok(format!(
+                "Change {} by testuser@test-client on 2024/01/14 09:12:01 *pending*\n\
+                 \n\
+                 \tShelved work in progress\n\
+                 \n\
+                 Shelved files ...\n\
+                 \n\
+                 ... //depot/main/file1.txt#3 edit\n\
+                 ... //depot/main/file3.h#1 edit\n",
+                changelist
+            )),
+
+            P4Command::DiffShelved { files, changelist } => Ok(files
+                .iter()
+                .map(|file| {
+                    format!(
+                        "--- {file}@={changelist}\t2024/01/14 09:12:01\n\
+                         +++ {file}\t2024/01/15 12:45:10\n\
+                         @@ -1,2 +1,2 @@\n\
+                         -shelved line\n\
+                         +workspace line\n\
+                         \x20unchanged line\n"
+                    )
+                })
+                .collect()),
+
+            P4Command::DescribeDiffs { changelist } => {
+                // A large rewrite of file2.cpp, for diff size caps to apply to
+                let rewrite: String = (1..=150)
+                    .map(|i| format!("+    parser.add_option(\"option_{}\", Kind::Flag);\n", i))
+                    .collect();
+                Ok(format!(
+                    "Change {} by testuser@test-client on 2024/01/15 12:30:45\n\
+                     \n\
+                     \tRework option parsing\n\
+                     \n\
+                     Affected files ...\n\
+                     \n\
+                     ... //depot/main/file1.txt#3 edit\n\
+                     ... //depot/main/file2.cpp#4 edit\n\
+                     \n\
+                     Differences ...\n\
+                     \n\
+                     ==== //depot/main/file1.txt#3 (text) ====\n\
+                     \n\
+                     @@ -1,2 +1,2 @@\n\
+                     -old line\n\
+                     +new line\n\
+                     \x20unchanged line\n\
+                     \n\
+                     ==== //depot/main/file2.cpp#4 (text) ====\n\
+                     \n\
+                     @@ -10,1 +10,150 @@\n\
+                     -    parse_options(&mut parser);\n\
+                     {}",
+                    changelist, rewrite
+                ))
+            }
+
+            P4Command::Diff2 { left, right } => Ok(format!(
+                "==== {} (text) - {} (text) ==== content\n\
+                 @@ -1,2 +1,2 @@\n\
+                 -old line\n\
+                 +new line\n\
+                 \x20unchanged line\n",
+                left, right
+            )),
+
+            P4Command::LoginStatus => {
+                Ok("User testuser ticket expires in 11 hours 59 minutes.".to_string())
+            }
+
+            P4Command::Version => Ok("Perforce - The Fast Software Configuration Management System.\n\
+                 Copyright 1995-2023 Perforce Software.  All rights reserved.\n\
+                 Rev. P4/LINUX26X86_64/2023.1/2442900 (2023/05/17).\n"
+                .to_string()),
+        };
+        Ok(Report {
+            output: output?,
+            ..Default::default()
+        })
+    }
+}
+
+/// The contents of `path` as `p4 print -q` prints them: an image for `.png` files,
+/// every byte value for `.bin` files, and source code otherwise.
+fn printed(path: &str) -> Vec<u8> {
+    if path.ends_with(".png") {
+        MOCK_PNG.to_vec()
+    } else if path.ends_with(".bin") {
+        (0..=255).collect()
+    } else {
+        format!(
+            "// Mock contents of {}\n\
+             int main() {{\n    return 0;\n}}\n",
+            path
+        )
+        .into_bytes()
+    }
+}
+
+/// A 1x1 PNG image, printed for `.png` files.
+const MOCK_PNG: [u8; 70] = [
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x64, 0x60, 0xf8, 0x5f,
+    0x0f, 0x00, 0x02, 0x87, 0x01, 0x80, 0xeb, 0x47, 0xba, 0x92, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45,
+    0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+];
+
+/// A pending change of the mock user, as `p4 -ztag changes` prints it.
+fn pending_change(change: &str, time: &str, desc: &str) -> String {
+    format!(
+        "... change {}\n... time {}\n... user testuser\n... client test-client\n\
+         ... status pending\n... changeType public\n... desc {}\n\n",
+        change, time, desc
+    )
+}
+
+/// Whether `flag` is among the global flags before `command` in `args`, rather than
+/// one of the command's own, like the `-s` of `p4 describe -s`.
+fn has_global_flag(command: &P4Command, args: &[String], flag: &str) -> bool {
+    let (_, command_args) = command.to_command_args();
+    args[..args.len().saturating_sub(command_args.len())]
+        .iter()
+        .any(|arg| arg == flag)
+}
+
+/// `report` as p4 prints it: tagged with `-s`, and otherwise with warnings and errors
+/// on stderr.
+fn output(report: Report, severity_tags: bool) -> Output {
+    let code = report
+        .exit_code
+        .unwrap_or(if report.errors.is_empty() { 0 } else { 1 });
+    let (stdout, stderr) = if severity_tags {
+        (severity::format(&report), String::new())
+    } else {
+        let stderr = report
+            .warnings
+            .iter()
+            .chain(&report.errors)
+            .map(|message| format!("{}\n", message))
+            .collect();
+        (report.output, stderr)
+    };
+    Output {
+        status: exit_status(code),
+        stdout: stdout.into_bytes(),
+        stderr: stderr.into_bytes(),
+    }
+}
+
+#[async_trait]
+impl P4Backend for MockBackend {
+    async fn execute(&self, invocation: &Invocation<'_>) -> Result<Output> {
+        let command = invocation.command;
+        let (latency, fault) = match (&self.faults, command) {
+            (Some(faults), Some(command)) => {
+                let (_, args) = command.to_command_args();
+                faults.next(args.first().map(String::as_str).unwrap_or_default())
+            }
+            _ => (Duration::ZERO, None),
+        };
+        // The delay counts towards the timeout, like a slow server's would
+        tokio::time::sleep(latency).await;
+
+        let report = match fault {
+            Some(fault) => Report {
+                errors: vec![fault.message(
+                    command
+                        .and_then(|command| command.paths().first().cloned())
+                        .as_deref(),
+                )],
+                ..Default::default()
+            },
+            None => self.respond(command, invocation.args),
+        };
+        let severity_tags =
+            command.is_some_and(|command| has_global_flag(command, invocation.args, "-s"));
+        let mut output = output(report, severity_tags);
+        // Binary files are printed as they are
+        if let (Some(P4Command::Print { path }), None, false) = (command, &fault, severity_tags) {
+            output.stdout = printed(path);
+        }
+        Ok(output)
+    }
+
+    fn program(&self) -> String {
+        "mock".to_string()
+    }
+}
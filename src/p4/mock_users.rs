@@ -0,0 +1,161 @@
+//! Other users in mock mode
+//!
+//! Mock mode models a few other users with files open, some of them exclusively (a
+//! `+l` file type) or locked with `p4 lock`, so the paths that ask who else has a file
+//! open, and that handle lock conflicts, can be exercised without a server:
+//! `p4 opened -a` lists them, `p4 edit` of a file another user has open exclusively
+//! fails as it would, and editing one they merely have open warns about it.
+//!
+//! The users can be replaced in the config file; `mock_users = []` removes them all:
+//!
+//! ```toml
+//! [[mock_users]]
+//! user = "alice"
+//! client = "alice-ws"
+//! change = "12346"
+//! exclusive = ["//depot/main/file3.h"]
+//!
+//! [[mock_users]]
+//! user = "bob"
+//! files = ["//depot/main/file1.txt"]
+//! locked = ["//depot/main/docs/readme.md"]
+//! ```
+
+use serde::Deserialize;
+
+/// Another user and the files they have open for edit.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MockUser {
+    pub user: String,
+    /// Their client workspace; `<user>-ws` if not given.
+    #[serde(default)]
+    pub client: Option<String>,
+    /// The pending change their files are open in; the default change if not given.
+    #[serde(default)]
+    pub change: Option<String>,
+    /// Files they have open for edit.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Files they have open with an exclusive (`+l`) file type, which nobody else
+    /// can open until they're submitted or reverted.
+    #[serde(default)]
+    pub exclusive: Vec<String>,
+    /// Files they have open and locked with `p4 lock`, which nobody else can submit.
+    #[serde(default)]
+    pub locked: Vec<String>,
+}
+
+impl MockUser {
+    fn new(user: &str, change: &str) -> Self {
+        Self {
+            user: user.to_string(),
+            client: None,
+            change: Some(change.to_string()),
+            files: Vec::new(),
+            exclusive: Vec::new(),
+            locked: Vec::new(),
+        }
+    }
+
+    /// `user@client`, as p4 names who has a file open.
+    pub fn owner(&self) -> String {
+        match &self.client {
+            Some(client) => format!("{}@{}", self.user, client),
+            None => format!("{}@{}-ws", self.user, self.user),
+        }
+    }
+
+    /// Lines of `p4 opened -a` output for this user's files under `paths`, or every
+    /// file when `paths` is empty.
+    pub fn opened_lines(&self, paths: &[String]) -> Vec<String> {
+        let change = match &self.change {
+            Some(change) => format!("change {}", change),
+            None => "default change".to_string(),
+        };
+        let opened = self
+            .exclusive
+            .iter()
+            .map(|file| (file, "text+l", " *exclusive*"))
+            .chain(self.locked.iter().map(|file| (file, "text", " *locked*")))
+            .chain(self.files.iter().map(|file| (file, "text", "")));
+        opened
+            .filter(|(file, _, _)| paths.is_empty() || paths.iter().any(|p| covers(p, file)))
+            .map(|(file, file_type, status)| {
+                format!(
+                    "{}#1 - edit {} ({}) by {}{}",
+                    file,
+                    change,
+                    file_type,
+                    self.owner(),
+                    status
+                )
+            })
+            .collect()
+    }
+}
+
+/// The other users mock mode starts with: alice has `file3.h` open exclusively, bob
+/// has `file1.txt` open too, and carol has a file locked.
+pub fn default_users() -> Vec<MockUser> {
+    vec![
+        MockUser {
+            client: Some("alice-ws".to_string()),
+            exclusive: vec!["//depot/main/file3.h".to_string()],
+            ..MockUser::new("alice", "12346")
+        },
+        MockUser {
+            client: Some("bob-ws".to_string()),
+            files: vec!["//depot/main/file1.txt".to_string()],
+            ..MockUser::new("bob", "12400")
+        },
+        MockUser {
+            client: Some("carol-ws".to_string()),
+            locked: vec!["//depot/main/docs/readme.md".to_string()],
+            ..MockUser::new("carol", "12401")
+        },
+    ]
+}
+
+/// Whether the path argument `path` names `file`: the same file, ignoring any
+/// revision, or a directory wildcard (`...`) above it.
+pub fn covers(path: &str, file: &str) -> bool {
+    let path = path.split(['#', '@']).next().unwrap_or(path);
+    match path.strip_suffix("...") {
+        Some(prefix) => file.starts_with(prefix),
+        None => path == file,
+    }
+}
+
+/// Whether `path` is a single file another user has open exclusively, so opening it
+/// opens nothing.
+pub fn is_held_exclusively(users: &[MockUser], path: &str) -> bool {
+    !path.ends_with("...")
+        && users
+            .iter()
+            .any(|user| user.exclusive.iter().any(|file| covers(path, file)))
+}
+
+/// What `p4 edit` reports about opening `files` while `users` have files open:
+/// errors for files another user has open exclusively, and warnings for files they
+/// have open otherwise.
+pub fn edit_conflicts(users: &[MockUser], files: &[String]) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    for user in users {
+        for file in &user.exclusive {
+            if files.iter().any(|path| covers(path, file)) {
+                errors.push(format!(
+                    "{} - can't edit exclusive file already opened",
+                    file
+                ));
+            }
+        }
+        for file in user.files.iter().chain(&user.locked) {
+            if files.iter().any(|path| covers(path, file)) {
+                warnings.push(format!("{} - also opened by {}", file, user.owner()));
+            }
+        }
+    }
+    (errors, warnings)
+}
@@ -1,182 +1,1377 @@
 use anyhow::Result;
-use std::process::Stdio;
-use tokio::process::Command;
-use tracing::debug;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn, Instrument};
 
+use crate::reload::Live;
+use cache::{CacheKey, ResultCache};
+
+pub mod audit;
+pub mod backend;
+pub mod broker;
+pub mod cache;
+pub mod charset;
+pub mod cli;
 pub mod commands;
+pub mod credentials;
+pub mod diff;
+pub mod environment;
+pub mod faults;
+pub mod health;
+pub mod login;
+pub mod mock;
+pub mod mock_users;
+pub mod output;
+pub mod p4config;
+pub mod paths;
+pub mod plan;
+pub mod policy;
+pub mod recording;
+pub mod results;
+pub mod retry;
+pub mod sandbox;
+pub mod severity;
+pub mod spec;
+pub mod streams;
+pub mod templates;
+pub mod view;
+mod workflows;
+pub mod ztag;
 
+pub use audit::{AuditLog, CallInfo, ExecutedCommand, ExecutionLog};
+pub use backend::{CancelToken, Invocation, MockMode, P4Backend};
+pub use cache::CacheSettings;
+pub use cli::CliBackend;
 pub use commands::P4Command;
+pub use credentials::CredentialProvider;
+pub use environment::EnvironmentPolicy;
+pub use faults::FaultInjection;
+pub use health::{HealthCheck, HealthReport};
+pub use login::LoginSettings;
+pub use mock::MockBackend;
+pub use mock_users::MockUser;
+pub use output::OutputChunk;
+pub use plan::{CommandPlan, PlannedCommand};
+pub use policy::SubmitPolicy;
+pub use recording::{Recorder, Replayer};
+pub use results::{
+    Changelist, ChangelistFile, ChangesPage, ClientSpec, FileContents, PendingChangelist,
+    PendingFile, PendingWork, SyncResult, SyncedFile, ViewMapping, Workspace,
+};
+pub use retry::RetryPolicy;
+pub use sandbox::PathSandbox;
+pub use spec::{Spec, SpecKind};
+pub use templates::{WorkspaceTemplate, WorkspaceTemplates};
+pub use ztag::{Change, FileStat, Info, Integration, OpenedFile, TaggedRecord, WhereFile};
+
+/// How many files are looked up per p4 command by [`P4Handler::fstat`] and
+/// [`P4Handler::where_files`].
+pub const LOOKUP_BATCH_SIZE: usize = 1000;
+
+/// How many p4 processes may run at once unless configured otherwise.
+pub const DEFAULT_MAX_PROCESSES: usize = 8;
+
+/// Receives a line for every p4 command run and for anything it reports on stderr.
+pub type ActivityLogger = Arc<dyn Fn(tracing::Level, &str) + Send + Sync>;
+
+/// Receives each line of a p4 command's output as the command prints it.
+pub type OutputListener = Arc<dyn Fn(&OutputChunk) + Send + Sync>;
+
+/// Connection settings passed as global flags on every p4 invocation. Settings left
+/// unset come from the environment p4 runs in (`P4PORT`, `P4CONFIG`, ...).
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct P4Connection {
+    /// `-p`
+    pub port: Option<String>,
+    /// `-u`
+    pub user: Option<String>,
+    /// `-c`
+    pub client: Option<String>,
+    /// `-C`; `auto` (the default) detects unicode servers and `none` never passes it.
+    pub charset: Option<String>,
+    /// The P4CONFIG file name to look for in the working directory and its parents;
+    /// defaults to `P4CONFIG`, or `.p4config`.
+    pub p4config: Option<String>,
+}
+
+impl P4Connection {
+    /// The global flags for these settings. An `auto` or `none` charset isn't passed;
+    /// see [`P4Handler::charset`].
+    pub fn global_args(&self) -> Vec<String> {
+        let charset = self
+            .charset
+            .clone()
+            .filter(|c| !charset::is_auto(c) && !c.eq_ignore_ascii_case(charset::NONE));
+        [
+            ("-p", &self.port),
+            ("-u", &self.user),
+            ("-c", &self.client),
+            ("-C", &charset),
+        ]
+        .into_iter()
+        .filter_map(|(flag, value)| Some([flag.to_string(), value.clone()?]))
+        .flatten()
+        .collect()
+    }
+}
+
+/// How long p4 commands may run before they are killed.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Seconds any command may run; 0 means no limit.
+    pub default_secs: u64,
+    /// Per-tool overrides in seconds, e.g. a longer limit for `p4_sync`.
+    pub tools: HashMap<String, u64>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            default_secs: 300,
+            tools: HashMap::new(),
+        }
+    }
+}
+
+impl Timeouts {
+    /// The limit for commands run by `tool`, or `None` for no limit.
+    pub fn for_tool(&self, tool: &str) -> Option<Duration> {
+        let secs = self.tools.get(tool).copied().unwrap_or(self.default_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+}
+
+/// How long the commands of a call may run; see [`P4Handler::set_timeout`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeLimit {
+    /// The `default_secs` of the configured [`Timeouts`].
+    #[default]
+    Default,
+    /// Commands run until they finish.
+    Disabled,
+    /// Commands are killed after running this long.
+    After(Duration),
+}
+
+impl From<Option<Duration>> for TimeLimit {
+    /// A limit from [`Timeouts::for_tool`], where `None` means no limit.
+    fn from(timeout: Option<Duration>) -> Self {
+        match timeout {
+            Some(timeout) => TimeLimit::After(timeout),
+            None => TimeLimit::Disabled,
+        }
+    }
+}
+
+/// A p4 command that was killed for running longer than its timeout.
+#[derive(Debug, Clone)]
+pub struct CommandTimeout {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for CommandTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "p4 {} timed out after {}s",
+            self.command,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for CommandTimeout {}
 
+#[derive(Clone)]
 pub struct P4Handler {
-    mock_mode: bool,
+    /// Shared between clones; see [`backend`].
+    mock_mode: MockMode,
+    /// Whether `p4_backend` may switch between mock and real mode.
+    allow_backend_switch: bool,
+    /// Runs the p4 command-line client, unless another backend is in use.
+    cli: Arc<CliBackend>,
+    /// Answers commands in mock mode.
+    mock: Arc<MockBackend>,
+    /// Runs commands in place of the command-line client; see [`Self::set_backend`].
+    backend: Option<Arc<dyn P4Backend>>,
+    submit_policy: SubmitPolicy,
+    /// What `p4_workspace_create` can fill new clients in from.
+    workspace_templates: WorkspaceTemplates,
+    /// Whether `p4 sync -f` may run; see [`Self::force_sync`].
+    allow_force_sync: bool,
+    activity_logger: Option<ActivityLogger>,
+    output_listener: Option<OutputListener>,
+    /// Set during a dry run; see [`plan`].
+    plan: Option<CommandPlan>,
+    connection: P4Connection,
+    /// The client a call names instead of the connection's; see [`Self::set_client`].
+    client: Option<String>,
+    working_dir: Option<PathBuf>,
+    /// Where commands run when a call doesn't name a working directory.
+    workspace_root: Option<PathBuf>,
+    environment: Arc<EnvironmentPolicy>,
+    /// The paths commands may name; see [`Self::reload`].
+    sandbox: Live<PathSandbox>,
+    timeouts: Live<Timeouts>,
+    retry: RetryPolicy,
+    /// Bounds the number of p4 processes running at once, across every clone.
+    processes: Arc<tokio::sync::Semaphore>,
+    /// The limit for the command being run, when not the default in `timeouts`; see
+    /// [`Self::set_timeout`].
+    timeout: TimeLimit,
+    /// Whether the p4 client supports `-Mj`, checked once and shared between clones.
+    json_output: Arc<tokio::sync::OnceCell<bool>>,
+    /// The character set p4 runs with, resolved once and shared between clones.
+    charset: Arc<tokio::sync::OnceCell<Option<String>>>,
+    /// The current client, once found, shared between clones; see [`Self::workspace`].
+    workspace: Arc<std::sync::Mutex<Option<Workspace>>>,
+    login: Arc<LoginSettings>,
+    /// Overrides the provider configured in `login`.
+    credentials: Option<Arc<dyn CredentialProvider>>,
+    /// Held while logging in, so commands that all find the ticket expired at once
+    /// don't each run `p4 login`.
+    login_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Recent results of read-only commands, shared between clones.
+    cache: Arc<ResultCache>,
+    /// Where every p4 run is recorded, if anywhere.
+    audit: Option<Arc<AuditLog>>,
+    /// The MCP call the current commands run for, for the audit log.
+    call: Option<CallInfo>,
+    /// Collects the commands run for the current call; see [`Self::set_execution_log`].
+    executions: Option<ExecutionLog>,
+    /// Where every p4 run and its output is recorded; see [`recording`].
+    recorder: Option<Arc<Recorder>>,
+    /// Counts and times every p4 run, shared between clones.
+    metrics: Option<Arc<crate::metrics::Metrics>>,
+    /// Cancels the commands of the current call; see [`Self::cancel`].
+    cancel: CancelToken,
 }
 
 impl P4Handler {
     pub fn new() -> Self {
         Self {
-            mock_mode: std::env::var("P4_MOCK_MODE").is_ok(),
+            mock_mode: MockMode::from_env(),
+            allow_backend_switch: false,
+            cli: Arc::new(CliBackend::new(PathBuf::from("p4"), None)),
+            mock: Arc::default(),
+            backend: None,
+            submit_policy: SubmitPolicy::from_env(),
+            workspace_templates: WorkspaceTemplates::default(),
+            allow_force_sync: false,
+            activity_logger: None,
+            output_listener: None,
+            plan: None,
+            connection: P4Connection::default(),
+            client: None,
+            working_dir: None,
+            workspace_root: None,
+            environment: Arc::default(),
+            sandbox: Live::default(),
+            timeouts: Live::default(),
+            retry: RetryPolicy::default(),
+            processes: Arc::new(tokio::sync::Semaphore::new(DEFAULT_MAX_PROCESSES)),
+            timeout: TimeLimit::Default,
+            json_output: Arc::new(tokio::sync::OnceCell::new_with(
+                std::env::var("P4_JSON_OUTPUT")
+                    .ok()
+                    .map(|v| v != "0" && !v.eq_ignore_ascii_case("false")),
+            )),
+            charset: Arc::default(),
+            workspace: Arc::default(),
+            login: Arc::default(),
+            credentials: None,
+            login_lock: Arc::default(),
+            cache: Arc::new(ResultCache::new(&CacheSettings::default())),
+            audit: None,
+            call: None,
+            executions: None,
+            metrics: None,
+            cancel: CancelToken::new(),
+            recorder: None,
         }
     }
 
-    pub async fn execute(&mut self, command: P4Command) -> Result<String> {
-        if self.mock_mode {
-            self.execute_mock(command).await
-        } else {
-            self.execute_real(command).await
+    pub fn set_p4_bin(&mut self, p4_bin: PathBuf) {
+        self.cli = Arc::new(CliBackend::new(p4_bin, self.cli.broker().cloned()));
+    }
+
+    /// Check that the p4 client can be run, returning the `Rev.` line of `p4 -V`.
+    pub async fn check_p4_bin(&self) -> Result<String> {
+        let output = self
+            .run_p4(Some(&P4Command::Version), &["-V".to_string()], None, None)
+            .await?;
+        let output = charset::decode(&output.stdout, None);
+        let version = output
+            .lines()
+            .map(str::trim)
+            .find(|line| line.starts_with("Rev. "))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{} -V did not print a version; is it the Helix command-line client?",
+                    self.backend().program()
+                )
+            })?;
+        // Saves checking again before the first tagged command
+        let _ = self.json_output.set(ztag::supports_json_output(version));
+        Ok(version.trim_end_matches('.').to_string())
+    }
+
+    pub fn set_activity_logger(&mut self, logger: ActivityLogger) {
+        self.activity_logger = Some(logger);
+    }
+
+    pub fn set_submit_policy(&mut self, policy: SubmitPolicy) {
+        self.submit_policy = policy;
+    }
+
+    pub fn set_workspace_templates(&mut self, templates: WorkspaceTemplates) {
+        self.workspace_templates = templates;
+    }
+
+    pub fn set_allow_force_sync(&mut self, allow: bool) {
+        self.allow_force_sync = allow;
+    }
+
+    pub fn mock_mode(&self) -> &MockMode {
+        &self.mock_mode
+    }
+
+    /// Use `mock_mode`, which may be shared with other services.
+    pub fn set_mock_mode(&mut self, mock_mode: MockMode) {
+        self.mock_mode = mock_mode;
+    }
+
+    /// Whether [`Self::switch_backend`] may switch between mock and real mode.
+    pub fn set_allow_backend_switch(&mut self, allow: bool) {
+        self.allow_backend_switch = allow;
+    }
+
+    pub fn set_environment_policy(&mut self, environment: EnvironmentPolicy) {
+        self.environment = Arc::new(environment);
+    }
+
+    pub fn set_path_sandbox(&mut self, sandbox: PathSandbox) {
+        self.sandbox = Live::new(sandbox);
+    }
+
+    /// The paths commands are confined to now.
+    pub fn path_sandbox(&self) -> Arc<PathSandbox> {
+        self.sandbox.get()
+    }
+
+    /// Send commands to a persistent broker started with `command`; an empty command
+    /// spawns p4 for every command.
+    pub fn set_broker(&mut self, command: Vec<String>) {
+        let broker = (!command.is_empty()).then(|| Arc::new(broker::Broker::new(command)));
+        self.cli = Arc::new(CliBackend::new(self.cli.p4_bin().to_path_buf(), broker));
+    }
+
+    pub fn set_connection(&mut self, connection: P4Connection) {
+        self.connection = connection;
+        self.charset = Arc::default();
+        self.workspace = Arc::default();
+        self.cache.invalidate();
+    }
+
+    /// Cache read-only results for the configured TTL. Handlers cloned before this is
+    /// called keep the previous cache.
+    pub fn set_cache_settings(&mut self, settings: &CacheSettings) {
+        self.cache = Arc::new(ResultCache::new(settings));
+    }
+
+    /// Forget cached results, e.g. after files were changed outside the server.
+    pub fn invalidate_cache(&self) {
+        self.cache.invalidate();
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+        self.timeouts = Live::new(timeouts);
+    }
+
+    /// Replace the sandbox and timeouts in every clone of this handler, for the
+    /// commands started from now on.
+    pub fn reload(&self, sandbox: PathSandbox, timeouts: Timeouts) {
+        self.sandbox.set(sandbox);
+        self.timeouts.set(timeouts);
+    }
+
+    /// Run at most `max` p4 processes at once. Handlers cloned before this is called
+    /// keep the previous limit.
+    pub fn set_max_processes(&mut self, max: usize) {
+        self.processes = Arc::new(tokio::sync::Semaphore::new(max.max(1)));
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn set_login_settings(&mut self, login: LoginSettings) {
+        self.login = Arc::new(login);
+    }
+
+    /// Log in with passwords from `provider` instead of the configured credentials.
+    pub fn set_credential_provider(&mut self, provider: Arc<dyn CredentialProvider>) {
+        self.credentials = Some(provider);
+    }
+
+    /// The configured limit for commands run by `tool`.
+    pub fn timeout_for(&self, tool: &str) -> Option<Duration> {
+        self.timeouts.get().for_tool(tool)
+    }
+
+    /// Kill commands that run longer than `timeout`. Returns the previous setting so
+    /// a caller can restore it.
+    pub fn set_timeout(&mut self, timeout: TimeLimit) -> TimeLimit {
+        std::mem::replace(&mut self.timeout, timeout)
+    }
+
+    /// Run p4 as `client` instead of the client from the connection settings. Returns
+    /// the previous setting so a caller can restore it.
+    pub fn set_client(&mut self, client: Option<String>) -> Option<String> {
+        std::mem::replace(&mut self.client, client)
+    }
+
+    /// The connection settings commands run with, including the client a call names.
+    fn call_connection(&self) -> P4Connection {
+        let mut connection = self.connection.clone();
+        if self.client.is_some() {
+            connection.client.clone_from(&self.client);
         }
+        connection
     }
 
-    async fn execute_real(&mut self, command: P4Command) -> Result<String> {
-        let (cmd, args) = command.to_command_args();
+    /// Run p4 in `dir` instead of the server's working directory. Returns the previous
+    /// setting so a caller can restore it.
+    pub fn set_working_dir(&mut self, dir: Option<PathBuf>) -> Option<PathBuf> {
+        std::mem::replace(&mut self.working_dir, dir)
+    }
 
-        debug!("Executing p4 command: {} {:?}", cmd, args);
+    /// Run p4 in `root` unless a call names another working directory.
+    pub fn set_workspace_root(&mut self, root: Option<PathBuf>) {
+        self.workspace_root = root;
+    }
 
-        let output = Command::new("p4")
-            .args(&args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .output()
-            .await?;
+    pub fn workspace_root(&self) -> Option<&Path> {
+        self.workspace_root.as_deref()
+    }
+
+    /// Find the current client with `p4 info`, as the server does at startup, and keep
+    /// it for [`Self::workspace`]. `None` when no client is set.
+    pub async fn detect_workspace(&self) -> Result<Option<Workspace>> {
+        let mut handler = self.clone();
+        handler.working_dir = None;
+        handler.client = None;
+        // Boxed, since p4 info goes through prepare_paths, which calls this
+        let info = Box::pin(handler.info()).await?;
+        let workspace = Workspace::from_info(&info);
+        if workspace.is_some() {
+            *self.workspace.lock().unwrap_or_else(|e| e.into_inner()) = workspace.clone();
+        }
+        Ok(workspace)
+    }
+
+    /// The current client's name, root and stream. Once found it's kept; until then
+    /// every call asks again, since the client or backend may change.
+    pub async fn workspace(&self) -> Option<Workspace> {
+        let cached = self
+            .workspace
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if cached.is_some() {
+            return cached;
+        }
+        match self.detect_workspace().await {
+            Ok(workspace) => workspace,
+            Err(e) => {
+                debug!("Could not find the client workspace: {}", e);
+                None
+            }
+        }
+    }
+
+    /// The root of the current client, if it's a directory on this machine.
+    pub async fn client_root(&self) -> Option<PathBuf> {
+        let workspace = self.workspace().await?;
+        let root = workspace.local_root();
+        if root.is_none() {
+            debug!("The client root {} is not on this machine", workspace.root);
+        }
+        root
+    }
+
+    /// Where relative paths are resolved when a call doesn't name a working directory:
+    /// the configured workspace root, or else the client root.
+    pub async fn default_dir(&self) -> Option<PathBuf> {
+        match &self.workspace_root {
+            Some(root) => Some(root.clone()),
+            None => self.client_root().await,
+        }
+    }
+
+    /// Record commands in `plan` instead of running those that change anything, or
+    /// run everything again with `None`. Returns the previous plan so a caller can
+    /// restore it.
+    pub fn set_command_plan(&mut self, plan: Option<CommandPlan>) -> Option<CommandPlan> {
+        std::mem::replace(&mut self.plan, plan)
+    }
 
-        if output.status.success() {
-            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    /// Record every p4 run in `log`.
+    pub fn set_audit_log(&mut self, log: Option<Arc<AuditLog>>) {
+        self.audit = log;
+    }
+
+    /// Record every p4 run and its output in `recorder`.
+    pub fn set_recorder(&mut self, recorder: Option<Arc<Recorder>>) {
+        self.recorder = recorder;
+    }
+
+    /// Run commands with `backend` instead of the p4 command-line client, e.g. a
+    /// [`Replayer`], or with the client again with `None`. Mock mode still takes
+    /// precedence.
+    pub fn set_backend(&mut self, backend: Option<Arc<dyn P4Backend>>) {
+        self.backend = backend;
+        self.charset = Arc::default();
+        self.cache.invalidate();
+    }
+
+    /// The backend commands run with now.
+    pub fn backend(&self) -> Arc<dyn P4Backend> {
+        if self.mock_mode.is_enabled() {
+            self.mock.clone()
         } else {
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            Err(anyhow::anyhow!("p4 command failed: {}", stderr))
+            self.backend.clone().unwrap_or_else(|| self.cli.clone())
         }
     }
 
-    async fn execute_mock(&mut self, command: P4Command) -> Result<String> {
-        debug!("Mock executing p4 command: {:?}", command);
+    /// Stop the p4 commands of the current call, in this handler and the clones that
+    /// share its [`CancelToken`]; they fail with an error, as do any run after.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
 
-        match command {
-            P4Command::Status { path } => {
-                let path_info = path.unwrap_or("current directory".to_string());
-                Ok(format!(
-                    "Mock P4 Status for {}:\n\
-                     //depot/main/file1.txt#1 - edit default change (text)\n\
-                     //depot/main/file2.cpp#2 - add default change (text)\n\
-                     ... (mock data)",
-                    path_info
-                ))
-            }
+    /// The token that cancels the commands run from now on. Returns the previous token
+    /// so a caller can restore it.
+    pub fn set_cancel_token(&mut self, token: CancelToken) -> CancelToken {
+        std::mem::replace(&mut self.cancel, token)
+    }
 
-            P4Command::Sync { path, force } => {
-                let force_flag = if force { " (forced)" } else { "" };
-                Ok(format!(
-                    "Mock P4 Sync{}:\n\
-                     //depot/main/{}#1 - updating /local/workspace/file1.txt\n\
-                     //depot/main/{}#2 - updating /local/workspace/file2.cpp\n\
-                     ... synced 15 files",
-                    force_flag, path, path
-                ))
-            }
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// Make mock commands fail and take time as `faults` says.
+    pub fn set_mock_faults(&mut self, faults: FaultInjection) {
+        self.mock = Arc::new((*self.mock).clone().with_faults(faults));
+    }
+
+    /// Model `users` as the other users with files open in mock mode.
+    pub fn set_mock_users(&mut self, users: Vec<MockUser>) {
+        self.mock = Arc::new((*self.mock).clone().with_users(users));
+    }
+
+    /// Attribute the commands run from now on to `call` in the audit log. Returns the
+    /// previous call so a caller can restore it.
+    pub fn set_call_info(&mut self, call: Option<CallInfo>) -> Option<CallInfo> {
+        std::mem::replace(&mut self.call, call)
+    }
+
+    /// Collect the commands run from now on in `log`, whether or not they succeed.
+    /// Returns the previous log so a caller can restore it.
+    pub fn set_execution_log(&mut self, log: Option<ExecutionLog>) -> Option<ExecutionLog> {
+        std::mem::replace(&mut self.executions, log)
+    }
+
+    /// Count and time every p4 run in `metrics`.
+    pub fn set_metrics(&mut self, metrics: Option<Arc<crate::metrics::Metrics>>) {
+        self.metrics = metrics;
+    }
+
+    /// Follow the output of the commands run from now on. Returns the previous listener
+    /// so a caller can restore it.
+    pub fn set_output_listener(
+        &mut self,
+        listener: Option<OutputListener>,
+    ) -> Option<OutputListener> {
+        std::mem::replace(&mut self.output_listener, listener)
+    }
+
+    fn log_activity(&self, level: tracing::Level, message: &str) {
+        if let Some(logger) = &self.activity_logger {
+            logger(level, message);
+        }
+    }
+
+    pub async fn execute(&mut self, command: P4Command) -> Result<String> {
+        let command = self.prepare_paths(command).await?;
+        let (_, args) = command.to_command_args();
+        self.log_activity(tracing::Level::INFO, &format!("p4 {}", args.join(" ")));
+        if !self.plan_command(&command, &["-s"]).await {
+            return Ok(String::new());
+        }
+        let listing = sandbox::lists_change_files(&command).then(|| command.clone());
+        let output = self.execute_real(command, &["-s"]).await?;
+        Ok(match listing {
+            Some(command) => self.sandbox.get().filter_output(&command, output),
+            None => output,
+        })
+    }
+
+    /// Record the command when planning a dry run. Returns whether it should run, which
+    /// only read-only commands do during a dry run.
+    async fn plan_command(&self, command: &P4Command, global_args: &[&str]) -> bool {
+        let Some(plan) = &self.plan else {
+            return true;
+        };
+        let mut connection = self.call_connection();
+        connection.charset = self.charset().await;
+        let args: Vec<String> = connection
+            .global_args()
+            .into_iter()
+            .chain(global_args.iter().map(|arg| arg.to_string()))
+            .chain(command.to_command_args().1)
+            .collect();
+        let executed = !command.is_mutating();
+        plan.push(plan::PlannedCommand {
+            command_line: plan::command_line(&self.cli.p4_bin().to_string_lossy(), &args),
+            cwd: self
+                .working_dir
+                .clone()
+                .or_else(|| self.workspace_root.clone()),
+            paths: command.paths(),
+            input: command.stdin_input(),
+            executed,
+        });
+        executed
+    }
 
-            P4Command::Edit { files } => {
-                let file_list = files.join(", ");
-                Ok(format!(
-                    "Mock P4 Edit:\n\
-                     Files opened for edit:\n\
-                     {}\n\
-                     ... {} file(s) opened for edit",
-                    file_list,
-                    files.len()
-                ))
+    /// `command` with its paths [normalized](paths), or an error if one can't be used
+    /// or is outside the sandbox.
+    ///
+    /// p4 resolves relative paths against the directory it runs in, which without a
+    /// `cwd` or workspace root is wherever the MCP host started the server. They're
+    /// made absolute against the [client root](Self::client_root) instead.
+    async fn prepare_paths(&self, mut command: P4Command) -> Result<P4Command> {
+        let dir = self
+            .working_dir
+            .clone()
+            .or_else(|| self.workspace_root.clone());
+        // The client root found is the connection's client's, not one a call names
+        let client_root = match dir {
+            None if self.client.is_none()
+                && command.paths().iter().any(|path| paths::is_relative(path)) =>
+            {
+                self.client_root().await
             }
+            _ => None,
+        };
+        let dir = dir
+            .or_else(|| client_root.clone())
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_default());
+        let escape_existing = !matches!(
+            command,
+            P4Command::Add { .. } | P4Command::AddPreview { .. }
+        );
+        let sandbox = self.sandbox.get();
+        let checked = command
+            .map_paths(|path| {
+                let path = paths::normalize(path, &dir, escape_existing)?;
+                Ok(match &client_root {
+                    Some(root) if paths::is_relative(&path) => {
+                        root.join(path).to_string_lossy().into_owned()
+                    }
+                    _ => path,
+                })
+            })
+            .and_then(|()| {
+                command
+                    .paths()
+                    .iter()
+                    .try_for_each(|path| sandbox.check(path, &dir))
+            });
+        let checked = match checked {
+            Ok(()) if sandbox.is_enabled() => self.check_change_files(&command, &sandbox).await,
+            checked => checked,
+        };
+        if let Err(e) = checked {
+            self.log_activity(tracing::Level::WARN, &e.to_string());
+            return Err(e);
+        }
+        Ok(command)
+    }
+
+    /// Check the files of a command that acts on a whole change, which isn't given
+    /// their paths, against the sandbox.
+    async fn check_change_files(&self, command: &P4Command, sandbox: &PathSandbox) -> Result<()> {
+        let changelist = match command {
+            // Would report on the whole client
+            P4Command::Status { path: None } => anyhow::bail!(
+                "p4 status needs a path while paths are sandboxed; pass one under the \
+                 allowed paths"
+            ),
+            // Would list changes, with their descriptions, across the whole depot
+            P4Command::Changes { path: None, .. } => anyhow::bail!(
+                "p4 changes needs a path while paths are sandboxed; pass one under the \
+                 allowed paths"
+            ),
+            P4Command::SubmitChangelist { changelist }
+            | P4Command::RevertChange { changelist }
+            | P4Command::DeleteChange { changelist } => changelist,
+            _ => return Ok(()),
+        };
+        // Run as it is, since `p4 opened` output is itself filtered by the sandbox
+        let mut handler = self.clone();
+        let output = handler
+            .execute_real(
+                P4Command::Opened {
+                    changelist: Some(changelist.clone()),
+                },
+                &["-ztag"],
+            )
+            .await?;
+        let records = ztag::parse_ztag(&output);
+        let outside: Vec<&str> = records
+            .iter()
+            .filter_map(|record| record.get("depotFile")?.as_str())
+            .filter(|file| !sandbox.allows(file))
+            .collect();
+        if !outside.is_empty() {
+            anyhow::bail!(
+                "Change {} has files outside the allowed paths: {}",
+                changelist,
+                outside.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Run a command with `-ztag` and parse the tagged records it prints.
+    ///
+    /// Clients that support it are asked for JSON (`-Mj -ztag`), which isn't subject to
+    /// the ambiguities of the text format; anything else falls back to tagged text.
+    pub async fn execute_tagged(&mut self, command: P4Command) -> Result<Vec<TaggedRecord>> {
+        let command = self.prepare_paths(command).await?;
+        let json = !self.mock_mode.is_enabled() && self.supports_json_output().await;
+        let (_, args) = command.to_command_args();
+        let global_args: &[&str] = if json { &["-Mj", "-ztag"] } else { &["-ztag"] };
+        self.log_activity(
+            tracing::Level::INFO,
+            &format!("p4 {} {}", global_args.join(" "), args.join(" ")),
+        );
+        if !self.plan_command(&command, global_args).await {
+            return Ok(Vec::new());
+        }
 
-            P4Command::Add { files } => {
-                let file_list = files.join(", ");
-                Ok(format!(
-                    "Mock P4 Add:\n\
-                     Files opened for add:\n\
-                     {}\n\
-                     ... {} file(s) opened for add",
-                    file_list,
-                    files.len()
-                ))
+        let listing = sandbox::lists_change_files(&command).then(|| command.clone());
+        let output = self.execute_real(command, global_args).await?;
+        let records = match json.then(|| ztag::parse_json_records(&output)) {
+            Some(Some(records)) => records,
+            Some(None) => {
+                warn!("p4 -Mj output was not JSON; parsing it as tagged text");
+                ztag::parse_ztag(&output)
             }
+            None => ztag::parse_ztag(&output),
+        };
+        Ok(match listing {
+            Some(command) => self.sandbox.get().filter_records(&command, records),
+            None => records,
+        })
+    }
 
-            P4Command::Submit { description, files } => {
-                let file_info = if let Some(files) = files {
-                    format!("Specific files: {}", files.join(", "))
-                } else {
-                    "All opened files".to_string()
+    /// Whether the installed p4 client understands `-Mj`, from its `p4 -V` release.
+    ///
+    /// Set `P4_JSON_OUTPUT=0` to always use tagged text, or `1` to skip the check.
+    pub async fn supports_json_output(&self) -> bool {
+        *self
+            .json_output
+            .get_or_init(|| async {
+                let mut handler = self.clone();
+                let supported = match handler.execute_real(P4Command::Version, &[]).await {
+                    Ok(version) => ztag::supports_json_output(&version),
+                    Err(e) => {
+                        warn!("Could not read the p4 client version: {}", e);
+                        false
+                    }
                 };
-                Ok(format!(
-                    "Mock P4 Submit:\n\
-                     Change description: {}\n\
-                     Files: {}\n\
-                     Change 12345 submitted successfully",
-                    description, file_info
-                ))
+                debug!("p4 -Mj JSON output supported: {}", supported);
+                supported
+            })
+            .await
+    }
+
+    /// The character set passed to p4 with `-C` and used to decode its output.
+    ///
+    /// This is the configured charset, or else `P4CHARSET`. When neither is set, or
+    /// either is `auto`, it's `utf8` if `p4 info` reports a unicode server and `None`
+    /// otherwise.
+    pub async fn charset(&self) -> Option<String> {
+        if self.mock_mode.is_enabled() {
+            return None;
+        }
+        self.charset
+            .get_or_init(|| async {
+                let configured = self
+                    .connection
+                    .charset
+                    .clone()
+                    .or_else(|| std::env::var("P4CHARSET").ok())
+                    .filter(|c| !c.is_empty() && !charset::is_auto(c));
+                let charset = match configured {
+                    Some(c) if c.eq_ignore_ascii_case(charset::NONE) => None,
+                    Some(c) => Some(c),
+                    None => self.detect_charset().await,
+                };
+                debug!("p4 charset: {:?}", charset);
+                charset
+            })
+            .await
+            .clone()
+    }
+
+    /// `utf8` if the server is unicode-enabled. Every command but `p4 info` fails on
+    /// such a server until a charset is given.
+    async fn detect_charset(&self) -> Option<String> {
+        let mut args = self.connection.global_args();
+        args.extend(["-ztag".to_string(), "info".to_string()]);
+        let info = match self.run_p4(None, &args, None, None).await {
+            Ok(output) => ztag::from_records::<ztag::Info>(ztag::parse_ztag(&charset::decode(
+                &output.stdout,
+                None,
+            ))),
+            Err(e) => Err(e),
+        };
+        match info {
+            Ok(info) if info.first().is_some_and(ztag::Info::is_unicode) => {
+                Some(charset::UTF8.to_string())
+            }
+            Ok(_) => None,
+            Err(e) => {
+                warn!(
+                    "Could not check whether the server is unicode-enabled: {}",
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Run a command with `-ztag` and convert its records into `T`.
+    pub async fn execute_typed<T: serde::de::DeserializeOwned>(
+        &mut self,
+        command: P4Command,
+    ) -> Result<Vec<T>> {
+        ztag::from_records(self.execute_tagged(command).await?)
+    }
+
+    /// The user, client and server from `p4 info`.
+    pub async fn info(&mut self) -> Result<ztag::Info> {
+        self.execute_typed(P4Command::Info)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("p4 info returned no output"))
+    }
+
+    /// Files opened in the current client, optionally limited to one changelist.
+    pub async fn opened(&mut self, changelist: Option<String>) -> Result<Vec<ztag::OpenedFile>> {
+        self.execute_typed(P4Command::Opened { changelist }).await
+    }
+
+    /// The most recent changes, newest first.
+    pub async fn changes(&mut self, max: u32, path: Option<String>) -> Result<Vec<ztag::Change>> {
+        self.execute_typed(P4Command::Changes { max, path }).await
+    }
+
+    /// Server metadata for each file matching `paths`.
+    pub async fn fstat(&mut self, paths: Vec<String>) -> Result<Vec<ztag::FileStat>> {
+        self.lookup_batched(paths, |paths| P4Command::Fstat { paths })
+            .await
+    }
+
+    /// Depot, client and local paths of files, from `p4 where`.
+    pub async fn where_files(&mut self, paths: Vec<String>) -> Result<Vec<ztag::WhereFile>> {
+        self.lookup_batched(paths, |paths| P4Command::Where { paths })
+            .await
+    }
+
+    /// Run a lookup for `paths` in batches of [`LOOKUP_BATCH_SIZE`], so thousands of
+    /// files don't make for one command that outlives its timeout.
+    async fn lookup_batched<T: serde::de::DeserializeOwned>(
+        &mut self,
+        paths: Vec<String>,
+        command: impl Fn(Vec<String>) -> P4Command,
+    ) -> Result<Vec<T>> {
+        let mut records = Vec::with_capacity(paths.len());
+        for batch in paths.chunks(LOOKUP_BATCH_SIZE) {
+            records.extend(self.execute_typed(command(batch.to_vec())).await?);
+        }
+        Ok(records)
+    }
+
+    /// Metadata, affected files and fixed jobs of a change.
+    pub async fn describe(&mut self, changelist: &str) -> Result<ztag::DescribedChange> {
+        let records = self
+            .execute_tagged(P4Command::Describe {
+                changelist: changelist.to_string(),
+            })
+            .await?;
+        let record = records
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("p4 describe returned no output for {}", changelist))?;
+        ztag::DescribedChange::from_record(record)
+    }
+
+    /// The contents of one depot file, with those of binary files as they are rather
+    /// than decoded as text.
+    pub async fn print(&mut self, path: &str) -> Result<FileContents> {
+        let command = self
+            .prepare_paths(P4Command::Print {
+                path: path.to_string(),
+            })
+            .await?;
+        let (_, args) = command.to_command_args();
+        self.log_activity(tracing::Level::INFO, &format!("p4 {}", args.join(" ")));
+        self.plan_command(&command, &[]).await;
+        // Without -s, which tags every line, so the contents come as they are
+        let printed = self.run_command_printed(command, &[]).await?;
+        // p4 only warns about a file it can't find, and prints nothing
+        if printed.stdout.is_empty() && !printed.warnings.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Can't print {}: {}",
+                path,
+                printed.warnings.join("\n")
+            ));
+        }
+        Ok(FileContents::detect(
+            printed.stdout,
+            self.charset().await.as_deref(),
+        ))
+    }
+
+    /// Read a spec form with `p4 <kind> -o`. Without a name p4 fills in a default: the
+    /// current client, or a template for a new change or job.
+    pub async fn spec(&mut self, kind: SpecKind, name: Option<&str>) -> Result<Spec> {
+        let form = self
+            .execute(P4Command::SpecOutput {
+                kind,
+                name: name.map(str::to_string),
+            })
+            .await?;
+        Ok(Spec::parse(&form))
+    }
+
+    /// Save a spec form with `p4 <kind> -i`, returning the name it was saved under,
+    /// such as the number of a new change, and p4's confirmation.
+    pub async fn save_spec(&mut self, kind: SpecKind, spec: &Spec) -> Result<(String, String)> {
+        let output = self
+            .execute(P4Command::SpecInput {
+                kind,
+                spec: spec.to_form(),
+            })
+            .await?;
+        let name = spec::saved_name(kind, &output).ok_or_else(|| {
+            anyhow::anyhow!("Unexpected output from p4 {} -i: {}", kind, output.trim())
+        })?;
+        Ok((name, output.trim().to_string()))
+    }
+
+    /// A changelist's description, files and jobs, from `p4 change -o`. Without a
+    /// number p4 fills in a new change with the files open in the default change.
+    pub async fn changelist(&mut self, changelist: Option<&str>) -> Result<Changelist> {
+        let spec = self.spec(SpecKind::Change, changelist).await?;
+        Ok(Changelist::from_spec(&spec))
+    }
+
+    /// A client workspace's root, options and view, from `p4 client -o`; the current
+    /// client without a name.
+    pub async fn client_spec(&mut self, name: Option<&str>) -> Result<ClientSpec> {
+        let spec = self.spec(SpecKind::Client, name).await?;
+        Ok(ClientSpec::from_spec(&spec))
+    }
+
+    /// Sync `path` to its head revision, returning the files that changed. Force syncs
+    /// go through [`Self::force_sync`].
+    pub async fn sync(&mut self, path: &str) -> Result<SyncResult> {
+        let output = self
+            .execute(P4Command::Sync {
+                path: path.to_string(),
+                force: false,
+            })
+            .await?;
+        Ok(SyncResult::parse(&output))
+    }
+
+    /// Run a command, answering cacheable reads from the result cache and emptying it
+    /// after commands that change anything. Mock responses aren't cached, so every
+    /// simulated failure shows.
+    async fn execute_real(&mut self, command: P4Command, global_args: &[&str]) -> Result<String> {
+        let cacheable =
+            command.is_cacheable() && self.cache.is_enabled() && !self.mock_mode.is_enabled();
+        let key = cacheable.then(|| CacheKey {
+            dir: self
+                .working_dir
+                .clone()
+                .or_else(|| self.workspace_root.clone()),
+            args: self
+                .client
+                .iter()
+                .flat_map(|client| ["-c".to_string(), client.clone()])
+                .chain(global_args.iter().map(|arg| arg.to_string()))
+                .chain(command.to_command_args().1)
+                .collect(),
+            input: command.stdin_input(),
+        });
+        if let Some(output) = key.as_ref().and_then(|key| self.cache.get(key)) {
+            debug!("Using cached output of p4 {}", key.unwrap().args.join(" "));
+            return Ok(output);
+        }
+
+        let mutating = command.is_mutating();
+        let result = self.run_command(command, global_args).await;
+        if mutating {
+            self.cache.invalidate();
+        }
+        if let (Some(key), Ok(output)) = (key, &result) {
+            self.cache.insert(key, output.clone());
+        }
+        result
+    }
+
+    async fn run_command(&mut self, command: P4Command, global_args: &[&str]) -> Result<String> {
+        Ok(self.run_command_printed(command, global_args).await?.output)
+    }
+
+    /// Run `command`, logging in again and retrying as needed, for what it printed.
+    async fn run_command_printed(
+        &mut self,
+        command: P4Command,
+        global_args: &[&str],
+    ) -> Result<Printed> {
+        let (cmd, command_args) = command.to_command_args();
+        let charset = self.charset().await;
+        let mut connection = self.call_connection();
+        connection.charset.clone_from(&charset);
+        let args: Vec<String> = connection
+            .global_args()
+            .into_iter()
+            .chain(global_args.iter().map(|arg| arg.to_string()))
+            .chain(command_args)
+            .collect();
+
+        debug!("Executing p4 command: {} {:?}", cmd, args);
+
+        let input = command
+            .stdin_input()
+            .map(|input| charset::encode(&input, charset.as_deref()));
+        let severity_tags = global_args.contains(&"-s");
+        // A command that changes files may have run on the server before the
+        // connection dropped, and running it again could repeat the change
+        let retryable = !command.is_mutating();
+        let mut attempt = 1;
+        let mut logged_in = false;
+        loop {
+            let output = self
+                .run_p4(Some(&command), &args, input.as_deref(), charset.as_deref())
+                .await?;
+
+            let stdout = charset::decode(&output.stdout, charset.as_deref());
+            let stderr = charset::decode(&output.stderr, charset.as_deref());
+            let mut report = if severity_tags {
+                severity::parse(&stdout)
+            } else {
+                severity::Report {
+                    output: stdout,
+                    ..Default::default()
+                }
+            };
+            let exited_cleanly = output.status.success() && report.exit_code.unwrap_or(0) == 0;
+            let stderr_lines = stderr
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string);
+            if exited_cleanly {
+                report.warnings.extend(stderr_lines);
+            } else {
+                report.errors.extend(stderr_lines);
             }
 
-            P4Command::Revert { files } => {
-                let file_list = files.join(", ");
-                Ok(format!(
-                    "Mock P4 Revert:\n\
-                     Files reverted:\n\
-                     {}\n\
-                     ... {} file(s) reverted",
-                    file_list,
-                    files.len()
-                ))
+            for warning in &report.warnings {
+                self.log_activity(tracing::Level::WARN, warning);
+            }
+            if exited_cleanly && report.errors.is_empty() {
+                // Warnings such as "file(s) up-to-date." are often the whole answer, but
+                // they'd corrupt tagged output
+                if severity_tags {
+                    for warning in &report.warnings {
+                        report.output.push_str(warning);
+                        report.output.push('\n');
+                    }
+                }
+                return Ok(Printed {
+                    output: report.output,
+                    stdout: output.stdout,
+                    warnings: report.warnings,
+                });
             }
 
-            P4Command::Opened { changelist } => {
-                let cl_info = if let Some(cl) = changelist {
-                    format!(" in changelist {}", cl)
-                } else {
-                    String::new()
-                };
-                Ok(format!(
-                    "Mock P4 Opened{}:\n\
-                     //depot/main/file1.txt#1 - edit default change (text)\n\
-                     //depot/main/file2.cpp#2 - add default change (text)\n\
-                     //depot/main/file3.h#1 - edit change 12346 (text)",
-                    cl_info
-                ))
+            let errors = if report.errors.is_empty() {
+                format!("p4 exited with status {}", output.status)
+            } else {
+                report.errors.join("\n")
+            };
+
+            if !logged_in && self.login.auto && login::is_session_expired(&errors) {
+                logged_in = true;
+                if self.login(charset.as_deref()).await? {
+                    continue;
+                }
             }
 
-            P4Command::Changes { max, path } => {
-                let path_info = if let Some(path) = path {
-                    format!(" for path {}", path)
-                } else {
-                    String::new()
-                };
+            if let Some(delay) = self
+                .retry
+                .backoff(attempt)
+                .filter(|_| retryable && retry::is_transient(&errors))
+            {
+                self.log_activity(
+                    tracing::Level::WARN,
+                    &format!(
+                        "p4 {} failed ({}); retrying in {}ms",
+                        args.join(" "),
+                        errors.trim(),
+                        delay.as_millis()
+                    ),
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
 
-                let mut result = format!("Mock P4 Changes (max: {}){}:\n", max, path_info);
+            self.log_activity(
+                tracing::Level::ERROR,
+                &format!("p4 {} failed: {}", args.join(" "), errors.trim()),
+            );
 
-                for i in 0..std::cmp::min(max, 5) {
-                    let change_num = 12350 - i;
-                    result.push_str(&format!(
-                        "Change {} on 2024/01/1{} by user@workspace 'Sample change description {}'\n",
-                        change_num,
-                        15 + i,
-                        i + 1
-                    ));
+            // Some files were processed; report them along with the ones that failed
+            if severity_tags && command.reports_per_file() && !report.output.trim().is_empty() {
+                let mut partial = report.output;
+                for warning in &report.warnings {
+                    partial.push_str(warning);
+                    partial.push('\n');
                 }
+                return Ok(Printed {
+                    output: partly_succeeded(partial, &errors),
+                    stdout: output.stdout,
+                    warnings: report.warnings,
+                });
+            }
+            return Err(anyhow::anyhow!("p4 command failed: {}", errors));
+        }
+    }
+
+    /// Run `p4 login` with a password from the credential provider. Returns `false`
+    /// when the provider has no password to log in with.
+    async fn login(&self, charset: Option<&str>) -> Result<bool> {
+        let provider = self
+            .credentials
+            .clone()
+            .unwrap_or_else(|| self.login.credentials.provider(&self.connection));
+        let Some(password) = provider.password()? else {
+            self.log_activity(
+                tracing::Level::WARN,
+                &format!(
+                    "The p4 ticket has expired and there is no password in the {} to log in again",
+                    provider.describe()
+                ),
+            );
+            return Ok(false);
+        };
+        let _guard = self.login_lock.lock().await;
+
+        // The resolved charset replaces any in the settings, so -C is passed once
+        let mut connection = self.connection.clone();
+        connection.charset = charset.map(str::to_string);
+        let mut args = connection.global_args();
+        args.push("login".to_string());
+        self.log_activity(
+            tracing::Level::INFO,
+            "The p4 ticket has expired; running p4 login",
+        );
+
+        let input = charset::encode(&format!("{}\n", password), charset);
+        let output = self.run_p4(None, &args, Some(&input), charset).await?;
+        if !output.status.success() {
+            let stderr = charset::decode(&output.stderr, charset);
+            return Err(anyhow::anyhow!("p4 login failed: {}", stderr.trim()));
+        }
+        Ok(true)
+    }
+
+    /// The environment p4 runs with in `dir`: the allowed variables from the server's
+    /// environment, then the settings from the P4CONFIG file above `dir`, if there is
+    /// one. Connection flags still take precedence over them.
+    fn command_env(&self, dir: Option<&Path>) -> Vec<(OsString, OsString)> {
+        let mut env = self.environment.apply(std::env::vars_os());
+        if let Some(dir) = dir {
+            // p4 prefers $PWD to the real working directory when looking for P4CONFIG
+            env.push(("PWD".into(), dir.into()));
+            env.extend(
+                self.p4config_env(dir)
+                    .into_iter()
+                    .map(|(name, value)| (name.into(), value.into())),
+            );
+        }
+        env
+    }
 
-                Ok(result)
+    fn p4config_env(&self, dir: &Path) -> Vec<(String, String)> {
+        let name = self
+            .connection
+            .p4config
+            .clone()
+            .or_else(|| std::env::var("P4CONFIG").ok())
+            .filter(|name| !name.is_empty() && !name.eq_ignore_ascii_case("noconfig"))
+            .unwrap_or_else(|| p4config::DEFAULT_FILE_NAME.to_string());
+        let Some(path) = p4config::find(dir, &name) else {
+            return Vec::new();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                debug!("Using P4CONFIG file {}", path.display());
+                let mut env = vec![("P4CONFIG".to_string(), name)];
+                env.extend(p4config::parse(&text));
+                env
+            }
+            Err(e) => {
+                warn!("Could not read P4CONFIG file {}: {}", path.display(), e);
+                Vec::new()
             }
+        }
+    }
 
-            P4Command::Info => Ok(format!(
-                "Mock P4 Info:\n\
-                     User name: testuser\n\
-                     Client name: test-client\n\
-                     Client host: test-host\n\
-                     Client root: C:\\workspace\\p4\\test-client\n\
-                     Current directory: C:\\workspace\\p4\\test-client\\main\n\
-                     Peer address: ssl:perforce.example.com:1666\n\
-                     Client address: 192.168.1.100\n\
-                     Server address: perforce.example.com:1666\n\
-                     Server root: /opt/perforce/depot\n\
-                     Server date: 2024/01/15 12:30:45 -0800 PST\n\
-                     Server uptime: 15:32:18\n\
-                     Server version: P4D/LINUX26X86_64/2023.1/2553040 (2023/06/15)\n\
-                     ServerID: perforce-server\n\
-                     Case Handling: insensitive"
-            )),
+    /// Run p4 once with the current backend, killing it if it outlives the current
+    /// timeout. Output passed to the listener is decoded with `charset`.
+    ///
+    /// Waits for a free process slot first; the wait doesn't count towards the timeout.
+    /// The run is recorded in the audit log and the recording, if there are any.
+    async fn run_p4(
+        &self,
+        command: Option<&P4Command>,
+        args: &[String],
+        input: Option<&[u8]>,
+        charset: Option<&str>,
+    ) -> Result<std::process::Output> {
+        let _permit = self.processes.acquire().await?;
+
+        let dir = self
+            .working_dir
+            .as_deref()
+            .or(self.workspace_root.as_deref());
+        let env = self.command_env(dir);
+        let invocation = Invocation {
+            command,
+            args,
+            input,
+            cwd: dir,
+            env: &env,
+            charset,
+        };
+        let backend = self.backend();
+        let name = command_name(command, args);
+        let _in_flight = self.metrics.as_ref().map(|m| m.p4_command_started());
+        let started = std::time::Instant::now();
+        let run = async {
+            match &self.output_listener {
+                Some(listener) => {
+                    let on_line = |line: &[u8]| {
+                        let text = charset::decode(line, charset);
+                        if let Some(chunk) = OutputChunk::from_line(text.trim_end()) {
+                            listener(&chunk);
+                        }
+                    };
+                    backend.stream(&invocation, &on_line).await
+                }
+                None => backend.execute(&invocation).await,
+            }
+        };
+        let span = tracing::info_span!("p4", command = %name);
+        let result = self
+            .cancel
+            .run(args, self.with_timeout(args, run))
+            .instrument(span)
+            .await;
+        let exit_code = result
+            .as_ref()
+            .ok()
+            .and_then(|output| match output.status.code()? {
+                0 => {
+                    Some(severity::exit_code(&String::from_utf8_lossy(&output.stdout)).unwrap_or(0))
+                }
+                code => Some(code),
+            });
+        if let Some(metrics) = &self.metrics {
+            metrics.record_p4_command(&name, exit_code == Some(0), started.elapsed());
+        }
+        if let (Some(recorder), Ok(output)) = (&self.recorder, &result) {
+            recorder.record(args, input, dir, output);
+        }
+        if let Some(executions) = &self.executions {
+            executions.push(ExecutedCommand {
+                command_line: plan::command_line(&backend.program(), args),
+                cwd: dir.map(Path::to_path_buf),
+                exit_code,
+                duration_ms: started.elapsed().as_millis() as u64,
+            });
+        }
+        if let Some(audit) = &self.audit {
+            let output = result.as_ref().ok();
+            audit.record(&audit::AuditEntry {
+                timestamp: audit::timestamp(),
+                request_id: self.call.as_ref().map(|call| call.request_id.as_str()),
+                tool: self.call.as_ref().map(|call| call.tool.as_str()),
+                program: backend.program(),
+                args,
+                cwd: dir,
+                exit_status: output.and_then(|output| output.status.code()),
+                error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                stdin_bytes: input.map_or(0, <[u8]>::len),
+                stdout_bytes: output.map_or(0, |output| output.stdout.len()),
+                stderr_bytes: output.map_or(0, |output| output.stderr.len()),
+                duration_ms: started.elapsed().as_millis(),
+            });
+        }
+        result
+    }
+
+    /// Wait for `run`, the command `args`, for at most the current timeout.
+    async fn with_timeout<T>(
+        &self,
+        args: &[String],
+        run: impl std::future::Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let timeout = match self.timeout {
+            TimeLimit::Default => self.timeout_for(""),
+            TimeLimit::Disabled => None,
+            TimeLimit::After(timeout) => Some(timeout),
+        };
+        match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(output) => output,
+                Err(_) => {
+                    let timed_out = CommandTimeout {
+                        command: args.join(" "),
+                        timeout,
+                    };
+                    self.log_activity(tracing::Level::ERROR, &timed_out.to_string());
+                    Err(timed_out.into())
+                }
+            },
+            None => run.await,
         }
     }
 }
@@ -186,3 +1381,36 @@ impl Default for P4Handler {
         Self::new()
     }
 }
+
+/// The p4 command a run is, e.g. `sync`, for its span and the metrics.
+fn command_name(command: Option<&P4Command>, args: &[String]) -> String {
+    match command {
+        Some(P4Command::Version) => "version".to_string(),
+        Some(command) => command
+            .to_command_args()
+            .1
+            .into_iter()
+            .next()
+            .unwrap_or_default(),
+        // `p4 info` and `p4 login` end with the command
+        None => args.last().cloned().unwrap_or_default(),
+    }
+}
+
+/// What a command printed, once it succeeded.
+struct Printed {
+    /// stdout decoded, without severity tags when run with -s, followed by the warnings.
+    output: String,
+    /// stdout as p4 printed it.
+    stdout: Vec<u8>,
+    warnings: Vec<String>,
+}
+
+/// `output` from a command that failed for some files, followed by p4's `errors`.
+fn partly_succeeded(mut output: String, errors: &str) -> String {
+    output.push_str(&format!(
+        "\nThe command only partly succeeded. p4 reported:\n{}\n",
+        errors.trim_end()
+    ));
+    output
+}
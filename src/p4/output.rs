@@ -0,0 +1,142 @@
+//! Command output as it's printed
+//!
+//! A command like `p4 sync //...` can print hundreds of thousands of lines over several
+//! minutes. [`P4Handler::execute_streamed`] yields them one [`OutputChunk`] at a time
+//! while the command runs, instead of one `String` once it's done; an
+//! [`OutputListener`](super::OutputListener), which reports progress for MCP tool
+//! calls, gets the same chunks.
+//!
+//! ```no_run
+//! use futures::StreamExt;
+//! use p4_mcp::p4::{OutputChunk, P4Command, P4Handler};
+//!
+//! # async fn run(p4: P4Handler) {
+//! let mut output = p4.execute_streamed(P4Command::Sync {
+//!     path: "//depot/main/...".to_string(),
+//!     force: false,
+//! });
+//! while let Some(chunk) = output.next().await {
+//!     match chunk {
+//!         OutputChunk::Line(line) => println!("{}", line),
+//!         OutputChunk::Warning(warning) => eprintln!("warning: {}", warning),
+//!         OutputChunk::Error(error) | OutputChunk::Failed(error) => eprintln!("{}", error),
+//!     }
+//! }
+//! # }
+//! ```
+
+use anyhow::Result;
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use super::{severity, P4Command, P4Handler};
+
+/// A line of a command's output, by the severity p4 tagged it with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutputChunk {
+    /// Output, including lines p4 printed without a tag, such as the rest of a
+    /// multi-line message.
+    Line(String),
+    /// A warning, e.g. "file(s) up-to-date.", which doesn't fail the command.
+    Warning(String),
+    /// An error p4 printed; the command may go on with other files.
+    Error(String),
+    /// The command failed, or couldn't be run. Always the last chunk.
+    Failed(String),
+}
+
+impl OutputChunk {
+    /// The chunk for a line of output, or `None` for the `exit:` line of `p4 -s`.
+    pub fn from_line(line: &str) -> Option<Self> {
+        let text = severity::message(line).to_string();
+        let chunk = match line.split_once(':').map(|(tag, _)| tag) {
+            Some("exit") => return None,
+            Some("warning") => OutputChunk::Warning(text),
+            Some("error") => OutputChunk::Error(text),
+            _ => OutputChunk::Line(text),
+        };
+        Some(chunk)
+    }
+
+    pub fn text(&self) -> &str {
+        match self {
+            OutputChunk::Line(text)
+            | OutputChunk::Warning(text)
+            | OutputChunk::Error(text)
+            | OutputChunk::Failed(text) => text,
+        }
+    }
+}
+
+/// A command running for [`P4Handler::execute_streamed`], and the chunks it has
+/// printed so far.
+struct Streamed {
+    run: Option<Pin<Box<dyn Future<Output = Result<()>> + Send>>>,
+    chunks: UnboundedReceiver<OutputChunk>,
+    failed: Option<OutputChunk>,
+}
+
+impl P4Handler {
+    /// Run a command, yielding its output as it's printed.
+    ///
+    /// The stream ends when the command does; if it failed, the last chunk is
+    /// [`OutputChunk::Failed`]. Dropping the stream stops the command. The output is
+    /// never answered from the result cache, and during a dry run only read-only
+    /// commands run.
+    pub fn execute_streamed(
+        &self,
+        command: P4Command,
+    ) -> impl Stream<Item = OutputChunk> + Send + Unpin {
+        let (tx, chunks) = mpsc::unbounded_channel();
+        let mut handler = self.clone();
+        handler.output_listener = Some(Arc::new(move |chunk: &OutputChunk| {
+            let _ = tx.send(chunk.clone());
+        }));
+        let run = async move {
+            let command = handler.prepare_paths(command).await?;
+            let (_, args) = command.to_command_args();
+            handler.log_activity(tracing::Level::INFO, &format!("p4 {}", args.join(" ")));
+            if !handler.plan_command(&command, &["-s"]).await {
+                return Ok(());
+            }
+            let mutating = command.is_mutating();
+            let result = handler.run_command(command, &["-s"]).await;
+            if mutating {
+                handler.cache.invalidate();
+            }
+            result.map(|_| ())
+        };
+        let streamed = Streamed {
+            run: Some(Box::pin(run)),
+            chunks,
+            failed: None,
+        };
+
+        Box::pin(futures::stream::unfold(
+            streamed,
+            |mut streamed| async move {
+                while let Some(run) = streamed.run.as_mut() {
+                    tokio::select! {
+                        biased;
+                        Some(chunk) = streamed.chunks.recv() => return Some((chunk, streamed)),
+                        result = run => {
+                            // Dropping the command drops the listener, which closes `chunks`
+                            // once everything it sent has been read
+                            streamed.run = None;
+                            streamed.failed = result
+                                .err()
+                                .map(|e| OutputChunk::Failed(format!("{:#}", e)));
+                        }
+                    }
+                }
+                match streamed.chunks.recv().await {
+                    Some(chunk) => Some((chunk, streamed)),
+                    None => streamed.failed.take().map(|chunk| (chunk, streamed)),
+                }
+            },
+        ))
+    }
+}
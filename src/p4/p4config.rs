@@ -0,0 +1,33 @@
+//! P4CONFIG files
+//!
+//! Developers usually keep a `.p4config` at the top of each workspace naming its port,
+//! user and client. p4 finds it by walking up from the current directory, but only
+//! when `P4CONFIG` is set, which MCP hosts that launch servers with a minimal
+//! environment often leave out. The handler does the same search for the directory a
+//! command runs in and puts the file's settings in the command's environment.
+
+use std::path::{Path, PathBuf};
+
+/// The file name looked for when neither the configuration nor `P4CONFIG` names one.
+pub const DEFAULT_FILE_NAME: &str = ".p4config";
+
+/// The nearest file called `name` in `dir` or one of its parents.
+pub fn find(dir: &Path, name: &str) -> Option<PathBuf> {
+    dir.ancestors()
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// The `NAME=value` settings in a P4CONFIG file. Blank lines and `#` comments are
+/// skipped.
+pub fn parse(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (name, value) = line.split_once('=')?;
+            let name = name.trim();
+            (!name.is_empty()).then(|| (name.to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! Normalizing the paths commands are given
+//!
+//! Assistants pass paths the way the user wrote them, which p4 often can't use as-is,
+//! and the "no such file(s)" it prints then doesn't say why. Every path a command is
+//! given is normalized before the sandbox checks it and p4 sees it:
+//!
+//! - Backslashes become `/`: in depot and client syntax (`//depot/main\src\a.c`), and
+//!   in local paths except on Windows, where p4 takes either. A leading `\\` is a UNC
+//!   path on Windows and depot syntax elsewhere.
+//! - Drive letters: the leading `/` of a path from a `file:` URI (`/C:/ws/a.c`) is
+//!   dropped and the letter uppercased. A drive-relative path (`C:a.c`) is refused, and
+//!   so is any drive letter when the server doesn't run on Windows.
+//! - A local file whose name has one of p4's special characters (`@ # % *`) is
+//!   escaped as p4 expects (`%40 %23 %25 %2A`), when the file exists. Otherwise the
+//!   characters are left to mean a revision or wildcard, as in `a.c#head`. `p4 add`
+//!   takes names literally instead, with `-f`.
+
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// The characters p4 gives a meaning in file specs, and how it escapes them.
+const SPECIAL_CHARACTERS: [(char, &str); 4] =
+    [('%', "%25"), ('@', "%40"), ('#', "%23"), ('*', "%2A")];
+
+/// Whether `path` has a character p4 reads as a revision or wildcard.
+pub fn has_special_characters(path: &str) -> bool {
+    path.contains(['@', '#', '%', '*'])
+}
+
+/// `name` with p4's special characters escaped, e.g. `logo%402x.png` for `logo@2x.png`.
+pub fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match SPECIAL_CHARACTERS.iter().find(|(special, _)| *special == c) {
+            Some((_, escape)) => escaped.push_str(escape),
+            None => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `path` as p4 should be given it. Relative local paths are looked up in `dir` to
+/// decide whether to escape them, which `escape_existing` turns off.
+pub fn normalize(path: &str, dir: &Path, escape_existing: bool) -> Result<String> {
+    // Most paths have nothing to normalize, and calls can name thousands of them
+    if !path
+        .bytes()
+        .any(|b| matches!(b, b'\\' | b':' | b'@' | b'#' | b'%' | b'*'))
+    {
+        return Ok(path.to_string());
+    }
+    let windows = cfg!(windows);
+    if path.starts_with("//") || (path.starts_with("\\\\") && !windows) {
+        return Ok(path.replace('\\', "/"));
+    }
+    if path.starts_with("\\\\") {
+        return Ok(path.to_string());
+    }
+
+    let mut local = path;
+    if let Some(rest) = local.strip_prefix(['/', '\\']) {
+        if drive_letter(rest).is_some() {
+            local = rest;
+        }
+    }
+    let mut normalized = match drive_letter(local) {
+        Some(letter) => {
+            let rest = &local[2..];
+            if !rest.starts_with(['/', '\\']) {
+                bail!(
+                    "Path {} is relative to the current directory of drive {}:, which p4 \
+                     can't use; pass an absolute path such as {}:\\{}",
+                    path,
+                    letter,
+                    letter,
+                    rest
+                );
+            }
+            if !windows {
+                bail!(
+                    "Path {} is a Windows path, but the server runs on {}; pass a path on \
+                     this machine or a depot path",
+                    path,
+                    std::env::consts::OS
+                );
+            }
+            format!("{}:{}", letter.to_ascii_uppercase(), rest)
+        }
+        None => local.to_string(),
+    };
+    if !windows {
+        normalized = normalized.replace('\\', "/");
+    }
+
+    if escape_existing && has_special_characters(&normalized) && dir.join(&normalized).exists() {
+        normalized = escape(&normalized);
+    }
+    Ok(normalized)
+}
+
+/// Whether `path` is a local path relative to the directory p4 runs in.
+pub fn is_relative(path: &str) -> bool {
+    !path.starts_with("//") && !path.starts_with("\\\\") && Path::new(path).is_relative()
+}
+
+/// The drive letter `path` starts with, as in `C:`.
+fn drive_letter(path: &str) -> Option<char> {
+    let mut chars = path.chars();
+    let letter = chars.next().filter(char::is_ascii_alphabetic)?;
+    (chars.next() == Some(':')).then_some(letter)
+}
@@ -0,0 +1,61 @@
+//! Dry runs: recording the commands a tool would run
+//!
+//! While a [`CommandPlan`] is set on the handler, commands that change anything are
+//! recorded instead of run. Read-only commands still run, since composite tools need
+//! their output to work out what to do next, and are recorded as having run.
+
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A p4 command as it would be run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedCommand {
+    /// The whole command line, quoted for a shell.
+    pub command_line: String,
+    /// Where p4 runs; the server's working directory when `None`.
+    pub cwd: Option<PathBuf>,
+    /// The depot and local paths the command names.
+    pub paths: Vec<String>,
+    /// What p4 reads from stdin, such as a spec form.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<String>,
+    /// Whether the command ran because it's read-only.
+    pub executed: bool,
+}
+
+/// The commands recorded during a dry run, shared by clones of the handler.
+#[derive(Debug, Clone, Default)]
+pub struct CommandPlan(Arc<Mutex<Vec<PlannedCommand>>>);
+
+impl CommandPlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&self, command: PlannedCommand) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(command);
+    }
+
+    pub fn commands(&self) -> Vec<PlannedCommand> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+/// `args` as one command line, with arguments containing spaces or quotes quoted.
+pub fn command_line(program: &str, args: &[String]) -> String {
+    std::iter::once(program.to_string())
+        .chain(args.iter().map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+                format!("\"{}\"", arg.replace('"', "\\\""))
+            } else {
+                arg.clone()
+            }
+        }))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
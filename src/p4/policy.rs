@@ -0,0 +1,200 @@
+//! Submit policies checked before a change is sent to the server
+//!
+//! Set in the `[submit]` section of the config file:
+//!
+//! ```toml
+//! [submit]
+//! min_description_length = 10
+//! description_pattern = '^[A-Z]+-\d+: '
+//! description_template = "PROJ-123: Fix the crash on startup"
+//! require_job = true
+//! banned_files = ["*.dll", "//depot/.../secrets/..."]
+//! ```
+
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+/// Placeholder text `p4 change -o` puts in new change descriptions.
+const DESCRIPTION_PLACEHOLDER: &str = "<enter description here>";
+
+/// Settings left out of the `[submit]` section come from [`SubmitPolicy::from_env`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default = "SubmitPolicy::from_env", deny_unknown_fields)]
+pub struct SubmitPolicy {
+    /// Minimum number of non-whitespace characters in a change description.
+    pub min_description_length: usize,
+    /// A regular expression change descriptions must match.
+    pub description_pattern: Option<DescriptionPattern>,
+    /// An example of a description matching the pattern, shown when one doesn't.
+    pub description_template: Option<String>,
+    /// Whether every submitted change must fix a job.
+    pub require_job: bool,
+    /// Files that may not be submitted. `...` matches anything and `*` anything
+    /// but `/`; a pattern without a `/` is matched against file names.
+    pub banned_files: Vec<FilePattern>,
+}
+
+/// A compiled `description_pattern`, so an invalid one fails when the config is read.
+#[derive(Debug, Clone)]
+pub struct DescriptionPattern(Regex);
+
+impl DescriptionPattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        Ok(Self(Regex::new(pattern)?))
+    }
+}
+
+impl PartialEq for DescriptionPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for DescriptionPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Self::new(&pattern).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A `banned_files` pattern, compiled once when the config is read.
+#[derive(Debug, Clone)]
+pub struct FilePattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl FilePattern {
+    pub fn new(pattern: &str) -> Self {
+        let mut regex = String::from("^");
+        for (i, part) in pattern.split("...").enumerate() {
+            if i > 0 {
+                regex.push_str(".*");
+            }
+            let part: Vec<String> = part.split('*').map(regex::escape).collect();
+            regex.push_str(&part.join("[^/]*"));
+        }
+        regex.push('$');
+        Self {
+            pattern: pattern.to_string(),
+            // Every character but the wildcards is escaped, so this always compiles
+            regex: Regex::new(&regex).expect("escaped file pattern"),
+        }
+    }
+
+    /// Whether `file`, without any revision, matches this pattern.
+    pub fn matches(&self, file: &str) -> bool {
+        let file = file.split(['#', '@']).next().unwrap_or(file).trim();
+        let file = if self.pattern.contains('/') {
+            file
+        } else {
+            file.rsplit(['/', '\\']).next().unwrap_or(file)
+        };
+        self.regex.is_match(file)
+    }
+}
+
+impl std::fmt::Display for FilePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+impl PartialEq for FilePattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern
+    }
+}
+
+impl<'de> Deserialize<'de> for FilePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Self::new(&String::deserialize(deserializer)?))
+    }
+}
+
+impl SubmitPolicy {
+    /// Load the policy from the environment.
+    ///
+    /// `P4_SUBMIT_MIN_DESCRIPTION_LENGTH` sets the minimum description length, unless
+    /// the config file sets `min_description_length`.
+    pub fn from_env() -> Self {
+        let mut policy = Self::default();
+        if let Some(length) = std::env::var("P4_SUBMIT_MIN_DESCRIPTION_LENGTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            policy.min_description_length = length;
+        }
+        policy
+    }
+
+    /// Return the reasons a description violates this policy, if any.
+    pub fn check_description(&self, description: &str) -> Vec<String> {
+        let mut violations = Vec::new();
+        let description = description.trim();
+
+        if description.is_empty() || description == DESCRIPTION_PLACEHOLDER {
+            violations.push("Change description is empty".to_string());
+            return violations;
+        }
+        let length = description.chars().filter(|c| !c.is_whitespace()).count();
+        if length < self.min_description_length {
+            violations.push(format!(
+                "Change description is too short ({} characters, minimum {})",
+                length, self.min_description_length
+            ));
+        }
+        if let Some(DescriptionPattern(pattern)) = &self.description_pattern {
+            if !pattern.is_match(description) {
+                let mut violation = format!(
+                    "Change description must match the pattern {}",
+                    pattern.as_str()
+                );
+                if let Some(template) = &self.description_template {
+                    violation.push_str(&format!(", e.g. \"{}\"", template));
+                }
+                violations.push(violation);
+            }
+        }
+
+        violations
+    }
+
+    /// Return the reasons the jobs a change fixes violate this policy, if any.
+    pub fn check_jobs(&self, jobs: &[String]) -> Vec<String> {
+        if self.require_job && jobs.iter().all(|job| job.trim().is_empty()) {
+            vec!["Changes must fix a job; name the job the change is for".to_string()]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Return the files that may not be submitted, with the pattern each matches.
+    pub fn check_files(&self, files: &[String]) -> Vec<String> {
+        files
+            .iter()
+            .filter_map(|file| {
+                let pattern = self
+                    .banned_files
+                    .iter()
+                    .find(|pattern| pattern.matches(file))?;
+                Some(format!(
+                    "{} may not be submitted (banned by {}); leave it out of the change",
+                    file, pattern
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Fail with every violation listed, if there are any.
+pub fn enforce(violations: Vec<String>) -> Result<()> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Submit refused by policy; nothing was submitted:\n- {}",
+        violations.join("\n- ")
+    ))
+}
@@ -0,0 +1,207 @@
+//! Recording p4 sessions and replaying them
+//!
+//! `--record FILE` appends every p4 command the server runs, with what it was given on
+//! stdin and everything it printed, to a JSON-lines file. `--replay FILE` then serves
+//! those responses instead of running p4, so a session against a real server can be
+//! reproduced without one, e.g. to chase a bug or pin it down in a regression test.
+//!
+//! A command is matched on its arguments and input. Commands run more than once get
+//! their responses in the order they were recorded, and the last one again after that.
+//! The password `p4 login` reads on stdin is never recorded, nor matched on.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use super::backend::{exit_status, Invocation, P4Backend};
+
+/// Output as text when it's valid UTF-8, so recordings can be read and edited, and as
+/// bytes otherwise, e.g. from servers that aren't unicode-enabled.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Data {
+    fn new(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Data::Text(text.to_string()),
+            Err(_) => Data::Bytes(bytes.to_vec()),
+        }
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// One command and its response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCommand {
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<Data>,
+    /// Where the command ran; not used for matching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    pub status: i32,
+    pub stdout: Data,
+    pub stderr: Data,
+}
+
+/// Appends the commands run to a file.
+#[derive(Debug)]
+pub struct Recorder {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open recording {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(
+        &self,
+        args: &[String],
+        input: Option<&[u8]>,
+        cwd: Option<&Path>,
+        output: &Output,
+    ) {
+        let command = RecordedCommand {
+            args: args.to_vec(),
+            input: recorded_input(args, input).map(Data::new),
+            cwd: cwd.map(Path::to_path_buf),
+            // A process killed by a signal has no code; replay it as a failure
+            status: output.status.code().unwrap_or(-1),
+            stdout: Data::new(&output.stdout),
+            stderr: Data::new(&output.stderr),
+        };
+        let mut line = match serde_json::to_string(&command) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Could not serialize recorded command: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!(
+                "Could not write to recording {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// The input recorded and matched for `args`: none for `p4 login`, whose input is a password.
+fn recorded_input<'a>(args: &[String], input: Option<&'a [u8]>) -> Option<&'a [u8]> {
+    if args.iter().any(|arg| arg == "login") {
+        None
+    } else {
+        input
+    }
+}
+
+type Key = (Vec<String>, Option<Vec<u8>>);
+
+/// Serves the responses from a recording.
+#[derive(Debug)]
+pub struct Replayer {
+    path: PathBuf,
+    responses: Mutex<HashMap<Key, VecDeque<RecordedCommand>>>,
+}
+
+impl Replayer {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read recording {}", path.display()))?;
+        let mut responses: HashMap<Key, VecDeque<RecordedCommand>> = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let command: RecordedCommand = serde_json::from_str(line).with_context(|| {
+                format!(
+                    "Invalid recording {} at line {}",
+                    path.display(),
+                    number + 1
+                )
+            })?;
+            let input = command.input.clone().map(Data::into_bytes);
+            let key = (
+                command.args.clone(),
+                recorded_input(&command.args, input.as_deref()).map(<[u8]>::to_vec),
+            );
+            responses.entry(key).or_default().push_back(command);
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            responses: Mutex::new(responses),
+        })
+    }
+
+    /// The next recorded response to `args` with `input`.
+    pub fn respond(&self, args: &[String], input: Option<&[u8]>) -> Result<Output> {
+        let key = (
+            args.to_vec(),
+            recorded_input(args, input).map(<[u8]>::to_vec),
+        );
+        let mut responses = self.responses.lock().unwrap_or_else(|e| e.into_inner());
+        let command = responses
+            .get_mut(&key)
+            .and_then(|queue| {
+                if queue.len() > 1 {
+                    queue.pop_front()
+                } else {
+                    queue.front().cloned()
+                }
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No response to p4 {} in the recording {}",
+                    args.join(" "),
+                    self.path.display()
+                )
+            })?;
+        debug!("Replaying the recorded response to p4 {}", args.join(" "));
+        Ok(Output {
+            status: exit_status(command.status),
+            stdout: command.stdout.into_bytes(),
+            stderr: command.stderr.into_bytes(),
+        })
+    }
+}
+
+#[async_trait]
+impl P4Backend for Replayer {
+    async fn execute(&self, invocation: &Invocation<'_>) -> Result<Output> {
+        self.respond(invocation.args, invocation.input)
+    }
+
+    fn program(&self) -> String {
+        format!("replay of {}", self.path.display())
+    }
+}
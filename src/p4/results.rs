@@ -0,0 +1,329 @@
+//! Typed results of p4 commands
+//!
+//! [`P4Handler`](super::P4Handler) returns these from [`changelist`],
+//! [`client_spec`], [`changes_page`], [`sync`] and [`print`] so programs embedding the
+//! crate don't have to parse p4's text themselves. They join the typed `-ztag` records in [`ztag`](super::ztag),
+//! such as [`FileStat`](super::FileStat) and [`OpenedFile`](super::OpenedFile).
+//!
+//! [`changelist`]: super::P4Handler::changelist
+//! [`client_spec`]: super::P4Handler::client_spec
+//! [`changes_page`]: super::P4Handler::changes_page
+//! [`sync`]: super::P4Handler::sync
+//! [`print`]: super::P4Handler::print
+
+use serde::Serialize;
+use std::path::PathBuf;
+
+use super::charset;
+use super::spec::Spec;
+use super::ztag::{Change, Info};
+
+/// A file open in a changelist.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangelistFile {
+    pub depot_file: String,
+    /// How the file is open, e.g. `edit` or `add`.
+    pub action: String,
+}
+
+/// A changelist as `p4 change -o` reports it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Changelist {
+    /// `None` for a new change that hasn't been saved.
+    pub change: Option<u32>,
+    pub client: String,
+    pub user: String,
+    /// `new`, `pending`, `shelved` or `submitted`.
+    pub status: String,
+    pub description: String,
+    pub files: Vec<ChangelistFile>,
+    pub jobs: Vec<String>,
+}
+
+impl Changelist {
+    pub fn from_spec(spec: &Spec) -> Self {
+        Self {
+            change: spec.field("Change").parse().ok(),
+            client: spec.field("Client").to_string(),
+            user: spec.field("User").to_string(),
+            status: spec.field("Status").to_string(),
+            description: spec.field("Description").to_string(),
+            // Entries are followed by a comment: `//depot/a.c\t# edit`
+            files: spec
+                .lines("Files")
+                .into_iter()
+                .filter_map(|line| {
+                    let (file, comment) = line.split_once('#').unwrap_or((line, ""));
+                    let depot_file = file.trim();
+                    (!depot_file.is_empty()).then(|| ChangelistFile {
+                        depot_file: depot_file.to_string(),
+                        action: comment.trim().to_string(),
+                    })
+                })
+                .collect(),
+            jobs: spec
+                .lines("Jobs")
+                .into_iter()
+                .filter_map(|line| line.split_whitespace().next())
+                .map(str::to_string)
+                .collect(),
+        }
+    }
+
+    /// The depot paths of the files in the change.
+    pub fn depot_files(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|file| file.depot_file.clone())
+            .collect()
+    }
+}
+
+/// A file open in a pending changelist, as `p4 opened` reports it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingFile {
+    pub depot_file: String,
+    pub rev: String,
+    /// How the file is open, e.g. `edit` or `add`.
+    pub action: String,
+    #[serde(rename = "type")]
+    pub file_type: String,
+    pub locked: bool,
+}
+
+/// A changelist with work in it that hasn't been submitted.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingChangelist {
+    /// The change number, or `default`.
+    pub change: String,
+    /// `None` for the default changelist.
+    pub description: Option<String>,
+    /// Whether the change has files shelved.
+    pub shelved: bool,
+    pub files: Vec<PendingFile>,
+}
+
+/// A user's pending work in a client workspace.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingWork {
+    pub user: String,
+    pub client: Option<String>,
+    /// The default changelist first, if it has files, then pending changes, newest
+    /// first.
+    pub changelists: Vec<PendingChangelist>,
+}
+
+/// One page of `p4 changes`, newest first.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangesPage {
+    pub changes: Vec<Change>,
+    /// The cursor to pass for the next page, if there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// One line of a client view: a depot path and where it's mapped in the workspace.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ViewMapping {
+    /// With a leading `-` for paths excluded from the workspace, or `+` for overlays.
+    pub depot_path: String,
+    pub client_path: String,
+}
+
+impl ViewMapping {
+    /// Parse a view line, whose paths are quoted when they contain spaces.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut paths = Vec::new();
+        let mut rest = line.trim();
+        while !rest.is_empty() {
+            let (path, remainder) = match rest.strip_prefix('"') {
+                Some(quoted) => quoted.split_once('"')?,
+                None => rest.split_once(char::is_whitespace).unwrap_or((rest, "")),
+            };
+            paths.push(path.to_string());
+            rest = remainder.trim_start();
+        }
+        match <[String; 2]>::try_from(paths) {
+            Ok([depot_path, client_path]) => Some(Self {
+                depot_path,
+                client_path,
+            }),
+            Err(_) => None,
+        }
+    }
+}
+
+/// A client workspace as `p4 client -o` reports it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientSpec {
+    pub client: String,
+    pub owner: String,
+    pub host: String,
+    pub root: String,
+    /// Such as `noallwrite` or `clobber`.
+    pub options: Vec<String>,
+    pub line_end: String,
+    /// The stream a stream client is bound to.
+    pub stream: Option<String>,
+    pub view: Vec<ViewMapping>,
+}
+
+impl ClientSpec {
+    pub fn from_spec(spec: &Spec) -> Self {
+        Self {
+            client: spec.field("Client").to_string(),
+            owner: spec.field("Owner").to_string(),
+            host: spec.field("Host").to_string(),
+            root: spec.field("Root").to_string(),
+            options: spec
+                .field("Options")
+                .split_whitespace()
+                .map(str::to_string)
+                .collect(),
+            line_end: spec.field("LineEnd").to_string(),
+            stream: spec
+                .get("Stream")
+                .filter(|stream| !stream.is_empty())
+                .map(str::to_string),
+            view: spec
+                .lines("View")
+                .into_iter()
+                .filter_map(ViewMapping::parse)
+                .collect(),
+        }
+    }
+}
+
+/// The client workspace p4 runs with, as `p4 info` reports it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Workspace {
+    pub client: String,
+    pub root: String,
+    /// The stream the client is bound to, if it's a stream client.
+    pub stream: Option<String>,
+}
+
+impl Workspace {
+    /// The current client from `info`, or `None` when no client is set.
+    pub fn from_info(info: &Info) -> Option<Self> {
+        Some(Self {
+            client: info.client()?.to_string(),
+            root: info.client_root.clone(),
+            stream: info.stream().map(str::to_string),
+        })
+    }
+
+    /// The root, if it's a directory on this machine.
+    pub fn local_root(&self) -> Option<PathBuf> {
+        let root = PathBuf::from(&self.root);
+        (root.is_absolute() && root.is_dir()).then_some(root)
+    }
+}
+
+/// A file `p4 sync` changed in the workspace.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncedFile {
+    pub depot_file: String,
+    pub rev: Option<u32>,
+    /// `updated`, `added`, `deleted`, `refreshed` or `replaced`.
+    pub action: String,
+    pub client_file: String,
+}
+
+/// What `p4 sync` did.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResult {
+    pub files: Vec<SyncedFile>,
+    /// Everything else p4 printed, such as `file(s) up-to-date.` warnings.
+    pub messages: Vec<String>,
+}
+
+impl SyncResult {
+    /// Parse `p4 sync` output, where each file synced is reported as
+    /// `//depot/a.c#3 - updating /ws/a.c`.
+    pub fn parse(output: &str) -> Self {
+        const ACTIONS: &[(&str, &str)] = &[
+            ("updating ", "updated"),
+            ("added as ", "added"),
+            ("deleted as ", "deleted"),
+            ("refreshing ", "refreshed"),
+            ("replacing ", "replaced"),
+        ];
+        let mut result = Self::default();
+        for line in output
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            let synced = line.split_once(" - ").and_then(|(file, report)| {
+                let (depot_file, rev) = file.rsplit_once('#').unwrap_or((file, ""));
+                ACTIONS.iter().find_map(|(prefix, action)| {
+                    report.strip_prefix(prefix).map(|client_file| SyncedFile {
+                        depot_file: depot_file.to_string(),
+                        rev: rev.parse().ok(),
+                        action: action.to_string(),
+                        client_file: client_file.to_string(),
+                    })
+                })
+            });
+            match synced {
+                Some(file) => result.files.push(file),
+                None => result.messages.push(line.to_string()),
+            }
+        }
+        result
+    }
+}
+
+/// The contents of a depot file, told apart by what they hold.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileContents {
+    Text(String),
+    /// An image of a type MCP clients show, with its MIME type.
+    Image {
+        mime_type: &'static str,
+        data: Vec<u8>,
+    },
+    /// Any other binary file.
+    Binary(Vec<u8>),
+}
+
+impl FileContents {
+    /// Images are known by their signature, and other binary files by a NUL byte near
+    /// the start, as Git tells them apart. Anything else is text in `charset`.
+    pub fn detect(data: Vec<u8>, charset: Option<&str>) -> Self {
+        if let Some(mime_type) = image_type(&data) {
+            return FileContents::Image { mime_type, data };
+        }
+        if data.iter().take(8000).any(|&byte| byte == 0) {
+            return FileContents::Binary(data);
+        }
+        FileContents::Text(charset::decode(&data, charset))
+    }
+}
+
+/// The MIME type of an image MCP clients can show, going by its first bytes.
+fn image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,63 @@
+//! Retrying p4 commands that failed on a flaky connection
+//!
+//! A command whose stderr shows a network failure (a TCP connect that timed out, a
+//! reset connection, ...) is run again after an exponentially growing delay, up to
+//! a configurable number of attempts. Commands that change anything, and other
+//! failures, are reported straight away.
+
+use serde::Deserialize;
+use std::time::Duration;
+
+/// Fragments of p4 error messages caused by the network rather than the command.
+const TRANSIENT_ERRORS: &[&str] = &[
+    "tcp connect to",
+    "tcp receive failed",
+    "tcp send failed",
+    "connect: connection timed out",
+    "connection reset",
+    "broken pipe",
+    "ssl receive failed",
+    "ssl send failed",
+];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// How many times a command may run in total; 1 disables retries.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubling for each one after it.
+    pub initial_backoff_ms: u64,
+    /// Upper bound on the delay between attempts.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before running a command again after its `attempt`th failure, or
+    /// `None` once the attempts are used up.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        (attempt < self.max_attempts).then(|| {
+            let delay = self
+                .initial_backoff_ms
+                .saturating_mul(1 << (attempt - 1).min(16));
+            Duration::from_millis(delay.min(self.max_backoff_ms))
+        })
+    }
+}
+
+/// Whether p4's stderr describes a network failure worth retrying.
+pub fn is_transient(stderr: &str) -> bool {
+    let stderr = stderr.to_lowercase();
+    TRANSIENT_ERRORS
+        .iter()
+        .any(|fragment| stderr.contains(fragment))
+}
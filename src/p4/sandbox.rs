@@ -0,0 +1,214 @@
+//! Confining commands to allowed depot and local paths
+//!
+//! With a sandbox configured, every path a command is given is checked before the
+//! command runs: depot paths (`//...`) must fall under one of the allowed depot
+//! prefixes, and local paths, resolved against the directory p4 runs in, under one of
+//! the allowed local directories. An assistant told to work in `//depot/team-x/...`
+//! then can't touch anything else however it's asked to.
+//!
+//! Client syntax (`//alice-ws/...`) looks like depot syntax and is checked as such, so
+//! list the client's prefix too if tools should accept it. A path of a kind with no
+//! allowed prefixes is refused.
+//!
+//! Commands that act on a whole changelist aren't given paths; the files in the
+//! change are checked instead. Those that list the files in changes (`opened`,
+//! `describe`) leave out the ones outside the sandbox.
+
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+
+use super::{P4Command, TaggedRecord};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PathSandbox {
+    /// Depot paths commands may use, e.g. `//depot/team-x/...`.
+    pub depot: Vec<String>,
+    /// Local directories commands may use, e.g. the workspace root.
+    pub local: Vec<PathBuf>,
+}
+
+impl PathSandbox {
+    /// Whether any paths are restricted.
+    pub fn is_enabled(&self) -> bool {
+        !self.depot.is_empty() || !self.local.is_empty()
+    }
+
+    /// Check that `path` is allowed. Relative local paths are resolved against `dir`.
+    pub fn check(&self, path: &str, dir: &Path) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let file = strip_revision(path);
+        if file.starts_with("//") {
+            if file.split('/').any(|part| part == "..") {
+                anyhow::bail!("Path {} is outside the allowed paths", path);
+            }
+            if !self
+                .depot
+                .iter()
+                .any(|prefix| depot_prefix_allows(prefix, file))
+            {
+                anyhow::bail!(
+                    "Path {} is outside the allowed depot paths ({})",
+                    path,
+                    list(self.depot.iter().map(String::as_str))
+                );
+            }
+        } else {
+            let resolved = normalize(&dir.join(file));
+            if !self
+                .local
+                .iter()
+                .any(|allowed| resolved.starts_with(normalize(allowed)))
+            {
+                anyhow::bail!(
+                    "Path {} is outside the allowed local paths ({})",
+                    path,
+                    list(self.local.iter().map(|dir| dir.to_str().unwrap_or("?")))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the depot file `path` may be shown, e.g. in a change's description.
+    pub fn allows(&self, path: &str) -> bool {
+        self.check(path, Path::new("/")).is_ok()
+    }
+
+    /// `output`, the text `command` printed, without the files outside the sandbox.
+    /// Describes lose their `... //depot/a.c#3 edit` lines and `==== //depot/a.c#3
+    /// (text) ====` diffs, and `p4 opened` the lines of files.
+    pub fn filter_output(&self, command: &P4Command, output: String) -> String {
+        if !self.is_enabled() || !lists_change_files(command) {
+            return output;
+        }
+        let mut filtered = String::with_capacity(output.len());
+        let mut in_hidden_diff = false;
+        for line in output.split_inclusive('\n') {
+            if let Some(header) = line.strip_prefix("==== ") {
+                in_hidden_diff = !self.allows(first_word(header));
+            }
+            let file = line.strip_prefix("... ").unwrap_or(line);
+            let hidden =
+                in_hidden_diff || (file.starts_with("//") && !self.allows(first_word(file)));
+            if !hidden {
+                filtered.push_str(line);
+            }
+        }
+        filtered
+    }
+
+    /// `records`, the tagged output of `command`, without the files outside the
+    /// sandbox: whole records for `p4 opened`, and a describe's indexed fields
+    /// (`depotFile0`, `action0`, ...), renumbered.
+    pub fn filter_records(
+        &self,
+        command: &P4Command,
+        records: Vec<TaggedRecord>,
+    ) -> Vec<TaggedRecord> {
+        if !self.is_enabled() || !lists_change_files(command) {
+            return records;
+        }
+        if let P4Command::Opened { .. } = command {
+            return records
+                .into_iter()
+                .filter(|record| {
+                    record
+                        .get("depotFile")
+                        .and_then(|file| file.as_str())
+                        .is_none_or(|file| self.allows(file))
+                })
+                .collect();
+        }
+        records
+            .into_iter()
+            .map(|record| self.filter_described_files(record))
+            .collect()
+    }
+
+    fn filter_described_files(&self, record: TaggedRecord) -> TaggedRecord {
+        let kept: Vec<usize> = super::ztag::indexed_field(&record, "depotFile")
+            .into_iter()
+            .enumerate()
+            .filter(|(_, file)| self.allows(file))
+            .map(|(i, _)| i)
+            .collect();
+        let mut filtered = TaggedRecord::new();
+        for (key, value) in record {
+            let name = key.trim_end_matches(|c: char| c.is_ascii_digit());
+            // Jobs are indexed too, but aren't files
+            let index = match key[name.len()..].parse::<usize>() {
+                Ok(index) if !name.is_empty() && !name.starts_with("job") => index,
+                _ => {
+                    filtered.insert(key, value);
+                    continue;
+                }
+            };
+            if let Some(new_index) = kept.iter().position(|&i| i == index) {
+                filtered.insert(format!("{}{}", name, new_index), value);
+            }
+        }
+        filtered
+    }
+}
+
+/// Whether `command` lists the files in changes, rather than being given them.
+pub(super) fn lists_change_files(command: &P4Command) -> bool {
+    matches!(
+        command,
+        P4Command::Opened { .. }
+            | P4Command::Describe { .. }
+            | P4Command::DescribeShelved { .. }
+            | P4Command::DescribeShelvedFiles { .. }
+            | P4Command::DescribeDiffs { .. }
+    )
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or_default()
+}
+
+/// The file part of a file spec: `//depot/a.txt` from `//depot/a.txt#3` or
+/// `//depot/...@2024/01/01,@now`.
+fn strip_revision(path: &str) -> &str {
+    path.split(['#', '@']).next().unwrap_or(path)
+}
+
+/// Whether `file` is the directory `prefix` names or below it. `//depot/team-x/...`
+/// allows `//depot/team-x/src/a.c` but not `//depot/team-xyz/a.c`.
+fn depot_prefix_allows(prefix: &str, file: &str) -> bool {
+    let prefix = strip_revision(prefix)
+        .trim_end_matches("...")
+        .trim_end_matches('/');
+    file == prefix
+        || file
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// `path` with `.` and `..` components resolved, without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+fn list<'a>(prefixes: impl Iterator<Item = &'a str>) -> String {
+    let prefixes: Vec<_> = prefixes.collect();
+    if prefixes.is_empty() {
+        "none".to_string()
+    } else {
+        prefixes.join(", ")
+    }
+}
@@ -0,0 +1,123 @@
+//! Message severity of p4 output
+//!
+//! p4 doesn't reliably signal failure with its exit status: `p4 sync` on an up-to-date
+//! workspace warns "file(s) up-to-date." on stderr and exits 0, and some per-file
+//! errors exit 0 too. Plain commands are therefore run with `-s`, which tags every
+//! line with its severity (`info:`, `warning:`, `error:`, `text:`) and ends with the
+//! command's `exit:` code, so results can be told apart from warnings and errors.
+
+/// p4 output split by severity.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    /// Informational and text output, with the tags removed.
+    pub output: String,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+    /// The code from the final `exit:` line.
+    pub exit_code: Option<i32>,
+}
+
+enum Kind {
+    Output,
+    Warning,
+    Error,
+}
+
+/// Split the tag off a line of `p4 -s` output.
+fn split_tag(line: &str) -> Option<(&str, &str)> {
+    let (tag, message) = line.split_once(':')?;
+    let known = matches!(tag, "text" | "warning" | "error" | "exit")
+        || tag
+            .strip_prefix("info")
+            .is_some_and(|level| level.chars().all(|c| c.is_ascii_digit()));
+    known.then(|| (tag, message.strip_prefix(' ').unwrap_or(message)))
+}
+
+/// The message of a `p4 -s` line without its tag.
+pub fn message(line: &str) -> &str {
+    split_tag(line).map_or(line, |(_, message)| message)
+}
+
+/// The code from the `exit:` line that ends the output of `p4 -s`, if there is one.
+pub fn exit_code(output: &str) -> Option<i32> {
+    let (tag, message) = split_tag(output.lines().rev().find(|line| !line.is_empty())?)?;
+    (tag == "exit").then(|| message.trim().parse().ok())?
+}
+
+/// Parse the output of a command run with `p4 -s`.
+///
+/// `infoN:` lines are indented with `... ` per level as p4 prints them without `-s`.
+/// Untagged lines continue the message before them.
+pub fn parse(output: &str) -> Report {
+    let mut report = Report::default();
+    let mut kind = Kind::Output;
+
+    for line in output.lines() {
+        let Some((tag, message)) = split_tag(line) else {
+            match kind {
+                Kind::Output => {
+                    report.output.push_str(line);
+                    report.output.push('\n');
+                }
+                Kind::Warning => append(&mut report.warnings, line),
+                Kind::Error => append(&mut report.errors, line),
+            }
+            continue;
+        };
+
+        match tag {
+            "warning" => {
+                kind = Kind::Warning;
+                report.warnings.push(message.to_string());
+            }
+            "error" => {
+                kind = Kind::Error;
+                report.errors.push(message.to_string());
+            }
+            "exit" => report.exit_code = message.trim().parse().ok(),
+            _ => {
+                kind = Kind::Output;
+                let level: usize = tag
+                    .strip_prefix("info")
+                    .and_then(|level| level.parse().ok())
+                    .unwrap_or(0);
+                report.output.push_str(&"... ".repeat(level));
+                report.output.push_str(message);
+                report.output.push('\n');
+            }
+        }
+    }
+
+    report
+}
+
+/// `report` as `p4 -s` prints it; the inverse of [`parse`].
+pub fn format(report: &Report) -> String {
+    let mut output = String::new();
+    let tagged = [
+        ("info", report.output.lines().collect::<Vec<_>>()),
+        (
+            "warning",
+            report.warnings.iter().map(String::as_str).collect(),
+        ),
+        ("error", report.errors.iter().map(String::as_str).collect()),
+    ];
+    for (tag, messages) in tagged {
+        for message in messages {
+            // Only the first line of a multi-line message is tagged, as p4 prints it
+            output.push_str(&format!("{}: {}\n", tag, message));
+        }
+    }
+    let code = report
+        .exit_code
+        .unwrap_or(if report.errors.is_empty() { 0 } else { 1 });
+    output.push_str(&format!("exit: {}\n", code));
+    output
+}
+
+fn append(messages: &mut [String], line: &str) {
+    if let Some(last) = messages.last_mut() {
+        last.push('\n');
+        last.push_str(line);
+    }
+}
@@ -0,0 +1,191 @@
+//! Spec forms: the text read with `p4 <kind> -o` and written back with `p4 <kind> -i`
+//!
+//! A form is a list of `Field:` entries. Short values follow the field name on the same
+//! line; lists and descriptions follow it on tab-indented lines. Lines starting with
+//! `#` are comments. [`Spec`] keeps the fields in order so a form can be read, edited
+//! and fed back to p4 without disturbing the fields it doesn't touch.
+
+use std::fmt;
+
+/// Fields written as indented lines even when they hold a single value.
+const LIST_FIELDS: &[&str] = &[
+    "Description",
+    "View",
+    "ChangeView",
+    "Files",
+    "Jobs",
+    "Paths",
+    "Remapped",
+    "Ignored",
+    "AltRoots",
+];
+
+/// The kinds of spec that can be read and written with `-o` and `-i`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecKind {
+    Change,
+    Client,
+    Job,
+    Label,
+    Stream,
+}
+
+impl SpecKind {
+    /// The p4 command that reads and writes this kind of spec.
+    pub fn command(self) -> &'static str {
+        match self {
+            SpecKind::Change => "change",
+            SpecKind::Client => "client",
+            SpecKind::Job => "job",
+            SpecKind::Label => "label",
+            SpecKind::Stream => "stream",
+        }
+    }
+
+    /// The field holding the spec's name, which is also the first word p4 prints on
+    /// saving it (`Change 123 created.`, `Client alice-ws saved.`).
+    pub fn name_field(self) -> &'static str {
+        match self {
+            SpecKind::Change => "Change",
+            SpecKind::Client => "Client",
+            SpecKind::Job => "Job",
+            SpecKind::Label => "Label",
+            SpecKind::Stream => "Stream",
+        }
+    }
+}
+
+impl fmt::Display for SpecKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.command())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    value: String,
+    multiline: bool,
+}
+
+/// A parsed spec form.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Spec {
+    fields: Vec<Field>,
+}
+
+impl Spec {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse the output of `p4 <kind> -o`.
+    pub fn parse(form: &str) -> Self {
+        let mut spec = Spec::new();
+        let mut blank_lines = 0;
+        for line in form.lines() {
+            let indented = line.starts_with('\t') || line.starts_with(' ');
+            if line.trim().is_empty() {
+                blank_lines += 1;
+                continue;
+            }
+            if indented {
+                if let Some(field) = spec.fields.last_mut() {
+                    let line = line.strip_prefix('\t').unwrap_or(line.trim_start());
+                    if !field.value.is_empty() {
+                        // Blank lines inside a description are part of it
+                        for _ in 0..=blank_lines {
+                            field.value.push('\n');
+                        }
+                    }
+                    field.value.push_str(line);
+                    field.multiline = true;
+                }
+            } else if !line.starts_with('#') {
+                if let Some((name, value)) = line.split_once(':') {
+                    spec.fields.push(Field {
+                        name: name.trim().to_string(),
+                        value: value.trim().to_string(),
+                        multiline: false,
+                    });
+                }
+            }
+            blank_lines = 0;
+        }
+        spec
+    }
+
+    /// The value of a field; list fields have one entry per line.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|field| field.name == name)
+            .map(|field| field.value.as_str())
+    }
+
+    /// The value of a field, or an empty string if the form doesn't have it.
+    pub fn field(&self, name: &str) -> &str {
+        self.get(name).unwrap_or_default()
+    }
+
+    /// The entries of a list field such as `View` or `Files`.
+    pub fn lines(&self, name: &str) -> Vec<&str> {
+        self.field(name)
+            .lines()
+            .filter(|line| !line.is_empty())
+            .collect()
+    }
+
+    /// Set a field, replacing its value or appending it to the form.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) {
+        let value = value.into();
+        let multiline = value.contains('\n') || LIST_FIELDS.contains(&name);
+        match self.fields.iter_mut().find(|field| field.name == name) {
+            Some(field) => {
+                field.multiline |= multiline;
+                field.value = value;
+            }
+            None => self.fields.push(Field {
+                name: name.to_string(),
+                value,
+                multiline,
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.fields.retain(|field| field.name != name);
+    }
+
+    /// The form to feed to `p4 <kind> -i`.
+    pub fn to_form(&self) -> String {
+        let mut form = String::new();
+        for field in &self.fields {
+            if field.multiline {
+                form.push_str(&field.name);
+                form.push_str(":\n");
+                for line in field.value.lines() {
+                    form.push('\t');
+                    form.push_str(line);
+                    form.push('\n');
+                }
+            } else {
+                form.push_str(&format!("{}:\t{}\n", field.name, field.value));
+            }
+            form.push('\n');
+        }
+        form
+    }
+}
+
+/// The name p4 reports saving a spec under, e.g. `12348` from `Change 12348 created.`
+/// or `alice-ws` from `Client alice-ws saved.`
+pub fn saved_name(kind: SpecKind, output: &str) -> Option<String> {
+    output.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        (words.next() == Some(kind.name_field()))
+            .then(|| words.next())
+            .flatten()
+            .map(str::to_string)
+    })
+}
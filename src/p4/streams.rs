@@ -0,0 +1,93 @@
+//! Stream hierarchy helpers
+
+use std::collections::BTreeMap;
+
+/// A stream as listed by `p4 streams` or read from `p4 stream -o`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamEntry {
+    pub name: String,
+    pub stream_type: String,
+    pub parent: Option<String>,
+    pub title: String,
+}
+
+/// Parse the `Stream <name> <type> <parent> '<title>'` lines printed by `p4 streams`.
+pub fn parse_streams(output: &str) -> Vec<StreamEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(5, ' ');
+            if parts.next()? != "Stream" {
+                return None;
+            }
+            let name = parts.next()?.to_string();
+            let stream_type = parts.next()?.to_string();
+            let parent = parts.next()?;
+            let title = parts.next().unwrap_or_default().trim().trim_matches('\'');
+            Some(StreamEntry {
+                name,
+                stream_type,
+                parent: (parent != "none").then(|| parent.to_string()),
+                title: title.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Build the parent/child forest rooted at streams without a (known) parent.
+pub fn stream_tree(streams: &[StreamEntry]) -> Vec<serde_json::Value> {
+    let mut children: BTreeMap<&str, Vec<&StreamEntry>> = BTreeMap::new();
+    let mut roots = Vec::new();
+    for stream in streams {
+        match &stream.parent {
+            Some(parent) if streams.iter().any(|s| &s.name == parent) => {
+                children.entry(parent.as_str()).or_default().push(stream)
+            }
+            _ => roots.push(stream),
+        }
+    }
+
+    fn node(
+        stream: &StreamEntry,
+        children: &BTreeMap<&str, Vec<&StreamEntry>>,
+    ) -> serde_json::Value {
+        let kids: Vec<serde_json::Value> = children
+            .get(stream.name.as_str())
+            .map(|kids| kids.iter().map(|kid| node(kid, children)).collect())
+            .unwrap_or_default();
+        serde_json::json!({
+            "stream": stream.name,
+            "type": stream.stream_type,
+            "title": stream.title,
+            "children": kids,
+        })
+    }
+
+    roots.iter().map(|root| node(root, &children)).collect()
+}
+
+/// Render the hierarchy as a Mermaid flowchart.
+pub fn stream_mermaid(streams: &[StreamEntry]) -> String {
+    let id = |name: &str| -> String {
+        name.trim_start_matches("//")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect()
+    };
+
+    let mut diagram = String::from("graph TD\n");
+    for stream in streams {
+        diagram.push_str(&format!(
+            "    {}[\"{} ({})\"]\n",
+            id(&stream.name),
+            stream.name,
+            stream.stream_type
+        ));
+    }
+    for stream in streams {
+        if let Some(parent) = &stream.parent {
+            diagram.push_str(&format!("    {} --> {}\n", id(parent), id(&stream.name)));
+        }
+    }
+    diagram
+}
@@ -0,0 +1,98 @@
+//! Templates for new client workspaces
+//!
+//! `p4_workspace_create` fills in the spec of a new client from a template in the
+//! `[workspace_templates]` section of the config file, so setting up a machine doesn't
+//! mean writing the spec by hand. `{client}` in a template is replaced by the new
+//! client's name, and `{user}` by its owner:
+//!
+//! ```toml
+//! [workspace_templates.main]
+//! root = "/home/alice/ws/{client}"
+//! view = ["//depot/main/... //{client}/..."]
+//! options = "allwrite noclobber nocompress unlocked nomodtime rmdir"
+//!
+//! [workspace_templates.dev]
+//! root = "/home/alice/ws/{client}"
+//! stream = "//streams/dev"
+//! ```
+//!
+//! Fields a template leaves out keep the defaults `p4 client -o` fills in.
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use super::Spec;
+
+/// The `[workspace_templates]` section: templates by name.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct WorkspaceTemplates(pub BTreeMap<String, WorkspaceTemplate>);
+
+impl WorkspaceTemplates {
+    /// The template called `name`, or why there isn't one.
+    pub fn get(&self, name: &str) -> Result<&WorkspaceTemplate> {
+        if self.0.is_empty() {
+            bail!(
+                "No workspace templates are configured; add one under \
+                 [workspace_templates] in the config file"
+            );
+        }
+        self.0.get(name).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unknown workspace template {}; the templates are {}",
+                name,
+                self.0.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkspaceTemplate {
+    /// The directory the client's files are synced to.
+    pub root: Option<String>,
+    /// The stream the client is bound to, which its view is generated from.
+    pub stream: Option<String>,
+    /// The view of a client not bound to a stream, one mapping per entry.
+    pub view: Vec<String>,
+    /// Such as `allwrite noclobber`.
+    pub options: Option<String>,
+    /// `local`, `unix`, `mac`, `win` or `share`.
+    pub line_end: Option<String>,
+}
+
+impl WorkspaceTemplate {
+    /// Fill in `spec`, the form `p4 client -o` printed for a new client, from this
+    /// template. `root` replaces the template's root.
+    pub fn apply(&self, spec: &mut Spec, root: Option<&str>) -> Result<()> {
+        if self.stream.is_some() && !self.view.is_empty() {
+            bail!(
+                "A workspace template can't set both a stream and a view; a stream \
+                 client's view comes from its stream"
+            );
+        }
+        let client = spec.field("Client").to_string();
+        let user = spec.field("Owner").to_string();
+        let expand = |value: &str| value.replace("{client}", &client).replace("{user}", &user);
+
+        if let Some(root) = root.or(self.root.as_deref()) {
+            spec.set("Root", expand(root));
+        }
+        if let Some(options) = &self.options {
+            spec.set("Options", expand(options));
+        }
+        if let Some(line_end) = &self.line_end {
+            spec.set("LineEnd", line_end.as_str());
+        }
+        if let Some(stream) = &self.stream {
+            spec.set("Stream", expand(stream));
+            spec.remove("View");
+        } else if !self.view.is_empty() {
+            let view: Vec<String> = self.view.iter().map(|line| expand(line)).collect();
+            spec.set("View", view.join("\n"));
+        }
+        Ok(())
+    }
+}
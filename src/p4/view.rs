@@ -0,0 +1,74 @@
+//! Client view generation
+
+/// Build a minimal client View mapping covering the given depot paths.
+///
+/// A path is mapped as a single file only when it's marked as one with a revision
+/// (`#head`, `@12345`), which is dropped since views can't hold one. Any other path
+/// without a wildcard is taken as a directory and widened to `/...`, even with a dot
+/// in its name like `//depot/rel/v1.2`. Duplicates and paths already covered by
+/// another entry are dropped. When every path lives in the same depot the depot
+/// name is stripped from the client side, otherwise it is kept to avoid collisions.
+pub fn generate_sparse_view(paths: &[String], client: &str) -> Vec<String> {
+    let mut specs: Vec<String> = paths
+        .iter()
+        .map(|p| normalize_view_path(p))
+        .filter(|p| p.starts_with("//"))
+        .collect();
+    specs.sort();
+    specs.dedup();
+
+    let covered = |path: &str, others: &[String]| {
+        others.iter().any(|other| {
+            other != path
+                && other
+                    .strip_suffix("...")
+                    .is_some_and(|prefix| path.starts_with(prefix))
+        })
+    };
+    let minimal: Vec<String> = specs
+        .iter()
+        .filter(|p| !covered(p, &specs))
+        .cloned()
+        .collect();
+
+    let depot_of = |p: &str| p[2..].split('/').next().unwrap_or_default().to_string();
+    let single_depot = minimal
+        .first()
+        .map(|first| minimal.iter().all(|p| depot_of(p) == depot_of(first)))
+        .unwrap_or(true);
+
+    minimal
+        .iter()
+        .map(|depot_path| {
+            let rest = &depot_path[2..];
+            let client_side = if single_depot {
+                rest.split_once('/').map(|(_, r)| r).unwrap_or(rest)
+            } else {
+                rest
+            };
+            let client_path = format!("//{}/{}", client, client_side);
+            format!("{} {}", quote(depot_path), quote(&client_path))
+        })
+        .collect()
+}
+
+fn normalize_view_path(path: &str) -> String {
+    let path = path.trim();
+    if let Some(index) = path.find(['#', '@']) {
+        return path[..index].to_string();
+    }
+    let path = path.trim_end_matches('/');
+    if path.ends_with("...") || path.contains('*') {
+        path.to_string()
+    } else {
+        format!("{}/...", path)
+    }
+}
+
+fn quote(path: &str) -> String {
+    if path.contains(' ') {
+        format!("\"{}\"", path)
+    } else {
+        path.to_string()
+    }
+}
@@ -0,0 +1,1230 @@
+//! Composite workflows that chain several p4 commands together
+
+use anyhow::{Context, Result};
+use tracing::{debug, info, warn};
+
+use super::diff;
+use super::spec::{self, SpecKind};
+use super::{
+    policy, ztag, Changelist, ChangesPage, ClientSpec, MockMode, P4Command, P4Handler,
+    PendingChangelist, PendingFile, PendingWork, SyncResult,
+};
+
+impl P4Handler {
+    /// Link a job to a pending changelist and submit it.
+    ///
+    /// Fails before anything is changed on the server if the job does not exist or
+    /// the change breaks the submit policy, so every submit made through this workflow
+    /// references a real job.
+    pub async fn submit_with_job(&mut self, job: &str, changelist: &str) -> Result<String> {
+        if job.is_empty() {
+            return Err(anyhow::anyhow!("A job ID is required to submit"));
+        }
+        if changelist.is_empty() || changelist == "default" {
+            return Err(anyhow::anyhow!(
+                "A numbered pending changelist is required to submit with a job"
+            ));
+        }
+
+        let change = self.changelist(Some(changelist)).await?;
+        let mut violations = self.submit_policy.check_description(&change.description);
+        violations.extend(self.submit_policy.check_files(&change.depot_files()));
+        policy::enforce(violations)?;
+
+        let jobs = self
+            .execute(P4Command::Jobs {
+                filter: Some(format!("Job={}", job)),
+                max: Some(1),
+            })
+            .await?;
+        if !jobs
+            .lines()
+            .any(|line| line.split_whitespace().next() == Some(job))
+        {
+            return Err(anyhow::anyhow!("Job {} does not exist", job));
+        }
+
+        info!("Linking job {} to change {} before submit", job, changelist);
+
+        let fix = self
+            .execute(P4Command::Fix {
+                changelist: changelist.to_string(),
+                jobs: vec![job.to_string()],
+            })
+            .await?;
+        let submit = self
+            .execute(P4Command::SubmitChangelist {
+                changelist: changelist.to_string(),
+            })
+            .await?;
+
+        Ok(format!(
+            "Job {} verified:\n{}\n{}\n{}",
+            job,
+            jobs.trim_end(),
+            fix.trim_end(),
+            submit.trim_end()
+        ))
+    }
+
+    /// Submit opened files with a new description.
+    ///
+    /// The files are moved into a new numbered change (`change -i`, then `reopen -c`)
+    /// and that change is submitted, so files opened in other changes can be picked and
+    /// a failed submit leaves a change that can be fixed and submitted again. Without
+    /// `files`, everything in the default changelist is submitted. The change fixes
+    /// `jobs`.
+    ///
+    /// The submit policy is checked first; only the default changelist is read before
+    /// a change that breaks it is refused.
+    pub async fn submit(
+        &mut self,
+        description: &str,
+        files: Option<Vec<String>>,
+        jobs: Vec<String>,
+    ) -> Result<String> {
+        let files = files.filter(|files| !files.is_empty());
+        let mut violations = self.submit_policy.check_description(description);
+        violations.extend(self.submit_policy.check_jobs(&jobs));
+        if let Some(files) = &files {
+            violations.extend(self.submit_policy.check_files(files));
+        }
+        policy::enforce(violations)?;
+
+        let mut spec = self.spec(SpecKind::Change, None).await?;
+        if files.is_none() {
+            if spec.lines("Files").is_empty() {
+                return Err(anyhow::anyhow!(
+                    "No files are open in the default changelist; nothing to submit"
+                ));
+            }
+            let files = Changelist::from_spec(&spec).depot_files();
+            policy::enforce(self.submit_policy.check_files(&files))?;
+        }
+        spec.set("Description", description);
+        if files.is_some() {
+            spec.remove("Files");
+        }
+        if !jobs.is_empty() {
+            spec.set("Jobs", jobs.join("\n"));
+        }
+        let (changelist, created) = self.save_spec(SpecKind::Change, &spec).await?;
+        let mut steps = vec![created];
+
+        if let Some(files) = files {
+            let reopened = self
+                .execute(P4Command::Reopen {
+                    changelist: changelist.clone(),
+                    files,
+                })
+                .await;
+            match reopened {
+                Ok(reopened) => steps.push(reopened.trim_end().to_string()),
+                Err(e) => {
+                    // p4 reopen moves files one at a time, so some may have moved
+                    // before it failed
+                    if let Err(discard_error) = self.discard_change(&changelist).await {
+                        warn!("Could not delete change {}: {}", changelist, discard_error);
+                        return Err(e.context(format!(
+                            "Submit failed before anything was submitted, and pending change \
+                             {} couldn't be removed ({}). Reopen its files in the default \
+                             changelist, then delete change {}",
+                            changelist, discard_error, changelist
+                        )));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let submitted = self
+            .execute(P4Command::SubmitChangelist {
+                changelist: changelist.clone(),
+            })
+            .await
+            .map_err(|e| {
+                e.context(format!(
+                    "Submit failed; the files are still in pending change {}. Fix the problems \
+                     above, then submit change {}",
+                    changelist, changelist
+                ))
+            })?;
+        let number = submitted_change(&submitted).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Could not find the submitted change number in: {}",
+                submitted.trim()
+            )
+        })?;
+        info!("Submitted change {}", number);
+        steps.push(submitted.trim_end().to_string());
+        steps.push(format!("Submitted change {}.", number));
+        Ok(steps.join("\n"))
+    }
+
+    /// Move the files in `changelist` back to the default changelist and delete it.
+    async fn discard_change(&mut self, changelist: &str) -> Result<()> {
+        let files: Vec<String> = self
+            .opened(Some(changelist.to_string()))
+            .await?
+            .into_iter()
+            .map(|file| file.depot_file)
+            .collect();
+        if !files.is_empty() {
+            self.execute(P4Command::Reopen {
+                changelist: "default".to_string(),
+                files,
+            })
+            .await?;
+        }
+        self.execute(P4Command::DeleteChange {
+            changelist: changelist.to_string(),
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Gather a one-shot picture of the current workspace as JSON.
+    ///
+    /// Each section is collected independently; a failing command is reported in
+    /// its section instead of failing the whole report.
+    pub async fn workspace_health(&mut self, path: Option<String>) -> Result<String> {
+        let info = self.info().await?;
+        let user = Some(info.user_name.clone()).filter(|u| !u.is_empty());
+        let client = info.client().map(str::to_string);
+        let sync_path = path.clone().unwrap_or_else(|| "...".to_string());
+
+        let opened = self.opened(None).await;
+        let resolve = self
+            .execute(P4Command::ResolvePreview {
+                path: path.clone(),
+                changelist: None,
+            })
+            .await;
+        let out_of_date = self
+            .execute(P4Command::SyncPreview {
+                paths: vec![sync_path],
+            })
+            .await;
+        let shelved = self
+            .execute(P4Command::ShelvedChanges {
+                user: user.clone(),
+                client: client.clone(),
+            })
+            .await;
+        let ticket = self.execute(P4Command::LoginStatus).await;
+
+        let report = serde_json::json!({
+            "user": user,
+            "client": client,
+            "opened": match opened {
+                Ok(files) => serde_json::json!({
+                    "count": files.len(),
+                    "files": files.iter().map(|f| &f.depot_file).collect::<Vec<_>>(),
+                }),
+                Err(e) => serde_json::json!({ "error": e.to_string() }),
+            },
+            "needs_resolve": file_section(resolve, "files"),
+            "out_of_date": file_section(out_of_date, "files"),
+            "shelved_changes": file_section(shelved, "changes"),
+            "ticket": match ticket {
+                Ok(status) => serde_json::json!({
+                    "valid": true,
+                    "status": status.trim(),
+                }),
+                Err(e) => serde_json::json!({
+                    "valid": false,
+                    "status": e.to_string().trim(),
+                }),
+            },
+        });
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// The current user's work in the current client, grouped by changelist: what each
+    /// change is for, the files open in it and whether it's shelved.
+    pub async fn pending_work(&mut self) -> Result<PendingWork> {
+        let info = self.info().await?;
+        let user = Some(info.user_name.clone()).filter(|u| !u.is_empty());
+        let client = info.client().map(str::to_string);
+
+        let opened = self.opened(None).await?;
+        let changes: Vec<ztag::Change> = self
+            .execute_typed(P4Command::PendingChanges {
+                user: user.clone(),
+                client: client.clone(),
+            })
+            .await?;
+        let shelved: Vec<ztag::Change> = self
+            .execute_typed(P4Command::ShelvedChanges {
+                user: user.clone(),
+                client: client.clone(),
+            })
+            .await?;
+
+        let mut changelists = vec![PendingChangelist {
+            change: "default".to_string(),
+            ..Default::default()
+        }];
+        changelists.extend(changes.into_iter().map(|change| PendingChangelist {
+            shelved: shelved.iter().any(|s| s.change == change.change),
+            description: Some(change.desc.trim_end().to_string()),
+            change: change.change,
+            files: Vec::new(),
+        }));
+        for file in opened {
+            let index = match changelists.iter().position(|c| c.change == file.change) {
+                Some(index) => index,
+                // Opened in a change that isn't the user's, e.g. one made by another
+                None => {
+                    changelists.push(PendingChangelist {
+                        change: file.change.clone(),
+                        ..Default::default()
+                    });
+                    changelists.len() - 1
+                }
+            };
+            changelists[index].files.push(PendingFile {
+                depot_file: file.depot_file,
+                rev: file.rev,
+                action: file.action,
+                file_type: file.file_type,
+                locked: file.our_lock.is_some(),
+            });
+        }
+        if changelists[0].files.is_empty() {
+            changelists.remove(0);
+        }
+
+        Ok(PendingWork {
+            user: info.user_name,
+            client,
+            changelists,
+        })
+    }
+
+    /// Check whether a pending changelist is ready to submit.
+    ///
+    /// Returns a JSON report with an overall `pass` flag and the itemized reasons
+    /// for every failed check.
+    pub async fn submit_preflight(&mut self, changelist: &str) -> Result<String> {
+        if changelist.is_empty() || changelist == "default" {
+            return Err(anyhow::anyhow!(
+                "A numbered pending changelist is required for a submit preflight"
+            ));
+        }
+
+        let user = self.info().await?.user_name;
+
+        let change = self.changelist(Some(changelist)).await?;
+        let files = change.depot_files();
+        let Changelist {
+            description, jobs, ..
+        } = change;
+
+        let mut checks = Vec::new();
+
+        checks.push(check_result(
+            "description_policy",
+            self.submit_policy.check_description(&description),
+        ));
+        checks.push(check_result(
+            "job_policy",
+            self.submit_policy.check_jobs(&jobs),
+        ));
+        checks.push(check_result(
+            "file_policy",
+            self.submit_policy.check_files(&files),
+        ));
+
+        if files.is_empty() {
+            checks.push(check_result(
+                "has_files",
+                vec![format!("Change {} has no files", changelist)],
+            ));
+        }
+
+        let unresolved = self
+            .execute(P4Command::ResolvePreview {
+                path: None,
+                changelist: Some(changelist.to_string()),
+            })
+            .await;
+        checks.push(check_result(
+            "all_resolved",
+            match unresolved {
+                Ok(output) => output
+                    .lines()
+                    .filter_map(|line| line.split_once(" - "))
+                    .map(|(file, _)| format!("{} needs resolve", file.trim()))
+                    .collect(),
+                Err(e) => vec![format!("Could not check resolve state: {}", e)],
+            },
+        ));
+
+        if !files.is_empty() {
+            let out_of_date = self
+                .execute(P4Command::SyncPreview {
+                    paths: files.clone(),
+                })
+                .await;
+            checks.push(check_result(
+                "up_to_date",
+                match out_of_date {
+                    Ok(output) => output
+                        .lines()
+                        .filter_map(|line| line.split_once(" - "))
+                        .map(|(file, _)| format!("{} is out of date", file.trim()))
+                        .collect(),
+                    Err(e) => vec![format!("Could not check for out-of-date files: {}", e)],
+                },
+            ));
+
+            let opened = self.execute(P4Command::OpenedAll { files }).await;
+            checks.push(check_result(
+                "no_foreign_locks",
+                match opened {
+                    Ok(output) => output
+                        .lines()
+                        .filter(|line| line.contains("*exclusive*") || line.contains("*locked*"))
+                        .filter_map(|line| {
+                            let (file, rest) = line.split_once(" - ")?;
+                            let owner = rest.split(" by ").nth(1)?.split_whitespace().next()?;
+                            let owner_user = owner.split('@').next().unwrap_or(owner);
+                            (owner_user != user)
+                                .then(|| format!("{} is locked by {}", file.trim(), owner))
+                        })
+                        .collect(),
+                    Err(e) => vec![format!("Could not check for locks: {}", e)],
+                },
+            ));
+        }
+
+        let pass = checks.iter().all(|check| check["pass"] == true);
+        let report = serde_json::json!({
+            "changelist": changelist,
+            "pass": pass,
+            "checks": checks,
+        });
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// The current client's View, and the one [`Self::sparse_client_view`] would
+    /// replace it with, to confirm the change before it's applied.
+    pub async fn sparse_view_preview(&mut self, paths: &[String]) -> Result<String> {
+        let spec = self.spec(SpecKind::Client, None).await?;
+        let client = spec.field("Client");
+        let view = super::view::generate_sparse_view(paths, client);
+        let mut preview = format!("Current view of {}:\n", client);
+        for line in spec.lines("View") {
+            preview.push_str(&format!("\t{}\n", line));
+        }
+        preview.push_str("New view:\n");
+        for line in &view {
+            preview.push_str(&format!("\t{}\n", line));
+        }
+        Ok(preview)
+    }
+
+    /// Generate a minimal client View for the given depot paths, optionally saving it
+    /// to the current client spec.
+    pub async fn sparse_client_view(&mut self, paths: &[String], apply: bool) -> Result<String> {
+        if paths.is_empty() {
+            return Err(anyhow::anyhow!("At least one depot path is required"));
+        }
+
+        let info = self.info().await?;
+        let client = info
+            .client()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("No client workspace is set"))?;
+
+        let view = super::view::generate_sparse_view(paths, &client);
+        if view.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No depot paths given; paths must start with //"
+            ));
+        }
+
+        let mut report = serde_json::json!({
+            "client": client,
+            "view": view,
+            "applied": false,
+        });
+
+        if apply {
+            let mut spec = self.spec(SpecKind::Client, Some(&client)).await?;
+            spec.set("View", view.join("\n"));
+            let (_, result) = self.save_spec(SpecKind::Client, &spec).await?;
+            info!(
+                "Applied sparse view with {} mappings to {}",
+                view.len(),
+                client
+            );
+            report["applied"] = serde_json::json!(true);
+            report["result"] = serde_json::json!(result);
+        }
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// Create the client `name` from a configured template, optionally syncing it to
+    /// head, and report the new client.
+    ///
+    /// The sync writes files under the root, so the root, once the template is filled
+    /// in, has to be within the sandbox and pass `check_root` before the client is
+    /// saved. The sync runs as the new client, wherever the call runs, on the depot
+    /// paths of its view. A stream client's view is generated by the server, so it's
+    /// read back once the client is saved.
+    pub async fn create_workspace(
+        &mut self,
+        template: &str,
+        name: &str,
+        root: Option<&str>,
+        sync: bool,
+        check_root: &(dyn Fn(&str) -> Result<()> + Send + Sync),
+    ) -> Result<String> {
+        if name.trim().is_empty() {
+            return Err(anyhow::anyhow!("A name for the new workspace is required"));
+        }
+        let template = self.workspace_templates.get(template)?.clone();
+
+        let mut spec = self.spec(SpecKind::Client, Some(name)).await?;
+        // p4 only reports when a client was last accessed if it exists
+        if spec.get("Access").is_some() {
+            return Err(anyhow::anyhow!(
+                "Client {} already exists; choose another name",
+                name
+            ));
+        }
+        template.apply(&mut spec, root)?;
+        let root = spec.field("Root");
+        let dir = self
+            .working_dir
+            .clone()
+            .or_else(|| self.workspace_root.clone())
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_default());
+        self.sandbox.get().check(root, &dir)?;
+        check_root(root)?;
+        let (_, result) = self.save_spec(SpecKind::Client, &spec).await?;
+        info!("Created client {} from a template", name);
+
+        let mut client = ClientSpec::from_spec(&spec);
+        if client.stream.is_some() {
+            client.view = self.client_spec(Some(name)).await?.view;
+        }
+        let mut report = serde_json::json!({
+            "client": client,
+            "result": result,
+            "synced": false,
+        });
+
+        if sync {
+            let mut new_client = self.clone();
+            new_client.set_client(Some(name.to_string()));
+            // The depot side, as a depot sandbox can't allow the new client's syntax
+            let mut synced = SyncResult::default();
+            for mapping in client.view.iter() {
+                if mapping.depot_path.starts_with('-') {
+                    continue;
+                }
+                let path = mapping.depot_path.trim_start_matches('+');
+                let result = new_client.sync(path).await?;
+                synced.files.extend(result.files);
+                synced.messages.extend(result.messages);
+            }
+            report["synced"] = serde_json::json!(true);
+            report["synced_files"] = serde_json::json!(synced.files.len());
+            report["messages"] = serde_json::json!(synced.messages);
+        }
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// Return the stream hierarchy as a JSON tree, optionally with a Mermaid diagram.
+    ///
+    /// Parents that fall outside the listed streams are read with `p4 stream -o` and
+    /// walked up to their mainline so the tree is always complete.
+    pub async fn stream_graph(&mut self, path: Option<String>, mermaid: bool) -> Result<String> {
+        let listing = self.execute(P4Command::Streams { path }).await?;
+        let mut streams = super::streams::parse_streams(&listing);
+
+        let mut missing: Vec<String> = streams
+            .iter()
+            .filter_map(|s| s.parent.clone())
+            .filter(|parent| !streams.iter().any(|s| &s.name == parent))
+            .collect();
+        while let Some(parent) = missing.pop() {
+            if streams.iter().any(|s| s.name == parent) {
+                continue;
+            }
+            let spec = self.spec(SpecKind::Stream, Some(&parent)).await?;
+            let grandparent = spec.field("Parent").to_string();
+            let entry = super::streams::StreamEntry {
+                name: parent,
+                stream_type: spec.field("Type").to_string(),
+                parent: (!grandparent.is_empty() && grandparent != "none").then_some(grandparent),
+                title: spec.field("Name").to_string(),
+            };
+            if let Some(grandparent) = &entry.parent {
+                missing.push(grandparent.clone());
+            }
+            streams.push(entry);
+        }
+
+        let mut report = serde_json::json!({
+            "count": streams.len(),
+            "streams": super::streams::stream_tree(&streams),
+        });
+        if mermaid {
+            report["mermaid"] = serde_json::json!(super::streams::stream_mermaid(&streams));
+        }
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// Collect the submitted changes after `from` up to and including `to`, with
+    /// descriptions, authors and linked jobs, as JSON for release notes.
+    ///
+    /// `from` and `to` may be labels or changelist numbers.
+    pub async fn release_notes(&mut self, path: &str, from: &str, to: &str) -> Result<String> {
+        let from = from.trim_start_matches('@');
+        let to = to.trim_start_matches('@');
+        if from.is_empty() || to.is_empty() {
+            return Err(anyhow::anyhow!("Both `from` and `to` are required"));
+        }
+
+        // Changes at or below the newest change in `from` are already released
+        let boundary = self
+            .execute(P4Command::Changes {
+                max: 1,
+                path: Some(format!("{}@{}", path, from)),
+            })
+            .await?;
+        let boundary = boundary
+            .lines()
+            .find_map(|line| line.strip_prefix("Change "))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|n| n.parse::<u32>().ok())
+            .unwrap_or(0);
+
+        let range = format!("{}@{},@{}", path, from, to);
+        let listing = self
+            .execute(P4Command::ChangesLong {
+                path: range.clone(),
+                max: None,
+            })
+            .await?;
+        let fixes = self.execute(P4Command::Fixes { path: range }).await?;
+
+        let mut changes: Vec<serde_json::Value> = Vec::new();
+        let mut description: Vec<&str> = Vec::new();
+        let mut current: Option<(u32, String, String, String)> = None;
+
+        let mut flush = |current: &mut Option<(u32, String, String, String)>,
+                         description: &mut Vec<&str>| {
+            if let Some((change, date, user, client)) = current.take() {
+                if change > boundary {
+                    let jobs: Vec<&str> = fixes
+                        .lines()
+                        .filter(|line| {
+                            line.split(" fixed by change ")
+                                .nth(1)
+                                .and_then(|rest| rest.split_whitespace().next())
+                                == Some(change.to_string().as_str())
+                        })
+                        .filter_map(|line| line.split_whitespace().next())
+                        .collect();
+                    changes.push(serde_json::json!({
+                        "change": change,
+                        "date": date,
+                        "user": user,
+                        "client": client,
+                        "description": description.join("\n"),
+                        "jobs": jobs,
+                    }));
+                }
+            }
+            description.clear();
+        };
+
+        for line in listing.lines() {
+            if let Some(rest) = line.strip_prefix("Change ") {
+                flush(&mut current, &mut description);
+                // <number> on <date> by <user>@<client>
+                let parts: Vec<&str> = rest.split_whitespace().collect();
+                let change = parts.first().and_then(|n| n.parse().ok()).unwrap_or(0);
+                let date = parts.get(2).copied().unwrap_or_default().to_string();
+                let (user, client) = parts
+                    .get(4)
+                    .and_then(|who| who.split_once('@'))
+                    .map(|(u, c)| (u.to_string(), c.to_string()))
+                    .unwrap_or_default();
+                current = Some((change, date, user, client));
+            } else if current.is_some() && !line.trim().is_empty() {
+                description.push(line.trim());
+            }
+        }
+        flush(&mut current, &mut description);
+
+        let report = serde_json::json!({
+            "path": path,
+            "from": from,
+            "to": to,
+            "count": changes.len(),
+            "changes": changes,
+        });
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    /// Predict whether integrating `from` into `to` will conflict.
+    ///
+    /// The integration is performed into a scratch changelist so the files can be
+    /// previewed with `resolve -am -n`; the changelist is reverted and deleted
+    /// afterwards, even if one of the intermediate steps fails.
+    pub async fn predict_conflicts(&mut self, from: &str, to: &str) -> Result<String> {
+        if from.is_empty() || to.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Both a source and a target path are required"
+            ));
+        }
+
+        let preview = self
+            .execute(P4Command::IntegratePreview {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+            .await?;
+        let to_integrate: Vec<&str> = preview
+            .lines()
+            .filter_map(|line| line.split_once(" - "))
+            .map(|(file, _)| file.trim())
+            .collect();
+
+        if to_integrate.is_empty() {
+            let report = serde_json::json!({
+                "from": from,
+                "to": to,
+                "files_to_integrate": 0,
+                "will_conflict": false,
+                "needs_resolve": [],
+                "conflicts": [],
+            });
+            return Ok(serde_json::to_string_pretty(&report)?);
+        }
+
+        let created = self
+            .execute(P4Command::CreateChange {
+                description: format!("p4-mcp conflict prediction: {} -> {}", from, to),
+            })
+            .await?;
+        let changelist = spec::saved_name(SpecKind::Change, &created)
+            .ok_or_else(|| anyhow::anyhow!("Could not create scratch changelist: {}", created))?;
+
+        debug!("Predicting conflicts in scratch change {}", changelist);
+
+        let resolve = self.scratch_resolve_preview(from, to, &changelist).await;
+
+        // Always clean up the scratch changelist before reporting
+        let reverted = self
+            .execute(P4Command::RevertChange {
+                changelist: changelist.clone(),
+            })
+            .await;
+        let deleted = self
+            .execute(P4Command::DeleteChange {
+                changelist: changelist.clone(),
+            })
+            .await;
+        if let Err(e) = reverted.and(deleted) {
+            warn!("Failed to clean up scratch change {}: {}", changelist, e);
+        }
+
+        let resolve = resolve?;
+        let mut needs_resolve = Vec::new();
+        let mut conflicts = Vec::new();
+        for line in resolve.lines() {
+            if let Some((file, _)) = line.split_once(" - ") {
+                needs_resolve.push(file.trim().to_string());
+            } else if let Some(count) = line
+                .split(" + ")
+                .find_map(|part| part.trim().strip_suffix(" conflicting"))
+                .and_then(|count| count.parse::<u32>().ok())
+            {
+                if let (true, Some(file)) = (count > 0, needs_resolve.last()) {
+                    conflicts
+                        .push(serde_json::json!({ "file": file, "conflicting_chunks": count }));
+                }
+            }
+        }
+
+        let report = serde_json::json!({
+            "from": from,
+            "to": to,
+            "files_to_integrate": to_integrate.len(),
+            "will_conflict": !conflicts.is_empty(),
+            "needs_resolve": needs_resolve,
+            "conflicts": conflicts,
+        });
+
+        Ok(serde_json::to_string_pretty(&report)?)
+    }
+
+    async fn scratch_resolve_preview(
+        &mut self,
+        from: &str,
+        to: &str,
+        changelist: &str,
+    ) -> Result<String> {
+        self.execute(P4Command::Integrate {
+            from: from.to_string(),
+            to: to.to_string(),
+            changelist: changelist.to_string(),
+        })
+        .await?;
+        self.execute(P4Command::AutoResolvePreview {
+            changelist: changelist.to_string(),
+        })
+        .await
+    }
+
+    /// Branch `from` into `to` with `p4 populate`, which submits the new files in one
+    /// change on the server without syncing them to the workspace.
+    ///
+    /// As it submits, the description and the files `p4 populate -n` would branch are
+    /// checked against the submit policy first.
+    pub async fn populate(&mut self, from: &str, to: &str, description: &str) -> Result<String> {
+        if from.is_empty() || to.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Both a source and a target path are required"
+            ));
+        }
+        if description.trim().is_empty() {
+            return Err(anyhow::anyhow!("A description for the change is required"));
+        }
+        let mut violations = self.submit_policy.check_description(description);
+        let preview = self
+            .execute(P4Command::PopulatePreview {
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+            .await?;
+        // `//depot/rel/a.c#1 - branch/sync from //depot/main/a.c#1,#3`
+        let files: Vec<String> = preview
+            .lines()
+            .filter(|line| line.starts_with("//"))
+            .filter_map(|line| line.split('#').next())
+            .map(str::to_string)
+            .collect();
+        violations.extend(self.submit_policy.check_files(&files));
+        policy::enforce(violations)?;
+
+        self.execute(P4Command::Populate {
+            from: from.to_string(),
+            to: to.to_string(),
+            description: description.to_string(),
+        })
+        .await
+    }
+
+    /// List changes one page at a time.
+    ///
+    /// The cursor is the highest change number of the next page; the page holds it
+    /// whenever more changes are available.
+    pub async fn changes_page(
+        &mut self,
+        path: Option<String>,
+        max: u32,
+        cursor: Option<&str>,
+    ) -> Result<ChangesPage> {
+        // Ask for one extra change to find out whether there is another page
+        let mut changes = self
+            .changes(max.saturating_add(1), paged_changes_path(path, cursor)?)
+            .await?;
+
+        let next_cursor = (changes.len() > max as usize)
+            .then(|| changes.get(max as usize).map(|c| c.change.clone()))
+            .flatten();
+        changes.truncate(max as usize);
+        Ok(ChangesPage {
+            changes,
+            next_cursor,
+        })
+    }
+
+    /// List depot files one page at a time. The cursor is the offset of the next page.
+    pub async fn files_page(
+        &mut self,
+        path: &str,
+        max: u32,
+        cursor: Option<&str>,
+    ) -> Result<String> {
+        let offset: u32 = match cursor {
+            Some(cursor) => cursor
+                .parse()
+                .map_err(|_| anyhow::anyhow!("Invalid cursor: {}", cursor))?,
+            None => 0,
+        };
+
+        // p4 files can't skip, so fetch through the end of the page plus one extra file
+        let output = self
+            .execute(P4Command::Files {
+                path: path.to_string(),
+                max: Some(offset.saturating_add(max).saturating_add(1)),
+            })
+            .await?;
+        let files: Vec<&str> = output.lines().filter(|l| l.starts_with("//")).collect();
+
+        let mut result: String = files
+            .iter()
+            .skip(offset as usize)
+            .take(max as usize)
+            .map(|f| format!("{}\n", f))
+            .collect();
+        if files.len() > (offset + max) as usize {
+            result.push_str(&format!(
+                "\nMore files available; pass cursor \"{}\" to continue.\n",
+                offset + max
+            ));
+        }
+        Ok(result)
+    }
+
+    /// A submitted change with the diffs of its files, only those of `files` if any are
+    /// given. Depot paths ending in `/...` select the files under them.
+    ///
+    /// A file's diff over `max_diff_bytes` is left out for a note of its size and the
+    /// revisions to compare with `p4_diff2` to see it, so a change of hundreds of files
+    /// can be described without its diffs swamping the result.
+    pub async fn describe_diffs(
+        &mut self,
+        changelist: &str,
+        files: &[String],
+        max_diff_bytes: usize,
+    ) -> Result<String> {
+        let output = self
+            .execute(P4Command::DescribeDiffs {
+                changelist: changelist.to_string(),
+            })
+            .await?;
+        let described = diff::split(&output);
+
+        let selects = |selection: &str, path: &str| match selection.strip_suffix("...") {
+            Some(dir) => path.starts_with(dir),
+            None => selection == path,
+        };
+        if let Some(unmatched) = files
+            .iter()
+            .find(|selection| !described.files.iter().any(|f| selects(selection, &f.path)))
+        {
+            return Err(anyhow::anyhow!(
+                "Change {} has no diff for {}",
+                changelist,
+                unmatched
+            ));
+        }
+
+        let mut sections = vec![described.preamble.clone()];
+        for file in &described.files {
+            if !files.is_empty() && !files.iter().any(|selection| selects(selection, &file.path)) {
+                continue;
+            }
+            let body = if file.body.len() > max_diff_bytes {
+                omitted_diff(file)
+            } else {
+                file.body.clone()
+            };
+            sections.push(format!("{}\n\n{}", file.header(), body));
+        }
+        Ok(sections.join("\n\n") + "\n")
+    }
+
+    /// The differences between files shelved in `changelist` and the workspace files,
+    /// or with `against_head` their head revisions, for reviewing a shelf as it's
+    /// reworked. Without `files`, every file in the shelf is compared.
+    pub async fn diff_shelf(
+        &mut self,
+        changelist: &str,
+        files: Vec<String>,
+        against_head: bool,
+    ) -> Result<String> {
+        let files = if files.is_empty() {
+            let records = self
+                .execute_tagged(P4Command::DescribeShelvedFiles {
+                    changelist: changelist.to_string(),
+                })
+                .await?;
+            let record = records.first().ok_or_else(|| {
+                anyhow::anyhow!("p4 describe returned no output for {}", changelist)
+            })?;
+            ztag::DescribedChange::from_record(record)?
+                .files
+                .into_iter()
+                .map(|file| file.depot_file)
+                .collect()
+        } else {
+            files
+        };
+        if files.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Change {} has no shelved files",
+                changelist
+            ));
+        }
+
+        if !against_head {
+            return self
+                .execute(P4Command::DiffShelved {
+                    files,
+                    changelist: changelist.to_string(),
+                })
+                .await;
+        }
+        let mut diffs = Vec::with_capacity(files.len());
+        for file in files {
+            let diff = self
+                .execute(P4Command::Diff2 {
+                    left: format!("{}#head", file),
+                    right: format!("{}@={}", file, changelist),
+                })
+                .await?;
+            diffs.push(diff.trim_end().to_string());
+        }
+        Ok(diffs.join("\n\n") + "\n")
+    }
+
+    /// Replace the description of a pending changelist.
+    pub async fn set_change_description(
+        &mut self,
+        changelist: &str,
+        description: &str,
+    ) -> Result<String> {
+        let mut spec = self.spec(SpecKind::Change, Some(changelist)).await?;
+        if !spec.field("Status").starts_with("pending") {
+            return Err(anyhow::anyhow!("Change {} is not pending", changelist));
+        }
+
+        spec.set("Description", description);
+        let (_, result) = self.save_spec(SpecKind::Change, &spec).await?;
+        Ok(result)
+    }
+
+    /// Report the backend in use, or switch every session to `backend`, `mock` or `real`.
+    ///
+    /// Switching needs `allow_backend_switch`. Before switching to the real server,
+    /// p4 is checked to run, so a failed switch leaves mock mode on.
+    pub async fn switch_backend(&mut self, backend: Option<&str>) -> Result<String> {
+        let current = self.mock_mode.name();
+        let Some(backend) = backend else {
+            let switching = if self.allow_backend_switch {
+                "pass mode to switch it"
+            } else {
+                "switching is disabled (allow_backend_switch = false)"
+            };
+            return Ok(format!("Using the {} backend; {}", current, switching));
+        };
+        let mock = match backend {
+            "mock" => true,
+            "real" => false,
+            other => {
+                return Err(anyhow::anyhow!(
+                    "Unknown backend {}; use \"mock\" or \"real\"",
+                    other
+                ))
+            }
+        };
+        if backend == current {
+            return Ok(format!("Already using the {} backend", current));
+        }
+        if !self.allow_backend_switch {
+            return Err(anyhow::anyhow!(
+                "Switching the backend is disabled; set allow_backend_switch = true in the \
+                 server's config file"
+            ));
+        }
+        if self.plan.is_some() {
+            return Err(anyhow::anyhow!(
+                "Dry runs don't switch the backend; still using the {} backend",
+                current
+            ));
+        }
+
+        if !mock {
+            let mut real = self.clone();
+            real.mock_mode = MockMode::new(false);
+            let version = real
+                .check_p4_bin()
+                .await
+                .context("Could not switch to the real backend")?;
+            info!("Switching to the real backend with {}", version);
+        }
+        self.mock_mode.set(mock);
+        // Results cached from one backend mean nothing to the other
+        self.cache.invalidate();
+        *self.workspace.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.log_activity(
+            tracing::Level::WARN,
+            &format!(
+                "Switched from the {} backend to the {} backend",
+                current, backend
+            ),
+        );
+        Ok(format!(
+            "Switched from the {} backend to the {} backend for every session",
+            current, backend
+        ))
+    }
+
+    /// Fail unless `allow_force_sync` is configured.
+    pub fn check_force_sync_allowed(&self) -> Result<()> {
+        if self.allow_force_sync {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Force sync is disabled: it overwrites files changed locally without being opened. \
+             Sync without force, or set allow_force_sync = true in the server's config file"
+        ))
+    }
+
+    /// Sync `path` with `-f`, rewriting every file, and say what was overwritten in a
+    /// warning after p4's messages.
+    ///
+    /// Unopened files that were changed locally are listed first with `p4 diff -se`,
+    /// since their changes are lost. Only runs if `allow_force_sync` is configured.
+    pub async fn force_sync(&mut self, path: String) -> Result<SyncResult> {
+        self.check_force_sync_allowed()?;
+
+        let changed = match self
+            .execute(P4Command::DiffUnopened { path: path.clone() })
+            .await
+        {
+            // Warnings such as "file(s) not on client." name the path, not a file
+            Ok(output) => output
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.contains(" - "))
+                .map(str::to_string)
+                .collect(),
+            Err(e) => {
+                debug!("Could not list locally changed files under {}: {}", path, e);
+                Vec::new()
+            }
+        };
+
+        let output = self
+            .execute(P4Command::Sync {
+                path: path.clone(),
+                force: true,
+            })
+            .await?;
+        let mut synced = SyncResult::parse(&output);
+
+        let mut warning = format!(
+            "Warning: force sync rewrote {} file(s) under {}",
+            synced.files.len(),
+            path
+        );
+        if changed.is_empty() {
+            warning.push_str("; none had been changed locally without being opened.");
+        } else {
+            warn!(
+                "Force sync of {} overwrote {} locally changed file(s)",
+                path,
+                changed.len()
+            );
+            warning.push_str(&format!(
+                ", overwriting local changes to {} file(s) that weren't opened:\n  {}",
+                changed.len(),
+                changed.join("\n  ")
+            ));
+        }
+        synced.messages.push(warning);
+        Ok(synced)
+    }
+}
+
+/// The number of the submitted change from `p4 submit` output. The server may have
+/// renumbered it: `Change 12348 renamed change 12350 and submitted.`
+fn submitted_change(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("Change ") && line.contains("submitted"))
+        .find_map(|line| {
+            line.split_whitespace()
+                .rev()
+                .find(|word| word.chars().all(|c| c.is_ascii_digit()))
+                .map(str::to_string)
+        })
+}
+
+/// Summarize a line-oriented p4 listing as `{count, <key>: [...]}`.
+///
+/// File listings are reduced to the path before the ` - ` separator; change
+/// listings keep the whole `Change ...` line. Anything else is ignored.
+fn file_section(output: Result<String>, key: &str) -> serde_json::Value {
+    match output {
+        Ok(output) => {
+            let entries: Vec<&str> = output
+                .lines()
+                .map(str::trim)
+                .filter_map(|line| match key {
+                    "files" => line.split_once(" - ").map(|(file, _)| file),
+                    _ => line.starts_with("Change ").then_some(line),
+                })
+                .collect();
+            serde_json::json!({ "count": entries.len(), key: entries })
+        }
+        Err(e) => serde_json::json!({ "error": e.to_string() }),
+    }
+}
+
+fn check_result(name: &str, reasons: Vec<String>) -> serde_json::Value {
+    serde_json::json!({
+        "name": name,
+        "pass": reasons.is_empty(),
+        "reasons": reasons,
+    })
+}
+
+/// The note standing in for a diff too large to include.
+fn omitted_diff(file: &diff::FileDiff) -> String {
+    let size = format!("diff omitted ({} KB)", file.body.len().div_ceil(1024));
+    let rev = file
+        .revision
+        .as_deref()
+        .and_then(|rev| rev.strip_prefix('#'))
+        .and_then(|rev| rev.parse::<u32>().ok());
+    match rev {
+        Some(rev) => {
+            let previous = match rev {
+                1 => "none".to_string(),
+                rev => (rev - 1).to_string(),
+            };
+            format!(
+                "{}, fetch with p4_diff2 {}#{} {}#{}",
+                size, file.path, previous, file.path, rev
+            )
+        }
+        None => format!("{}, fetch with p4_diff2", size),
+    }
+}
+
+/// The path to pass to `p4 changes` for the page starting at `cursor`.
+fn paged_changes_path(path: Option<String>, cursor: Option<&str>) -> Result<Option<String>> {
+    let Some(cursor) = cursor else {
+        return Ok(path);
+    };
+
+    let upper: u32 = cursor
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid cursor: {}", cursor))?;
+    let base = path.unwrap_or_else(|| "//...".to_string());
+    if base.contains(['@', '#']) {
+        return Err(anyhow::anyhow!(
+            "A cursor cannot be combined with a revision specifier in the path"
+        ));
+    }
+    Ok(Some(format!("{}@1,@{}", base, upper)))
+}
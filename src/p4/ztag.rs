@@ -0,0 +1,316 @@
+//! Parser for `p4 -ztag` tagged output
+//!
+//! Tagged output prints one `... field value` line per field, with a blank line
+//! between records. Multi-line values (such as descriptions) continue on the
+//! following untagged lines. [`parse_ztag`] keeps records as JSON maps; the typed
+//! records below cover the commands the server reads fields from.
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+pub type TaggedRecord = Map<String, Value>;
+
+pub fn parse_ztag(output: &str) -> Vec<TaggedRecord> {
+    let mut records = Vec::new();
+    let mut record = TaggedRecord::new();
+    let mut last_key: Option<String> = None;
+
+    for line in output.lines() {
+        if let Some(field) = line.strip_prefix("... ") {
+            let (key, value) = field.split_once(' ').unwrap_or((field, ""));
+            // Records are separated by blank lines, but so are the paragraphs of a
+            // description; a field seen twice is what really starts the next record
+            if record.contains_key(key) {
+                push_record(&mut records, &mut record);
+            }
+            record.insert(key.to_string(), Value::String(value.to_string()));
+            last_key = Some(key.to_string());
+        } else if let Some(Value::String(value)) =
+            last_key.as_ref().and_then(|key| record.get_mut(key))
+        {
+            value.push('\n');
+            value.push_str(line);
+        }
+    }
+
+    push_record(&mut records, &mut record);
+    records
+}
+
+fn push_record(records: &mut Vec<TaggedRecord>, record: &mut TaggedRecord) {
+    if record.is_empty() {
+        return;
+    }
+    for value in record.values_mut() {
+        if let Value::String(s) = value {
+            s.truncate(s.trim_end_matches('\n').len());
+        }
+    }
+    records.push(std::mem::take(record));
+}
+
+/// First p4 client release with `-Mj` JSON output.
+const JSON_OUTPUT_RELEASE: (u32, u32) = (2016, 1);
+
+/// Whether the client release in `p4 -V` output (`Rev. P4/LINUX26X86_64/2023.1/...`)
+/// supports `-Mj`.
+pub fn supports_json_output(version: &str) -> bool {
+    version
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Rev. "))
+        .flat_map(|rev| rev.split('/'))
+        .find_map(|part| {
+            let (year, minor) = part.split_once('.')?;
+            Some((year.parse::<u32>().ok()?, minor.parse::<u32>().ok()?))
+        })
+        .is_some_and(|release| release >= JSON_OUTPUT_RELEASE)
+}
+
+/// Parse `p4 -Mj -ztag` output, one JSON object per line, into tagged records.
+///
+/// Messages p4 reports alongside the data (objects with a `severity`) are dropped, and
+/// non-string values are kept as their JSON text so records match [`parse_ztag`]'s.
+/// Returns `None` if any line isn't a JSON object.
+pub fn parse_json_records(output: &str) -> Option<Vec<TaggedRecord>> {
+    let mut records = Vec::new();
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let Ok(Value::Object(record)) = serde_json::from_str(line) else {
+            return None;
+        };
+        if record.contains_key("severity") && record.contains_key("generic") {
+            continue;
+        }
+        records.push(
+            record
+                .into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(s) => (key, Value::String(s.trim_end_matches('\n').to_string())),
+                    other => (key, Value::String(other.to_string())),
+                })
+                .collect(),
+        );
+    }
+    Some(records)
+}
+
+/// Convert tagged records into typed records.
+///
+/// Fields the type doesn't know about are ignored, so newer servers that add fields
+/// still parse.
+pub fn from_records<T: DeserializeOwned>(records: Vec<TaggedRecord>) -> Result<Vec<T>> {
+    records
+        .into_iter()
+        .map(|record| Ok(serde_json::from_value(Value::Object(record))?))
+        .collect()
+}
+
+/// Values of an indexed field (`depotFile0`, `depotFile1`, ...) in order, stopping at
+/// the first missing index.
+pub fn indexed_field<'a>(record: &'a TaggedRecord, field: &str) -> Vec<&'a str> {
+    (0..)
+        .map_while(|i| record.get(&format!("{}{}", field, i)))
+        .map(|value| value.as_str().unwrap_or_default())
+        .collect()
+}
+
+/// `p4 -ztag info`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Info {
+    pub user_name: String,
+    pub client_name: String,
+    pub client_host: String,
+    pub client_root: String,
+    /// The stream a stream client is bound to; absent for other clients.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub client_stream: String,
+    pub server_address: String,
+    pub server_root: String,
+    pub server_version: String,
+    #[serde(rename = "serverID")]
+    pub server_id: String,
+    pub case_handling: String,
+    /// `enabled` on unicode servers; absent otherwise.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub unicode: String,
+}
+
+impl Info {
+    /// The current client, or `None` when p4 reports it as `*unknown*`.
+    pub fn client(&self) -> Option<&str> {
+        Some(self.client_name.as_str()).filter(|c| !c.is_empty() && *c != "*unknown*")
+    }
+
+    /// The stream the current client is bound to, if it's a stream client.
+    pub fn stream(&self) -> Option<&str> {
+        Some(self.client_stream.as_str()).filter(|s| !s.is_empty())
+    }
+
+    /// Whether the server only accepts clients that name a character set.
+    pub fn is_unicode(&self) -> bool {
+        self.unicode == "enabled"
+    }
+}
+
+/// One file from `p4 -ztag opened` (and `p4 -ztag status`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct OpenedFile {
+    pub depot_file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_file: Option<String>,
+    pub rev: String,
+    pub action: String,
+    pub change: String,
+    #[serde(rename = "type")]
+    pub file_type: String,
+    pub user: String,
+    pub client: String,
+    /// Present, and empty, when the file is locked.
+    #[serde(rename = "ourLock", skip_serializing_if = "Option::is_none")]
+    pub our_lock: Option<String>,
+}
+
+/// One change from `p4 -ztag changes`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Change {
+    pub change: String,
+    pub time: String,
+    pub user: String,
+    pub client: String,
+    pub status: String,
+    pub change_type: String,
+    pub path: String,
+    pub desc: String,
+}
+
+impl Change {
+    pub fn number(&self) -> Option<u32> {
+        self.change.parse().ok()
+    }
+}
+
+/// One file from `p4 -ztag fstat`. Fields p4 leaves out (e.g. `haveRev` for a file
+/// that isn't synced) are `None`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FileStat {
+    pub depot_file: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_rev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_change: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub head_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub have_rev: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<String>,
+}
+
+/// One file from `p4 -ztag where`. Files excluded by the client view have `unmap`
+/// set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct WhereFile {
+    pub depot_file: String,
+    pub client_file: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unmap: Option<String>,
+}
+
+/// One record from `p4 -ztag integrated`: revisions of `from_file` integrated into
+/// `to_file` by `change`. A range starts at the revision before its first, so `#3,#5`
+/// in the text output is a `startFromRev` of `#2` and an `endFromRev` of `#5`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Integration {
+    pub to_file: String,
+    pub start_to_rev: String,
+    pub end_to_rev: String,
+    pub from_file: String,
+    pub start_from_rev: String,
+    pub end_from_rev: String,
+    /// E.g. `copy from`, `merge from` or `branch from`; `... into` with `-r`.
+    pub how: String,
+    pub change: String,
+}
+
+/// A file affected by a described change.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DescribedFile {
+    pub depot_file: String,
+    pub rev: Option<u32>,
+    pub action: String,
+    #[serde(rename = "type")]
+    pub file_type: String,
+}
+
+/// `p4 -ztag describe -s`, with the indexed file and job fields gathered into lists.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DescribedChange {
+    pub change: u32,
+    pub user: String,
+    pub client: String,
+    pub time: String,
+    pub status: String,
+    pub description: String,
+    pub files: Vec<DescribedFile>,
+    pub jobs: Vec<String>,
+}
+
+impl DescribedChange {
+    pub fn from_record(record: &TaggedRecord) -> Result<Self> {
+        let field = |key: &str| {
+            record
+                .get(key)
+                .and_then(|v| v.as_str())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let change = field("change")
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Tagged describe output has no change number"))?;
+
+        let revs = indexed_field(record, "rev");
+        let actions = indexed_field(record, "action");
+        let types = indexed_field(record, "type");
+        let files = indexed_field(record, "depotFile")
+            .into_iter()
+            .enumerate()
+            .map(|(i, depot_file)| DescribedFile {
+                depot_file: depot_file.to_string(),
+                rev: revs.get(i).and_then(|rev| rev.parse().ok()),
+                action: actions.get(i).copied().unwrap_or_default().to_string(),
+                file_type: types.get(i).copied().unwrap_or_default().to_string(),
+            })
+            .collect();
+
+        Ok(Self {
+            change,
+            user: field("user"),
+            client: field("client"),
+            time: field("time"),
+            status: field("status"),
+            description: field("desc"),
+            files,
+            jobs: indexed_field(record, "job")
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,140 @@
+//! Reloading the config file while the server runs
+//!
+//! A server started with `--config` watches the file, and on Unix also reloads it on
+//! `SIGHUP`. These sections apply to every session as soon as the file is read again:
+//!
+//! - `[tools]`: sessions whose exposed tools change are sent
+//!   `notifications/tools/list_changed`
+//! - `[sandbox]`
+//! - `[timeouts]`: for commands started from then on
+//!
+//! Changes to anything else are logged and take effect after a restart. A file that
+//! can't be read or parsed is reported and the running settings are kept.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::mcp::ServerContext;
+
+/// How often the config file is checked for changes.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A setting that can be replaced while the server runs. Clones share the setting,
+/// so replacing it changes it for every clone.
+#[derive(Debug, Default)]
+pub struct Live<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for Live<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Live<T> {
+    pub fn new(value: T) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(value))))
+    }
+
+    /// The setting now.
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Replace the setting for every clone.
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(value);
+    }
+}
+
+/// Whether `config` differs from `running` in anything that isn't reloaded.
+fn needs_restart(running: &Config, config: &Config) -> bool {
+    let reloaded = Config {
+        tools: running.tools.clone(),
+        sandbox: running.sandbox.clone(),
+        timeouts: running.timeouts.clone(),
+        ..config.clone()
+    };
+    reloaded != *running
+}
+
+/// Apply `config` to `context` if it differs from `running`, which it then replaces.
+pub fn apply(context: &ServerContext, running: &mut Config, config: Config) {
+    if config == *running {
+        return;
+    }
+    let mut changed = Vec::new();
+    if config.tools != running.tools {
+        changed.push("[tools]");
+    }
+    if config.sandbox != running.sandbox {
+        changed.push("[sandbox]");
+    }
+    if config.timeouts != running.timeouts {
+        changed.push("[timeouts]");
+    }
+    if !changed.is_empty() {
+        context.reload(&config);
+        info!("Reloaded {} from the config file", changed.join(", "));
+    }
+    if needs_restart(running, &config) {
+        warn!("The config file has changes that take effect after a restart");
+    }
+    *running = config;
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reload the config file at `path` whenever it changes or the process gets
+/// `SIGHUP`, until the process exits. `load` reads it, with any command line options
+/// applied on top; `running` is the config the server started with.
+pub async fn watch(
+    path: PathBuf,
+    context: ServerContext,
+    mut running: Config,
+    load: impl Fn() -> Result<Config>,
+) {
+    let mut last_modified = modified(&path);
+    let mut ticker = tokio::time::interval(POLL_INTERVAL);
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            warn!("Can't reload the config file on SIGHUP: {}", e);
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let signalled = tokio::select! {
+            _ = ticker.tick() => false,
+            Some(()) = async {
+                match hangup.as_mut() {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => true,
+        };
+        #[cfg(not(unix))]
+        let signalled = {
+            ticker.tick().await;
+            false
+        };
+
+        let now_modified = modified(&path);
+        if !signalled && now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+        match load() {
+            Ok(config) => apply(&context, &mut running, config),
+            Err(e) => warn!("Keeping the running settings: {:#}", e),
+        }
+    }
+}
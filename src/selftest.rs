@@ -0,0 +1,161 @@
+//! `p4-mcp selftest`: checking a setup end to end
+//!
+//! Runs the [connection checks](crate::p4::health), then plays an MCP client against
+//! an in-process session: `initialize`, `tools/list` and a `tools/call` of `p4_info`,
+//! sent as JSON text through the same session loop the transports use. Each step
+//! passes or fails with a hint, so a setup can be checked before an MCP host is
+//! configured to start the server.
+
+use anyhow::anyhow;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::mcp::{MCPServer, ServerContext};
+use crate::p4::{HealthCheck, HealthReport};
+use crate::transport;
+
+/// The messages a client sends, as text.
+fn client_messages() -> Vec<Value> {
+    vec![
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "protocolVersion": "2025-06-18",
+                "capabilities": {},
+                "clientInfo": {"name": "p4-mcp-selftest", "version": env!("CARGO_PKG_VERSION")}
+            }
+        }),
+        json!({"jsonrpc": "2.0", "method": "notifications/initialized"}),
+        json!({"jsonrpc": "2.0", "id": 2, "method": "tools/list"}),
+        json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "tools/call",
+            "params": {"name": "p4_info", "arguments": {}}
+        }),
+    ]
+}
+
+/// Run the connection checks and a client session against `context`.
+pub async fn run(context: &ServerContext) -> HealthReport {
+    let mut report = context.check_health().await;
+
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel();
+    for message in client_messages() {
+        let _ = incoming_tx.send(message.to_string());
+    }
+    drop(incoming_tx);
+    transport::serve_session(MCPServer::with_context(context), incoming_rx, outgoing_tx).await;
+
+    let mut responses = Vec::new();
+    while let Ok(json) = outgoing_rx.try_recv() {
+        match serde_json::from_str::<Value>(&json) {
+            Ok(message) if message.get("id").is_some() => responses.push(message),
+            Ok(_) => {}
+            Err(e) => report.checks.push(HealthCheck::failed(
+                "parse",
+                anyhow!("The server sent a message that isn't JSON: {}", e),
+                None,
+            )),
+        }
+    }
+    let response = |id: i64| responses.iter().find(|message| message["id"] == id);
+
+    report.checks.push(match response(1) {
+        Some(message) if message["result"]["protocolVersion"].is_string() => HealthCheck::passed(
+            "initialize",
+            format!(
+                "protocol {}, {} {}",
+                message["result"]["protocolVersion"]
+                    .as_str()
+                    .unwrap_or_default(),
+                message["result"]["serverInfo"]["name"]
+                    .as_str()
+                    .unwrap_or_default(),
+                message["result"]["serverInfo"]["version"]
+                    .as_str()
+                    .unwrap_or_default()
+            ),
+        ),
+        other => HealthCheck::failed("initialize", unexpected(other), None),
+    });
+
+    report.checks.push(match response(2) {
+        Some(message) => match message["result"]["tools"].as_array() {
+            Some(tools) if tools.iter().any(|tool| tool["name"] == "p4_info") => {
+                HealthCheck::passed("tools/list", format!("{} tools", tools.len()))
+            }
+            Some(tools) => HealthCheck::failed(
+                "tools/list",
+                anyhow!("{} tools, without p4_info", tools.len()),
+                Some(
+                    "Check the [tools] section of the config file; the tools/call check \
+                     needs p4_info",
+                ),
+            ),
+            None => HealthCheck::failed("tools/list", unexpected(Some(message)), None),
+        },
+        None => HealthCheck::failed("tools/list", unexpected(None), None),
+    });
+
+    report.checks.push(match response(3) {
+        Some(message) if message["result"]["isError"] != true && message["result"].is_object() => {
+            HealthCheck::passed("tools/call", "p4_info answered".to_string())
+        }
+        Some(message) => HealthCheck::failed(
+            "tools/call",
+            anyhow!("p4_info failed: {}", error_text(message)),
+            Some(
+                "See the failed connection checks above, or run with --debug for the p4 \
+                 commands run",
+            ),
+        ),
+        None => HealthCheck::failed("tools/call", unexpected(None), None),
+    });
+
+    report
+}
+
+fn unexpected(response: Option<&Value>) -> anyhow::Error {
+    match response {
+        Some(message) => anyhow!("Unexpected response: {}", error_text(message)),
+        None => anyhow!("No response"),
+    }
+}
+
+/// The error message of a response, or the whole response.
+fn error_text(message: &Value) -> String {
+    if let Some(error) = message["error"]["message"].as_str() {
+        return error.to_string();
+    }
+    match message["result"]["content"][0]["text"].as_str() {
+        Some(text) => text.trim().to_string(),
+        None => message.to_string(),
+    }
+}
+
+/// `report` as lines of text, ending with a summary.
+pub fn render(report: &HealthReport) -> String {
+    let mut out = format!("backend: {}\n", report.backend);
+    for check in &report.checks {
+        out.push_str(&format!(
+            "{} {}: {}\n",
+            if check.ok { "PASS" } else { "FAIL" },
+            check.name,
+            check.detail
+        ));
+        if let Some(hint) = &check.hint {
+            out.push_str(&format!("     hint: {}\n", hint));
+        }
+    }
+    let passed = report.checks.iter().filter(|check| check.ok).count();
+    out.push_str(&format!(
+        "{} of {} checks passed\n",
+        passed,
+        report.checks.len()
+    ));
+    out
+}
@@ -0,0 +1,331 @@
+//! Optional Helix Swarm integration
+//!
+//! Swarm is configured through the `SWARM_URL`, `SWARM_USER` and `SWARM_TOKEN`
+//! environment variables. In mock mode, requests are answered with canned responses
+//! instead of contacting a Swarm server.
+
+use anyhow::Result;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::p4::backend::MockMode;
+
+const API_VERSION: &str = "v9";
+
+/// A review id from a tool call, which goes into API URLs, so only a number will do.
+fn review_number(review_id: &str) -> Result<u64> {
+    review_id.trim().parse().map_err(|_| {
+        anyhow::anyhow!(
+            "Invalid Swarm review id {:?}; expected a review number",
+            review_id
+        )
+    })
+}
+
+pub struct SwarmClient {
+    base_url: String,
+    user: String,
+    token: String,
+    http: reqwest::Client,
+    /// Shared with the p4 handler, so switching the backend switches Swarm too.
+    mock_mode: MockMode,
+    /// Whether `SWARM_URL` and `SWARM_TOKEN` were set, rather than this being the
+    /// placeholder client mock mode starts with.
+    configured: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReviewEnvelope {
+    review: Review,
+}
+
+#[derive(Debug, Deserialize)]
+struct Review {
+    id: u64,
+    #[serde(default)]
+    author: Option<String>,
+    #[serde(default)]
+    state: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    participants: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentsEnvelope {
+    comments: Vec<Comment>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Comment {
+    user: String,
+    body: String,
+    #[serde(default)]
+    time: Option<u64>,
+    #[serde(default)]
+    context: Option<CommentContext>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommentContext {
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default, rename = "rightLine")]
+    right_line: Option<u64>,
+}
+
+impl SwarmClient {
+    pub fn new(base_url: &str, user: &str, token: &str) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            user: user.to_string(),
+            token: token.to_string(),
+            http: reqwest::Client::new(),
+            mock_mode: MockMode::from_env(),
+            configured: true,
+        }
+    }
+
+    /// Build a client from the environment, returning `None` when Swarm is not configured.
+    ///
+    /// In mock mode a placeholder client is always returned so the Swarm tools can be
+    /// exercised; it refuses requests if the backend is later switched to the real one.
+    pub fn from_env(mock_mode: &MockMode) -> Option<Self> {
+        let url = std::env::var("SWARM_URL").ok();
+
+        let mut client = match (url, std::env::var("SWARM_TOKEN").ok()) {
+            (Some(url), Some(token)) => {
+                let user = std::env::var("SWARM_USER")
+                    .or_else(|_| std::env::var("P4USER"))
+                    .unwrap_or_default();
+                Self::new(&url, &user, &token)
+            }
+            _ if mock_mode.is_enabled() => Self {
+                configured: false,
+                ..Self::new("https://swarm.example.com", "testuser", "mock")
+            },
+            _ => return None,
+        };
+        client.mock_mode = mock_mode.clone();
+        Some(client)
+    }
+
+    fn check_configured(&self) -> Result<()> {
+        if self.configured {
+            return Ok(());
+        }
+        Err(anyhow::anyhow!(
+            "Swarm is not configured; set SWARM_URL and SWARM_TOKEN to use it outside mock mode"
+        ))
+    }
+
+    pub fn review_url(&self, review_id: u64) -> String {
+        format!("{}/reviews/{}", self.base_url, review_id)
+    }
+
+    /// Create a review from a shelved changelist and return the review URL.
+    pub async fn create_review(
+        &self,
+        change: &str,
+        description: Option<&str>,
+        reviewers: &[String],
+    ) -> Result<String> {
+        if self.mock_mode.is_enabled() {
+            debug!("Mock creating Swarm review for change {}", change);
+            return Ok(format!(
+                "Mock Swarm review created for change {}: {}",
+                change,
+                self.review_url(12206)
+            ));
+        }
+        self.check_configured()?;
+
+        let mut form: Vec<(&str, &str)> = vec![("change", change)];
+        if let Some(description) = description {
+            form.push(("description", description));
+        }
+        for reviewer in reviewers {
+            form.push(("reviewers[]", reviewer));
+        }
+
+        let url = format!("{}/api/{}/reviews", self.base_url, API_VERSION);
+        debug!("Creating Swarm review: POST {}", url);
+
+        let response = self
+            .http
+            .post(&url)
+            .basic_auth(&self.user, Some(&self.token))
+            .form(&form)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Swarm review creation failed ({}): {}",
+                status,
+                body
+            ));
+        }
+
+        let envelope: ReviewEnvelope = response.json().await?;
+        Ok(format!(
+            "Swarm review {} created for change {}: {}",
+            envelope.review.id,
+            change,
+            self.review_url(envelope.review.id)
+        ))
+    }
+
+    /// Report the state of a review, including reviewer votes and whether it is approved.
+    pub async fn review_status(&self, review_id: &str) -> Result<String> {
+        let review_id = review_number(review_id)?;
+        let review = if self.mock_mode.is_enabled() {
+            debug!("Mock fetching Swarm review {}", review_id);
+            Review {
+                id: review_id,
+                author: Some("testuser".to_string()),
+                state: Some("approved".to_string()),
+                description: Some("Sample change description".to_string()),
+                participants: serde_json::json!({
+                    "testuser": {},
+                    "alice": {"vote": {"value": 1}},
+                    "bob": {"vote": {"value": 1}}
+                })
+                .as_object()
+                .cloned()
+                .unwrap_or_default(),
+            }
+        } else {
+            let url = format!(
+                "{}/api/{}/reviews/{}",
+                self.base_url, API_VERSION, review_id
+            );
+            let envelope: ReviewEnvelope = self.get_json(&url).await?;
+            envelope.review
+        };
+
+        let state = review.state.unwrap_or_else(|| "unknown".to_string());
+        let mut result = format!(
+            "Swarm review {} ({})\n\
+             State: {}\n\
+             Approved: {}\n\
+             Author: {}\n\
+             Description: {}\n\
+             Votes:\n",
+            review.id,
+            self.review_url(review.id),
+            state,
+            if state.starts_with("approved") {
+                "yes"
+            } else {
+                "no"
+            },
+            review.author.unwrap_or_default(),
+            review.description.unwrap_or_default().trim()
+        );
+
+        for (user, participant) in &review.participants {
+            let vote = participant
+                .get("vote")
+                .and_then(|v| v.get("value"))
+                .and_then(|v| v.as_i64())
+                .unwrap_or(0);
+            let vote = match vote {
+                v if v > 0 => "up",
+                v if v < 0 => "down",
+                _ => "none",
+            };
+            result.push_str(&format!("  {}: {}\n", user, vote));
+        }
+
+        Ok(result)
+    }
+
+    /// List the comments left on a review.
+    pub async fn review_comments(&self, review_id: &str) -> Result<String> {
+        let review_id = review_number(review_id)?;
+        let comments = if self.mock_mode.is_enabled() {
+            debug!("Mock fetching Swarm comments for review {}", review_id);
+            vec![
+                Comment {
+                    user: "alice".to_string(),
+                    body: "Looks good, but please add a test.".to_string(),
+                    time: Some(1705350645),
+                    context: None,
+                },
+                Comment {
+                    user: "bob".to_string(),
+                    body: "This could overflow for large inputs.".to_string(),
+                    time: Some(1705354245),
+                    context: Some(CommentContext {
+                        file: Some("//depot/main/file2.cpp".to_string()),
+                        right_line: Some(42),
+                    }),
+                },
+            ]
+        } else {
+            let url = format!(
+                "{}/api/{}/comments?topic=reviews/{}",
+                self.base_url, API_VERSION, review_id
+            );
+            let envelope: CommentsEnvelope = self.get_json(&url).await?;
+            envelope.comments
+        };
+
+        let mut result = format!(
+            "Swarm review {} comments ({}):\n",
+            review_id,
+            comments.len()
+        );
+
+        for comment in comments {
+            let location = match comment.context {
+                Some(CommentContext {
+                    file: Some(file),
+                    right_line,
+                }) => match right_line {
+                    Some(line) => format!(" on {}:{}", file, line),
+                    None => format!(" on {}", file),
+                },
+                _ => String::new(),
+            };
+            let time = comment
+                .time
+                .map(|t| format!(" at {}", t))
+                .unwrap_or_default();
+            result.push_str(&format!(
+                "- {}{}{}: {}\n",
+                comment.user, location, time, comment.body
+            ));
+        }
+
+        Ok(result)
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T> {
+        self.check_configured()?;
+        debug!("Swarm request: GET {}", url);
+
+        let response = self
+            .http
+            .get(url)
+            .basic_auth(&self.user, Some(&self.token))
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!(
+                "Swarm request failed ({}): {}",
+                status,
+                body
+            ));
+        }
+
+        Ok(response.json().await?)
+    }
+}
@@ -0,0 +1,140 @@
+//! Message framing for byte-stream transports
+//!
+//! MCP's stdio transport is newline-delimited JSON. Some hosts frame messages the way
+//! LSP does instead: a header block with `Content-Length`, a blank line, then exactly
+//! that many bytes of JSON.
+
+use std::io::{self, BufRead};
+use tracing::warn;
+
+/// The largest `Content-Length` accepted. The body is allocated before it's read, so
+/// a larger length is refused rather than trusted.
+pub const MAX_CONTENT_LENGTH: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Framing {
+    /// One JSON message per line.
+    Ndjson,
+    /// `Content-Length` headers before each message, as in LSP.
+    Lsp,
+    /// Detect the framing from the first message.
+    Auto,
+}
+
+/// Reads framed messages from a byte stream.
+pub struct FrameReader<R> {
+    reader: R,
+    framing: Framing,
+}
+
+impl<R: BufRead> FrameReader<R> {
+    pub fn new(reader: R, framing: Framing) -> Self {
+        Self { reader, framing }
+    }
+
+    /// The framing in use. `Auto` is resolved once the first message has been read.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Read the next message body, or `None` at the end of the input.
+    pub fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(None);
+            };
+
+            if self.framing == Framing::Ndjson {
+                return Ok(Some(line));
+            }
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+            if self.framing == Framing::Auto {
+                self.framing = if is_header(&line) {
+                    Framing::Lsp
+                } else {
+                    Framing::Ndjson
+                };
+                if self.framing == Framing::Ndjson {
+                    return Ok(Some(line));
+                }
+            }
+
+            match self.read_headers(line)? {
+                Some(Some(length)) if length > MAX_CONTENT_LENGTH => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Content-Length {} is over the limit of {} bytes",
+                            length, MAX_CONTENT_LENGTH
+                        ),
+                    ));
+                }
+                Some(Some(length)) => {
+                    let mut body = vec![0; length];
+                    self.reader.read_exact(&mut body)?;
+                    return Ok(Some(body));
+                }
+                Some(None) => warn!("Skipping message without a Content-Length header"),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Read a header block starting with `first`, returning its `Content-Length`.
+    fn read_headers(&mut self, first: Vec<u8>) -> io::Result<Option<Option<usize>>> {
+        let mut length = content_length(&first);
+        loop {
+            let Some(line) = self.read_line()? else {
+                return Ok(None);
+            };
+            if line.trim_ascii().is_empty() {
+                return Ok(Some(length));
+            }
+            length = length.or_else(|| content_length(&line));
+        }
+    }
+
+    /// Read one line without its line ending.
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+        }
+        if self.framing != Framing::Ndjson && line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Ok(Some(line))
+    }
+}
+
+fn header_value<'a>(line: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let colon = line.iter().position(|&b| b == b':')?;
+    line[..colon]
+        .trim_ascii()
+        .eq_ignore_ascii_case(name.as_bytes())
+        .then(|| line[colon + 1..].trim_ascii())
+}
+
+fn is_header(line: &[u8]) -> bool {
+    header_value(line, "Content-Length").is_some() || header_value(line, "Content-Type").is_some()
+}
+
+fn content_length(line: &[u8]) -> Option<usize> {
+    std::str::from_utf8(header_value(line, "Content-Length")?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Frame one outgoing JSON message. `Auto` frames as newline-delimited JSON.
+pub fn frame(json: &str, framing: Framing) -> String {
+    match framing {
+        Framing::Lsp => format!("Content-Length: {}\r\n\r\n{}", json.len(), json),
+        Framing::Ndjson | Framing::Auto => format!("{}\n", json),
+    }
+}
@@ -0,0 +1,311 @@
+//! Transports between MCP clients and an [`MCPServer`]
+//!
+//! A transport hands each incoming JSON-RPC message to [`serve_session`] as text and
+//! delivers whatever it sends back. Responses, notifications and server-initiated
+//! requests share one outgoing channel, so the client sees them in the order they
+//! were produced.
+//!
+//! Programs embedding the server can host a session over their own channel by
+//! implementing [`Transport`] and passing it to [`serve`], as the stdio transport does:
+//!
+//! ```no_run
+//! use p4_mcp::transport::{self, framing::Framing, stdio::StdioTransport};
+//! use p4_mcp::MCPServer;
+//!
+//! # async fn run() -> anyhow::Result<()> {
+//! transport::serve(MCPServer::new(), StdioTransport::new(Framing::Ndjson)).await
+//! # }
+//! ```
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, warn};
+
+use crate::mcp::{
+    ClientRequester, MCPMessage, MCPNotification, MCPResponse, MCPServer, RequestId,
+    INTERNAL_ERROR, PARSE_ERROR,
+};
+use crate::p4::CancelToken;
+
+pub mod framing;
+pub mod socket;
+pub mod sse;
+pub mod stdio;
+
+/// How long sessions finish their tool calls for once the client has gone or the
+/// server is shutting down, by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+/// A connection to one client that messages are read from and written to.
+///
+/// Reads and writes may run at the same time: the server keeps reading while a reply
+/// is being written.
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Read the next message, or `None` once the client has disconnected. A message
+    /// that isn't UTF-8 is answered with a parse error.
+    async fn read_message(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Send one JSON message to the client.
+    async fn write_message(&self, json: &str) -> Result<()>;
+
+    /// Called once the session is over and every reply has been written.
+    async fn close(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Run a session over `transport` until the client disconnects and every request read
+/// from it has been answered, then close it.
+pub async fn serve(server: MCPServer, transport: impl Transport + 'static) -> Result<()> {
+    let transport = Arc::new(transport);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<String>();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<String>();
+
+    let parse_error_tx = outgoing_tx.clone();
+    let reader = transport.clone();
+    let reading = tokio::spawn(async move {
+        // Messages are read as raw bytes so one that isn't UTF-8 is answered with a
+        // parse error instead of ending the session
+        loop {
+            let message = match reader.read_message().await {
+                Ok(Some(bytes)) => decode_line(bytes),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Error reading message: {}", e);
+                    break;
+                }
+            };
+            let sent = match message {
+                Ok(message) => incoming_tx.send(message).is_ok(),
+                Err(parse_error) => parse_error_tx.send(parse_error).is_ok(),
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+
+    let writer = transport.clone();
+    let written = tokio::spawn(async move {
+        while let Some(json) = outgoing_rx.recv().await {
+            if let Err(e) = writer.write_message(&json).await {
+                debug!("Error writing message: {}", e);
+                break;
+            }
+        }
+    });
+
+    serve_session(server, incoming_rx, outgoing_tx).await;
+    // On shutdown the client may still be connected, with the reader waiting on it
+    reading.abort();
+    written.await?;
+    transport.close().await
+}
+
+/// Decode one line (or framed message) of input.
+///
+/// A line that isn't UTF-8 can't be parsed, so the error is the parse error response
+/// to send back in its place.
+pub fn decode_line(bytes: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(bytes).map_err(|e| {
+        warn!("Rejected input that is not valid UTF-8: {}", e);
+        let response =
+            MCPResponse::error(RequestId::Null, PARSE_ERROR, format!("Parse error: {}", e));
+        serde_json::to_string(&response).unwrap_or_default()
+    })
+}
+
+/// Run one client session until `incoming` closes or the server shuts down, and every
+/// request read from it has been answered.
+///
+/// Responses may go out in a different order than the requests arrived; clients match
+/// them up by id. Tool calls still running when the session's shutdown timeout is up
+/// are cancelled, which kills their p4 processes, and answered with an error.
+pub async fn serve_session(
+    mut server: MCPServer,
+    mut incoming: UnboundedReceiver<String>,
+    outgoing: UnboundedSender<String>,
+) {
+    let (notification_tx, mut notification_rx) = mpsc::unbounded_channel::<MCPNotification>();
+    server.set_notification_sender(notification_tx);
+    let (client, mut request_rx) = ClientRequester::new();
+    server.set_client_requester(client.clone());
+
+    // Server-initiated messages are sent as they arrive, even while a request is
+    // being handled, since the handler may be waiting on the client's answer
+    let writer_tx = outgoing.clone();
+    let writer = tokio::spawn(async move {
+        loop {
+            let json = tokio::select! {
+                Some(notification) = notification_rx.recv() => serde_json::to_string(&notification),
+                Some(request) = request_rx.recv() => serde_json::to_string(&request),
+                else => break,
+            };
+            match json {
+                Ok(json) => {
+                    if writer_tx.send(json).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => error!("Failed to serialize outgoing message: {}", e),
+            }
+        }
+    });
+
+    // Responses to server requests are routed before reaching the message loop,
+    // which may be the one waiting on them
+    let (message_tx, mut message_rx) =
+        mpsc::unbounded_channel::<Result<MCPMessage, Box<MCPResponse>>>();
+    tokio::spawn(async move {
+        while let Some(line) = incoming.recv().await {
+            if line.trim().is_empty() || client.handle_response(&line) {
+                continue;
+            }
+            let message = match MCPMessage::parse(&line) {
+                Ok(Some(message)) => Ok(message),
+                Ok(None) => {
+                    debug!("Ignoring notification: {}", line);
+                    continue;
+                }
+                Err(response) => {
+                    warn!("Rejected invalid message: {}", line);
+                    Err(response)
+                }
+            };
+            if message_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    // Requests that may be slow run on forks of the session, so a long `p4 sync`
+    // doesn't hold up pings or other calls; their responses go out as they finish
+    let mut in_flight = JoinSet::new();
+    let mut in_flight_ids = HashMap::new();
+    // Cancelling a request stops only its own p4 commands
+    let mut cancel_tokens: HashMap<RequestId, CancelToken> = HashMap::new();
+    let mut shutdown = server.shutdown_signal();
+    loop {
+        let message = tokio::select! {
+            message = message_rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            // A session whose context is gone can't be shut down
+            Ok(_) = shutdown.wait_for(|shutdown| *shutdown) => {
+                info!("Shutting down; finishing {} requests in flight", in_flight.len());
+                break;
+            }
+        };
+        while let Some(joined) = in_flight.try_join_next_with_id() {
+            let task = joined.map_or_else(|e| e.id(), |(task, ())| task);
+            if let Some(id) = in_flight_ids.remove(&task) {
+                cancel_tokens.remove(&id);
+            }
+        }
+
+        match message {
+            Ok(MCPMessage::Cancelled { params }) => match cancel_tokens.get(&params.request_id) {
+                Some(token) => {
+                    info!(
+                        "Cancelling request {}: {}",
+                        params.request_id,
+                        params.reason.as_deref().unwrap_or("no reason given")
+                    );
+                    token.cancel();
+                }
+                None => debug!("No request {} in flight to cancel", params.request_id),
+            },
+            Ok(message) if message.runs_concurrently() => {
+                let mut worker = server.fork();
+                let cancel = worker.cancel_token();
+                let outgoing = outgoing.clone();
+                let id = message.id().cloned();
+                let task = in_flight.spawn({
+                    let cancel = cancel.clone();
+                    async move {
+                        let response = respond(&mut worker, message).await;
+                        // The client isn't waiting for the answer to a cancelled request
+                        if !cancel.is_cancelled() {
+                            send_response(&outgoing, response);
+                        }
+                    }
+                });
+                if let Some(id) = id {
+                    in_flight_ids.insert(task.id(), id.clone());
+                    cancel_tokens.insert(id, cancel);
+                }
+            }
+            Ok(message) => {
+                let response = respond(&mut server, message).await;
+                if !send_response(&outgoing, response) {
+                    break;
+                }
+            }
+            Err(response) => {
+                if !send_response(&outgoing, Some(*response)) {
+                    break;
+                }
+            }
+        }
+    }
+    let timeout = server.shutdown_timeout();
+    let drained = tokio::time::timeout(timeout, async {
+        while in_flight.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Cancelling {} requests still running after {}s",
+            in_flight.len(),
+            timeout.as_secs()
+        );
+        in_flight.abort_all();
+        while let Some(joined) = in_flight.join_next_with_id().await {
+            let Err(e) = joined else { continue };
+            if let Some(id) = in_flight_ids.remove(&e.id()) {
+                send_response(
+                    &outgoing,
+                    Some(MCPResponse::error(
+                        id,
+                        INTERNAL_ERROR,
+                        "Cancelled: the server shut down before the request finished",
+                    )),
+                );
+            }
+        }
+    }
+
+    writer.abort();
+    let _ = writer.await;
+}
+
+async fn respond(server: &mut MCPServer, message: MCPMessage) -> Option<MCPResponse> {
+    let id = message.id().cloned();
+    match server.handle_message(message).await {
+        Ok(response) => response,
+        Err(e) => {
+            error!("Error handling message: {}", e);
+            id.map(|id| MCPResponse::error(id, INTERNAL_ERROR, e.to_string()))
+        }
+    }
+}
+
+/// Queue a response for the client. Returns `false` once the client is gone.
+fn send_response(outgoing: &UnboundedSender<String>, response: Option<MCPResponse>) -> bool {
+    let Some(response) = response else {
+        return true;
+    };
+    match serde_json::to_string(&response) {
+        Ok(json) => outgoing.send(json).is_ok(),
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            true
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Local socket transport: a Unix domain socket, or a named pipe on Windows
+//!
+//! Connections speak newline-delimited JSON like stdio, and each one is a separate
+//! session over the shared [`ServerContext`]. This lets a supervisor keep one
+//! long-lived server that several local tools connect to, without opening a TCP port.
+
+use anyhow::Result;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+
+use crate::mcp::{MCPServer, ServerContext};
+
+/// Accept connections on the Unix domain socket at `path` until the server shuts down
+/// and its sessions have finished.
+///
+/// A stale socket left behind by a previous server is replaced. The socket is only
+/// accessible to the current user.
+#[cfg(unix)]
+pub async fn serve(path: &Path, context: ServerContext) -> Result<()> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow::anyhow!(
+                "{} exists and is not a socket",
+                path.display()
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Serving MCP on Unix socket {}", path.display());
+
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = context.shutdown_requested() => break,
+        };
+        while connections.try_join_next().is_some() {}
+        connections.spawn(serve_connection(stream, context.clone()));
+    }
+    while connections.join_next().await.is_some() {}
+    let _ = std::fs::remove_file(path);
+    Ok(())
+}
+
+/// Accept connections on the named pipe `path` (e.g. `\\.\pipe\p4-mcp`) until the
+/// server shuts down and its sessions have finished.
+#[cfg(windows)]
+pub async fn serve(path: &Path, context: ServerContext) -> Result<()> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let mut pipe = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)?;
+    info!("Serving MCP on named pipe {}", path.display());
+
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            connected = pipe.connect() => connected?,
+            _ = context.shutdown_requested() => break,
+        }
+        // Create the next instance before handing this one off, so clients never
+        // find the pipe missing
+        let connected = std::mem::replace(&mut pipe, ServerOptions::new().create(path)?);
+        while connections.try_join_next().is_some() {}
+        connections.spawn(serve_connection(connected, context.clone()));
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn serve_connection<S>(stream: S, context: ServerContext)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<String>();
+    let (outgoing_tx, mut outgoing_rx) = mpsc::unbounded_channel::<String>();
+    debug!("Local socket connection opened");
+
+    let parse_error_tx = outgoing_tx.clone();
+    let reading = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        let mut bytes = Vec::new();
+        loop {
+            bytes.clear();
+            match reader.read_until(b'\n', &mut bytes).await {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Error reading from local socket: {}", e);
+                    break;
+                }
+            }
+            if bytes.last() == Some(&b'\n') {
+                bytes.pop();
+            }
+            let sent = match super::decode_line(std::mem::take(&mut bytes)) {
+                Ok(line) => incoming_tx.send(line).is_ok(),
+                Err(parse_error) => parse_error_tx.send(parse_error).is_ok(),
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+
+    let server = MCPServer::with_context(&context);
+    tokio::spawn(async move {
+        super::serve_session(server, incoming_rx, outgoing_tx).await;
+        // On shutdown the client may still be connected, with the reader waiting on it
+        reading.abort();
+    });
+
+    while let Some(json) = outgoing_rx.recv().await {
+        let written = async {
+            writer.write_all(json.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+            writer.flush().await
+        };
+        if written.await.is_err() {
+            break;
+        }
+    }
+    debug!("Local socket connection closed");
+}
@@ -0,0 +1,127 @@
+//! Legacy HTTP+SSE transport (MCP 2024-11-05)
+//!
+//! `GET /sse` opens an event stream whose first `endpoint` event names the URL to POST
+//! messages to, `/messages?sessionId=<id>`. POSTs are answered with `202 Accepted`;
+//! responses, notifications and server requests arrive as `message` events on the
+//! stream. Every stream is a separate [`MCPServer`] session over the shared
+//! [`ServerContext`], and ends when the client disconnects.
+
+use anyhow::Result;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::routing::{get, post};
+use axum::Router;
+use futures::stream::{self, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tracing::info;
+
+use crate::mcp::{MCPServer, ServerContext};
+
+type Sessions = Arc<Mutex<HashMap<String, UnboundedSender<String>>>>;
+
+#[derive(Clone)]
+struct TransportState {
+    context: ServerContext,
+    sessions: Sessions,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageParams {
+    #[serde(rename = "sessionId")]
+    session_id: String,
+}
+
+/// Removes a session when its event stream is dropped, which closes the session's input.
+struct SessionGuard {
+    id: String,
+    sessions: Sessions,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        info!("SSE session {} closed", self.id);
+        lock(&self.sessions).remove(&self.id);
+    }
+}
+
+fn lock(
+    sessions: &Sessions,
+) -> std::sync::MutexGuard<'_, HashMap<String, UnboundedSender<String>>> {
+    sessions.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Routes for the `/sse` event stream, the `/messages` endpoint and `/metrics`.
+pub fn router(context: ServerContext) -> Router {
+    let metrics = crate::metrics::router(context.metrics());
+    Router::new()
+        .route("/sse", get(open_stream))
+        .route("/messages", post(post_message))
+        .with_state(TransportState {
+            context,
+            sessions: Sessions::default(),
+        })
+        .merge(metrics)
+}
+
+/// Serve the HTTP+SSE transport on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, context: ServerContext) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!(
+        "Serving MCP over HTTP+SSE at http://{}/sse",
+        listener.local_addr()?
+    );
+    // Streams end when their sessions do, which lets the server stop
+    let shutdown = context.clone();
+    axum::serve(listener, router(context))
+        .with_graceful_shutdown(async move { shutdown.shutdown_requested().await })
+        .await?;
+    Ok(())
+}
+
+async fn open_stream(
+    State(TransportState { context, sessions }): State<TransportState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let id = uuid::Uuid::new_v4().to_string();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let (outgoing_tx, outgoing_rx) = mpsc::unbounded_channel();
+    lock(&sessions).insert(id.clone(), incoming_tx);
+    let mut server = MCPServer::with_context(&context);
+    server.set_session_id(&id);
+    tokio::spawn(super::serve_session(server, incoming_rx, outgoing_tx));
+    info!("SSE session {} opened", id);
+
+    let endpoint = Event::default()
+        .event("endpoint")
+        .data(format!("/messages?sessionId={}", id));
+    let guard = SessionGuard { id, sessions };
+    let messages = stream::unfold(
+        (outgoing_rx, guard),
+        |(mut outgoing, guard): (UnboundedReceiver<String>, SessionGuard)| async move {
+            let json = outgoing.recv().await?;
+            Some((
+                Ok(Event::default().event("message").data(json)),
+                (outgoing, guard),
+            ))
+        },
+    );
+
+    Sse::new(stream::once(async { Ok(endpoint) }).chain(messages)).keep_alive(KeepAlive::default())
+}
+
+async fn post_message(
+    State(TransportState { sessions, .. }): State<TransportState>,
+    Query(params): Query<MessageParams>,
+    body: String,
+) -> StatusCode {
+    let session = lock(&sessions).get(&params.session_id).cloned();
+    match session {
+        Some(session) if session.send(body).is_ok() => StatusCode::ACCEPTED,
+        _ => StatusCode::NOT_FOUND,
+    }
+}
@@ -0,0 +1,66 @@
+//! The stdio transport: messages on stdin, replies on stdout
+//!
+//! Messages are framed as newline-delimited JSON by default, or with `Content-Length`
+//! headers; see [`framing`](super::framing).
+
+use anyhow::Result;
+use async_trait::async_trait;
+use std::io::{self, BufReader, Stdin, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::framing::{self, FrameReader, Framing};
+use super::Transport;
+
+pub struct StdioTransport {
+    reader: Arc<Mutex<FrameReader<BufReader<Stdin>>>>,
+    /// Replies use the framing the client sends, which `auto` only learns from its
+    /// first message.
+    lsp_output: AtomicBool,
+}
+
+impl StdioTransport {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            reader: Arc::new(Mutex::new(FrameReader::new(
+                BufReader::new(io::stdin()),
+                framing,
+            ))),
+            lsp_output: AtomicBool::new(framing == Framing::Lsp),
+        }
+    }
+}
+
+#[async_trait]
+impl Transport for StdioTransport {
+    async fn read_message(&self) -> Result<Option<Vec<u8>>> {
+        // Read stdin on a blocking thread so it can't starve the runtime's workers
+        let reader = self.reader.clone();
+        let (message, framing) = tokio::task::spawn_blocking(move || {
+            let mut reader = reader.lock().unwrap_or_else(|e| e.into_inner());
+            (reader.read_message(), reader.framing())
+        })
+        .await?;
+        if framing == Framing::Lsp {
+            self.lsp_output.store(true, Ordering::SeqCst);
+        }
+        Ok(message?)
+    }
+
+    async fn write_message(&self, json: &str) -> Result<()> {
+        let framing = if self.lsp_output.load(Ordering::SeqCst) {
+            Framing::Lsp
+        } else {
+            Framing::Ndjson
+        };
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(framing::frame(json, framing).as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    async fn close(&self) -> Result<()> {
+        io::stdout().flush()?;
+        Ok(())
+    }
+}
@@ -1,8 +1,13 @@
 //! Integration tests for the p4-mcp server
 //! These tests read JSON messages from test_data files to ensure consistency with manual testing
 
-use p4_mcp::mcp::{MCPMessage, MCPResponse, MCPServer, ToolContent};
+#![allow(clippy::collapsible_match, clippy::single_component_path_imports)]
+
+use p4_mcp::mcp::{
+    ClientRequester, MCPMessage, MCPResponse, MCPServer, RequestId, ServerContext, ToolContent,
+};
 use serde_json;
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::path::Path;
@@ -27,6 +32,16 @@ fn load_test_message(filename: &str) -> MCPMessage {
     })
 }
 
+/// Create a server that has completed the initialize handshake
+async fn initialized_server() -> MCPServer {
+    let mut server = MCPServer::new();
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    server
+}
+
 #[tokio::test]
 async fn test_initialize_endpoint() {
     setup_mock_mode();
@@ -41,13 +56,10 @@ async fn test_initialize_endpoint() {
     let response = response.unwrap();
     assert!(response.is_some());
 
-    if let Some(MCPResponse::InitializeResult {
-        jsonrpc,
-        id,
-        result,
-    }) = response
-    {
-        assert_eq!(jsonrpc, "2.0");
+    let serialized = serde_json::to_value(response.as_ref().unwrap()).unwrap();
+    assert_eq!(serialized["jsonrpc"], "2.0");
+
+    if let Some(MCPResponse::InitializeResult { id, result }) = response {
         assert_eq!(id, 0);
         assert_eq!(result.protocol_version, "2024-11-05");
         assert!(result.capabilities.tools.is_some());
@@ -75,7 +87,7 @@ async fn test_list_tools_endpoint() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::ListToolsResult { id, result }) = response {
-        assert_eq!(id, 2);
+        assert_eq!(id, "2");
         assert!(!result.tools.is_empty());
 
         // Verify we have expected tools
@@ -91,7 +103,7 @@ async fn test_list_tools_endpoint() {
 #[tokio::test]
 async fn test_ping_endpoint() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     // Create a simple ping message (not in test_data, so we'll create it inline)
     let ping_message = serde_json::from_str(r#"{"method": "ping", "id": "ping-test"}"#).unwrap();
@@ -103,7 +115,7 @@ async fn test_ping_endpoint() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::Pong { id }) = response {
-        assert_eq!(id, 1);
+        assert_eq!(id, "ping-test");
     } else {
         panic!("Expected Pong response");
     }
@@ -128,13 +140,12 @@ async fn test_p4_status_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 3);
+        assert_eq!(id, "3");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
             if let ToolContent::Text { text } = content {
-                assert!(text.contains("Mock P4 Status"));
-                assert!(text.contains("//depot/main/src/..."));
+                assert!(text.contains("//depot/main/file1.txt#1 - edit default change (text)\n"));
             }
         }
     } else {
@@ -161,7 +172,7 @@ async fn test_p4_sync_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "sync-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
@@ -194,7 +205,7 @@ async fn test_p4_edit_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "edit-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
@@ -228,7 +239,7 @@ async fn test_p4_add_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "add-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
@@ -274,18 +285,86 @@ async fn test_p4_submit_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "submit-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
             if let ToolContent::Text { text } = content {
-                assert!(text.contains("Mock P4 Submit"));
-                assert!(text.contains("Test submission from integration test"));
+                assert!(text.contains("Change 12348 created."));
+                assert!(text.contains("test1.txt#1 - reopened; change 12348"));
+                assert!(text.ends_with("Submitted change 12348."));
+            } else {
+                panic!("Expected text content");
             }
         }
     } else {
         panic!("Expected CallToolResult response");
     }
+    // Without files, the default changelist is moved into the new change as a whole
+    let submit_default = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": "submit-default",
+        "params": {"name": "p4_submit", "arguments": {"description": "Submit everything"}}
+    }))
+    .unwrap();
+    match server.handle_message(submit_default).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => {
+                assert!(!text.contains("reopened"));
+                assert!(text.contains("Change 12348 submitted."));
+            }
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_submit_reports_the_change_left_by_a_failed_reopen() {
+    use p4_mcp::p4::{ExecutionLog, P4Handler};
+
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[mock_faults.commands.reopen]\nerror_rate = 1.0\nerrors = [\"locked_file\"]\n",
+    )
+    .unwrap();
+    let mut handler = P4Handler::new();
+    handler.set_mock_faults(config.mock_faults.clone());
+    let executions = ExecutionLog::new();
+    handler.set_execution_log(Some(executions.clone()));
+
+    let error = handler
+        .submit(
+            "Fix the crash on startup",
+            Some(vec!["//depot/main/file1.txt".to_string()]),
+            Vec::new(),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("pending change 12348 couldn't be removed"),
+        "{}",
+        error
+    );
+
+    // The files in the new change were to be moved back before deleting it, and
+    // nothing was submitted
+    let lines: Vec<String> = executions
+        .commands()
+        .into_iter()
+        .map(|executed| executed.command_line)
+        .collect();
+    assert!(
+        lines.iter().any(|line| line.contains("reopen -c default")),
+        "{:?}",
+        lines
+    );
+    assert!(
+        !lines.iter().any(|line| line.contains(" submit ")),
+        "{:?}",
+        lines
+    );
 }
 
 #[tokio::test]
@@ -320,7 +399,7 @@ async fn test_p4_revert_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "revert-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
@@ -353,12 +432,12 @@ async fn test_p4_opened_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "opened-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
             if let ToolContent::Text { text } = content {
-                assert!(text.contains("Mock P4 Opened"));
+                assert!(text.contains("//depot/main/file3.h#1 - edit change 12346 (text)\n"));
             }
         }
     } else {
@@ -385,12 +464,14 @@ async fn test_p4_changes_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "changes-test");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
             if let ToolContent::Text { text } = content {
-                assert!(text.contains("Mock P4 Changes"));
+                assert!(text.contains(
+                    "Change 12350 on 2024/01/15 by user@workspace 'Sample change description 1'\n"
+                ));
             }
         }
     } else {
@@ -417,12 +498,20 @@ async fn test_p4_info_tool() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::CallToolResult { id, result }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "4");
         assert!(result.content.len() == 1);
 
         if let Some(content) = result.content.first() {
             if let ToolContent::Text { text } = content {
-                assert!(text.contains("Mock P4 Info"));
+                let info: serde_json::Value = serde_json::from_str(text).unwrap();
+                assert_eq!(info["userName"], "testuser");
+                assert_eq!(info["clientName"], "test-client");
+                assert_eq!(info["clientRoot"], "C:\\workspace\\p4\\test-client");
+                assert_eq!(info["clientHost"], "test-host");
+                assert_eq!(info["serverAddress"], "perforce.example.com:1666");
+                assert_eq!(info["serverRoot"], "/opt/perforce/depot");
+                // The mock client isn't a stream client
+                assert!(info.get("clientStream").is_none());
             }
         }
     } else {
@@ -430,6 +519,156 @@ async fn test_p4_info_tool() {
     }
 }
 
+#[tokio::test]
+async fn test_p4_info_reports_the_detected_workspace() {
+    use p4_mcp::p4::backend::exit_status;
+    use p4_mcp::p4::{Invocation, MockMode, P4Backend, P4Connection, P4Handler};
+    use std::process::Output;
+    use std::sync::Arc;
+
+    /// A stream client rooted at `root`.
+    struct StreamClient {
+        root: std::path::PathBuf,
+    }
+
+    #[async_trait::async_trait]
+    impl P4Backend for StreamClient {
+        async fn execute(&self, invocation: &Invocation<'_>) -> anyhow::Result<Output> {
+            let stdout = if invocation.args.iter().any(|arg| arg == "info") {
+                format!(
+                    "... userName alice\n... clientName alice-dev\n... clientHost build-01\n\
+                     ... clientRoot {}\n... clientStream //streams/dev\n\
+                     ... serverAddress ssl:perforce:1666\n... serverRoot /p4/root\n",
+                    self.root.display()
+                )
+            } else {
+                "exit: 0\n".to_string()
+            };
+            Ok(Output {
+                status: exit_status(0),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn program(&self) -> String {
+            "stream-client".to_string()
+        }
+    }
+
+    let root = tempfile::tempdir().unwrap();
+    let mut handler = P4Handler::new();
+    handler.set_mock_mode(MockMode::new(false));
+    handler.set_connection(P4Connection {
+        charset: Some("none".to_string()),
+        ..Default::default()
+    });
+    handler.set_backend(Some(Arc::new(StreamClient {
+        root: root.path().to_path_buf(),
+    })));
+
+    // What the server finds at startup
+    let workspace = handler.detect_workspace().await.unwrap().unwrap();
+    assert_eq!(workspace.client, "alice-dev");
+    assert_eq!(workspace.root, root.path().display().to_string());
+    assert_eq!(workspace.stream.as_deref(), Some("//streams/dev"));
+    assert_eq!(workspace.local_root().as_deref(), Some(root.path()));
+    assert_eq!(handler.client_root().await.as_deref(), Some(root.path()));
+
+    let mut server = MCPServer::builder().p4_handler(handler).build();
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": "info",
+        "params": {"name": "p4_info", "arguments": {"format": "text"}}
+    }))
+    .unwrap();
+    let text = match server.handle_message(call).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult response, got {:?}", other),
+    };
+    let info: serde_json::Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(info["userName"], "alice");
+    assert_eq!(info["clientName"], "alice-dev");
+    assert_eq!(info["clientHost"], "build-01");
+    assert_eq!(info["clientRoot"], root.path().display().to_string());
+    assert_eq!(info["clientStream"], "//streams/dev");
+    assert_eq!(info["serverAddress"], "ssl:perforce:1666");
+    assert_eq!(info["serverRoot"], "/p4/root");
+}
+
+#[tokio::test]
+async fn test_arguments_checked_against_input_schema() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    for (id, name, arguments, pointer, message) in [
+        (
+            1,
+            "p4_edit",
+            serde_json::json!({}),
+            "/files",
+            "Invalid arguments for p4_edit: /files: is required",
+        ),
+        (
+            2,
+            "p4_edit",
+            serde_json::json!({"files": ["a.c", 3]}),
+            "/files/1",
+            "Invalid arguments for p4_edit: /files/1: expected string, got integer",
+        ),
+        (
+            3,
+            "p4_changes",
+            serde_json::json!({"max": "10"}),
+            "/max",
+            "Invalid arguments for p4_changes: /max: expected integer, got string",
+        ),
+    ] {
+        match server
+            .handle_message(call(id, name, arguments))
+            .await
+            .unwrap()
+        {
+            Some(MCPResponse::Error { error, .. }) => {
+                assert_eq!(error.code, -32602);
+                assert_eq!(error.message, message);
+                assert_eq!(error.data.unwrap()["pointer"], pointer);
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    // Arguments the schema doesn't declare are let through
+    let response = server
+        .handle_message(call(
+            4,
+            "p4_changes",
+            serde_json::json!({"max": 5, "extra": 1}),
+        ))
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, Some(MCPResponse::CallToolResult { .. })),
+        "{:?}",
+        response
+    );
+}
+
 #[tokio::test]
 async fn test_unknown_tool_error() {
     setup_mock_mode();
@@ -460,7 +699,7 @@ async fn test_unknown_tool_error() {
     assert!(response.is_some());
 
     if let Some(MCPResponse::Error { id, error }) = response {
-        assert_eq!(id, 123);
+        assert_eq!(id, "unknown-test");
         assert!(error.message.contains("Unknown tool"));
     } else {
         panic!("Expected Error response");
@@ -582,3 +821,5047 @@ async fn test_edge_cases_and_boundary_values() {
     let response = server.handle_message(long_desc_message).await;
     assert!(response.is_ok());
 }
+
+#[tokio::test]
+async fn test_swarm_create_review_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let review_message = serde_json::from_str(
+        r#"
+    {
+        "method": "tools/call",
+        "id": 42,
+        "params": {
+            "name": "swarm_create_review",
+            "arguments": {
+                "changelist": "12345",
+                "reviewers": ["alice"]
+            }
+        }
+    }"#,
+    )
+    .unwrap();
+
+    let response = server.handle_message(review_message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { id, result }) = response {
+        assert_eq!(id, 42);
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.contains("change 12345"));
+            assert!(text.contains("/reviews/"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_swarm_review_status_and_comments_tools() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let status_message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 43,
+        "params": {"name": "swarm_review_status", "arguments": {"review": "12206"}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(status_message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.contains("Swarm review 12206"));
+            assert!(text.contains("Approved: yes"));
+            assert!(text.contains("alice: up"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let comments_message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 44,
+        "params": {"name": "swarm_review_comments", "arguments": {"review": "12206"}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(comments_message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.contains("comments (2)"));
+            assert!(text.contains("//depot/main/file2.cpp:42"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Review ids go into API URLs, so anything but a number is refused
+    for (id, tool, review) in [
+        (45, "swarm_review_status", "1/../../projects"),
+        (46, "swarm_review_comments", "1&max=1000"),
+    ] {
+        let message = serde_json::from_value(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": tool, "arguments": {"review": review}}
+        }))
+        .unwrap();
+        let error = server
+            .handle_message(message)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Invalid Swarm review id"), "{}", error);
+    }
+}
+
+#[tokio::test]
+async fn test_p4_submit_with_job_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 45,
+        "params": {
+            "name": "p4_submit_with_job",
+            "arguments": {"job": "job000123", "changelist": "12345"}
+        }
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.contains("job000123 fixed by change 12345"));
+            assert!(text.contains("Change 12345 submitted."));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Submitting from the default changelist is rejected before running any command
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 46,
+        "params": {
+            "name": "p4_submit_with_job",
+            "arguments": {"job": "job000123", "changelist": "default"}
+        }
+    }))
+    .unwrap();
+
+    assert!(server.handle_message(message).await.is_err());
+}
+
+#[tokio::test]
+async fn test_p4_workspace_health_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 47,
+        "params": {"name": "p4_workspace_health", "arguments": {}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(report["client"], "test-client");
+            assert_eq!(report["opened"]["count"], 3);
+            assert_eq!(report["needs_resolve"]["count"], 2);
+            assert_eq!(
+                report["out_of_date"]["files"][0],
+                "//depot/main/file4.cpp#5"
+            );
+            assert_eq!(report["shelved_changes"]["count"], 1);
+            assert_eq!(report["ticket"]["valid"], true);
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_describe_diff_caps() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_describe", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        _ => panic!("Expected CallToolResult response"),
+    };
+
+    // Without diffs, just the change
+    let response = server
+        .handle_message(call(1, serde_json::json!({"changelist": "12345"})))
+        .await
+        .unwrap();
+    let described = text(response);
+    assert!(described.contains("Affected files ..."));
+    assert!(!described.contains("===="));
+
+    // Both diffs fit under the default cap
+    let response = server
+        .handle_message(call(
+            2,
+            serde_json::json!({"changelist": "12345", "diffs": true}),
+        ))
+        .await
+        .unwrap();
+    let described = text(response);
+    assert!(described.contains("==== //depot/main/file1.txt#3 (text) ====\n\n@@ -1,2 +1,2 @@"));
+    assert!(described.contains("option_150"));
+
+    // The large one is left out for a note on how to fetch it
+    let response = server
+        .handle_message(call(
+            3,
+            serde_json::json!({"changelist": "12345", "diffs": true, "max_diff_kb": 2}),
+        ))
+        .await
+        .unwrap();
+    let described = text(response);
+    assert!(described.contains("+new line"));
+    assert!(!described.contains("option_150"));
+    assert!(described.contains(
+        "==== //depot/main/file2.cpp#4 (text) ====\n\ndiff omitted (8 KB), fetch with \
+         p4_diff2 //depot/main/file2.cpp#3 //depot/main/file2.cpp#4"
+    ));
+
+    // Only the selected files' diffs
+    let response = server
+        .handle_message(call(
+            4,
+            serde_json::json!({
+                "changelist": "12345",
+                "diffs": true,
+                "files": ["//depot/main/file2.cpp"]
+            }),
+        ))
+        .await
+        .unwrap();
+    let described = text(response);
+    assert!(!described.contains("==== //depot/main/file1.txt"));
+    assert!(described.contains("==== //depot/main/file2.cpp#4"));
+
+    let error = server
+        .handle_message(call(
+            5,
+            serde_json::json!({
+                "changelist": "12345",
+                "diffs": true,
+                "files": ["//depot/other/..."]
+            }),
+        ))
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("has no diff for //depot/other/..."));
+}
+
+#[tokio::test]
+async fn test_p4_diff_shelf_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_diff_shelf", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        _ => panic!("Expected CallToolResult response"),
+    };
+
+    // Every shelved file against the workspace, by default
+    let response = server
+        .handle_message(call(1, serde_json::json!({"changelist": "12347"})))
+        .await
+        .unwrap();
+    let diff = text(response);
+    assert!(diff.contains("--- //depot/main/file1.txt@=12347"));
+    assert!(diff.contains("--- //depot/main/file3.h@=12347"));
+    assert!(diff.contains("+workspace line"));
+
+    // Selected files against their head revisions
+    let response = server
+        .handle_message(call(
+            2,
+            serde_json::json!({
+                "changelist": "12347",
+                "files": ["//depot/main/file3.h"],
+                "against": "head"
+            }),
+        ))
+        .await
+        .unwrap();
+    let diff = text(response);
+    assert!(diff.starts_with(
+        "==== //depot/main/file3.h#head (text) - //depot/main/file3.h@=12347 (text) ===="
+    ));
+    assert!(!diff.contains("file1.txt"));
+}
+
+#[tokio::test]
+async fn test_p4_integrated_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 47,
+        "params": {
+            "name": "p4_integrated",
+            "arguments": {"path": "//depot/release/1.0/..."}
+        }
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        // The text is laid out from the same records, as p4 integrated prints them
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert_eq!(
+                text,
+                "//depot/release/1.0/file1.txt#1 - branch from //depot/main/file1.txt#1,#2\n\
+                 //depot/release/1.0/file1.txt#2 - copy from //depot/main/file1.txt#3\n\
+                 //depot/release/1.0/file2.cpp#2 - merge from //depot/main/file2.cpp#2,#4\n"
+            );
+        }
+
+        // Change 12361 brought main's file2.cpp#2-#4 to the release branch
+        let structured = result.structured_content.unwrap();
+        let integrations = structured["integrations"].as_array().unwrap();
+        assert_eq!(integrations.len(), 3);
+        assert_eq!(integrations[2]["fromFile"], "//depot/main/file2.cpp");
+        assert_eq!(integrations[2]["endFromRev"], "#4");
+        assert_eq!(integrations[2]["how"], "merge from");
+        assert_eq!(integrations[2]["change"], "12361");
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_populate_tool() {
+    setup_mock_mode();
+    let config =
+        p4_mcp::Config::parse("[confirmation]\ntools = { p4_populate = \"token\" }\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_populate", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let result = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => (text.clone(), result.structured_content),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    let branch = serde_json::json!({
+        "from": "//depot/main/...",
+        "to": "//depot/rel/2.0/...",
+        "description": "Branch release 2.0"
+    });
+
+    // The first call previews the files it would branch
+    let (text, structured) = result(
+        server
+            .handle_message(call(1, branch.clone()))
+            .await
+            .unwrap(),
+    );
+    assert!(
+        text.starts_with("Confirmation required: Branch //depot/main/... into //depot/rel/2.0/...")
+    );
+    assert!(
+        text.contains("//depot/rel/2.0/file1.txt#1 - branch/sync from //depot/main/file1.txt#1,#3")
+    );
+    assert!(!text.contains("files branched"));
+    let mut confirmed = branch.clone();
+    confirmed["confirmation_token"] = structured.unwrap()["confirmationToken"].clone();
+
+    let (text, _) = result(server.handle_message(call(2, confirmed)).await.unwrap());
+    assert_eq!(text.trim_end(), "3 files branched (change 12362).");
+
+    let mut undescribed = branch.clone();
+    undescribed["description"] = " ".into();
+    let (text, structured) = result(
+        server
+            .handle_message(call(3, undescribed.clone()))
+            .await
+            .unwrap(),
+    );
+    undescribed["confirmation_token"] = structured.unwrap()["confirmationToken"].clone();
+    assert!(text.starts_with("Confirmation required"));
+    let error = server
+        .handle_message(call(4, undescribed))
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("A description for the change is required"));
+
+    // populate submits, so it's held to the submit policy before anything is branched
+    let config =
+        p4_mcp::Config::parse("[submit]\nmin_description_length = 10\nbanned_files = [\"*.h\"]\n")
+            .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let error = server
+        .handle_message(call(5, branch.clone()))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("//depot/rel/2.0/file3.h may not be submitted"),
+        "{}",
+        error
+    );
+    let mut short = branch.clone();
+    short["description"] = "2.0".into();
+    let error = server
+        .handle_message(call(6, short))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("Change description is too short"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn test_p4_workspace_create_tool() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[workspace_templates.main]\n\
+         root = \"/home/{user}/ws/{client}\"\n\
+         view = [\"//depot/main/... //{client}/...\"]\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_workspace_create", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    let response = server
+        .handle_message(call(
+            1,
+            serde_json::json!({"template": "main", "name": "alice-main", "sync": true}),
+        ))
+        .await
+        .unwrap();
+    let report: serde_json::Value = match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str(text).unwrap(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert_eq!(report["client"]["client"], "alice-main");
+    assert_eq!(report["client"]["root"], "/home/testuser/ws/alice-main");
+    assert_eq!(
+        report["client"]["view"][0]["clientPath"],
+        "//alice-main/..."
+    );
+    assert_eq!(report["result"], "Client alice-main saved.");
+    assert_eq!(report["synced"], true);
+    assert_eq!(report["synced_files"], 2);
+
+    // An existing client is never overwritten
+    let error = server
+        .handle_message(call(
+            2,
+            serde_json::json!({"template": "main", "name": "test-client"}),
+        ))
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Client test-client already exists"));
+
+    let error = server
+        .handle_message(call(
+            3,
+            serde_json::json!({"template": "release", "name": "alice-release"}),
+        ))
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("Unknown workspace template release"));
+
+    // In a sandbox, the root has to be an allowed local path, and the new client is
+    // synced through the depot paths of its view
+    let config = p4_mcp::Config::parse(
+        "[workspace_templates.main]\n\
+         root = \"/home/{user}/ws/{client}\"\n\
+         view = [\"//depot/main/... //{client}/...\"]\n\n\
+         [sandbox]\n\
+         depot = [\"//depot/main/...\"]\n\
+         local = [\"/home/testuser/ws\"]\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let error = server
+        .handle_message(call(
+            4,
+            serde_json::json!({"template": "main", "name": "alice-main", "root": "/etc/alice"}),
+        ))
+        .await
+        .unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Path /etc/alice is outside the allowed local paths"),
+        "{}",
+        error
+    );
+    let response = server
+        .handle_message(call(
+            5,
+            serde_json::json!({"template": "main", "name": "alice-main", "sync": true}),
+        ))
+        .await
+        .unwrap();
+    let report: serde_json::Value = match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str(text).unwrap(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert_eq!(report["synced"], true);
+}
+
+#[tokio::test]
+async fn test_p4_pending_work_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 47,
+        "params": {"name": "p4_pending_work", "arguments": {}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        let work = result.structured_content.unwrap();
+        assert_eq!(work["user"], "testuser");
+        assert_eq!(work["client"], "test-client");
+
+        let changelists = work["changelists"].as_array().unwrap();
+        assert_eq!(changelists.len(), 3);
+        assert_eq!(changelists[0]["change"], "default");
+        assert_eq!(
+            changelists[0]["files"][0]["depotFile"],
+            "//depot/main/file1.txt"
+        );
+        assert_eq!(
+            changelists[0]["files"][1]["depotFile"],
+            "//depot/main/file2.cpp"
+        );
+
+        assert_eq!(changelists[1]["change"], "12346");
+        assert_eq!(changelists[1]["description"], "Refactor the config loader");
+        assert_eq!(changelists[1]["shelved"], false);
+        assert_eq!(
+            changelists[1]["files"][0]["depotFile"],
+            "//depot/main/file3.h"
+        );
+
+        // Shelved, with nothing left open in the workspace
+        assert_eq!(changelists[2]["change"], "12347");
+        assert_eq!(changelists[2]["shelved"], true);
+        assert!(changelists[2]["files"].as_array().unwrap().is_empty());
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_submit_preflight_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 48,
+        "params": {"name": "p4_submit_preflight", "arguments": {"changelist": "12345"}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            let checks = report["checks"].as_array().unwrap();
+            let check = |name: &str| checks.iter().find(|c| c["name"] == name).unwrap();
+
+            // The mock workspace has unresolved files and a file locked by alice
+            assert_eq!(report["pass"], false);
+            assert_eq!(check("description_policy")["pass"], true);
+            assert_eq!(check("all_resolved")["pass"], false);
+            assert_eq!(check("no_foreign_locks")["pass"], false);
+            assert!(check("no_foreign_locks")["reasons"][0]
+                .as_str()
+                .unwrap()
+                .contains("alice@alice-ws"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_predict_conflicts_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 49,
+        "params": {
+            "name": "p4_predict_conflicts",
+            "arguments": {"from": "//depot/main/...", "to": "//depot/rel/..."}
+        }
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(report["files_to_integrate"], 3);
+            assert_eq!(report["will_conflict"], true);
+            assert_eq!(report["needs_resolve"].as_array().unwrap().len(), 2);
+            assert_eq!(report["conflicts"].as_array().unwrap().len(), 1);
+            assert_eq!(report["conflicts"][0]["conflicting_chunks"], 1);
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_sparse_view_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 50,
+        "params": {
+            "name": "p4_sparse_view",
+            "arguments": {"paths": ["//depot/main/src/...", "//depot/main/src/engine"], "apply": true}
+        }
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(report["client"], "test-client");
+            assert_eq!(report["view"].as_array().unwrap().len(), 1);
+            assert_eq!(
+                report["view"][0],
+                "//depot/main/src/... //test-client/main/src/..."
+            );
+            assert_eq!(report["applied"], true);
+            assert_eq!(report["result"], "Client test-client saved.");
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Confirming with a token previews the view it replaces
+    let config =
+        p4_mcp::Config::parse("[confirmation]\ntools = { p4_sparse_view = \"token\" }\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 51,
+        "params": {
+            "name": "p4_sparse_view",
+            "arguments": {"paths": ["//depot/main/src/..."], "apply": true}
+        }
+    }))
+    .unwrap();
+    match server.handle_message(message).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => {
+                assert!(text.starts_with("Confirmation required: Replace the current client view"));
+                assert!(
+                    text.contains(
+                        "Client view:\n\
+                         Current view of test-client:\n\
+                         \t//depot/... //test-client/...\n\
+                         New view:\n\
+                         \t//depot/main/src/... //test-client/main/src/...\n"
+                    ),
+                    "{}",
+                    text
+                );
+            }
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_p4_stream_graph_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 51,
+        "params": {"name": "p4_stream_graph", "arguments": {"mermaid": true}}
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(report["count"], 4);
+            assert_eq!(report["streams"][0]["stream"], "//streams/main");
+            assert!(report["mermaid"]
+                .as_str()
+                .unwrap()
+                .contains("streams_dev --> streams_feature_x"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_p4_release_notes_tool() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 52,
+        "params": {
+            "name": "p4_release_notes",
+            "arguments": {"from": "rel-1.0", "to": "rel-1.1", "path": "//depot/main/..."}
+        }
+    }))
+    .unwrap();
+
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            let report: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(report["count"], 3);
+            assert_eq!(report["changes"][0]["change"], 12353);
+            assert_eq!(report["changes"][0]["user"], "alice");
+            assert_eq!(report["changes"][0]["jobs"][0], "job000125");
+            assert!(report["changes"][0]["description"]
+                .as_str()
+                .unwrap()
+                .contains("retried up to three times"));
+            assert_eq!(report["changes"][2]["jobs"].as_array().unwrap().len(), 0);
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_resources_list_endpoint() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = load_test_message("test_resources_list.json");
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ListResourcesResult { id, result }) = response {
+        assert_eq!(id, 5);
+        let uris: Vec<&str> = result.resources.iter().map(|r| r.uri.as_str()).collect();
+        assert!(uris.contains(&"p4://depot/..."));
+        assert!(uris.contains(&"p4://streams/..."));
+    } else {
+        panic!("Expected ListResourcesResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_resources_read_endpoint() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = load_test_message("test_resources_read.json");
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ReadResourceResult { id, result }) = response {
+        assert_eq!(id, 6);
+        assert_eq!(result.contents[0].uri, "p4://depot/main/src/main.cpp");
+        assert_eq!(result.contents[0].mime_type.as_deref(), Some("text/x-c++"));
+        assert!(result.contents[0]
+            .text
+            .as_ref()
+            .unwrap()
+            .contains("//depot/main/src/main.cpp"));
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+
+    // URIs outside the p4: scheme are reported as not found
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "resources/read",
+        "id": 7,
+        "params": {"uri": "file:///etc/passwd"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::Error { error, .. }) = response {
+        assert_eq!(error.code, -32002);
+    } else {
+        panic!("Expected Error response");
+    }
+}
+
+#[tokio::test]
+async fn test_binary_files_as_images_and_blobs() {
+    use base64::Engine;
+    let decode = |data: &str| {
+        base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .unwrap()
+    };
+
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let call = |id: i64, path: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_print", "arguments": {"path": path}}
+        }))
+        .unwrap()
+    };
+    let read = |id: i64, uri: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "resources/read",
+            "id": id,
+            "params": {"uri": uri}
+        }))
+        .unwrap()
+    };
+
+    // An image comes back as one, after a line of text about it
+    let response = server
+        .handle_message(call(1, "//depot/main/art/logo.png"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        assert_eq!(result.content.len(), 2);
+        assert!(matches!(&result.content[0], ToolContent::Text { text }
+            if text == "//depot/main/art/logo.png: image/png, 70 bytes"));
+        match &result.content[1] {
+            ToolContent::Image { data, mime_type } => {
+                assert_eq!(mime_type, "image/png");
+                assert!(decode(data).starts_with(b"\x89PNG"));
+            }
+            other => panic!("Expected image content, got {:?}", other),
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Text files are printed as before, and other binary files aren't printed
+    let response = server
+        .handle_message(call(2, "//depot/main/src/main.cpp"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        assert_eq!(result.content.len(), 1);
+        assert!(matches!(&result.content[0], ToolContent::Text { text }
+            if text.contains("int main()")));
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+    let response = server
+        .handle_message(call(3, "//depot/main/data/table.bin"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        assert!(matches!(&result.content[0], ToolContent::Text { text }
+            if text == "//depot/main/data/table.bin: binary file of 256 bytes, not shown"));
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Resources hold binary files as base64 blobs
+    let response = server
+        .handle_message(read(4, "p4://depot/main/art/logo.png"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::ReadResourceResult { result, .. }) = response {
+        let contents = &result.contents[0];
+        assert_eq!(contents.mime_type.as_deref(), Some("image/png"));
+        assert!(contents.text.is_none());
+        assert!(decode(contents.blob.as_ref().unwrap()).starts_with(b"\x89PNG"));
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+    let response = server
+        .handle_message(read(5, "p4://depot/main/data/table.bin"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::ReadResourceResult { result, .. }) = response {
+        let contents = &result.contents[0];
+        assert_eq!(
+            contents.mime_type.as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(decode(contents.blob.as_ref().unwrap()).len(), 256);
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_printed_images_count_against_the_response_limit() {
+    use p4_mcp::p4::{ExecutionLog, P4Handler};
+
+    setup_mock_mode();
+
+    // The file is printed to stdout, so the same print is the same command each time
+    let mut handler = P4Handler::new();
+    let executions = ExecutionLog::new();
+    handler.set_execution_log(Some(executions.clone()));
+    handler.print("//depot/main/art/logo.png").await.unwrap();
+    handler.print("//depot/main/art/logo.png").await.unwrap();
+    let lines: Vec<String> = executions
+        .commands()
+        .into_iter()
+        .map(|executed| executed.command_line)
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], lines[1]);
+    assert!(
+        lines[0].ends_with("print -q //depot/main/art/logo.png"),
+        "{}",
+        lines[0]
+    );
+
+    // 70 bytes of PNG are 96 once encoded, over a limit of 60
+    let config = p4_mcp::Config {
+        max_response_bytes: 60,
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let message = serde_json::from_value::<MCPMessage>(serde_json::json!({
+        "method": "tools/call",
+        "id": 1,
+        "params": {"name": "p4_print", "arguments": {"path": "//depot/main/art/logo.png"}}
+    }))
+    .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        // Only the line about the image, itself cut to the limit
+        assert_eq!(result.content.len(), 1);
+        assert!(matches!(&result.content[0], ToolContent::Text { text }
+            if text.starts_with("//depot/main/art/logo.png: image/png, 70 bytes")));
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let message = serde_json::from_value::<MCPMessage>(serde_json::json!({
+        "method": "resources/read",
+        "id": 2,
+        "params": {"uri": "p4://depot/main/art/logo.png"}
+    }))
+    .unwrap();
+    if let Some(MCPResponse::ReadResourceResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        let contents = &result.contents[0];
+        assert!(contents.blob.is_none());
+        assert_eq!(contents.mime_type.as_deref(), Some("text/plain"));
+        assert!(contents.text.as_ref().unwrap().contains("not shown"));
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_resource_templates_endpoint() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "resources/templates/list",
+        "id": 8
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ListResourceTemplatesResult { id, result }) = response {
+        assert_eq!(id, 8);
+        let templates: Vec<&str> = result
+            .resource_templates
+            .iter()
+            .map(|t| t.uri_template.as_str())
+            .collect();
+        assert!(templates.contains(&"p4://{depotPath}@{revision}"));
+    } else {
+        panic!("Expected ListResourceTemplatesResult response");
+    }
+
+    // A URI built from the revision template reads the file at that revision
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "resources/read",
+        "id": 9,
+        "params": {"uri": "p4://depot/main/README.md@12345"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ReadResourceResult { result, .. }) = response {
+        assert_eq!(
+            result.contents[0].mime_type.as_deref(),
+            Some("text/markdown")
+        );
+        assert!(result.contents[0]
+            .text
+            .as_ref()
+            .unwrap()
+            .contains("//depot/main/README.md@12345"));
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_resource_subscription_polling() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+    server.set_notification_sender(tx);
+
+    let subscribe = serde_json::from_value(serde_json::json!({
+        "method": "resources/subscribe",
+        "id": 10,
+        "params": {"uri": "p4://depot/main/..."}
+    }))
+    .unwrap();
+    let response = server.handle_message(subscribe).await.unwrap();
+    assert!(matches!(
+        response,
+        Some(MCPResponse::Empty {
+            id: RequestId::Number(10),
+            ..
+        })
+    ));
+
+    let unsubscribe = serde_json::from_value(serde_json::json!({
+        "method": "resources/unsubscribe",
+        "id": 11,
+        "params": {"uri": "p4://depot/main/..."}
+    }))
+    .unwrap();
+    let response = server.handle_message(unsubscribe).await.unwrap();
+    assert!(matches!(
+        response,
+        Some(MCPResponse::Empty {
+            id: RequestId::Number(11),
+            ..
+        })
+    ));
+
+    // Subscribing to something that isn't a depot resource is rejected
+    let subscribe = serde_json::from_value(serde_json::json!({
+        "method": "resources/subscribe",
+        "id": 12,
+        "params": {"uri": "https://example.com"}
+    }))
+    .unwrap();
+    let response = server.handle_message(subscribe).await.unwrap();
+    assert!(matches!(
+        response,
+        Some(MCPResponse::Error {
+            id: RequestId::Number(12),
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_changelist_resource_read() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "resources/read",
+        "id": 13,
+        "params": {"uri": "p4://change/12345"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ReadResourceResult { result, .. }) = response {
+        assert_eq!(result.contents[0].uri, "p4://change/12345");
+        assert_eq!(
+            result.contents[0].mime_type.as_deref(),
+            Some("application/json")
+        );
+        let described: serde_json::Value =
+            serde_json::from_str(result.contents[0].text.as_ref().unwrap()).unwrap();
+        assert_eq!(described["change"], 12345);
+        assert_eq!(described["status"], "submitted");
+        assert_eq!(described["files"].as_array().unwrap().len(), 2);
+        assert_eq!(described["jobs"][0], "job000123");
+    } else {
+        panic!("Expected ReadResourceResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_prompts_endpoints() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "prompts/list", "id": 14})).unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::ListPromptsResult { result, .. }) = response {
+        let names: Vec<&str> = result.prompts.iter().map(|p| p.name.as_str()).collect();
+        assert!(names.contains(&"write_change_description"));
+        assert!(names.contains(&"review_shelved_change"));
+        assert!(names.contains(&"summarize_recent_changes"));
+    } else {
+        panic!("Expected ListPromptsResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "prompts/get",
+        "id": 15,
+        "params": {"name": "review_shelved_change", "arguments": {"changelist": "12347"}}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::GetPromptResult { result, .. }) = response {
+        assert_eq!(result.messages[0].role, "user");
+        if let ToolContent::Text { text } = &result.messages[0].content {
+            assert!(text.contains("Review shelved change 12347"));
+            assert!(text.contains("+second line, revised"));
+            assert!(text.contains(
+                "#### `//depot/main/file1.txt`\n\nRevision #3, file type text\n\n```diff\n"
+            ));
+        } else {
+            panic!("Expected text prompt content");
+        }
+    } else {
+        panic!("Expected GetPromptResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "prompts/get",
+        "id": 16,
+        "params": {"name": "write_change_description"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    if let Some(MCPResponse::GetPromptResult { result, .. }) = response {
+        if let ToolContent::Text { text } = &result.messages[0].content {
+            assert!(text.contains("//depot/main/file3.h#1"));
+            assert!(text.contains("#### `//depot/main/file1.txt`\n\n```diff\n@@ -1,2 +1,2 @@"));
+        }
+    } else {
+        panic!("Expected GetPromptResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "prompts/get",
+        "id": 17,
+        "params": {"name": "nonexistent_prompt"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(
+        response,
+        Some(MCPResponse::Error {
+            id: RequestId::Number(17),
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn test_tool_toggle_sends_list_changed() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    server.set_notification_sender(tx);
+
+    assert!(server.set_tool_enabled("p4_submit", false));
+    let notification = rx.try_recv().unwrap();
+    assert_eq!(notification.method, "notifications/tools/list_changed");
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 18})).unwrap();
+    if let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        assert!(!result.tools.iter().any(|t| t.name == "p4_submit"));
+    } else {
+        panic!("Expected ListToolsResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 19,
+        "params": {"name": "p4_submit", "arguments": {"description": "Disabled submit"}}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(
+        response,
+        Some(MCPResponse::Error {
+            id: RequestId::Number(19),
+            ..
+        })
+    ));
+
+    // Disabling again is a no-op and doesn't notify
+    assert!(server.set_tool_enabled("p4_submit", false));
+    assert!(rx.try_recv().is_err());
+
+    assert!(server.set_tool_enabled("p4_submit", true));
+    assert!(rx.try_recv().is_ok());
+    assert!(!server.set_tool_enabled("p4_nonexistent", true));
+}
+
+#[tokio::test]
+async fn test_initialization_handshake() {
+    setup_mock_mode();
+    let mut server = MCPServer::new();
+
+    // Tool calls are rejected until the client has sent initialize
+    let message = load_test_message("test_p4_info.json");
+    let response = server.handle_message(message).await.unwrap();
+    if let Some(MCPResponse::Error { id, error }) = response {
+        assert_eq!(id, "4");
+        assert_eq!(error.code, -32600);
+    } else {
+        panic!("Expected Error response before initialization");
+    }
+
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let response = server
+        .handle_message(load_test_message("test_initialized.json"))
+        .await
+        .unwrap();
+    assert!(response.is_none());
+
+    let message = load_test_message("test_p4_info.json");
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+}
+
+#[tokio::test]
+async fn test_paginated_tool_output() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let call = |id: i32, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    let response = server
+        .handle_message(call(20, "p4_changes", serde_json::json!({"max": 2})))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.contains("Change 12349"));
+            assert!(!text.contains("Change 12348"));
+            assert!(text.contains("pass cursor \"12348\""));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let response = server
+        .handle_message(call(
+            21,
+            "p4_changes",
+            serde_json::json!({"max": 2, "cursor": "12348"}),
+        ))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(!text.contains("Change 12349"));
+            assert!(text.contains("Change 12348"));
+            assert!(text.contains("Change 12347"));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let response = server
+        .handle_message(call(
+            22,
+            "p4_files",
+            serde_json::json!({"path": "//depot/main/...", "max": 2, "cursor": "2"}),
+        ))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert_eq!(
+                text.trim(),
+                "//depot/main/file3.h#1 - add change 12340 (text)"
+            );
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/list",
+        "id": 23,
+        "params": {"cursor": "not-a-cursor"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::Error { .. })));
+}
+
+#[tokio::test]
+async fn test_depot_path_completion() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let complete = |id: i32, reference: serde_json::Value, name: &str, value: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "completion/complete",
+            "id": id,
+            "params": {"ref": reference, "argument": {"name": name, "value": value}}
+        }))
+        .unwrap()
+    };
+    let prompt = serde_json::json!({"type": "ref/prompt", "name": "summarize_recent_changes"});
+    let template = serde_json::json!({"type": "ref/resource", "uri": "p4://{depotPath}"});
+
+    let cases = [
+        (
+            complete(24, prompt.clone(), "path", "//de"),
+            vec!["//depot/"],
+        ),
+        (
+            complete(25, prompt.clone(), "path", "//depot/ma"),
+            vec!["//depot/main/"],
+        ),
+        (
+            complete(26, template, "depotPath", "depot/main/file"),
+            vec![
+                "depot/main/file1.txt",
+                "depot/main/file2.cpp",
+                "depot/main/file3.h",
+            ],
+        ),
+        (complete(27, prompt, "max", "1"), vec![]),
+    ];
+
+    for (message, expected) in cases {
+        let response = server.handle_message(message).await.unwrap();
+        if let Some(MCPResponse::CompleteResult { result, .. }) = response {
+            assert_eq!(result.completion.values, expected);
+        } else {
+            panic!("Expected CompleteResult response");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_logging_set_level() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    server.set_notification_sender(tx);
+
+    // Commands are logged at info, below the default warning threshold
+    server
+        .handle_message(load_test_message("test_p4_info.json"))
+        .await
+        .unwrap();
+    assert!(rx.try_recv().is_err());
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "logging/setLevel",
+        "id": 28,
+        "params": {"level": "info"}
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::Empty { .. })));
+
+    server
+        .handle_message(load_test_message("test_p4_info.json"))
+        .await
+        .unwrap();
+    let notification = rx.try_recv().unwrap();
+    assert_eq!(notification.method, "notifications/message");
+    assert_eq!(notification.params["level"], "info");
+    assert_eq!(notification.params["logger"], "p4");
+    assert_eq!(notification.params["data"], "p4 -ztag info");
+}
+
+#[tokio::test]
+async fn test_structured_tool_output() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 29})).unwrap();
+    if let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        let changes = result
+            .tools
+            .iter()
+            .find(|t| t.name == "p4_changes")
+            .unwrap();
+        assert!(changes.output_schema.is_some());
+        let sync = result.tools.iter().find(|t| t.name == "p4_sync").unwrap();
+        assert!(sync.output_schema.is_some());
+        let edit = result.tools.iter().find(|t| t.name == "p4_edit").unwrap();
+        assert!(edit.output_schema.is_none());
+    } else {
+        panic!("Expected ListToolsResult response");
+    }
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 30,
+        "params": {"name": "p4_changes", "arguments": {"max": 2}}
+    }))
+    .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        assert!(matches!(
+            result.content.first(),
+            Some(ToolContent::Text { .. })
+        ));
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["changes"].as_array().unwrap().len(), 2);
+        assert_eq!(structured["changes"][0]["change"], "12350");
+        assert_eq!(structured["nextCursor"], "12348");
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let response = server
+        .handle_message(load_test_message("test_p4_opened.json"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["files"][2]["depotFile"], "//depot/main/file3.h");
+        assert_eq!(structured["files"][2]["change"], "12346");
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // The sync that ran is reported, not a second one
+    let response = server
+        .handle_message(load_test_message("test_p4_sync_example.json"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["files"].as_array().unwrap().len(), 2);
+        assert_eq!(structured["files"][0]["action"], "updated");
+        assert_eq!(
+            structured["files"][1]["clientFile"],
+            "/local/workspace/file2.cpp"
+        );
+        assert_eq!(structured["messages"][0], "Mock P4 Sync:");
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    // Typed results come back as they are, with the text rendered from them
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let response = server
+        .handle_message(call(
+            31,
+            "p4_describe",
+            serde_json::json!({"changelist": "12345"}),
+        ))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        let ToolContent::Text { text } = &result.content[0] else {
+            panic!("Expected text content");
+        };
+        assert!(
+            text.starts_with("Change 12345 by testuser@test-client on 2024/01/15 12:30:45\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("\tFix crash on startup when config is missing\n"));
+        assert!(
+            text.ends_with("... //depot/main/file2.cpp#1 add\n"),
+            "{}",
+            text
+        );
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["change"], 12345);
+        assert_eq!(structured["jobs"], serde_json::json!(["job000123"]));
+        assert_eq!(
+            structured["files"][1]["depotFile"],
+            "//depot/main/file2.cpp"
+        );
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let response = server
+        .handle_message(call(
+            32,
+            "p4_client",
+            serde_json::json!({"name": "build-ws"}),
+        ))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        let structured = result.structured_content.unwrap();
+        assert_eq!(structured["client"], "build-ws");
+        assert_eq!(structured["view"][0]["clientPath"], "//build-ws/...");
+        let ToolContent::Text { text } = &result.content[0] else {
+            panic!("Expected text content");
+        };
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(text).unwrap(),
+            structured
+        );
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+
+    let response = server
+        .handle_message(load_test_message("test_p4_edit.json"))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        assert!(result.structured_content.is_none());
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_tool_annotations() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 31})).unwrap();
+    let response = server.handle_message(message).await.unwrap().unwrap();
+    let parsed = serde_json::to_value(&response).unwrap();
+    let tools = parsed["result"]["tools"].as_array().unwrap();
+    let annotations = |name: &str| {
+        tools
+            .iter()
+            .find(|t| t["name"] == name)
+            .map(|t| t["annotations"].clone())
+            .unwrap()
+    };
+
+    assert!(tools.iter().all(|t| t["annotations"]["title"].is_string()));
+    assert_eq!(annotations("p4_changes")["readOnlyHint"], true);
+    assert_eq!(annotations("p4_revert")["readOnlyHint"], false);
+    assert_eq!(annotations("p4_revert")["destructiveHint"], true);
+    assert_eq!(annotations("p4_submit")["destructiveHint"], false);
+    assert_eq!(annotations("p4_submit")["idempotentHint"], false);
+}
+
+#[tokio::test]
+async fn test_suggest_description_with_sampling() {
+    setup_mock_mode();
+
+    // Without the sampling capability the tool isn't offered
+    let mut server = MCPServer::new();
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "initialize",
+        "id": 32,
+        "params": {
+            "protocolVersion": "2024-11-05",
+            "capabilities": {},
+            "clientInfo": {"name": "no-sampling", "version": "1.0"}
+        }
+    }))
+    .unwrap();
+    server.handle_message(message).await.unwrap();
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 33})).unwrap();
+    if let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    {
+        assert!(!result
+            .tools
+            .iter()
+            .any(|t| t.name == "p4_suggest_description"));
+    } else {
+        panic!("Expected ListToolsResult response");
+    }
+
+    // test_initialize.json advertises sampling
+    let mut server = initialized_server().await;
+    let (client, mut requests) = ClientRequester::new();
+    server.set_client_requester(client.clone());
+    let responder = tokio::spawn(async move {
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "sampling/createMessage");
+        let prompt = request.params["messages"][0]["content"]["text"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request.id,
+            "result": {
+                "role": "assistant",
+                "content": {"type": "text", "text": "Handle missing config on startup\n"},
+                "model": "test-model"
+            }
+        });
+        assert!(client.handle_response(&response.to_string()));
+        prompt
+    });
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 34,
+        "params": {
+            "name": "p4_suggest_description",
+            "arguments": {"changelist": "12346", "apply": true}
+        }
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+
+    let prompt = responder.await.unwrap();
+    assert!(prompt.contains("Diff:"));
+    if let Some(MCPResponse::CallToolResult { result, .. }) = response {
+        if let Some(ToolContent::Text { text }) = result.content.first() {
+            assert!(text.starts_with("Handle missing config on startup"));
+            assert!(text.contains("Change 12346 updated."));
+        }
+    } else {
+        panic!("Expected CallToolResult response");
+    }
+}
+
+#[tokio::test]
+async fn test_tool_calls_scoped_to_client_roots() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let (client, mut requests) = ClientRequester::new();
+    server.set_client_requester(client.clone());
+
+    let root = env::current_dir().unwrap().join("src");
+    let responder = tokio::spawn(async move {
+        let request = requests.recv().await.unwrap();
+        assert_eq!(request.method, "roots/list");
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": request.id,
+            "result": {"roots": [{"uri": format!("file://{}", root.display()), "name": "src"}]}
+        });
+        assert!(client.handle_response(&response.to_string()));
+    });
+
+    // test_initialize.json advertises roots, so they're listed once the handshake completes
+    server
+        .handle_message(load_test_message("test_initialized.json"))
+        .await
+        .unwrap();
+
+    let call = |id: i64, files: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_edit", "arguments": {"files": files}}
+        }))
+        .unwrap()
+    };
+
+    let response = server
+        .handle_message(call(
+            35,
+            serde_json::json!(["src/main.rs", "//depot/main/x.cpp"]),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+    responder.await.unwrap();
+
+    let response = server
+        .handle_message(call(
+            36,
+            serde_json::json!(["src/main.rs", "src/../Cargo.toml"]),
+        ))
+        .await
+        .unwrap();
+    if let Some(MCPResponse::Error { id, error }) = response {
+        assert_eq!(id, 36);
+        assert_eq!(error.code, -32602);
+        assert!(error
+            .message
+            .starts_with("src/../Cargo.toml is outside the client's roots"));
+    } else {
+        panic!("Expected Error response");
+    }
+
+    // Relative paths resolve against the call's cwd, which must itself be in the roots
+    let call_in = |id: i64, cwd: &str, files: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_edit", "arguments": {"cwd": cwd, "files": files}}
+        }))
+        .unwrap()
+    };
+    let response = server
+        .handle_message(call_in(37, "src", serde_json::json!(["main.rs"])))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    for (id, cwd, expected) in [
+        (38, "tests", "tests is outside the client's roots"),
+        (39, "src/missing", "cwd src/missing is not a directory"),
+        (40, "//depot/main", "cwd must be a local directory"),
+    ] {
+        let response = server
+            .handle_message(call_in(id, cwd, serde_json::json!(["main.rs"])))
+            .await
+            .unwrap();
+        match response {
+            Some(MCPResponse::Error { error, .. }) => {
+                assert_eq!(error.code, -32602);
+                assert!(error.message.starts_with(expected), "{}", error.message);
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+
+    // Every argument naming a path is checked, not just the files to act on
+    for (id, name, arguments, outside) in [
+        (
+            41,
+            "p4_predict_conflicts",
+            serde_json::json!({"from": "//depot/main/...", "to": "../secret/..."}),
+            "../secret/...",
+        ),
+        (
+            42,
+            "p4_diff2",
+            serde_json::json!({"left": "Cargo.toml", "right": "//depot/main/a.c"}),
+            "Cargo.toml",
+        ),
+        (
+            43,
+            "p4_workspace_create",
+            serde_json::json!({"template": "main", "name": "ws", "root": "/tmp/ws"}),
+            "/tmp/ws",
+        ),
+    ] {
+        let message = serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap();
+        match server.handle_message(message).await.unwrap() {
+            Some(MCPResponse::Error { error, .. }) => {
+                assert_eq!(error.code, -32602);
+                assert!(
+                    error
+                        .message
+                        .starts_with(&format!("{} is outside the client's roots", outside)),
+                    "{}",
+                    error.message
+                );
+            }
+            other => panic!("Expected Error response, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_destructive_tools_confirmed_with_elicitation() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse("allow_force_sync = true\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    // test_initialize.json advertises elicitation
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let (client, mut requests) = ClientRequester::new();
+    server.set_client_requester(client.clone());
+
+    let responder = tokio::spawn(async move {
+        let answers = [
+            serde_json::json!({"action": "decline"}),
+            serde_json::json!({"action": "accept", "content": {"confirm": true}}),
+        ];
+        for answer in answers {
+            let request = requests.recv().await.unwrap();
+            assert_eq!(request.method, "elicitation/create");
+            assert!(request.params["message"]
+                .as_str()
+                .unwrap()
+                .contains("//depot/main/..."));
+            assert_eq!(
+                request.params["requestedSchema"]["properties"]["confirm"]["type"],
+                "boolean"
+            );
+            let response =
+                serde_json::json!({"jsonrpc": "2.0", "id": request.id, "result": answer});
+            assert!(client.handle_response(&response.to_string()));
+        }
+        requests
+    });
+
+    let sync = |id: i64, force: bool| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_sync", "arguments": {"path": "//depot/main/...", "force": force}}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match result.content.first() {
+            Some(ToolContent::Text { text }) => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        _ => panic!("Expected CallToolResult response"),
+    };
+
+    let declined = text(server.handle_message(sync(37, true)).await.unwrap());
+    assert!(declined.starts_with("Cancelled"));
+
+    let accepted = text(server.handle_message(sync(38, true)).await.unwrap());
+    assert!(accepted.contains("Mock P4 Sync"));
+
+    // Plain syncs don't ask
+    let plain = text(server.handle_message(sync(39, false)).await.unwrap());
+    assert!(plain.contains("Mock P4 Sync"));
+    let mut requests = responder.await.unwrap();
+    assert!(requests.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn test_sse_transport_round_trip() {
+    setup_mock_mode();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            p4_mcp::transport::sse::router(ServerContext::from_env()),
+        )
+        .await
+        .unwrap();
+    });
+
+    let http = reqwest::Client::new();
+    let mut stream = http.get(format!("{}/sse", base)).send().await.unwrap();
+    assert_eq!(
+        stream.headers()["content-type"].to_str().unwrap(),
+        "text/event-stream"
+    );
+
+    // Read events until one carries `needle`
+    let mut events = String::new();
+    let mut read_until = async |needle: &str| {
+        while !events.contains(needle) {
+            let chunk = stream.chunk().await.unwrap().expect("event stream ended");
+            events.push_str(&String::from_utf8_lossy(&chunk));
+        }
+        events.clone()
+    };
+
+    let endpoint = read_until("event: endpoint").await;
+    let path = endpoint
+        .lines()
+        .find_map(|line| line.strip_prefix("data: "))
+        .unwrap()
+        .to_string();
+    assert!(path.starts_with("/messages?sessionId="));
+
+    let response = http
+        .post(format!("{}{}", base, path))
+        .body(r#"{"jsonrpc": "2.0", "id": 40, "method": "ping"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::ACCEPTED);
+    let events = read_until(r#""id":40"#).await;
+    assert!(events.contains("event: message\ndata: {\"jsonrpc\":\"2.0\",\"id\":40,\"result\":{}}"));
+
+    let response = http
+        .post(format!("{}/messages?sessionId=unknown", base))
+        .body(r#"{"jsonrpc": "2.0", "id": 41, "method": "ping"}"#)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_unix_socket_transport_sessions() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    setup_mock_mode();
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("p4-mcp.sock");
+    let server_path = path.clone();
+    tokio::spawn(async move {
+        p4_mcp::transport::socket::serve(&server_path, ServerContext::from_env()).await
+    });
+    while !path.exists() {
+        tokio::task::yield_now().await;
+    }
+
+    // Each connection is its own session: the second isn't initialized by the first
+    let mut first = BufReader::new(tokio::net::UnixStream::connect(&path).await.unwrap());
+    let mut second = BufReader::new(tokio::net::UnixStream::connect(&path).await.unwrap());
+    let mut line = String::new();
+
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    first
+        .get_mut()
+        .write_all(format!("{}\n", initialize.trim()).as_bytes())
+        .await
+        .unwrap();
+    first.read_line(&mut line).await.unwrap();
+    let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["id"], 0);
+    assert_eq!(response["result"]["serverInfo"]["name"], "P4Server");
+
+    let call = r#"{"jsonrpc": "2.0", "id": 42, "method": "tools/call", "params": {"name": "p4_info", "arguments": {}}}"#;
+    second
+        .get_mut()
+        .write_all(format!("{}\n", call).as_bytes())
+        .await
+        .unwrap();
+    line.clear();
+    second.read_line(&mut line).await.unwrap();
+    let response: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(response["id"], 42);
+    assert_eq!(response["error"]["code"], -32600);
+}
+
+#[tokio::test]
+async fn test_sessions_share_context_but_not_state() {
+    setup_mock_mode();
+    let context = ServerContext::from_env();
+    let mut first = MCPServer::with_context(&context);
+    let mut second = MCPServer::with_context(&context);
+
+    first
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let call = || {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": 43,
+            "params": {"name": "p4_info", "arguments": {}}
+        }))
+        .unwrap()
+    };
+
+    let response = first.handle_message(call()).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    // The second session hasn't been initialized, and sampling tools enabled for the
+    // first client aren't offered to it
+    let response = second.handle_message(call()).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::Error { error, .. }) if error.code == -32600));
+    second
+        .handle_message(
+            serde_json::from_value(serde_json::json!({
+                "method": "initialize",
+                "id": 44,
+                "params": {
+                    "protocolVersion": "2024-11-05",
+                    "capabilities": {},
+                    "clientInfo": {"name": "second", "version": "1.0"}
+                }
+            }))
+            .unwrap(),
+        )
+        .await
+        .unwrap();
+    let list = || {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({"method": "tools/list", "id": 45}))
+            .unwrap()
+    };
+    let tool_names = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::ListToolsResult { result, .. }) => {
+            result.tools.into_iter().map(|t| t.name).collect::<Vec<_>>()
+        }
+        _ => panic!("Expected ListToolsResult response"),
+    };
+    assert!(tool_names(first.handle_message(list()).await.unwrap())
+        .contains(&"p4_suggest_description".to_string()));
+    assert!(!tool_names(second.handle_message(list()).await.unwrap())
+        .contains(&"p4_suggest_description".to_string()));
+}
+
+#[tokio::test]
+async fn test_shutdown_drains_then_cancels_tool_calls() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "shutdown_timeout_secs = 1\n\
+         [mock_faults.commands.changes]\nlatency_ms = 500\n\n\
+         [mock_faults.commands.sync]\nlatency_ms = 60000\n",
+    )
+    .unwrap();
+    let context = ServerContext::from_config(&config).unwrap();
+    let (incoming, incoming_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let (outgoing_tx, mut outgoing) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let session = tokio::spawn(p4_mcp::transport::serve_session(
+        MCPServer::with_context(&context),
+        incoming_rx,
+        outgoing_tx,
+    ));
+
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    incoming.send(initialize).unwrap();
+    for (id, tool) in [(1, "p4_changes"), (2, "p4_sync")] {
+        let call = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": {"name": tool, "arguments": {}}
+        });
+        incoming.send(call.to_string()).unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    context.shutdown();
+
+    // Requests sent after the shutdown aren't read, and the session ends although the
+    // client is still connected
+    incoming
+        .send(r#"{"jsonrpc": "2.0", "id": 3, "method": "ping"}"#.to_string())
+        .unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), session)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut responses = HashMap::new();
+    while let Ok(json) = outgoing.try_recv() {
+        let message: serde_json::Value = serde_json::from_str(&json).unwrap();
+        responses.insert(message["id"].to_string(), message);
+    }
+    // The quick call finished in time; the slow one was cancelled
+    assert!(responses["1"]["result"]["content"].is_array());
+    assert_eq!(
+        responses["2"]["error"]["message"],
+        "Cancelled: the server shut down before the request finished"
+    );
+    assert!(!responses.contains_key("3"));
+}
+
+#[tokio::test]
+async fn test_cancelled_requests_stop_only_their_own_commands() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[mock_faults.commands.changes]\nlatency_ms = 300\n\n\
+         [mock_faults.commands.sync]\nlatency_ms = 60000\n",
+    )
+    .unwrap();
+    let context = ServerContext::from_config(&config).unwrap();
+    let (incoming, incoming_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let (outgoing_tx, mut outgoing) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let session = tokio::spawn(p4_mcp::transport::serve_session(
+        MCPServer::with_context(&context),
+        incoming_rx,
+        outgoing_tx,
+    ));
+
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    incoming.send(initialize).unwrap();
+    for (id, tool) in [(1, "p4_sync"), (2, "p4_changes")] {
+        let call = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": {"name": tool, "arguments": {}}
+        });
+        incoming.send(call.to_string()).unwrap();
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let cancelled = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": "notifications/cancelled",
+        "params": {"requestId": 1, "reason": "User pressed stop"}
+    });
+    incoming.send(cancelled.to_string()).unwrap();
+
+    // The sync stops at once, so the session ends well before it would have finished
+    drop(incoming);
+    tokio::time::timeout(std::time::Duration::from_secs(5), session)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut responses = HashMap::new();
+    while let Ok(json) = outgoing.try_recv() {
+        let message: serde_json::Value = serde_json::from_str(&json).unwrap();
+        responses.insert(message["id"].to_string(), message);
+    }
+    // The other call finished, and the cancelled one isn't answered
+    assert!(responses["2"]["result"]["content"].is_array());
+    assert!(!responses.contains_key("1"));
+}
+
+#[tokio::test]
+async fn test_session_answers_pings_while_a_tool_call_waits() {
+    setup_mock_mode();
+    let (incoming, incoming_rx) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let (outgoing_tx, mut outgoing) = tokio::sync::mpsc::unbounded_channel::<String>();
+    let session = tokio::spawn(p4_mcp::transport::serve_session(
+        MCPServer::new(),
+        incoming_rx,
+        outgoing_tx,
+    ));
+
+    // Read outgoing messages until one has the given id
+    let mut next_with_id = async |id: serde_json::Value| loop {
+        let message: serde_json::Value =
+            serde_json::from_str(&outgoing.recv().await.unwrap()).unwrap();
+        if message["id"] == id {
+            return message;
+        }
+    };
+
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    incoming.send(initialize).unwrap();
+    next_with_id(serde_json::json!(0)).await;
+
+    // The sampling request leaves this call waiting on the client...
+    incoming
+        .send(r#"{"jsonrpc": "2.0", "id": 46, "method": "tools/call", "params": {"name": "p4_suggest_description", "arguments": {}}}"#.to_string())
+        .unwrap();
+    let sampling = next_with_id(serde_json::json!("p4-mcp-1")).await;
+    assert_eq!(sampling["method"], "sampling/createMessage");
+
+    // ...which doesn't hold up a ping sent after it
+    incoming
+        .send(r#"{"jsonrpc": "2.0", "id": 47, "method": "ping"}"#.to_string())
+        .unwrap();
+    let pong = next_with_id(serde_json::json!(47)).await;
+    assert_eq!(pong["result"], serde_json::json!({}));
+
+    incoming
+        .send(
+            serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": "p4-mcp-1",
+                "result": {"role": "assistant", "content": {"type": "text", "text": "Tidy up"}, "model": "m"}
+            })
+            .to_string(),
+        )
+        .unwrap();
+    let result = next_with_id(serde_json::json!(46)).await;
+    assert_eq!(result["result"]["content"][0]["text"], "Tidy up");
+
+    // Closing the input ends the session once everything is answered
+    drop(incoming);
+    session.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_long_output_truncated_and_continued() {
+    setup_mock_mode();
+    let config = p4_mcp::Config {
+        max_response_bytes: 60,
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let mut unlimited = initialized_server().await;
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_files", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let arguments = serde_json::json!({"path": "//depot/main/..."});
+    let full = text(
+        unlimited
+            .handle_message(call(1, arguments.clone()))
+            .await
+            .unwrap(),
+    );
+    let mut chunk = text(server.handle_message(call(2, arguments)).await.unwrap());
+
+    let mut reassembled = String::new();
+    let mut id = 3;
+    while let Some((head, marker)) = chunk.split_once("\n[Output truncated: ") {
+        assert!(head.len() <= 60);
+        reassembled.push_str(head);
+        let cursor = marker
+            .split('"')
+            .nth(3)
+            .expect("truncation marker names a cursor");
+        chunk = text(
+            server
+                .handle_message(call(id, serde_json::json!({"output_cursor": cursor})))
+                .await
+                .unwrap(),
+        );
+        id += 1;
+    }
+    reassembled.push_str(&chunk);
+    assert!(id > 4, "Expected the output to span several chunks");
+    assert_eq!(reassembled, full);
+
+    let response = server
+        .handle_message(call(id, serde_json::json!({"output_cursor": "nope:0"})))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::Error { error, .. }) if error.code == -32602));
+}
+
+#[tokio::test]
+async fn test_tool_response_policy_summarizes() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[responses]\ntools = { p4_changes = { max_bytes = 300, summary_lines = 2 } }\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let mut unlimited = initialized_server().await;
+
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let arguments = serde_json::json!({"max": 20});
+    let full = text(
+        unlimited
+            .handle_message(call(1, "p4_changes", arguments.clone()))
+            .await
+            .unwrap(),
+    );
+    let summary = text(
+        server
+            .handle_message(call(2, "p4_changes", arguments))
+            .await
+            .unwrap(),
+    );
+    let lines: Vec<&str> = full.lines().filter(|line| !line.is_empty()).collect();
+    assert!(summary.starts_with(&format!(
+        "[Output summarized: {} lines, {} bytes, over the 300-byte limit for p4_changes.]\nFirst 2 lines:\n{}\n{}\n",
+        lines.len(),
+        full.len(),
+        lines[0],
+        lines[1]
+    )));
+    assert!(summary.contains(&format!(
+        "Last 2 lines:\n{}\n{}\n",
+        lines[lines.len() - 2],
+        lines[lines.len() - 1]
+    )));
+
+    // The cursor pages through the whole output at the tool's limit
+    let mut cursor = summary
+        .split("\"output_cursor\": \"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap()
+        .to_string();
+    let mut reassembled = String::new();
+    for id in 3.. {
+        let chunk = text(
+            server
+                .handle_message(call(
+                    id,
+                    "p4_changes",
+                    serde_json::json!({"output_cursor": cursor}),
+                ))
+                .await
+                .unwrap(),
+        );
+        match chunk.split_once("\n[Output truncated: ") {
+            Some((head, marker)) => {
+                assert!(head.len() <= 300);
+                reassembled.push_str(head);
+                cursor = marker.split('"').nth(3).unwrap().to_string();
+            }
+            None => {
+                reassembled.push_str(&chunk);
+                break;
+            }
+        }
+    }
+    assert_eq!(reassembled, full);
+
+    // Other tools keep the server-wide limit
+    let info = text(
+        server
+            .handle_message(call(100, "p4_info", serde_json::json!({})))
+            .await
+            .unwrap(),
+    );
+    assert!(!info.contains("[Output"));
+
+    let config = p4_mcp::Config::parse(
+        "[responses]\ntools = { p4_changes = { max_bytes = \"16k\" }, p4_files = { max_bytes = \"1MB\" } }\n",
+    )
+    .unwrap();
+    assert_eq!(config.responses.tools["p4_changes"].max_bytes, 16 * 1024);
+    assert_eq!(config.responses.tools["p4_changes"].summary_lines, 5);
+    assert_eq!(config.responses.tools["p4_files"].max_bytes, 1024 * 1024);
+    assert!(p4_mcp::Config::parse(
+        "[responses]\ntools = { p4_changes = { max_bytes = \"lots\" } }\n"
+    )
+    .is_err());
+}
+
+#[tokio::test]
+async fn test_tool_output_reported_as_progress() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    server.set_notification_sender(tx);
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 50,
+        "params": {
+            "name": "p4_sync",
+            "arguments": {"path": "//depot/main/..."},
+            "_meta": {"progressToken": "sync-1"}
+        }
+    }))
+    .unwrap();
+    let response = server.handle_message(message).await.unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    // Notifications are throttled, so only the first line is reported this quickly
+    let progress: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+        .filter(|n| n.method == "notifications/progress")
+        .collect();
+    assert_eq!(progress.len(), 1);
+    assert_eq!(progress[0].params["progressToken"], "sync-1");
+    assert_eq!(progress[0].params["progress"], 1);
+    assert_eq!(progress[0].params["message"], "Mock P4 Sync:");
+
+    // Without a token, output lines are debug log messages
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "logging/setLevel",
+        "id": 51,
+        "params": {"level": "debug"}
+    }))
+    .unwrap();
+    server.handle_message(message).await.unwrap();
+    server
+        .handle_message(load_test_message("test_p4_info.json"))
+        .await
+        .unwrap();
+    let debug_lines: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+        .filter(|n| n.params["level"] == "debug")
+        .map(|n| n.params["data"].as_str().unwrap().to_string())
+        .collect();
+    assert!(debug_lines
+        .iter()
+        .any(|line| line == "... userName testuser"));
+}
+
+#[tokio::test]
+async fn test_p4_client_checked_at_startup() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse("p4_bin = \"/opt/perforce/bin/p4\"\n").unwrap();
+    assert_eq!(
+        config.p4_bin.as_deref(),
+        Some(Path::new("/opt/perforce/bin/p4"))
+    );
+
+    let version = ServerContext::from_config(&config)
+        .unwrap()
+        .check_p4()
+        .await
+        .unwrap();
+    assert_eq!(version, "Rev. P4/LINUX26X86_64/2023.1/2442900 (2023/05/17)");
+}
+
+#[tokio::test]
+async fn test_p4_health_reports_each_check() {
+    setup_mock_mode();
+    let health = |config: &str| {
+        let config = p4_mcp::Config::parse(config).unwrap();
+        let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+        async move {
+            server
+                .handle_message(load_test_message("test_initialize.json"))
+                .await
+                .unwrap();
+            let call = serde_json::from_value::<MCPMessage>(serde_json::json!({
+                "method": "tools/call",
+                "id": 1,
+                "params": {"name": "p4_health", "arguments": {}}
+            }))
+            .unwrap();
+            match server.handle_message(call).await.unwrap() {
+                Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+                    ToolContent::Text { text } => {
+                        serde_json::from_str::<serde_json::Value>(text).unwrap()
+                    }
+                    other => panic!("Expected text content, got {:?}", other),
+                },
+                other => panic!("Expected CallToolResult, got {:?}", other),
+            }
+        }
+    };
+
+    let report = health("").await;
+    assert_eq!(report["backend"], "mock");
+    let checks = report["checks"].as_array().unwrap();
+    let names: Vec<_> = checks.iter().map(|check| check["name"].clone()).collect();
+    assert_eq!(names, ["p4", "server", "ticket"]);
+    assert!(checks.iter().all(|check| check["ok"] == true));
+    assert!(checks[1]["detail"].as_str().unwrap().contains("testuser"));
+
+    // Without a password to log in again with, an expired ticket stays expired
+    let report = health(
+        "[mock_faults.commands.login]\nerror_rate = 1.0\nerrors = [\"expired_ticket\"]\n\n\
+         [login.credentials]\nprovider = \"env\"\nvar = \"P4_MCP_TEST_NO_SUCH_PASSWORD\"\n",
+    )
+    .await;
+    let ticket = &report["checks"][2];
+    assert_eq!(ticket["ok"], false);
+    assert!(ticket["detail"]
+        .as_str()
+        .unwrap()
+        .contains("Your session has expired"));
+    assert!(ticket["hint"].as_str().unwrap().contains("p4 login"));
+}
+
+#[tokio::test]
+async fn test_health_stops_at_a_missing_p4_client() {
+    let mut handler = p4_mcp::p4::P4Handler::new();
+    handler.set_mock_mode(p4_mcp::p4::MockMode::new(false));
+    handler.set_p4_bin("/nonexistent/bin/p4".into());
+
+    let report = handler.health().await;
+    assert!(!report.is_healthy());
+    assert_eq!(report.checks.len(), 1);
+    assert_eq!(report.checks[0].name, "p4");
+    assert!(report.checks[0].detail.contains("was not found"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_health_asks_the_server_each_time() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let p4 = dir.path().join("p4");
+    let log = dir.path().join("args.log");
+    fs::write(
+        &p4,
+        format!(
+            "#!/bin/sh\necho \"$*\" >> {}\ncase \"$*\" in *-V*) echo 'Rev. P4/LINUX/2024.1/2596294';; \
+             *info*) echo '... userName real-user'; echo '... serverAddress perforce:1666';; \
+             *) echo 'info: User real-user ticket expires in 11 hours'; echo 'exit: 0';; esac\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&p4, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut handler = p4_mcp::p4::P4Handler::new();
+    handler.set_mock_mode(p4_mcp::p4::MockMode::new(false));
+    handler.set_p4_bin(p4);
+
+    // p4 info is cached for tools, but not for the health check
+    handler.info().await.unwrap();
+    handler.info().await.unwrap();
+    assert!(handler.health().await.is_healthy());
+    let runs = fs::read_to_string(&log).unwrap();
+    assert_eq!(
+        runs.lines()
+            .filter(|line| *line == "-Mj -ztag info")
+            .count(),
+        2,
+        "{}",
+        runs
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_only_per_file_commands_partly_succeed() {
+    use p4_mcp::p4::P4Command;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let p4 = dir.path().join("p4");
+    fs::write(
+        &p4,
+        "#!/bin/sh\ncase \"$*\" in \
+         *submit*) echo 'info: Submitting change 12345.'; \
+         echo 'error: //depot/a.c - must resolve #2 before submitting'; echo 'exit: 1';; \
+         *) echo 'info: //depot/a.c#1 - opened for edit'; \
+         echo 'error: //depot/b.c - file(s) not on client.'; echo 'exit: 1';; esac\n",
+    )
+    .unwrap();
+    fs::set_permissions(&p4, fs::Permissions::from_mode(0o755)).unwrap();
+    let mut handler = p4_mcp::p4::P4Handler::new();
+    handler.set_mock_mode(p4_mcp::p4::MockMode::new(false));
+    handler.set_p4_bin(p4);
+
+    let output = handler
+        .execute(P4Command::Edit {
+            files: vec!["//depot/a.c".to_string(), "//depot/b.c".to_string()],
+        })
+        .await
+        .unwrap();
+    assert!(
+        output.starts_with("//depot/a.c#1 - opened for edit"),
+        "{}",
+        output
+    );
+    assert!(output.contains("The command only partly succeeded"));
+
+    // Nothing was submitted, whatever p4 printed first
+    let error = handler
+        .execute(P4Command::SubmitChangelist {
+            changelist: "12345".to_string(),
+        })
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("must resolve #2 before submitting"),
+        "{}",
+        error
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_login_passes_the_charset_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let p4 = dir.path().join("p4");
+    let log = dir.path().join("args.log");
+    let password = dir.path().join("password");
+    fs::write(&password, "secret\n").unwrap();
+    // The first command finds the ticket expired; after logging in, it succeeds
+    fs::write(
+        &p4,
+        format!(
+            "#!/bin/sh\necho \"$*\" >> {log}\ncase \"$*\" in *login*) cat > /dev/null; exit 0;; esac\n\
+             if [ -e {log}.expired ]; then echo 'info: //depot/a.c#1 - edit default change (text)'; \
+             echo 'exit: 0'; else touch {log}.expired; \
+             echo 'error: Your session has expired, please login again.'; echo 'exit: 1'; fi\n",
+            log = log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&p4, fs::Permissions::from_mode(0o755)).unwrap();
+    let config = p4_mcp::Config::parse(&format!(
+        "[p4]\ncharset = \"utf8\"\n\n[login.credentials]\nprovider = \"file\"\npath = {:?}\n",
+        password.to_str().unwrap()
+    ))
+    .unwrap();
+
+    let mut handler = p4_mcp::p4::P4Handler::new();
+    handler.set_mock_mode(p4_mcp::p4::MockMode::new(false));
+    handler.set_p4_bin(p4);
+    handler.set_connection(config.p4.clone());
+    handler.set_login_settings(config.login.clone());
+    let output = handler
+        .execute(p4_mcp::p4::P4Command::Opened { changelist: None })
+        .await
+        .unwrap();
+    assert!(output.contains("//depot/a.c#1"), "{}", output);
+
+    let runs = fs::read_to_string(&log).unwrap();
+    let login = runs
+        .lines()
+        .find(|line| line.ends_with("login"))
+        .unwrap_or_else(|| panic!("p4 login wasn't run: {}", runs));
+    assert_eq!(login, "-C utf8 login");
+}
+
+#[test]
+fn test_list_tools_cli_honors_tool_filter() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("p4-mcp.toml");
+    fs::write(&config, "[tools]\ndeny = [\"p4_submit*\", \"p4_revert\"]\n").unwrap();
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_p4-mcp"))
+        .arg("--list-tools")
+        .arg("--mock")
+        .arg("--config")
+        .arg(&config)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let listed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let names: Vec<&str> = listed["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tool| tool["name"].as_str().unwrap())
+        .collect();
+    assert!(names.contains(&"p4_info"));
+    // Tools that need sampling are listed as a client supporting it would see them
+    assert!(names.contains(&"p4_suggest_description"));
+    assert!(!names
+        .iter()
+        .any(|name| name.starts_with("p4_submit") || *name == "p4_revert"));
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted);
+    let info = &listed["tools"][names.iter().position(|name| *name == "p4_info").unwrap()];
+    assert_eq!(info["inputSchema"]["type"], "object");
+    assert_eq!(info["annotations"]["readOnlyHint"], true);
+}
+
+#[test]
+fn test_stdio_answers_invalid_utf8_and_keeps_serving() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_p4-mcp"))
+        .arg("--mock")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    stdin
+        .write_all(
+            serde_json::to_string(&serde_json::from_str::<serde_json::Value>(&initialize).unwrap())
+                .unwrap()
+                .as_bytes(),
+        )
+        .unwrap();
+    stdin
+        .write_all(b"\n{\"jsonrpc\": \"2.0\", \"id\": \"\xff\xfe\"}\n")
+        .unwrap();
+    stdin
+        .write_all(b"{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"ping\"}\n")
+        .unwrap();
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+
+    let messages: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let parse_error = messages
+        .iter()
+        .position(|m| m["id"].is_null() && m["error"]["code"] == -32700)
+        .unwrap_or_else(|| panic!("No parse error in {:?}", messages));
+    // The line after the one that isn't UTF-8 is still answered
+    let ping = messages
+        .iter()
+        .position(|m| m["id"] == 2 && m["result"] == serde_json::json!({}))
+        .unwrap_or_else(|| panic!("Ping wasn't answered: {:?}", messages));
+    assert!(parse_error < ping, "{:?}", messages);
+}
+
+#[test]
+fn test_selftest_cli_reports_each_step() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_p4-mcp"))
+        .args(["--mock", "selftest"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "{}", stdout);
+    for step in [
+        "p4",
+        "server",
+        "ticket",
+        "initialize",
+        "tools/list",
+        "tools/call",
+    ] {
+        assert!(stdout.contains(&format!("PASS {}: ", step)), "{}", stdout);
+    }
+    assert!(stdout.ends_with("6 of 6 checks passed\n"), "{}", stdout);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_p4-mcp"))
+        .args(["--p4-bin", "/nonexistent/p4", "selftest"])
+        .env_remove("P4_MOCK_MODE")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1), "{}", stdout);
+    assert!(stdout.contains("FAIL p4: "), "{}", stdout);
+    assert!(stdout.contains("PASS initialize: "), "{}", stdout);
+    assert!(stdout.contains("FAIL tools/call: "), "{}", stdout);
+    assert!(stdout.contains("hint: "), "{}", stdout);
+}
+
+#[tokio::test]
+async fn test_tool_allow_and_deny_lists() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[tools]\n\
+         allow = [\"p4_info\", \"p4_opened\", \"p4_submit\", \"p4_suggest_description\"]\n\
+         deny = [\"p4_submit\", \"p4_suggest_*\"]\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    // The client supports sampling, which would otherwise enable p4_suggest_description
+    let initialize = serde_json::from_value(serde_json::json!({
+        "method": "initialize",
+        "id": 1,
+        "params": {
+            "protocolVersion": "2024-11-05",
+            "capabilities": {"sampling": {}},
+            "clientInfo": {"name": "test", "version": "1.0"}
+        }
+    }))
+    .unwrap();
+    server.handle_message(initialize).await.unwrap();
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 2})).unwrap();
+    let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    else {
+        panic!("Expected ListToolsResult response");
+    };
+    let mut names: Vec<_> = result.tools.iter().map(|tool| tool.name.as_str()).collect();
+    names.sort();
+    assert_eq!(names, vec!["p4_info", "p4_opened"]);
+    assert!(!server.set_tool_enabled("p4_submit", true));
+
+    let message = serde_json::from_value(serde_json::json!({
+        "method": "tools/call",
+        "id": 3,
+        "params": {"name": "p4_submit", "arguments": {"description": "Ship it"}}
+    }))
+    .unwrap();
+    match server.handle_message(message).await.unwrap() {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.message, "Unknown tool: p4_submit")
+        }
+        other => panic!("Expected Error response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_alias_tools_from_config() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        r#"
+[aliases.nightly_sync]
+description = "Preview syncing the main branch to head"
+tool = "p4_sync"
+arguments = { path = "//depot/main/...#head", dry_run = true }
+
+[aliases.edit_release]
+tool = "p4_edit"
+arguments = { files = ["//depot/release/{{version}}/VERSION", "{{extra}}"] }
+parameters = { version = { description = "Release version" }, extra = { required = false } }
+"#,
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 1})).unwrap();
+    let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    else {
+        panic!("Expected ListToolsResult response");
+    };
+    let nightly = result
+        .tools
+        .iter()
+        .find(|tool| tool.name == "nightly_sync")
+        .unwrap();
+    assert_eq!(
+        nightly.description,
+        "Preview syncing the main branch to head"
+    );
+    assert_eq!(
+        nightly.annotations.as_ref().unwrap().destructive_hint,
+        Some(true)
+    );
+    // Fixed arguments aren't offered; the server's own ones are
+    assert!(nightly.input_schema["properties"]["cwd"].is_object());
+    assert!(nightly.input_schema["properties"]["dry_run"].is_null());
+    let release = result
+        .tools
+        .iter()
+        .find(|tool| tool.name == "edit_release")
+        .unwrap();
+    assert_eq!(
+        release.input_schema["required"],
+        serde_json::json!(["version"])
+    );
+
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let plan = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str::<serde_json::Value>(text).unwrap(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let nightly = plan(
+        server
+            .handle_message(call(2, "nightly_sync", serde_json::json!({})))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(
+        nightly["commands"][0]["commandLine"],
+        "p4 -s sync //depot/main/...#head"
+    );
+
+    // An optional parameter that isn't passed leaves its placeholder out
+    let release = plan(
+        server
+            .handle_message(call(
+                3,
+                "edit_release",
+                serde_json::json!({"version": "2024.1", "dry_run": true}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(
+        release["commands"][0]["paths"],
+        serde_json::json!(["//depot/release/2024.1/VERSION"])
+    );
+
+    match server
+        .handle_message(call(4, "edit_release", serde_json::json!({})))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.code, -32602);
+            assert_eq!(error.data.unwrap()["pointer"], "/version");
+        }
+        other => panic!("Expected an error, got {:?}", other),
+    }
+
+    for (text, expected) in [
+        (
+            "[aliases.p4_info]\ntool = \"p4_opened\"\n",
+            "has the name of a built-in tool",
+        ),
+        (
+            "[aliases.x]\ntool = \"p4_nothing\"\n",
+            "isn't a built-in tool",
+        ),
+        (
+            "[aliases.x]\ntool = \"p4_sync\"\narguments = { path = \"{{branch}}\" }\n",
+            "placeholder {{branch}}",
+        ),
+    ] {
+        let config = p4_mcp::Config::parse(text).unwrap();
+        let error = ServerContext::from_config(&config)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains(expected), "{}", error);
+    }
+}
+
+#[tokio::test]
+async fn test_config_reload_applies_to_running_sessions() {
+    setup_mock_mode();
+    let mut running = p4_mcp::Config::parse("[sandbox]\ndepot = [\"//depot/main/...\"]\n").unwrap();
+    let context = ServerContext::from_config(&running).unwrap();
+    let mut server = MCPServer::with_context(&context);
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    server.set_notification_sender(tx);
+
+    let sync_secret = |id: i64| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_sync", "arguments": {"path": "//depot/secret/..."}}
+        }))
+        .unwrap()
+    };
+    assert!(server.handle_message(sync_secret(2)).await.is_err());
+
+    let config = p4_mcp::Config::parse(
+        "max_files = 10\n\
+         [tools]\n\
+         deny = [\"p4_submit\"]\n\
+         [sandbox]\n\
+         depot = [\"//depot/...\"]\n",
+    )
+    .unwrap();
+    p4_mcp::reload::apply(&context, &mut running, config.clone());
+    assert_eq!(running, config);
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while rx.recv().await.unwrap().method != "notifications/tools/list_changed" {}
+    })
+    .await
+    .unwrap();
+    assert!(!server.list_tools().iter().any(|t| t.name == "p4_submit"));
+    assert!(server.list_tools().iter().any(|t| t.name == "p4_sync"));
+    assert!(server.handle_message(sync_secret(3)).await.is_ok());
+
+    // Reloading settings that leave the tools alone doesn't notify
+    let config = p4_mcp::Config {
+        timeouts: p4_mcp::p4::Timeouts {
+            default_secs: 5,
+            ..Default::default()
+        },
+        ..running.clone()
+    };
+    p4_mcp::reload::apply(&context, &mut running, config);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    while let Ok(notification) = rx.try_recv() {
+        assert_ne!(notification.method, "notifications/tools/list_changed");
+    }
+}
+
+#[tokio::test]
+async fn test_paths_are_normalized_before_running() {
+    setup_mock_mode();
+    let config = p4_mcp::Config {
+        sandbox: p4_mcp::p4::PathSandbox {
+            depot: vec!["//depot/main/...".to_string()],
+            local: Vec::new(),
+        },
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let sync = |id: i64, path: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_sync", "arguments": {"path": path}}
+        }))
+        .unwrap()
+    };
+
+    // Backslashes in depot syntax become slashes, so the sandbox allows the path
+    let response = server
+        .handle_message(sync(2, "//depot/main\\src\\..."))
+        .await;
+    assert!(
+        matches!(response, Ok(Some(MCPResponse::CallToolResult { .. }))),
+        "{:?}",
+        response
+    );
+
+    #[cfg(not(windows))]
+    {
+        let error = server
+            .handle_message(sync(3, "C:\\ws\\main\\..."))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("is a Windows path"), "{}", error);
+    }
+}
+
+#[tokio::test]
+async fn test_glob_patterns_expand_to_local_files() {
+    setup_mock_mode();
+    let workspace = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(workspace.path().join("src/sub")).unwrap();
+    for file in ["src/a.cpp", "src/sub/b.cpp", "src/c.h"] {
+        std::fs::write(workspace.path().join(file), "").unwrap();
+    }
+    let mut server = initialized_server().await;
+    let call = |id: i64, name: &str, files: &[&str]| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {
+                "name": name,
+                "arguments": {
+                    "files": files,
+                    "cwd": workspace.path().to_str().unwrap(),
+                    "dry_run": true
+                }
+            }
+        }))
+        .unwrap()
+    };
+    let paths = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str::<serde_json::Value>(text).unwrap()
+                ["commands"][0]["paths"]
+                .clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let response = server
+        .handle_message(call(1, "p4_edit", &["src/**/*.cpp", "//depot/main/*.h"]))
+        .await
+        .unwrap();
+    assert_eq!(
+        paths(response),
+        serde_json::json!(["src/a.cpp", "src/sub/b.cpp", "//depot/main/*.h"])
+    );
+
+    // p4 add takes * literally, so it's expanded too; p4 edit understands it
+    let response = server
+        .handle_message(call(2, "p4_add", &["src/*.h"]))
+        .await
+        .unwrap();
+    assert_eq!(paths(response), serde_json::json!(["src/c.h"]));
+    let response = server
+        .handle_message(call(3, "p4_edit", &["src/*.h"]))
+        .await
+        .unwrap();
+    assert_eq!(paths(response), serde_json::json!(["src/*.h"]));
+
+    match server
+        .handle_message(call(4, "p4_edit", &["src/**/*.rs"]))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.code, -32602);
+            assert!(
+                error.message.contains("No files match"),
+                "{}",
+                error.message
+            );
+        }
+        other => panic!("Expected an error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_glob_patterns_search_only_allowed_directories() {
+    setup_mock_mode();
+    let workspace = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(workspace.path().join("src/sub")).unwrap();
+    for file in ["src/a.cpp", "src/sub/b.cpp", "other.cpp"] {
+        std::fs::write(workspace.path().join(file), "").unwrap();
+    }
+    let src = workspace.path().join("src");
+    let config = p4_mcp::Config {
+        sandbox: p4_mcp::p4::PathSandbox {
+            depot: vec![],
+            local: vec![src.clone()],
+        },
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, files: &[&str]| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {
+                "name": "p4_edit",
+                "arguments": {
+                    "files": files,
+                    "cwd": src.to_str().unwrap(),
+                    "dry_run": true
+                }
+            }
+        }))
+        .unwrap()
+    };
+
+    let response = server.handle_message(call(2, &["**/*.cpp"])).await.unwrap();
+    assert!(
+        matches!(response, Some(MCPResponse::CallToolResult { .. })),
+        "{:?}",
+        response
+    );
+
+    // Refused before anything outside the sandbox is read
+    for (id, pattern) in [(3, "../**/*.cpp"), (4, "/**/*.cpp")] {
+        match server.handle_message(call(id, &[pattern])).await.unwrap() {
+            Some(MCPResponse::Error { error, .. }) => {
+                assert_eq!(error.code, -32602);
+                assert!(
+                    error.message.contains(&format!("Can't expand {}", pattern))
+                        && error.message.contains("outside the allowed local paths"),
+                    "{}",
+                    error.message
+                );
+            }
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_cwd_resolves_relative_paths() {
+    setup_mock_mode();
+    let workspace = tempfile::tempdir().unwrap();
+    for dir in ["src", "other"] {
+        std::fs::create_dir_all(workspace.path().join(dir)).unwrap();
+    }
+    let src = workspace.path().join("src");
+    let other = workspace.path().join("other");
+    let config = p4_mcp::Config {
+        sandbox: p4_mcp::p4::PathSandbox {
+            depot: vec![],
+            local: vec![src.clone()],
+        },
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, cwd: &Path, file: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {
+                "name": "p4_edit",
+                "arguments": {"files": [file], "cwd": cwd.to_str().unwrap()}
+            }
+        }))
+        .unwrap()
+    };
+
+    // p4 runs in the call's cwd, so a relative path names a file under it
+    match server.handle_message(call(1, &src, "a.c")).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => {
+            let commands = result.meta.unwrap().commands;
+            assert!(!commands.is_empty());
+            assert!(
+                commands
+                    .iter()
+                    .all(|command| command.cwd.as_deref() == Some(src.as_path())),
+                "{:?}",
+                commands
+            );
+        }
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+
+    // The same relative path from another cwd is outside the sandbox
+    let error = server
+        .handle_message(call(2, &other, "a.c"))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("outside the allowed local paths"),
+        "{}",
+        error
+    );
+    let response = server
+        .handle_message(call(3, &other, "../src/a.c"))
+        .await
+        .unwrap();
+    assert!(
+        matches!(response, Some(MCPResponse::CallToolResult { .. })),
+        "{:?}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn test_paths_outside_sandbox_refused() {
+    setup_mock_mode();
+    let workspace = tempfile::tempdir().unwrap();
+    let config = p4_mcp::Config {
+        sandbox: p4_mcp::p4::PathSandbox {
+            depot: vec!["//depot/main/...".to_string()],
+            local: vec![workspace.path().to_path_buf()],
+        },
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: anyhow::Result<Option<MCPResponse>>| match response {
+        Ok(Some(MCPResponse::CallToolResult { result, .. })) => match &result.content[0] {
+            ToolContent::Text { text } => Ok(text.clone()),
+            _ => panic!("Expected text content"),
+        },
+        Err(e) => Err(e.to_string()),
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let output = text(
+        server
+            .handle_message(call(
+                2,
+                "p4_sync",
+                serde_json::json!({"path": "//depot/main/..."}),
+            ))
+            .await,
+    );
+    assert!(output.is_ok(), "{:?}", output);
+
+    let output = text(
+        server
+            .handle_message(call(
+                3,
+                "p4_sync",
+                serde_json::json!({"path": "//depot/secret/..."}),
+            ))
+            .await,
+    );
+    let output = output.unwrap_err();
+    assert!(
+        output.contains("Path //depot/secret/... is outside the allowed depot paths"),
+        "{}",
+        output
+    );
+
+    let output = text(
+        server
+            .handle_message(call(
+                4,
+                "p4_edit",
+                serde_json::json!({"files": ["src/a.c", "../../etc/passwd"], "cwd": workspace.path()}),
+            ))
+            .await,
+    );
+    let output = output.unwrap_err();
+    assert!(output.contains("../../etc/passwd"), "{}", output);
+}
+
+#[tokio::test]
+async fn test_sandboxed_changes_need_a_path() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse("[sandbox]\ndepot = [\"//depot/main/...\"]\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_changes", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    // Without a path, p4 changes would describe changes anywhere in the depot
+    let error = server
+        .handle_message(call(1, serde_json::json!({})))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("p4 changes needs a path"), "{}", error);
+
+    let response = server
+        .handle_message(call(2, serde_json::json!({"path": "//depot/main/..."})))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+}
+
+#[tokio::test]
+async fn test_sandbox_checks_the_files_of_changes() {
+    use p4_mcp::p4::{P4Command, P4Handler, PathSandbox};
+
+    setup_mock_mode();
+    let mut handler = P4Handler::new();
+    handler.set_path_sandbox(PathSandbox {
+        depot: vec![
+            "//depot/main/file1.txt".to_string(),
+            "//depot/main/file2.cpp".to_string(),
+        ],
+        local: Vec::new(),
+    });
+
+    // file3.h is open in every mock change, and outside the sandbox
+    for command in [
+        P4Command::SubmitChangelist {
+            changelist: "12346".to_string(),
+        },
+        P4Command::RevertChange {
+            changelist: "12346".to_string(),
+        },
+        P4Command::DeleteChange {
+            changelist: "12346".to_string(),
+        },
+    ] {
+        let error = handler.execute(command).await.unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "Change 12346 has files outside the allowed paths: //depot/main/file3.h"
+        );
+    }
+    let error = handler
+        .execute(P4Command::Status { path: None })
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("needs a path"), "{}", error);
+
+    // Changes and opened files are listed without the files outside it
+    let text = handler
+        .execute(P4Command::Describe {
+            changelist: "12345".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(
+        text.contains("... //depot/main/file1.txt#3 edit"),
+        "{}",
+        text
+    );
+    assert!(
+        text.contains("... //depot/main/file2.cpp#1 add"),
+        "{}",
+        text
+    );
+    let text = handler
+        .execute(P4Command::DescribeShelved {
+            changelist: "12347".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(text.contains("==== //depot/main/file1.txt#3 (text) ===="));
+    let shelved = handler
+        .execute(P4Command::DescribeShelvedFiles {
+            changelist: "12347".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(!shelved.contains("file3.h"), "{}", shelved);
+    let opened = handler.opened(None).await.unwrap();
+    assert_eq!(opened.len(), 2);
+    assert!(opened
+        .iter()
+        .all(|file| !file.depot_file.contains("file3.h")));
+
+    let mut handler = P4Handler::new();
+    handler.set_path_sandbox(PathSandbox {
+        depot: vec!["//depot/main/file1.txt".to_string()],
+        local: Vec::new(),
+    });
+    let change = handler.describe("12345").await.unwrap();
+    let files: Vec<&str> = change.files.iter().map(|f| f.depot_file.as_str()).collect();
+    assert_eq!(files, ["//depot/main/file1.txt"]);
+    assert_eq!(change.jobs, ["job000123"]);
+    let text = handler
+        .execute(P4Command::Describe {
+            changelist: "12345".to_string(),
+        })
+        .await
+        .unwrap();
+    assert!(!text.contains("file2.cpp"), "{}", text);
+    assert!(text.contains("Fix crash on startup"));
+}
+
+#[tokio::test]
+async fn test_destructive_call_needs_confirmation_token() {
+    setup_mock_mode();
+    let config =
+        p4_mcp::Config::parse("[confirmation]\ntools = { p4_revert = \"token\" }\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 2})).unwrap();
+    let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    else {
+        panic!("Expected ListToolsResult response");
+    };
+    let schema = |name: &str| {
+        result
+            .tools
+            .iter()
+            .find(|tool| tool.name == name)
+            .unwrap()
+            .input_schema
+            .clone()
+    };
+    assert!(schema("p4_revert")["properties"]["confirmation_token"].is_object());
+    assert!(schema("p4_sync")["properties"]["confirmation_token"].is_null());
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_revert", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let wipe = serde_json::json!({"files": ["//depot/main/file1.txt"], "wipe": true});
+
+    let (text, structured) = match server.handle_message(call(3, wipe.clone())).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => (text.clone(), result.structured_content.unwrap()),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert!(text.starts_with("Confirmation required: Revert //depot/main/file1.txt"));
+    assert!(text.contains("//depot/main/file1.txt#1 - was edit, reverted (wiped)"));
+    assert!(!text.contains("Mock P4 Revert"));
+    assert_eq!(structured["confirmationRequired"], true);
+    let token = structured["confirmationToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let error = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::Error { error, .. }) => error.message,
+        other => panic!("Expected Error response, got {:?}", other),
+    };
+    // The token only confirms the call it was issued for
+    let mut other_files = wipe.clone();
+    other_files["files"] = serde_json::json!(["//depot/main/file2.cpp"]);
+    other_files["confirmation_token"] = token.clone().into();
+    assert!(
+        error(server.handle_message(call(4, other_files)).await.unwrap())
+            .starts_with("Invalid or expired confirmation_token")
+    );
+
+    // ... and was used up by the mismatched call
+    let (text, structured) = match server.handle_message(call(5, wipe.clone())).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => (text.clone(), result.structured_content.unwrap()),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert!(text.starts_with("Confirmation required"));
+    let token = structured["confirmationToken"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut confirmed = wipe.clone();
+    confirmed["confirmation_token"] = token.into();
+    match server
+        .handle_message(call(6, confirmed.clone()))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => assert!(text.contains("Mock P4 Revert"), "{}", text),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+    assert!(
+        error(server.handle_message(call(7, confirmed)).await.unwrap())
+            .starts_with("Invalid or expired confirmation_token")
+    );
+
+    // Reverts that keep local files need no confirmation
+    match server
+        .handle_message(call(
+            8,
+            serde_json::json!({"files": ["//depot/main/file1.txt"]}),
+        ))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => assert!(text.contains("Mock P4 Revert")),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_client_named_per_call() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let listed = server.list_tools();
+    let schema = |name: &str| {
+        listed
+            .iter()
+            .find(|tool| tool.name == name)
+            .unwrap()
+            .input_schema
+            .clone()
+    };
+    assert!(schema("p4_edit")["properties"]["client"].is_object());
+    assert!(schema("p4_changes")["properties"]["client"].is_null());
+
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_edit", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let command_line = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => {
+                let plan: serde_json::Value = serde_json::from_str(text).unwrap();
+                plan["commands"][0]["commandLine"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            }
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let edit = serde_json::json!({"files": ["//depot/rel/a.c"], "dry_run": true});
+    let mut in_release = edit.clone();
+    in_release["client"] = "alice-release".into();
+    assert_eq!(
+        command_line(server.handle_message(call(1, in_release)).await.unwrap()),
+        "p4 -c alice-release -s edit //depot/rel/a.c"
+    );
+    // The next call is back in the server's client
+    assert_eq!(
+        command_line(server.handle_message(call(2, edit.clone())).await.unwrap()),
+        "p4 -s edit //depot/rel/a.c"
+    );
+
+    let mut flag = edit.clone();
+    flag["client"] = "-d".into();
+    match server.handle_message(call(3, flag)).await.unwrap() {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.code, -32602);
+            assert_eq!(error.message, "-d is not a valid client name");
+        }
+        other => panic!("Expected Error response, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_dry_run_returns_command_plan() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let plan = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str::<serde_json::Value>(text).unwrap(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let edit = plan(
+        server
+            .handle_message(call(
+                1,
+                "p4_edit",
+                serde_json::json!({"files": ["//depot/main/a file.txt"], "dry_run": true}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(edit["dryRun"], true);
+    assert_eq!(
+        edit["commands"],
+        serde_json::json!([{
+            "commandLine": "p4 -s edit \"//depot/main/a file.txt\"",
+            "cwd": null,
+            "paths": ["//depot/main/a file.txt"],
+            "executed": false
+        }])
+    );
+
+    // Reads run so later steps can be planned; the first write stops the workflow
+    let submit = plan(
+        server
+            .handle_message(call(
+                2,
+                "p4_submit",
+                serde_json::json!({"description": "Fix the build", "dry_run": true}),
+            ))
+            .await
+            .unwrap(),
+    );
+    let commands = submit["commands"].as_array().unwrap();
+    assert_eq!(commands[0]["commandLine"], "p4 -s change -o");
+    assert_eq!(commands[0]["executed"], true);
+    assert_eq!(commands[1]["commandLine"], "p4 -s change -i");
+    assert_eq!(commands[1]["executed"], false);
+    assert!(commands[1]["input"]
+        .as_str()
+        .unwrap()
+        .contains("\tFix the build"));
+    assert!(submit["stoppedEarly"].is_string());
+
+    // Destructive calls aren't confirmed, since nothing happens
+    let config = p4_mcp::Config::parse(
+        "dry_run = true\n[confirmation]\ntools = { p4_revert = \"token\" }\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let revert = plan(
+        server
+            .handle_message(call(
+                3,
+                "p4_revert",
+                serde_json::json!({"files": ["//depot/main/file1.txt"], "wipe": true}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(
+        revert["commands"][0]["commandLine"],
+        "p4 -s revert -w //depot/main/file1.txt"
+    );
+    assert_eq!(revert["commands"][0]["executed"], false);
+}
+
+#[tokio::test]
+async fn test_rate_limited_calls_refused() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[rate_limits]\ntools = { p4_opened = { calls = 2, per_secs = 3600 } }\n",
+    )
+    .unwrap();
+    let context = ServerContext::from_config(&config).unwrap();
+    let mut server = MCPServer::with_context(&context);
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    // Dry runs don't count, and other tools aren't limited
+    for (id, name, arguments) in [
+        (1, "p4_opened", serde_json::json!({"dry_run": true})),
+        (2, "p4_info", serde_json::json!({})),
+        (3, "p4_opened", serde_json::json!({})),
+    ] {
+        let response = server.handle_message(call(id, name, arguments)).await;
+        assert!(matches!(
+            response.unwrap(),
+            Some(MCPResponse::CallToolResult { .. })
+        ));
+    }
+
+    // The limit is shared by every session
+    let mut other = MCPServer::with_context(&context);
+    other
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let response = other
+        .handle_message(call(4, "p4_opened", serde_json::json!({})))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    let response = server
+        .handle_message(call(5, "p4_opened", serde_json::json!({})))
+        .await
+        .unwrap();
+    match response {
+        Some(MCPResponse::Error { id, error }) => {
+            assert_eq!(id, 5);
+            assert_eq!(error.code, p4_mcp::mcp::RATE_LIMITED);
+            let data = error.data.unwrap();
+            assert_eq!(data["rateLimited"], true);
+            assert_eq!(data["tool"], "p4_opened");
+            assert_eq!(data["calls"], 2);
+            assert_eq!(data["perSecs"], 3600);
+            let retry_after = data["retryAfterSecs"].as_u64().unwrap();
+            assert!(retry_after > 3500 && retry_after <= 3600);
+        }
+        other => panic!("Expected a rate limit error, got {:?}", other),
+    }
+
+    // A limit of no calls, or of a window of no time, is a mistake in the config
+    for limit in ["calls = 0, per_secs = 60", "calls = 5, per_secs = 0"] {
+        let error = p4_mcp::Config::parse(&format!(
+            "[rate_limits]\ntools = {{ p4_sync = {{ {} }} }}\n",
+            limit
+        ))
+        .unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("must be at least 1"),
+            "{:#}",
+            error
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_markdown_format() {
+    setup_mock_mode();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let mut server = initialized_server().await;
+    let changes = text(
+        server
+            .handle_message(call(
+                1,
+                "p4_changes",
+                serde_json::json!({"format": "markdown"}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert!(changes.contains("| Change | Date | User | Client | Description |"));
+    let plain = text(
+        server
+            .handle_message(call(2, "p4_changes", serde_json::json!({})))
+            .await
+            .unwrap(),
+    );
+    assert!(plain.starts_with("Change 12350 on "), "{}", plain);
+
+    // The config sets the default, which a call can still override
+    let config = p4_mcp::Config::parse("format = \"markdown\"\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let info = text(
+        server
+            .handle_message(call(3, "p4_info", serde_json::json!({})))
+            .await
+            .unwrap(),
+    );
+    assert!(info.starts_with("```json\n"), "{}", info);
+    assert!(info.contains("\"userName\": \"testuser\""));
+    let info = text(
+        server
+            .handle_message(call(4, "p4_info", serde_json::json!({"format": "text"})))
+            .await
+            .unwrap(),
+    );
+    assert!(info.starts_with("{\n"), "{}", info);
+    let info: serde_json::Value = serde_json::from_str(&info).unwrap();
+    assert_eq!(info["userName"], "testuser");
+}
+
+#[tokio::test]
+async fn test_tool_results_report_execution() {
+    setup_mock_mode();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let meta = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => result.meta.unwrap(),
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    let mut server = initialized_server().await;
+    let changes = meta(
+        server
+            .handle_message(call(1, "p4_changes", serde_json::json!({"max": 5})))
+            .await
+            .unwrap(),
+    );
+    // One more change than asked for tells whether there's another page, and the text
+    // and structured content both come from the one tagged run
+    let lines: Vec<_> = changes
+        .commands
+        .iter()
+        .map(|command| command.command_line.as_str())
+        .collect();
+    assert_eq!(lines, ["mock -ztag changes -m 6"]);
+    assert_eq!(changes.commands[0].exit_code, Some(0));
+    assert!(!changes.truncated);
+
+    // alice has file3.h open exclusively, so the edit only partly succeeds
+    let edit = meta(
+        server
+            .handle_message(call(
+                2,
+                "p4_edit",
+                serde_json::json!({"files": ["//depot/main/file1.txt", "//depot/main/file3.h"]}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(edit.commands[0].exit_code, Some(1));
+
+    let config = p4_mcp::Config {
+        max_response_bytes: 60,
+        ..Default::default()
+    };
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let response = server
+        .handle_message(call(3, "p4_changes", serde_json::json!({})))
+        .await
+        .unwrap()
+        .unwrap();
+    let json = serde_json::to_value(&response).unwrap();
+    assert_eq!(json["result"]["_meta"]["truncated"], true);
+    assert!(json["result"]["_meta"]["durationMs"].is_u64());
+    assert!(json["result"]["_meta"]["commands"][0]["commandLine"]
+        .as_str()
+        .unwrap()
+        .contains("changes"));
+}
+
+#[tokio::test]
+async fn test_request_spans_in_json_logs() {
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+    impl std::io::Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    setup_mock_mode();
+    let logs = Buffer::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_writer(move || writer.clone())
+        .json()
+        .with_span_list(true)
+        .finish();
+    let _default = tracing::subscriber::set_default(subscriber);
+
+    let mut server = MCPServer::new();
+    server.set_session_id("session-1");
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": {}}
+        }))
+        .unwrap()
+    };
+    let (mut first, mut second) = (server.fork(), server.fork());
+    let (info, opened) = tokio::join!(
+        first.handle_message(call(7, "p4_info")),
+        second.handle_message(call(8, "p4_opened"))
+    );
+    info.unwrap();
+    opened.unwrap();
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<serde_json::Value> = logs
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    // Each p4 command's log lines name the request and tool it ran for
+    let command_spans = |command: &str| -> Vec<serde_json::Value> {
+        lines
+            .iter()
+            .filter(|line| line["span"]["command"] == command)
+            .map(|line| line["spans"][0].clone())
+            .collect()
+    };
+    let info = command_spans("info");
+    assert!(!info.is_empty());
+    for request in &info {
+        assert_eq!(request["name"], "request");
+        assert_eq!(request["session"], "session-1");
+        assert_eq!(request["id"], "7");
+        assert_eq!(request["method"], "tools/call");
+        assert_eq!(request["tool"], "p4_info");
+    }
+    let opened = command_spans("opened");
+    assert!(!opened.is_empty());
+    assert!(opened
+        .iter()
+        .all(|request| request["id"] == "8" && request["tool"] == "p4_opened"));
+}
+
+#[tokio::test]
+async fn test_server_stats_counts_session_usage() {
+    setup_mock_mode();
+    let mut server = MCPServer::new();
+    server.set_session_id("stats-session");
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    let info = match server
+        .handle_message(call(1, "p4_info", serde_json::json!({})))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.len(),
+            other => panic!("Expected text content, got {:?}", other),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    // A fork is part of the same session
+    let _ = server
+        .fork()
+        .handle_message(call(
+            2,
+            "p4_edit",
+            serde_json::json!({"files": ["//depot/main/file3.h"]}),
+        ))
+        .await;
+    server
+        .handle_message(call(3, "p4_no_such_tool", serde_json::json!({})))
+        .await
+        .unwrap();
+
+    let stats = match server
+        .handle_message(call(4, "server_stats", serde_json::json!({})))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str::<serde_json::Value>(text).unwrap(),
+            other => panic!("Expected text content, got {:?}", other),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert_eq!(stats["session"], "stats-session");
+    let p4_info = &stats["tools"]["p4_info"];
+    assert_eq!(p4_info["calls"], 1);
+    assert_eq!(p4_info["errors"], 0);
+    assert_eq!(p4_info["bytes_returned"], info as u64);
+    assert!(p4_info["p4_commands"].as_u64().unwrap() >= 1);
+    assert_eq!(stats["tools"]["p4_edit"]["errors"], 1);
+    assert_eq!(stats["tools"]["p4_edit"]["p4_commands"], 1);
+    assert_eq!(stats["tools"]["unknown"]["errors"], 1);
+    assert_eq!(stats["totals"]["calls"], 3);
+    assert_eq!(stats["totals"]["errors"], 2);
+    assert_eq!(stats["errors"]["tool_failed"], 1);
+    assert_eq!(stats["errors"]["invalid_params"], 1);
+
+    // Every session starts from zero
+    let mut other = MCPServer::new();
+    other
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    match other
+        .handle_message(call(1, "server_stats", serde_json::json!({})))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => assert!(text.contains("\"tools\": {}")),
+            other => panic!("Expected text content, got {:?}", other),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    setup_mock_mode();
+    let context = ServerContext::from_env();
+    let mut server = MCPServer::with_context(&context);
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    server
+        .handle_message(call(1, "p4_info", serde_json::json!({})))
+        .await
+        .unwrap();
+    server
+        .handle_message(call(2, "p4_no_such_tool", serde_json::json!({})))
+        .await
+        .unwrap();
+    // alice has file3.h open exclusively, so p4 edit fails for it
+    let _ = server
+        .handle_message(call(
+            3,
+            "p4_edit",
+            serde_json::json!({"files": ["//depot/main/file3.h"]}),
+        ))
+        .await;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        axum::serve(listener, p4_mcp::transport::sse::router(context))
+            .await
+            .unwrap();
+    });
+    let response = reqwest::get(format!("{}/metrics", base)).await.unwrap();
+    assert_eq!(
+        response.headers()["content-type"].to_str().unwrap(),
+        "text/plain; version=0.0.4"
+    );
+    let metrics = response.text().await.unwrap();
+    assert!(metrics.contains(r#"p4mcp_tool_calls_total{tool="p4_info",outcome="ok"} 1"#));
+    assert!(metrics.contains(r#"p4mcp_tool_calls_total{tool="unknown",outcome="error"} 1"#));
+    assert!(metrics.contains(r#"p4mcp_tool_calls_total{tool="p4_edit",outcome="error"} 1"#));
+    assert!(metrics.contains(r#"p4mcp_p4_commands_total{command="info",result="ok"}"#));
+    assert!(metrics.contains(r#"p4mcp_p4_commands_total{command="edit",result="error"} 1"#));
+    assert!(
+        metrics.contains(r#"p4mcp_p4_command_duration_seconds_bucket{command="info",le="+Inf"}"#)
+    );
+    assert!(metrics.contains("p4mcp_errors_total{category=\"p4_command\"} 1"));
+    assert!(metrics.contains("p4mcp_errors_total{category=\"invalid_params\"} 1"));
+    assert!(metrics.contains("p4mcp_requests_in_flight 0\n"));
+    assert!(metrics.contains("p4mcp_p4_commands_in_flight 0\n"));
+}
+
+#[tokio::test]
+async fn test_calls_touching_too_many_files_refused() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse("max_files = 2\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let refused_count = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::Error { error, .. }) => {
+            let data = error.data.unwrap();
+            assert_eq!(data["tooManyFiles"], true);
+            assert_eq!(data["maxFiles"], 2);
+            assert!(error.message.contains("allow_many_files"));
+            data["files"].as_u64().unwrap()
+        }
+        other => panic!("Expected a file count error, got {:?}", other),
+    };
+
+    let response = server
+        .handle_message(call(
+            1,
+            "p4_edit",
+            serde_json::json!({"files": ["a.txt", "b.txt"]}),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    let response = server
+        .handle_message(call(
+            2,
+            "p4_add",
+            serde_json::json!({"files": ["a.txt", "b.txt", "c.txt"]}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(refused_count(response), 3);
+
+    // A wildcard counts as the files it expands to
+    let response = server
+        .handle_message(call(
+            3,
+            "p4_edit",
+            serde_json::json!({"files": ["//depot/main/..."]}),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(refused_count(response), 3);
+
+    let response = server
+        .handle_message(call(
+            4,
+            "p4_edit",
+            serde_json::json!({"files": ["//depot/main/..."], "allow_many_files": true}),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+
+    // Other tools aren't limited
+    let response = server
+        .handle_message(call(
+            5,
+            "p4_files",
+            serde_json::json!({"path": "//depot/main/..."}),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+}
+
+#[test]
+fn test_submit_policy_settings_left_out_come_from_the_environment() {
+    // Only ever lowers the minimum, so the other tests' descriptions still pass, and
+    // no test compares a whole Config
+    std::env::set_var("P4_SUBMIT_MIN_DESCRIPTION_LENGTH", "5");
+    let partial = p4_mcp::Config::parse("[submit]\nrequire_job = true\n").unwrap();
+    let explicit = p4_mcp::Config::parse("[submit]\nmin_description_length = 20\n").unwrap();
+    let missing = p4_mcp::Config::parse("").unwrap();
+    std::env::remove_var("P4_SUBMIT_MIN_DESCRIPTION_LENGTH");
+
+    assert_eq!(partial.submit.min_description_length, 5);
+    assert!(partial.submit.require_job);
+    assert_eq!(explicit.submit.min_description_length, 20);
+    assert_eq!(missing.submit.min_description_length, 5);
+}
+
+#[tokio::test]
+async fn test_submit_policy_enforced() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[submit]\nmin_description_length = 10\nrequire_job = true\nbanned_files = [\"*.dll\"]\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_submit", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+
+    let error = server
+        .handle_message(call(
+            1,
+            serde_json::json!({"description": "fix", "files": ["//depot/main/bin/tool.dll"]}),
+        ))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.starts_with("Submit refused by policy; nothing was submitted:"));
+    assert!(error.contains("- Change description is too short"));
+    assert!(error.contains("- Changes must fix a job"));
+    assert!(error.contains("- //depot/main/bin/tool.dll may not be submitted (banned by *.dll)"));
+
+    // The jobs are saved with the change
+    let response = server
+        .handle_message(call(
+            2,
+            serde_json::json!({
+                "description": "Fix crash on startup",
+                "jobs": ["job000123"],
+                "dry_run": true
+            }),
+        ))
+        .await
+        .unwrap();
+    let plan: serde_json::Value = match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => serde_json::from_str(text).unwrap(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert!(plan["commands"][1]["input"]
+        .as_str()
+        .unwrap()
+        .contains("Jobs:\n\tjob000123\n"));
+
+    // Without a policy configured, a short description still submits
+    let mut server = initialized_server().await;
+    let response = server
+        .handle_message(call(
+            3,
+            serde_json::json!({"description": "Fix bug", "files": ["//depot/main/file1.txt"]}),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::CallToolResult { .. })));
+}
+
+#[tokio::test]
+async fn test_force_sync_needs_opt_in() {
+    setup_mock_mode();
+    let sync = |id: i64| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_sync", "arguments": {"path": "//depot/main/...", "force": true}}
+        }))
+        .unwrap()
+    };
+
+    let mut server = initialized_server().await;
+    match server.handle_message(sync(1)).await.unwrap() {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert!(error.message.starts_with("Force sync is disabled"));
+            assert!(error.message.contains("allow_force_sync = true"));
+        }
+        other => panic!("Expected an error, got {:?}", other),
+    }
+
+    let config = p4_mcp::Config::parse(
+        "allow_force_sync = true\n[confirmation]\ntools = { p4_sync = \"none\" }\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let text = match server.handle_message(sync(2)).await.unwrap() {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    assert!(text.contains("Mock P4 Sync (forced)"));
+    assert!(text.ends_with(
+        "Warning: force sync rewrote 2 file(s) under //depot/main/..., overwriting local \
+         changes to 1 file(s) that weren't opened:\n  \
+         C:\\workspace\\p4\\test-client\\main\\file2.cpp\n"
+    ));
+}
+
+#[tokio::test]
+async fn test_mock_faults_fail_tool_calls() {
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[mock_faults.commands.opened]\nerror_rate = 1.0\nerrors = [\"expired_ticket\"]\n\n\
+         [mock_faults.commands.changes]\nlatency_ms = 5000\n\n[timeouts]\ndefault_secs = 1\n",
+    )
+    .unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let call = |id: i64, name: &str| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": {}}
+        }))
+        .unwrap()
+    };
+
+    let error = server
+        .handle_message(call(1, "p4_opened"))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Your session has expired"), "{}", error);
+
+    // Latency counts towards the timeout
+    match server.handle_message(call(2, "p4_changes")).await.unwrap() {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.data.unwrap()["timedOut"], true);
+        }
+        other => panic!("Expected a timeout, got {:?}", other),
+    }
+
+    // Other commands are unaffected
+    match server.handle_message(call(3, "p4_info")).await.unwrap() {
+        Some(MCPResponse::CallToolResult { .. }) => {}
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_only_read_commands_are_retried() {
+    use p4_mcp::p4::{ExecutionLog, P4Command, P4Handler};
+
+    setup_mock_mode();
+    let config = p4_mcp::Config::parse(
+        "[mock_faults]\nerror_rate = 1.0\nerrors = [\"connection_reset\"]\n\n\
+         [retry]\nmax_attempts = 3\ninitial_backoff_ms = 1\n",
+    )
+    .unwrap();
+    let mut handler = P4Handler::new();
+    handler.set_mock_faults(config.mock_faults.clone());
+    handler.set_retry_policy(config.retry.clone());
+
+    let executions = ExecutionLog::new();
+    handler.set_execution_log(Some(executions.clone()));
+    let error = handler
+        .execute(P4Command::Opened { changelist: None })
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("Connection reset"), "{}", error);
+    assert_eq!(executions.commands().len(), 3);
+
+    // The edit may have reached the server before the connection dropped
+    let executions = ExecutionLog::new();
+    handler.set_execution_log(Some(executions.clone()));
+    handler
+        .execute(P4Command::Edit {
+            files: vec!["//depot/main/file1.txt".to_string()],
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(executions.commands().len(), 1);
+
+    // As may a populate, which submits as well
+    let executions = ExecutionLog::new();
+    handler.set_execution_log(Some(executions.clone()));
+    handler
+        .execute(P4Command::Populate {
+            from: "//depot/main/...".to_string(),
+            to: "//depot/rel/...".to_string(),
+            description: "Branch release 2.0".to_string(),
+        })
+        .await
+        .unwrap_err();
+    assert_eq!(executions.commands().len(), 1);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_backend_switches_at_runtime() {
+    use std::os::unix::fs::PermissionsExt;
+
+    setup_mock_mode();
+    let call = |id: i64, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_backend", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let info = |id: i64| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "p4_info", "arguments": {}}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+
+    // Switching is off unless configured
+    let mut server = initialized_server().await;
+    let report = text(
+        server
+            .handle_message(call(1, serde_json::json!({})))
+            .await
+            .unwrap(),
+    );
+    assert!(report.starts_with("Using the mock backend"), "{}", report);
+    let error = server
+        .handle_message(call(2, serde_json::json!({"mode": "real"})))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("allow_backend_switch = true"), "{}", error);
+
+    let dir = tempfile::tempdir().unwrap();
+    let p4 = dir.path().join("p4");
+    fs::write(
+        &p4,
+        "#!/bin/sh\ncase \"$*\" in *-V*) echo 'Rev. P4/LINUX/2024.1/2596294 (2024/05/01).';; \
+         *info*) echo '... userName real-user';; \
+         *) echo 'info: User name: real-user'; echo 'exit: 0';; esac\n",
+    )
+    .unwrap();
+    fs::set_permissions(&p4, fs::Permissions::from_mode(0o755)).unwrap();
+    let config = p4_mcp::Config::parse(&format!(
+        "p4_bin = {:?}\nallow_backend_switch = true\n",
+        p4.to_str().unwrap()
+    ))
+    .unwrap();
+    let context = ServerContext::from_config(&config).unwrap();
+    let mut first = MCPServer::with_context(&context);
+    let mut second = MCPServer::with_context(&context);
+    for server in [&mut first, &mut second] {
+        server
+            .handle_message(load_test_message("test_initialize.json"))
+            .await
+            .unwrap();
+    }
+
+    // A switch in one session applies to every session
+    let report = text(
+        first
+            .handle_message(call(3, serde_json::json!({"mode": "real"})))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(
+        report,
+        "Switched from the mock backend to the real backend for every session"
+    );
+    let output = text(second.handle_message(info(10)).await.unwrap());
+    assert!(output.contains("real-user"), "{}", output);
+
+    // Dry runs report the switch without making it
+    let response = second
+        .handle_message(call(
+            4,
+            serde_json::json!({"mode": "mock", "dry_run": true}),
+        ))
+        .await
+        .unwrap();
+    assert!(text(response).contains("Dry runs don't switch the backend"));
+
+    text(
+        second
+            .handle_message(call(5, serde_json::json!({"mode": "mock"})))
+            .await
+            .unwrap(),
+    );
+    let output = text(first.handle_message(info(11)).await.unwrap());
+    assert!(output.contains("\"userName\": \"testuser\""), "{}", output);
+}
+
+#[tokio::test]
+async fn test_mock_users_hold_files_open_and_locked() {
+    setup_mock_mode();
+    let call = |id: i64, name: &str, arguments: serde_json::Value| {
+        serde_json::from_value::<MCPMessage>(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => text.clone(),
+            _ => panic!("Expected text content"),
+        },
+        other => panic!("Expected CallToolResult, got {:?}", other),
+    };
+    let mut server = initialized_server().await;
+
+    // alice has file3.h open exclusively, so it can't be opened
+    let error = server
+        .handle_message(call(
+            1,
+            "p4_edit",
+            serde_json::json!({"files": ["//depot/main/file3.h"]}),
+        ))
+        .await
+        .unwrap_err()
+        .to_string();
+    assert!(
+        error.contains("//depot/main/file3.h - can't edit exclusive file already opened"),
+        "{}",
+        error
+    );
+
+    // Other files still open, with a warning about bob having one of them open too
+    let output = text(
+        server
+            .handle_message(call(
+                2,
+                "p4_edit",
+                serde_json::json!({"files": ["//depot/main/file1.txt", "//depot/main/file3.h"]}),
+            ))
+            .await
+            .unwrap(),
+    );
+    assert!(output.contains("1 file(s) opened for edit"), "{}", output);
+    assert!(output.contains("//depot/main/file1.txt - also opened by bob@bob-ws"));
+    assert!(output.contains("The command only partly succeeded"));
+    assert!(output.contains("can't edit exclusive file already opened"));
+
+    // Without other users there are no foreign locks to report
+    let config = p4_mcp::Config::parse("mock_users = []\n").unwrap();
+    let mut server = MCPServer::with_context(&ServerContext::from_config(&config).unwrap());
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+    let report: serde_json::Value = serde_json::from_str(&text(
+        server
+            .handle_message(call(
+                3,
+                "p4_submit_preflight",
+                serde_json::json!({"changelist": "12345"}),
+            ))
+            .await
+            .unwrap(),
+    ))
+    .unwrap();
+    let checks = report["checks"].as_array().unwrap();
+    let locks = checks
+        .iter()
+        .find(|c| c["name"] == "no_foreign_locks")
+        .unwrap();
+    assert_eq!(locks["pass"], true, "{}", report);
+}
+
+#[tokio::test]
+async fn test_server_builder_customizes_tools() {
+    use p4_mcp::mcp::{Tool, ToolAnnotations, ToolHandler};
+    use p4_mcp::p4::{P4Command, P4Handler};
+
+    /// Reports who is logged in, through the session's p4 handler.
+    struct WhoAmI;
+
+    #[async_trait::async_trait]
+    impl ToolHandler for WhoAmI {
+        async fn call(
+            &self,
+            p4: &mut P4Handler,
+            arguments: serde_json::Value,
+        ) -> anyhow::Result<String> {
+            let info = p4.info().await?;
+            let greeting = arguments["greeting"].as_str().unwrap_or("Hello");
+            Ok(format!("{}, {}", greeting, info.user_name))
+        }
+    }
+
+    /// Answers in place of the built-in p4_opened.
+    struct NothingOpened;
+
+    #[async_trait::async_trait]
+    impl ToolHandler for NothingOpened {
+        async fn call(&self, p4: &mut P4Handler, _: serde_json::Value) -> anyhow::Result<String> {
+            p4.execute(P4Command::Info).await?;
+            Ok("Nothing is open".to_string())
+        }
+    }
+
+    setup_mock_mode();
+    let mut server = MCPServer::builder()
+        .remove_tool("p4_submit")
+        .description("p4_sync", "Sync the build workspace")
+        .output_schema("p4_changes", None)
+        .tool(
+            Tool {
+                name: "whoami".to_string(),
+                description: "Say who is logged in".to_string(),
+                input_schema: serde_json::json!({
+                    "type": "object",
+                    "properties": {"greeting": {"type": "string"}}
+                }),
+                output_schema: None,
+                annotations: Some(ToolAnnotations::read_only("Who Am I")),
+            },
+            WhoAmI,
+        )
+        .handler("p4_opened", NothingOpened)
+        .handler("no_such_tool", NothingOpened)
+        .build();
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 1})).unwrap();
+    let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    else {
+        panic!("Expected ListToolsResult response");
+    };
+    let tool = |name: &str| result.tools.iter().find(|t| t.name == name);
+    assert!(tool("p4_submit").is_none());
+    assert!(tool("no_such_tool").is_none());
+    assert_eq!(
+        tool("p4_sync").unwrap().description,
+        "Sync the build workspace"
+    );
+    assert!(tool("p4_changes").unwrap().output_schema.is_none());
+    // Added tools take the arguments the server handles itself
+    let whoami = tool("whoami").unwrap();
+    assert!(whoami.input_schema["properties"]["greeting"].is_object());
+    assert!(whoami.input_schema["properties"]["cwd"].is_object());
+
+    let call = |id: i64, name: &str, arguments: serde_json::Value| -> MCPMessage {
+        serde_json::from_value(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": name, "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let text = |response: Option<MCPResponse>| match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => {
+            assert!(result.structured_content.is_none());
+            match &result.content[0] {
+                ToolContent::Text { text } => text.clone(),
+                other => panic!("Expected text content, got {:?}", other),
+            }
+        }
+        other => panic!("Expected CallToolResult response, got {:?}", other),
+    };
+
+    let response = server
+        .handle_message(call(2, "whoami", serde_json::json!({"greeting": "Hi"})))
+        .await
+        .unwrap();
+    assert_eq!(text(response), "Hi, testuser");
+    let response = server
+        .handle_message(call(3, "p4_opened", serde_json::json!({})))
+        .await
+        .unwrap();
+    assert_eq!(text(response), "Nothing is open");
+    let response = server
+        .handle_message(call(
+            4,
+            "p4_submit",
+            serde_json::json!({"description": "x"}),
+        ))
+        .await
+        .unwrap();
+    assert!(matches!(response, Some(MCPResponse::Error { .. })));
+}
+
+#[tokio::test]
+async fn test_register_tool_with_closure() {
+    setup_mock_mode();
+    let triggered = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let builds = triggered.clone();
+    let mut server = MCPServer::builder()
+        .register_tool(
+            "trigger_build",
+            serde_json::json!({
+                "type": "object",
+                "description": "Start a CI build of a submitted change",
+                "properties": {"change": {"type": "string"}},
+                "required": ["change"]
+            }),
+            move |mut p4, arguments| {
+                let builds = builds.clone();
+                async move {
+                    let Some(change) = arguments["change"].as_str() else {
+                        anyhow::bail!("A change is required");
+                    };
+                    let described = p4.describe(change).await?;
+                    builds.lock().unwrap().push(described.change);
+                    Ok(format!("Build queued for change {}", described.change))
+                }
+            },
+        )
+        .build();
+    server
+        .handle_message(load_test_message("test_initialize.json"))
+        .await
+        .unwrap();
+
+    let message =
+        serde_json::from_value(serde_json::json!({"method": "tools/list", "id": 1})).unwrap();
+    let Some(MCPResponse::ListToolsResult { result, .. }) =
+        server.handle_message(message).await.unwrap()
+    else {
+        panic!("Expected ListToolsResult response");
+    };
+    let tool = result
+        .tools
+        .iter()
+        .find(|t| t.name == "trigger_build")
+        .unwrap();
+    assert_eq!(tool.description, "Start a CI build of a submitted change");
+    assert_eq!(tool.input_schema["required"][0], "change");
+
+    let call = |id: i64, arguments: serde_json::Value| -> MCPMessage {
+        serde_json::from_value(serde_json::json!({
+            "method": "tools/call",
+            "id": id,
+            "params": {"name": "trigger_build", "arguments": arguments}
+        }))
+        .unwrap()
+    };
+    let response = server
+        .handle_message(call(2, serde_json::json!({"change": "12345"})))
+        .await
+        .unwrap();
+    match response {
+        Some(MCPResponse::CallToolResult { result, .. }) => match &result.content[0] {
+            ToolContent::Text { text } => assert_eq!(text, "Build queued for change 12345"),
+            other => panic!("Expected text content, got {:?}", other),
+        },
+        other => panic!("Expected CallToolResult response, got {:?}", other),
+    }
+    assert_eq!(*triggered.lock().unwrap(), [12345]);
+
+    // The declared schema is checked before the handler runs
+    match server
+        .handle_message(call(3, serde_json::json!({})))
+        .await
+        .unwrap()
+    {
+        Some(MCPResponse::Error { error, .. }) => {
+            assert_eq!(error.code, -32602);
+            assert_eq!(error.data.unwrap()["pointer"], "/change");
+        }
+        other => panic!("Expected an error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_serve_over_custom_transport() {
+    use p4_mcp::transport::{self, Transport};
+    use tokio::sync::{mpsc, Mutex};
+
+    // An embedder's own channel, e.g. a message queue or an in-process client
+    struct ChannelTransport {
+        incoming: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+        outgoing: mpsc::UnboundedSender<String>,
+        closed: mpsc::UnboundedSender<()>,
+    }
+
+    #[async_trait::async_trait]
+    impl Transport for ChannelTransport {
+        async fn read_message(&self) -> anyhow::Result<Option<Vec<u8>>> {
+            Ok(self.incoming.lock().await.recv().await)
+        }
+
+        async fn write_message(&self, json: &str) -> anyhow::Result<()> {
+            Ok(self.outgoing.send(json.to_string())?)
+        }
+
+        async fn close(&self) -> anyhow::Result<()> {
+            Ok(self.closed.send(())?)
+        }
+    }
+
+    setup_mock_mode();
+    let (incoming, incoming_rx) = mpsc::unbounded_channel();
+    let (outgoing_tx, mut outgoing) = mpsc::unbounded_channel();
+    let (closed_tx, mut closed) = mpsc::unbounded_channel();
+    let session = tokio::spawn(transport::serve(
+        MCPServer::new(),
+        ChannelTransport {
+            incoming: Mutex::new(incoming_rx),
+            outgoing: outgoing_tx,
+            closed: closed_tx,
+        },
+    ));
+
+    let initialize = fs::read_to_string("test_data/test_initialize.json").unwrap();
+    incoming.send(initialize.into_bytes()).unwrap();
+    incoming
+        .send(br#"{"jsonrpc": "2.0", "id": 2, "method": "ping"}"#.to_vec())
+        .unwrap();
+    incoming.send(vec![0xff, 0xfe]).unwrap();
+    drop(incoming);
+    session.await.unwrap().unwrap();
+
+    let mut messages = Vec::new();
+    while let Ok(json) = outgoing.try_recv() {
+        messages.push(serde_json::from_str::<serde_json::Value>(&json).unwrap());
+    }
+    assert!(messages
+        .iter()
+        .any(|m| m["id"] == 0 && m["result"].is_object()));
+    assert!(messages
+        .iter()
+        .any(|m| m["id"] == 2 && m["result"] == serde_json::json!({})));
+    // Input that isn't UTF-8 is answered with a parse error rather than ending the session
+    assert!(messages
+        .iter()
+        .any(|m| m["id"].is_null() && m["error"]["code"] == -32700));
+    assert!(closed.try_recv().is_ok());
+}
+
+#[tokio::test]
+async fn test_responses_deserialize() {
+    setup_mock_mode();
+    let mut server = initialized_server().await;
+
+    let mut messages = vec![
+        load_test_message("test_initialize.json"),
+        load_test_message("test_list_tools.json"),
+        load_test_message("test_p4_status.json"),
+        load_test_message("test_resources_list.json"),
+        load_test_message("test_resources_read.json"),
+    ];
+    for message in [
+        serde_json::json!({"method": "resources/templates/list", "id": 61}),
+        serde_json::json!({"method": "prompts/list", "id": 62}),
+        serde_json::json!({
+            "method": "prompts/get",
+            "id": 63,
+            "params": {"name": "review_shelved_change", "arguments": {"changelist": "12347"}}
+        }),
+        serde_json::json!({
+            "method": "completion/complete",
+            "id": 64,
+            "params": {
+                "ref": {"type": "ref/prompt", "name": "summarize_recent_changes"},
+                "argument": {"name": "path", "value": "//de"}
+            }
+        }),
+        serde_json::json!({"method": "logging/setLevel", "id": 65, "params": {"level": "info"}}),
+        serde_json::json!({
+            "method": "tools/call",
+            "id": 66,
+            "params": {"name": "p4_nonexistent", "arguments": {}}
+        }),
+    ] {
+        messages.push(serde_json::from_value(message).unwrap());
+    }
+
+    for message in messages {
+        let response = server.handle_message(message).await.unwrap().unwrap();
+        let json = serde_json::to_string(&response).unwrap();
+        let parsed: MCPResponse = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, response, "{}", json);
+    }
+
+    // A pong is indistinguishable from any other empty result
+    let pong = serde_json::to_string(&MCPResponse::Pong {
+        id: RequestId::Number(1),
+    })
+    .unwrap();
+    assert!(matches!(
+        serde_json::from_str(&pong).unwrap(),
+        MCPResponse::Empty { .. }
+    ));
+    assert!(serde_json::from_str::<MCPResponse>(r#"{"jsonrpc": "2.0", "id": 1}"#).is_err());
+}
@@ -1,5 +1,12 @@
 //! Performance and stress tests for the p4-mcp server in mock mode
 
+#![allow(
+    unused_mut,
+    unused_variables,
+    clippy::if_same_then_else,
+    clippy::useless_vec
+)]
+
 use p4_mcp::mcp::*;
 use serde_json::json;
 use std::env;
@@ -24,10 +31,31 @@ fn create_call_tool_message(id: i32, tool_name: &str, arguments: serde_json::Val
     .unwrap()
 }
 
+/// Test helper to create a server that has completed the initialize handshake
+async fn initialized_server() -> MCPServer {
+    initialize(MCPServer::new()).await
+}
+
+/// Test helper to complete the initialize handshake on `server`
+async fn initialize(mut server: MCPServer) -> MCPServer {
+    let message = serde_json::from_value(json!({
+        "method": "initialize",
+        "id": 0,
+        "params": {
+            "protocolVersion": "2024-11-05",
+            "capabilities": {},
+            "clientInfo": {"name": "perf-test", "version": "1.0"}
+        }
+    }))
+    .unwrap();
+    server.handle_message(message).await.unwrap();
+    server
+}
+
 #[tokio::test]
 async fn test_high_volume_message_processing() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     let message_count = 1000;
     let start_time = Instant::now();
@@ -68,7 +96,17 @@ async fn test_high_volume_message_processing() {
 #[tokio::test]
 async fn test_large_file_lists_performance() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    // The summary line comes last, so keep the whole output, and allow editing
+    // thousands of files in one call
+    let config = p4_mcp::Config {
+        max_response_bytes: 0,
+        max_files: 0,
+        ..Default::default()
+    };
+    let mut server = initialize(MCPServer::with_context(
+        &ServerContext::from_config(&config).unwrap(),
+    ))
+    .await;
 
     // Test with increasingly large file lists
     let file_counts = vec![10, 100, 1000, 5000];
@@ -123,7 +161,7 @@ async fn test_concurrent_message_processing() {
 
     for i in 0..concurrent_requests {
         let handle = tokio::spawn(async move {
-            let mut server = MCPServer::new();
+            let mut server = initialized_server().await;
             let message = create_call_tool_message(
                 i,
                 "p4_status",
@@ -173,7 +211,7 @@ async fn test_concurrent_message_processing() {
 #[tokio::test]
 async fn test_memory_usage_with_large_responses() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     // Test that large responses don't cause memory issues
     for i in 0..100 {
@@ -190,7 +228,7 @@ async fn test_memory_usage_with_large_responses() {
                 assert_eq!(id, i);
                 if let ToolContent::Text { text } = &result.content[0] {
                     // Verify we get the expected mock response
-                    assert!(text.contains("Mock P4 Changes"));
+                    assert!(text.starts_with("Change 12350 on "));
                 }
             }
             _ => panic!("Expected CallToolResult"),
@@ -206,7 +244,7 @@ async fn test_memory_usage_with_large_responses() {
 #[tokio::test]
 async fn test_response_time_consistency() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     let iterations = 100;
     let mut response_times = Vec::new();
@@ -258,7 +296,14 @@ async fn test_response_time_consistency() {
 #[tokio::test]
 async fn test_timeout_handling() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let config = p4_mcp::Config {
+        allow_force_sync: true,
+        ..Default::default()
+    };
+    let mut server = initialize(MCPServer::with_context(
+        &ServerContext::from_config(&config).unwrap(),
+    ))
+    .await;
 
     // Test that operations complete well within timeout
     let message = create_call_tool_message(
@@ -283,7 +328,7 @@ async fn test_timeout_handling() {
 #[tokio::test]
 async fn test_all_tools_performance() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     let tools_and_args = vec![
         ("p4_status", json!({"path": "//depot/perf/..."})),
@@ -338,7 +383,7 @@ async fn test_all_tools_performance() {
 #[tokio::test]
 async fn test_mixed_workload_performance() {
     setup_mock_mode();
-    let mut server = MCPServer::new();
+    let mut server = initialized_server().await;
 
     let operations = vec![
         // Mix of different operations with varying complexity
@@ -390,15 +435,7 @@ async fn test_mixed_workload_performance() {
         let response = server.handle_message(message).await.unwrap().unwrap();
 
         // Verify we got a response with correct ID
-        let response_id = match &response {
-            MCPResponse::InitializeResult { id, .. } => id,
-            MCPResponse::ListToolsResult { id, .. } => id,
-            MCPResponse::CallToolResult { id, .. } => id,
-            MCPResponse::Pong { id } => id,
-            MCPResponse::Error { id, .. } => id,
-        };
-
-        assert_eq!(*response_id, i as i32);
+        assert_eq!(*response.id(), RequestId::String(format!("mixed-{}", i)));
     }
 
     let duration = start_time.elapsed();
@@ -439,7 +476,7 @@ async fn test_stress_test_rapid_fire() {
 
         // Clone server state for each task (in real usage, each connection would have its own server)
         tasks.push(tokio::spawn(async move {
-            let mut local_server = MCPServer::new();
+            let mut local_server = initialized_server().await;
             local_server.handle_message(message).await
         }));
     }
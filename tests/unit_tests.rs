@@ -1,5 +1,7 @@
 //! Unit tests for MCP types and individual components
 
+#![allow(unused_variables)]
+
 use p4_mcp::mcp::*;
 use p4_mcp::p4::*;
 use serde_json::json;
@@ -14,7 +16,7 @@ fn test_mcp_message_deserialization() {
 
     match message {
         MCPMessage::Initialize { id, params } => {
-            assert_eq!(id, 1);
+            assert_eq!(id, "1");
             assert_eq!(params.protocol_version, "2024-11-05");
             assert_eq!(params.client_info.name, "test");
             assert_eq!(params.client_info.version, "1.0");
@@ -30,8 +32,8 @@ fn test_list_tools_message_deserialization() {
     let message: MCPMessage = serde_json::from_str(json_str).unwrap();
 
     match message {
-        MCPMessage::ListTools { id } => {
-            assert_eq!(id, 2);
+        MCPMessage::ListTools { id, .. } => {
+            assert_eq!(id, "2");
         }
         _ => panic!("Expected ListTools message"),
     }
@@ -45,7 +47,7 @@ fn test_call_tool_message_deserialization() {
 
     match message {
         MCPMessage::CallTool { id, params } => {
-            assert_eq!(id, 3);
+            assert_eq!(id, "3");
             assert_eq!(params.name, "p4_status");
             assert_eq!(params.arguments["path"], "//depot/main/...");
         }
@@ -61,7 +63,7 @@ fn test_ping_message_deserialization() {
 
     match message {
         MCPMessage::Ping { id } => {
-            assert_eq!(id, 1);
+            assert_eq!(id, "ping-1");
         }
         _ => panic!("Expected Ping message"),
     }
@@ -82,6 +84,8 @@ fn test_list_tools_response_serialization() {
                     }
                 }
             }),
+            output_schema: None,
+            annotations: None,
         },
         Tool {
             name: "p4_sync".to_string(),
@@ -95,12 +99,17 @@ fn test_list_tools_response_serialization() {
                     }
                 }
             }),
+            output_schema: None,
+            annotations: None,
         },
     ];
 
     let response = MCPResponse::ListToolsResult {
-        id: 2,
-        result: ListToolsResult { tools },
+        id: 2.into(),
+        result: ListToolsResult {
+            tools,
+            next_cursor: None,
+        },
     };
 
     let json_str = serde_json::to_string(&response).unwrap();
@@ -115,11 +124,13 @@ fn test_list_tools_response_serialization() {
 #[test]
 fn test_call_tool_response_serialization() {
     let response = MCPResponse::CallToolResult {
-        id: 3,
+        id: 3.into(),
         result: CallToolResult {
             content: vec![ToolContent::Text {
                 text: "Mock P4 Status result".to_string(),
             }],
+            structured_content: None,
+            meta: None,
         },
     };
 
@@ -137,7 +148,7 @@ fn test_call_tool_response_serialization() {
 #[test]
 fn test_error_response_serialization() {
     let response = MCPResponse::Error {
-        id: 123,
+        id: 123.into(),
         error: MCPError {
             code: -32602,
             message: "Invalid params".to_string(),
@@ -159,7 +170,7 @@ fn test_error_response_serialization() {
 
 #[test]
 fn test_pong_response_serialization() {
-    let response = MCPResponse::Pong { id: 456 };
+    let response = MCPResponse::Pong { id: 456.into() };
 
     let json_str = serde_json::to_string(&response).unwrap();
     let parsed: serde_json::Value = serde_json::from_str(&json_str).unwrap();
@@ -203,29 +214,29 @@ fn test_p4_command_to_args() {
     let (_, args) = cmd.to_command_args();
     assert_eq!(args, vec!["add", "new_file.cpp"]);
 
-    // Test Submit command with description only
-    let cmd = P4Command::Submit {
-        description: "Fix bug".to_string(),
-        files: None,
-    };
-    let (_, args) = cmd.to_command_args();
-    assert_eq!(args, vec!["submit", "-d", "Fix bug"]);
-
-    // Test Submit command with files
-    let cmd = P4Command::Submit {
-        description: "Fix bug".to_string(),
-        files: Some(vec!["file1.cpp".to_string()]),
+    // Test Reopen command, which moves files into the change p4_submit submits
+    let cmd = P4Command::Reopen {
+        changelist: "12348".to_string(),
+        files: vec!["file1.cpp".to_string()],
     };
     let (_, args) = cmd.to_command_args();
-    assert_eq!(args, vec!["submit", "-d", "Fix bug", "file1.cpp"]);
+    assert_eq!(args, vec!["reopen", "-c", "12348", "file1.cpp"]);
 
     // Test Revert command
     let cmd = P4Command::Revert {
         files: vec!["file1.cpp".to_string(), "file2.h".to_string()],
+        wipe: false,
     };
     let (_, args) = cmd.to_command_args();
     assert_eq!(args, vec!["revert", "file1.cpp", "file2.h"]);
 
+    let cmd = P4Command::Revert {
+        files: vec!["new_file.cpp".to_string()],
+        wipe: true,
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["revert", "-w", "new_file.cpp"]);
+
     // Test Opened command without changelist
     let cmd = P4Command::Opened { changelist: None };
     let (_, args) = cmd.to_command_args();
@@ -255,6 +266,218 @@ fn test_p4_command_to_args() {
     let cmd = P4Command::Info;
     let (_, args) = cmd.to_command_args();
     assert_eq!(args, vec!["info"]);
+
+    // Test Jobs command with filter
+    let cmd = P4Command::Jobs {
+        filter: Some("Job=job000123".to_string()),
+        max: Some(1),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["jobs", "-e", "Job=job000123", "-m", "1"]);
+
+    // Test Fix command
+    let cmd = P4Command::Fix {
+        changelist: "12345".to_string(),
+        jobs: vec!["job000123".to_string()],
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["fix", "-c", "12345", "job000123"]);
+
+    // Test SubmitChangelist command
+    let cmd = P4Command::SubmitChangelist {
+        changelist: "12345".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["submit", "-c", "12345"]);
+
+    // Test ShelvedChanges command
+    let cmd = P4Command::ShelvedChanges {
+        user: Some("testuser".to_string()),
+        client: Some("test-client".to_string()),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec![
+            "changes",
+            "-s",
+            "shelved",
+            "-u",
+            "testuser",
+            "-c",
+            "test-client"
+        ]
+    );
+
+    // Test preview commands
+    let cmd = P4Command::ResolvePreview {
+        path: None,
+        changelist: None,
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["resolve", "-n"]);
+
+    let cmd = P4Command::ResolvePreview {
+        path: None,
+        changelist: Some("12345".to_string()),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["resolve", "-n", "-c", "12345"]);
+
+    let cmd = P4Command::SyncPreview {
+        paths: vec!["...".to_string()],
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["sync", "-n", "..."]);
+
+    // Test SpecOutput and OpenedAll commands
+    let cmd = P4Command::SpecOutput {
+        kind: p4_mcp::p4::SpecKind::Change,
+        name: Some("12345".to_string()),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["change", "-o", "12345"]);
+
+    let cmd = P4Command::OpenedAll {
+        files: vec!["//depot/main/file1.txt".to_string()],
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["opened", "-a", "//depot/main/file1.txt"]);
+
+    // Test scratch changelist commands
+    let cmd = P4Command::Integrate {
+        from: "//depot/main/...".to_string(),
+        to: "//depot/rel/...".to_string(),
+        changelist: "12348".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec![
+            "integrate",
+            "-c",
+            "12348",
+            "//depot/main/...",
+            "//depot/rel/..."
+        ]
+    );
+
+    let cmd = P4Command::RevertChange {
+        changelist: "12348".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["revert", "-c", "12348", "//..."]);
+
+    // Test ChangesLong command
+    let cmd = P4Command::ChangesLong {
+        path: "//depot/main/...@rel-1.0,@rel-1.1".to_string(),
+        max: None,
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec![
+            "changes",
+            "-l",
+            "-s",
+            "submitted",
+            "//depot/main/...@rel-1.0,@rel-1.1"
+        ]
+    );
+
+    // Test DescribeShelved command
+    let cmd = P4Command::DescribeShelved {
+        changelist: "12347".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["describe", "-S", "-du", "12347"]);
+
+    // Test Integrated command
+    let cmd = P4Command::Integrated {
+        path: "//depot/release/1.0/...".to_string(),
+        reverse: true,
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["integrated", "-r", "//depot/release/1.0/..."]);
+
+    // Test PopulatePreview and Populate commands
+    let cmd = P4Command::PopulatePreview {
+        from: "//depot/main/...".to_string(),
+        to: "//depot/rel/2.0/...".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec![
+            "populate",
+            "-n",
+            "-o",
+            "//depot/main/...",
+            "//depot/rel/2.0/..."
+        ]
+    );
+    assert!(!cmd.is_mutating());
+
+    let cmd = P4Command::Populate {
+        from: "//depot/main/...".to_string(),
+        to: "//depot/rel/2.0/...".to_string(),
+        description: "Branch 2.0".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec![
+            "populate",
+            "-d",
+            "Branch 2.0",
+            "//depot/main/...",
+            "//depot/rel/2.0/..."
+        ]
+    );
+    assert!(cmd.is_mutating());
+
+    // Test DescribeDiffs, DiffShelved and Diff2 commands
+    let cmd = P4Command::DescribeDiffs {
+        changelist: "12345".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["describe", "-du", "12345"]);
+
+    let cmd = P4Command::DiffShelved {
+        files: vec!["//depot/main/a.c".to_string(), "b.c".to_string()],
+        changelist: "12347".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec!["diff", "-du", "-f", "//depot/main/a.c@=12347", "b.c@=12347"]
+    );
+    // The sandbox sees the paths without the shelf
+    assert_eq!(cmd.paths(), vec!["//depot/main/a.c", "b.c"]);
+
+    let cmd = P4Command::Diff2 {
+        left: "//depot/main/a.c#3".to_string(),
+        right: "//depot/main/a.c#4".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(
+        args,
+        vec!["diff2", "-du", "//depot/main/a.c#3", "//depot/main/a.c#4"]
+    );
+
+    // Test Dirs command
+    let cmd = P4Command::Dirs {
+        path: "//depot/ma*".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["dirs", "//depot/ma*"]);
+
+    // Test Diff command
+    let cmd = P4Command::Diff {
+        files: vec!["//depot/main/file1.txt".to_string()],
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["diff", "-du", "//depot/main/file1.txt"]);
 }
 
 #[tokio::test]
@@ -387,13 +610,11 @@ fn test_large_data_handling() {
 
     // Test with very long description
     let long_description = "x".repeat(10000);
-    let cmd = P4Command::Submit {
+    let cmd = P4Command::CreateChange {
         description: long_description.clone(),
-        files: None,
     };
 
-    let (_, args) = cmd.to_command_args();
-    assert_eq!(args[2], long_description);
+    assert!(cmd.stdin_input().unwrap().contains(&long_description));
 }
 
 #[test]
@@ -412,10 +633,67 @@ fn test_special_characters_in_paths() {
     };
 
     let (_, args) = cmd.to_command_args();
-    assert_eq!(args.len(), 6); // "add" + 5 files
+    assert_eq!(args.len(), 7); // "add" + "-f" for the @ + 5 files
+    assert_eq!(args[1], "-f");
 
     for (i, expected_file) in special_files.iter().enumerate() {
-        assert_eq!(args[i + 1], *expected_file);
+        assert_eq!(args[i + 2], *expected_file);
+    }
+}
+
+#[test]
+fn test_path_normalization() {
+    use p4_mcp::p4::paths::{escape, normalize};
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("logo@2x.png"), "").unwrap();
+
+    assert_eq!(escape("50%@#*"), "50%25%40%23%2A");
+    assert_eq!(
+        normalize("//depot/main\\src\\a.c#head", dir.path(), true).unwrap(),
+        "//depot/main/src/a.c#head"
+    );
+    // An existing file is escaped; otherwise @ and # are revisions
+    assert_eq!(
+        normalize("logo@2x.png", dir.path(), true).unwrap(),
+        "logo%402x.png"
+    );
+    assert_eq!(
+        normalize("logo@2x.png", dir.path(), false).unwrap(),
+        "logo@2x.png"
+    );
+    assert_eq!(
+        normalize("main.c@12345", dir.path(), true).unwrap(),
+        "main.c@12345"
+    );
+    assert!(normalize("C:a.c", dir.path(), true)
+        .unwrap_err()
+        .to_string()
+        .contains("relative to the current directory of drive C:"));
+
+    #[cfg(windows)]
+    {
+        assert_eq!(
+            normalize("/c:/ws/main/a.c", dir.path(), true).unwrap(),
+            "C:/ws/main/a.c"
+        );
+        assert_eq!(
+            normalize("\\\\build\\share\\a.c", dir.path(), true).unwrap(),
+            "\\\\build\\share\\a.c"
+        );
+    }
+    #[cfg(not(windows))]
+    {
+        assert_eq!(
+            normalize("src\\main\\...", dir.path(), true).unwrap(),
+            "src/main/..."
+        );
+        assert_eq!(
+            normalize("\\\\depot\\main\\...", dir.path(), true).unwrap(),
+            "//depot/main/..."
+        );
+        let error = normalize("/C:/ws/a.c", dir.path(), true).unwrap_err();
+        assert!(error.to_string().contains("is a Windows path"), "{}", error);
     }
 }
 
@@ -430,6 +708,11 @@ fn test_empty_collections() {
     let cmd = P4Command::Opened { changelist: None };
     let (_, args) = cmd.to_command_args();
     assert_eq!(args, vec!["opened"]);
+
+    let cmd = P4Command::Fstat { paths: vec![] };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["-x", "-", "fstat"]);
+    assert_eq!(cmd.stdin_input().as_deref(), Some(""));
 }
 
 #[tokio::test]
@@ -450,3 +733,2193 @@ fn test_mcp_server_initialization() {
     // Should create server with all expected tools registered
     // The actual tool validation is covered in integration tests
 }
+
+#[test]
+fn test_submit_policy_description_checks() {
+    let policy = SubmitPolicy {
+        min_description_length: 10,
+        ..Default::default()
+    };
+
+    assert!(policy.check_description("Fix crash on startup").is_empty());
+    assert_eq!(policy.check_description("").len(), 1);
+    assert_eq!(
+        policy.check_description("<enter description here>").len(),
+        1
+    );
+    assert!(policy.check_description("fix")[0].contains("too short"));
+}
+
+#[test]
+fn test_submit_policy_patterns_jobs_and_files() {
+    use p4_mcp::Config;
+
+    let config = Config::parse(
+        "[submit]
+         description_pattern = '^[A-Z]+-\\d+: '
+         description_template = \"PROJ-123: Fix the crash on startup\"
+         require_job = true
+         banned_files = [\"*.dll\", \"//depot/.../secrets/...\"]
+",
+    )
+    .unwrap();
+    let policy = config.submit;
+
+    assert!(policy
+        .check_description("PROJ-42: Fix crash on startup")
+        .is_empty());
+    let violations = policy.check_description("Fix crash on startup");
+    assert_eq!(
+        violations,
+        vec!["Change description must match the pattern ^[A-Z]+-\\d+: , e.g. \"PROJ-123: Fix the crash on startup\""]
+    );
+
+    assert_eq!(policy.check_jobs(&[]).len(), 1);
+    assert!(policy.check_jobs(&["job000123".to_string()]).is_empty());
+    assert!(SubmitPolicy::default().check_jobs(&[]).is_empty());
+
+    let files = [
+        "//depot/main/bin/tool.dll#3",
+        "//depot/main/src/dll.rs",
+        "//depot/main/config/secrets/key.pem",
+        "C:\\ws\\bin\\other.dll",
+        "//depot/main/src/a.c",
+    ]
+    .map(String::from);
+    let violations = policy.check_files(&files);
+    assert_eq!(violations.len(), 3);
+    assert!(violations[0]
+        .starts_with("//depot/main/bin/tool.dll#3 may not be submitted (banned by *.dll)"));
+    assert!(violations[1].contains("banned by //depot/.../secrets/..."));
+    assert!(violations[2].starts_with("C:\\ws\\bin\\other.dll"));
+
+    let error = Config::parse("[submit]\ndescription_pattern = '('\n").unwrap_err();
+    assert!(format!("{:#}", error).contains("regex"));
+}
+
+#[test]
+fn test_create_change_stdin_spec() {
+    let cmd = P4Command::CreateChange {
+        description: "First line\nSecond line".to_string(),
+    };
+    let (_, args) = cmd.to_command_args();
+    assert_eq!(args, vec!["change", "-i"]);
+    assert_eq!(
+        cmd.stdin_input().unwrap(),
+        "Change: new\n\nDescription:\n\tFirst line\n\tSecond line\n"
+    );
+
+    assert!(P4Command::Info.stdin_input().is_none());
+}
+
+#[test]
+fn test_spec_forms() {
+    use p4_mcp::p4::spec::saved_name;
+    use p4_mcp::p4::{Spec, SpecKind};
+
+    let form = "# A Perforce Change Specification.\n\
+                #\n\
+                \n\
+                Change:\t12345\n\
+                \n\
+                Status:\tpending\n\
+                \n\
+                Description:\n\
+                \tFix crash on startup\n\
+                \t\n\
+                \tThe config file may be missing.\n\
+                \n\
+                Files:\n\
+                \t//depot/main/file1.txt\t# edit\n";
+    let mut spec = Spec::parse(form);
+    assert_eq!(spec.field("Change"), "12345");
+    assert_eq!(
+        spec.field("Description"),
+        "Fix crash on startup\n\nThe config file may be missing."
+    );
+    assert_eq!(spec.lines("Files"), vec!["//depot/main/file1.txt\t# edit"]);
+    assert_eq!(spec.get("Jobs"), None);
+
+    spec.set("Description", "Fix crash");
+    spec.set("Jobs", "job000123");
+    spec.remove("Files");
+    assert_eq!(
+        spec.to_form(),
+        "Change:\t12345\n\nStatus:\tpending\n\nDescription:\n\tFix crash\n\nJobs:\n\tjob000123\n\n"
+    );
+    assert_eq!(Spec::parse(&spec.to_form()), spec);
+
+    let (_, args) = P4Command::SpecInput {
+        kind: SpecKind::Job,
+        spec: spec.to_form(),
+    }
+    .to_command_args();
+    assert_eq!(args, vec!["job", "-i"]);
+
+    assert_eq!(
+        saved_name(
+            SpecKind::Change,
+            "Change 12348 created with 2 open file(s).\n"
+        )
+        .as_deref(),
+        Some("12348")
+    );
+    assert_eq!(
+        saved_name(SpecKind::Label, "Label rel-1.0 saved.\n").as_deref(),
+        Some("rel-1.0")
+    );
+    assert_eq!(saved_name(SpecKind::Client, "Label rel-1.0 saved.\n"), None);
+}
+
+#[test]
+fn test_generate_sparse_view() {
+    let paths = vec![
+        "//depot/main/src".to_string(),
+        "//depot/main/src/engine/...".to_string(),
+        "//depot/main/docs/".to_string(),
+        "//depot/main/build.cfg#head".to_string(),
+        "//depot/main/src/...".to_string(),
+    ];
+
+    let view = p4_mcp::p4::view::generate_sparse_view(&paths, "ws");
+    assert_eq!(
+        view,
+        vec![
+            "//depot/main/build.cfg //ws/main/build.cfg",
+            "//depot/main/docs/... //ws/main/docs/...",
+            "//depot/main/src/... //ws/main/src/...",
+        ]
+    );
+
+    // Only a path with a revision is a file; dotted names are directories too
+    let paths = vec![
+        "//depot/rel/v1.2".to_string(),
+        "//depot/lib.d/".to_string(),
+        "//depot/main/app.ini@12345".to_string(),
+    ];
+    let view = p4_mcp::p4::view::generate_sparse_view(&paths, "ws");
+    assert_eq!(
+        view,
+        vec![
+            "//depot/lib.d/... //ws/lib.d/...",
+            "//depot/main/app.ini //ws/main/app.ini",
+            "//depot/rel/v1.2/... //ws/rel/v1.2/...",
+        ]
+    );
+
+    // Paths from several depots keep the depot name on the client side
+    let paths = vec![
+        "//depot/main/...".to_string(),
+        "//tools/bin/...".to_string(),
+    ];
+    let view = p4_mcp::p4::view::generate_sparse_view(&paths, "ws");
+    assert_eq!(
+        view,
+        vec![
+            "//depot/main/... //ws/depot/main/...",
+            "//tools/bin/... //ws/tools/bin/...",
+        ]
+    );
+}
+
+#[test]
+fn test_stream_hierarchy_parsing() {
+    use p4_mcp::p4::streams::*;
+
+    let output = "Stream //streams/main mainline none 'Main line'\n\
+                  Stream //streams/dev development //streams/main 'Dev'\n\
+                  Stream //streams/rel1 release //streams/main 'Release 1'\n";
+    let streams = parse_streams(output);
+
+    assert_eq!(streams.len(), 3);
+    assert_eq!(streams[0].title, "Main line");
+    assert_eq!(streams[0].parent, None);
+    assert_eq!(streams[1].parent.as_deref(), Some("//streams/main"));
+
+    let tree = stream_tree(&streams);
+    assert_eq!(tree.len(), 1);
+    assert_eq!(tree[0]["stream"], "//streams/main");
+    assert_eq!(tree[0]["children"].as_array().unwrap().len(), 2);
+
+    let diagram = stream_mermaid(&streams);
+    assert!(diagram.starts_with("graph TD\n"));
+    assert!(diagram.contains("streams_main --> streams_dev"));
+}
+
+#[test]
+fn test_resource_uri_mapping() {
+    use p4_mcp::mcp::resources::*;
+
+    assert_eq!(
+        depot_path_to_uri("//depot/main/a.cpp"),
+        "p4://depot/main/a.cpp"
+    );
+    assert_eq!(
+        uri_to_depot_path("p4://depot/main/a.cpp").as_deref(),
+        Some("//depot/main/a.cpp")
+    );
+    assert_eq!(
+        uri_to_depot_path("p4://depot%2Fmain%2Fa.cpp@12345").as_deref(),
+        Some("//depot/main/a.cpp@12345")
+    );
+    assert_eq!(uri_to_depot_path("file:///tmp/a.cpp"), None);
+    assert_eq!(uri_to_depot_path("p4://"), None);
+
+    assert_eq!(mime_type_for("//depot/README.md"), "text/markdown");
+    assert_eq!(mime_type_for("//depot/main/a.cpp#3"), "text/x-c++");
+    assert_eq!(mime_type_for("//depot/main/Makefile"), "text/plain");
+}
+
+#[test]
+fn test_resource_updated_notification() {
+    let notification = MCPNotification::resource_updated("p4://depot/main/...");
+    let parsed = serde_json::to_value(&notification).unwrap();
+
+    assert_eq!(parsed["jsonrpc"], "2.0");
+    assert_eq!(parsed["method"], "notifications/resources/updated");
+    assert_eq!(parsed["params"]["uri"], "p4://depot/main/...");
+}
+
+#[test]
+fn test_latest_change_parsing() {
+    use p4_mcp::mcp::subscriptions::latest_change;
+
+    let output = "Change 12350 on 2024/01/15 by user@ws 'Latest'\n\
+                  Change 12349 on 2024/01/14 by user@ws 'Older'\n";
+    assert_eq!(latest_change(output), Some(12350));
+    assert_eq!(latest_change(""), None);
+}
+
+#[test]
+fn test_describe_from_tagged_record() {
+    use p4_mcp::p4::ztag::{parse_ztag, DescribedChange};
+
+    let output = "... change 12345\n\
+                  ... user alice\n\
+                  ... client alice-ws\n\
+                  ... time 1705321845\n\
+                  ... desc Add retry support\n\
+                  \n\
+                  Second paragraph\n\
+                  \n\
+                  ... status pending\n\
+                  ... job0 job000125\n\
+                  ... depotFile0 //depot/main/net.cpp\n\
+                  ... action0 edit\n\
+                  ... type0 text\n\
+                  ... rev0 4\n\
+                  ... depotFile1 //depot/main/new file.h\n\
+                  ... action1 add\n\
+                  ... type1 text\n\
+                  ... rev1 1\n";
+
+    let records = parse_ztag(output);
+    assert_eq!(records.len(), 1);
+    let described = DescribedChange::from_record(&records[0]).unwrap();
+
+    assert_eq!(described.change, 12345);
+    assert_eq!(described.user, "alice");
+    assert_eq!(described.client, "alice-ws");
+    assert_eq!(described.status, "pending");
+    assert_eq!(
+        described.description,
+        "Add retry support\n\nSecond paragraph"
+    );
+    assert_eq!(described.jobs, vec!["job000125"]);
+    assert_eq!(described.files.len(), 2);
+    assert_eq!(described.files[0].depot_file, "//depot/main/net.cpp");
+    assert_eq!(described.files[0].rev, Some(4));
+    assert_eq!(described.files[1].depot_file, "//depot/main/new file.h");
+    assert_eq!(described.files[1].action, "add");
+
+    assert!(DescribedChange::from_record(&Default::default()).is_err());
+}
+
+#[test]
+fn test_jsonrpc_message_parsing() {
+    // Requests may carry jsonrpc and use string or numeric ids
+    let message = MCPMessage::parse(r#"{"jsonrpc": "2.0", "method": "ping", "id": 7}"#)
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.id(), Some(&RequestId::Number(7)));
+    let message = MCPMessage::parse(r#"{"method": "ping", "id": "abc"}"#)
+        .unwrap()
+        .unwrap();
+    assert_eq!(message.id(), Some(&RequestId::from("abc")));
+
+    // Notifications are accepted without a reply; unknown ones are ignored
+    let message = MCPMessage::parse(r#"{"jsonrpc": "2.0", "method": "notifications/initialized"}"#);
+    assert!(matches!(message, Ok(Some(MCPMessage::Initialized {}))));
+    let message = MCPMessage::parse(r#"{"jsonrpc": "2.0", "method": "notifications/cancelled"}"#);
+    assert!(matches!(message, Ok(None)));
+    let message = MCPMessage::parse(
+        r#"{"jsonrpc": "2.0", "method": "notifications/cancelled", "params": {"requestId": 4}}"#,
+    );
+    assert!(matches!(message, Ok(Some(MCPMessage::Cancelled { params }))
+        if params.request_id == RequestId::Number(4) && params.reason.is_none()));
+    // stdin lines are split on '\n' only, so CRLF input keeps its '\r'
+    let message = MCPMessage::parse("{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"ping\"}\r");
+    assert!(matches!(message, Ok(Some(MCPMessage::Ping { .. }))));
+
+    let error_code = |line: &str| match MCPMessage::parse(line).map_err(|e| *e) {
+        Err(MCPResponse::Error { error, .. }) => error.code,
+        _ => panic!("Expected error response for {}", line),
+    };
+    assert_eq!(error_code("{not json"), PARSE_ERROR);
+    assert_eq!(error_code(r#"{"id": 1}"#), INVALID_REQUEST);
+    assert_eq!(
+        error_code(r#"{"jsonrpc": "1.0", "method": "ping", "id": 1}"#),
+        INVALID_REQUEST
+    );
+    assert_eq!(
+        error_code(r#"{"method": "nope", "id": 1}"#),
+        METHOD_NOT_FOUND
+    );
+    assert_eq!(
+        error_code(r#"{"method": "tools/call", "id": 1}"#),
+        INVALID_PARAMS
+    );
+}
+
+#[test]
+fn test_responses_include_jsonrpc_version() {
+    let parsed = serde_json::to_value(MCPResponse::Pong { id: "p".into() }).unwrap();
+    assert_eq!(parsed, json!({"jsonrpc": "2.0", "id": "p", "result": {}}));
+
+    let parsed = serde_json::to_value(MCPResponse::error(
+        RequestId::Null,
+        PARSE_ERROR,
+        "Parse error",
+    ))
+    .unwrap();
+    assert_eq!(parsed["jsonrpc"], "2.0");
+    assert!(parsed["id"].is_null());
+    assert_eq!(parsed["error"]["code"], -32700);
+    assert!(parsed["error"].get("data").is_none());
+}
+
+#[test]
+fn test_paginate() {
+    use p4_mcp::mcp::pagination::paginate;
+
+    let items: Vec<u32> = (0..5).collect();
+    let (page, next) = paginate(items.clone(), None, 2).unwrap();
+    assert_eq!(page, vec![0, 1]);
+    assert_eq!(next.as_deref(), Some("2"));
+
+    let (page, next) = paginate(items.clone(), Some("4"), 2).unwrap();
+    assert_eq!(page, vec![4]);
+    assert_eq!(next, None);
+
+    assert!(paginate(items.clone(), Some("bogus"), 2).is_none());
+    assert!(paginate(items, Some("6"), 2).is_none());
+}
+
+#[test]
+fn test_parse_ztag() {
+    use p4_mcp::p4::ztag::parse_ztag;
+
+    let output = "... change 12350\n\
+                  ... user alice\n\
+                  ... desc First line\n\
+                  second line\n\
+                  \n\
+                  ... change 12349\n\
+                  ... shelved\n";
+    let records = parse_ztag(output);
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["change"], "12350");
+    assert_eq!(records[0]["desc"], "First line\nsecond line");
+    assert_eq!(records[1]["change"], "12349");
+    assert_eq!(records[1]["shelved"], "");
+    assert!(parse_ztag("").is_empty());
+}
+
+#[test]
+fn test_json_output_records() {
+    use p4_mcp::p4::ztag::{parse_json_records, supports_json_output};
+
+    assert!(supports_json_output(
+        "Perforce - The Fast Software Configuration Management System.\n\
+         Rev. P4/LINUX26X86_64/2023.1/2442900 (2023/05/17).\n"
+    ));
+    assert!(!supports_json_output(
+        "Rev. P4/NTX64/2015.2/1234567 (2015/11/01).\n"
+    ));
+    assert!(!supports_json_output("p4: command not found"));
+
+    let output = "{\"change\":\"12350\",\"desc\":\"First line\\n\\nsecond\\n\"}\n\
+                  {\"data\":\"Path has no changes\",\"generic\":17,\"severity\":2}\n\
+                  {\"change\":\"12349\",\"shelved\":\"\"}\n";
+    let records = parse_json_records(output).unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["desc"], "First line\n\nsecond");
+    assert_eq!(records[1]["shelved"], "");
+
+    assert!(parse_json_records("... change 12350\n").is_none());
+}
+
+#[test]
+fn test_typed_ztag_records() {
+    use p4_mcp::p4::ztag::{from_records, parse_ztag, FileStat, Info, OpenedFile};
+
+    let info: Vec<Info> = from_records(parse_ztag(
+        "... userName alice\n... clientName *unknown*\n... serverID main\n... unknownField x\n",
+    ))
+    .unwrap();
+    assert_eq!(info[0].user_name, "alice");
+    assert_eq!(info[0].server_id, "main");
+    assert_eq!(info[0].client(), None);
+
+    let opened: Vec<OpenedFile> = from_records(parse_ztag(
+        "... depotFile //depot/a.txt\n... rev 2\n... action edit\n... type text\n\n\
+         ... depotFile //depot/b.txt\n... rev 1\n... action add\n... ourLock\n",
+    ))
+    .unwrap();
+    assert_eq!(opened.len(), 2);
+    assert_eq!(opened[0].file_type, "text");
+    assert_eq!(opened[0].our_lock, None);
+    assert_eq!(opened[1].our_lock.as_deref(), Some(""));
+
+    let stats: Vec<FileStat> = from_records(parse_ztag(
+        "... depotFile //depot/a.txt\n... headRev 3\n\n... depotFile //depot/b.txt\n",
+    ))
+    .unwrap();
+    assert_eq!(stats[0].head_rev.as_deref(), Some("3"));
+    assert_eq!(stats[1].have_rev, None);
+    let json = serde_json::to_value(&stats[1]).unwrap();
+    assert_eq!(json, serde_json::json!({"depotFile": "//depot/b.txt"}));
+}
+
+#[tokio::test]
+async fn test_client_requester_routes_responses() {
+    let (client, mut requests) = ClientRequester::new();
+
+    let pending = {
+        let client = client.clone();
+        tokio::spawn(async move { client.request("roots/list", json!({})).await })
+    };
+    let request = requests.recv().await.unwrap();
+    assert_eq!(request.method, "roots/list");
+
+    // Requests and notifications from the client are not responses
+    assert!(!client.handle_response(r#"{"jsonrpc": "2.0", "id": 1, "method": "ping"}"#));
+    assert!(!client.handle_response(r#"{"jsonrpc": "2.0", "method": "notifications/initialized"}"#));
+
+    let response = json!({"jsonrpc": "2.0", "id": request.id, "result": {"roots": []}});
+    assert!(client.handle_response(&response.to_string()));
+    assert_eq!(pending.await.unwrap().unwrap(), json!({"roots": []}));
+
+    // Errors from the client surface as request failures
+    let pending = {
+        let client = client.clone();
+        tokio::spawn(async move { client.request("roots/list", json!({})).await })
+    };
+    let request = requests.recv().await.unwrap();
+    let response = json!({
+        "jsonrpc": "2.0",
+        "id": request.id,
+        "error": {"code": -32601, "message": "Method not found"}
+    });
+    assert!(client.handle_response(&response.to_string()));
+    assert!(pending.await.unwrap().is_err());
+}
+
+#[test]
+fn test_client_root_paths() {
+    use p4_mcp::mcp::roots::{is_within, local_path, root_uri_to_path};
+    use std::path::{Path, PathBuf};
+
+    assert_eq!(
+        root_uri_to_path("file:///home/dev/My%20Project"),
+        Some(PathBuf::from("/home/dev/My Project"))
+    );
+    assert_eq!(
+        root_uri_to_path("file://localhost/home/dev/ws/"),
+        Some(PathBuf::from("/home/dev/ws"))
+    );
+    assert_eq!(root_uri_to_path("https://example.com/ws"), None);
+
+    let cwd = Path::new("/home/dev/ws");
+    assert_eq!(local_path("//depot/main/...", cwd), None);
+    assert_eq!(
+        local_path("src/main.cpp#head", cwd),
+        Some(PathBuf::from("/home/dev/ws/src/main.cpp"))
+    );
+    assert_eq!(
+        local_path("src/.../*.h@123", cwd),
+        Some(PathBuf::from("/home/dev/ws/src"))
+    );
+    assert_eq!(
+        local_path("../other/file.txt", cwd),
+        Some(PathBuf::from("/home/dev/other/file.txt"))
+    );
+
+    let roots = vec![PathBuf::from("/home/dev/ws")];
+    assert!(is_within(Path::new("/home/dev/ws"), &roots));
+    assert!(is_within(Path::new("/home/dev/ws/src/main.cpp"), &roots));
+    assert!(!is_within(Path::new("/home/dev/wsx/main.cpp"), &roots));
+    assert!(!is_within(Path::new("/home/dev/other/file.txt"), &roots));
+}
+
+#[test]
+fn test_frame_reader() {
+    use p4_mcp::transport::framing::{frame, FrameReader, Framing, MAX_CONTENT_LENGTH};
+    use std::io::Cursor;
+
+    let ping = r#"{"jsonrpc":"2.0","id":1,"method":"ping"}"#;
+    let lsp = format!(
+        "Content-Length: {}\r\nContent-Type: application/vscode-jsonrpc; charset=utf-8\r\n\r\n{}\r\ncontent-length: 2\r\n\r\n{{}}",
+        ping.len(),
+        ping
+    );
+
+    // Auto-detection picks up LSP framing, and tolerates blank lines between messages
+    let mut reader = FrameReader::new(Cursor::new(lsp.clone()), Framing::Auto);
+    assert_eq!(reader.read_message().unwrap().unwrap(), ping.as_bytes());
+    assert_eq!(reader.framing(), Framing::Lsp);
+    assert_eq!(reader.read_message().unwrap().unwrap(), b"{}");
+    assert!(reader.read_message().unwrap().is_none());
+
+    // ...and newline-delimited JSON, keeping blank lines for the session to skip
+    let ndjson = format!("{}\n\n{}\n", ping, ping);
+    let mut reader = FrameReader::new(Cursor::new(ndjson), Framing::Auto);
+    assert_eq!(reader.read_message().unwrap().unwrap(), ping.as_bytes());
+    assert_eq!(reader.framing(), Framing::Ndjson);
+    assert_eq!(reader.read_message().unwrap().unwrap(), b"");
+    assert_eq!(reader.read_message().unwrap().unwrap(), ping.as_bytes());
+    assert!(reader.read_message().unwrap().is_none());
+
+    // A header block without a length is skipped
+    let mut reader = FrameReader::new(
+        Cursor::new(format!("Content-Type: x\r\n\r\n{}", lsp)),
+        Framing::Lsp,
+    );
+    assert_eq!(reader.read_message().unwrap().unwrap(), ping.as_bytes());
+
+    // A length over the limit is refused before anything is allocated for it
+    let mut reader = FrameReader::new(
+        Cursor::new(format!(
+            "Content-Length: {}\r\n\r\n{{}}",
+            MAX_CONTENT_LENGTH + 1
+        )),
+        Framing::Lsp,
+    );
+    let error = reader.read_message().unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("over the limit"));
+
+    assert_eq!(frame("{}", Framing::Lsp), "Content-Length: 2\r\n\r\n{}");
+    assert_eq!(frame("{}", Framing::Ndjson), "{}\n");
+}
+
+#[test]
+fn test_connection_settings() {
+    use p4_mcp::p4::P4Connection;
+    use p4_mcp::Config;
+
+    assert!(P4Connection::default().global_args().is_empty());
+
+    let config = Config::parse(
+        "[p4]\n\
+         port = \"ssl:perforce.example.com:1666\"\n\
+         client = \"alice-main\"\n\
+         charset = \"utf8\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        config.p4.global_args(),
+        vec![
+            "-p",
+            "ssl:perforce.example.com:1666",
+            "-c",
+            "alice-main",
+            "-C",
+            "utf8"
+        ]
+    );
+
+    assert_eq!(Config::parse("").unwrap(), Config::default());
+    assert_eq!(
+        Config::parse("max_processes = 2\n").unwrap().max_processes,
+        2
+    );
+    assert!(Config::parse("[p4]\nprot = \"1666\"\n").is_err());
+}
+
+#[test]
+fn test_charset_decoding() {
+    use p4_mcp::p4::{charset, ztag, P4Connection};
+
+    // "Café/日本.txt" as UTF-8, and Shift-JIS and Windows-1252 equivalents
+    let utf8 = "//depot/Café/日本.txt";
+    assert_eq!(charset::decode(utf8.as_bytes(), None), utf8);
+    assert_eq!(charset::decode(utf8.as_bytes(), Some("shiftjis")), utf8);
+    assert_eq!(
+        charset::decode(b"//depot/\x93\xfa\x96{.txt", Some("shiftjis")),
+        "//depot/日本.txt"
+    );
+    assert_eq!(
+        charset::decode(b"//depot/Caf\xe9.txt", None),
+        "//depot/Café.txt"
+    );
+    assert_eq!(
+        charset::encode("Café", Some("winansi")),
+        b"Caf\xe9".to_vec()
+    );
+    assert_eq!(charset::encode("Café", Some("utf16")), "Café".as_bytes());
+    assert_eq!(charset::encode("日本", None), "日本".as_bytes());
+
+    let connection = |charset: &str| P4Connection {
+        charset: Some(charset.to_string()),
+        ..Default::default()
+    };
+    assert_eq!(connection("utf8").global_args(), vec!["-C", "utf8"]);
+    assert!(connection("auto").global_args().is_empty());
+    assert!(connection("none").global_args().is_empty());
+
+    let info: Vec<ztag::Info> = ztag::from_records(ztag::parse_ztag(
+        "... userName alice\n... unicode enabled\n",
+    ))
+    .unwrap();
+    assert!(info[0].is_unicode());
+    assert!(!ztag::Info::default().is_unicode());
+}
+
+#[test]
+fn test_p4config_discovery() {
+    use p4_mcp::p4::p4config;
+    use p4_mcp::Config;
+
+    let workspace = tempfile::tempdir().unwrap();
+    let nested = workspace.path().join("src").join("lib");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(
+        workspace.path().join(".p4config"),
+        "# alice's workspace\nP4PORT=ssl:perforce.example.com:1666\n\nP4CLIENT = alice-main\n",
+    )
+    .unwrap();
+
+    let found = p4config::find(&nested, ".p4config").unwrap();
+    assert_eq!(found, workspace.path().join(".p4config"));
+    assert_eq!(
+        p4config::parse(&std::fs::read_to_string(found).unwrap()),
+        vec![
+            (
+                "P4PORT".to_string(),
+                "ssl:perforce.example.com:1666".to_string()
+            ),
+            ("P4CLIENT".to_string(), "alice-main".to_string()),
+        ]
+    );
+    assert_eq!(p4config::find(&nested, "p4config.txt"), None);
+
+    let config = Config::parse(
+        "workspace_root = \"/home/alice/ws\"\n\
+         [p4]\n\
+         p4config = \"p4config.txt\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        config.workspace_root.unwrap(),
+        std::path::Path::new("/home/alice/ws")
+    );
+    assert_eq!(config.p4.p4config.as_deref(), Some("p4config.txt"));
+    assert!(config.p4.global_args().is_empty());
+}
+
+#[test]
+fn test_p4_environment_policy() {
+    use p4_mcp::p4::EnvironmentPolicy;
+    use p4_mcp::Config;
+    use std::ffi::OsString;
+
+    let vars = || {
+        [
+            ("PATH", "/usr/bin"),
+            ("P4PORT", "ssl:perforce.example.com:1666"),
+            ("P4PASSWD", "hunter2"),
+            ("AWS_SECRET_ACCESS_KEY", "secret"),
+            ("HTTPS_PROXY", "http://proxy:3128"),
+            ("P4IGNORE", ".gitignore"),
+        ]
+        .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+    };
+    let names = |env: Vec<(OsString, OsString)>| {
+        env.into_iter()
+            .map(|(name, value)| format!("{}={}", name.to_string_lossy(), value.to_string_lossy()))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        names(EnvironmentPolicy::default().apply(vars())),
+        vec![
+            "PATH=/usr/bin",
+            "P4PORT=ssl:perforce.example.com:1666",
+            "P4IGNORE=.gitignore"
+        ]
+    );
+
+    let config = Config::parse(
+        "[environment]\n\
+         inherit = [\"HTTPS_PROXY\"]\n\
+         inherit_p4passwd = true\n\
+         set = { P4IGNORE = \".p4ignore\" }\n",
+    )
+    .unwrap();
+    assert_eq!(
+        names(config.environment.apply(vars())),
+        vec![
+            "PATH=/usr/bin",
+            "P4PORT=ssl:perforce.example.com:1666",
+            "P4PASSWD=hunter2",
+            "HTTPS_PROXY=http://proxy:3128",
+            "P4IGNORE=.p4ignore"
+        ]
+    );
+
+    let everything = EnvironmentPolicy {
+        inherit_all: true,
+        ..Default::default()
+    };
+    assert_eq!(everything.apply(vars()).len(), 6);
+}
+
+#[test]
+fn test_command_timeouts() {
+    use p4_mcp::p4::{CommandTimeout, Timeouts};
+    use p4_mcp::Config;
+    use std::time::Duration;
+
+    let defaults = Timeouts::default();
+    assert_eq!(defaults.for_tool("p4_sync"), Some(Duration::from_secs(300)));
+
+    let config = Config::parse(
+        "[timeouts]\n\
+         default_secs = 0\n\
+         tools = { p4_sync = 3600 }\n",
+    )
+    .unwrap();
+    assert_eq!(config.timeouts.for_tool("p4_info"), None);
+    assert_eq!(
+        config.timeouts.for_tool("p4_sync"),
+        Some(Duration::from_secs(3600))
+    );
+
+    let timeout = CommandTimeout {
+        command: "sync //depot/...".to_string(),
+        timeout: Duration::from_secs(3600),
+    };
+    assert_eq!(
+        timeout.to_string(),
+        "p4 sync //depot/... timed out after 3600s"
+    );
+}
+
+#[test]
+fn test_reloaded_settings_reach_every_clone() {
+    use p4_mcp::p4::{PathSandbox, TimeLimit, Timeouts};
+    use p4_mcp::Config;
+    use std::time::Duration;
+
+    let handler = P4Handler::new();
+    let session = handler.clone();
+    assert_eq!(
+        session.timeout_for("p4_sync"),
+        Some(Duration::from_secs(300))
+    );
+
+    let config = Config::parse("[timeouts]\ntools = { p4_sync = 60 }\n").unwrap();
+    handler.reload(PathSandbox::default(), config.timeouts.clone());
+    assert_eq!(
+        session.timeout_for("p4_sync"),
+        Some(Duration::from_secs(60))
+    );
+
+    handler.reload(PathSandbox::default(), Timeouts::default());
+    assert_eq!(
+        session.timeout_for("p4_sync"),
+        Some(Duration::from_secs(300))
+    );
+    // A configured 0 turns the limit off rather than falling back to the default
+    assert_eq!(
+        TimeLimit::from(session.timeout_for("p4_sync")),
+        TimeLimit::After(Duration::from_secs(300))
+    );
+    assert_eq!(TimeLimit::from(None), TimeLimit::Disabled);
+}
+
+#[test]
+fn test_retry_policy() {
+    use p4_mcp::p4::retry::is_transient;
+    use p4_mcp::p4::RetryPolicy;
+    use std::time::Duration;
+
+    assert!(is_transient(
+        "Perforce client error:\n\tConnect to server failed; check $P4PORT.\n\
+         \tTCP connect to perforce:1666 failed.\n\tconnect: 10.0.0.1:1666: Connection timed out\n"
+    ));
+    assert!(is_transient(
+        "Partner exited unexpectedly.\nsocket: Connection reset by peer\n"
+    ));
+    assert!(!is_transient("//depot/missing/... - no such file(s).\n"));
+    assert!(!is_transient(
+        "Perforce password (P4PASSWD) invalid or unset.\n"
+    ));
+
+    let policy = RetryPolicy {
+        max_attempts: 4,
+        initial_backoff_ms: 500,
+        max_backoff_ms: 1500,
+    };
+    assert_eq!(policy.backoff(1), Some(Duration::from_millis(500)));
+    assert_eq!(policy.backoff(2), Some(Duration::from_millis(1000)));
+    assert_eq!(policy.backoff(3), Some(Duration::from_millis(1500)));
+    assert_eq!(policy.backoff(4), None);
+}
+
+#[test]
+fn test_session_expired_errors() {
+    use p4_mcp::p4::login::is_session_expired;
+    use p4_mcp::p4::LoginSettings;
+
+    assert!(is_session_expired(
+        "Your session has expired, please login again.\n"
+    ));
+    assert!(is_session_expired(
+        "Perforce password (P4PASSWD) invalid or unset.\n"
+    ));
+    assert!(!is_session_expired(
+        "//depot/missing/... - no such file(s).\n"
+    ));
+
+    assert!(LoginSettings::default().auto);
+}
+
+#[test]
+fn test_credential_providers() {
+    use p4_mcp::p4::credentials::Credentials;
+    use p4_mcp::p4::P4Connection;
+    use p4_mcp::Config;
+
+    let connection = P4Connection::default();
+    let config = Config::parse(
+        "[login.credentials]\n\
+         provider = \"env\"\n\
+         var = \"P4_MCP_TEST_PASSWORD\"\n",
+    )
+    .unwrap();
+    let provider = config.login.credentials.provider(&connection);
+    assert_eq!(provider.password().unwrap(), None);
+    std::env::set_var("P4_MCP_TEST_PASSWORD", "s3cret");
+    assert_eq!(provider.password().unwrap().as_deref(), Some("s3cret"));
+    assert!(!provider.describe().contains("s3cret"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("password");
+    std::fs::write(&path, "hunter2\n").unwrap();
+    let provider = Credentials::File { path }.provider(&connection);
+    assert_eq!(provider.password().unwrap().as_deref(), Some("hunter2"));
+    let missing = Credentials::File {
+        path: dir.path().join("missing"),
+    };
+    assert!(missing.provider(&connection).password().is_err());
+
+    let config = Config::parse("[login.credentials]\nprovider = \"keyring\"\n").unwrap();
+    assert_eq!(
+        config.login.credentials,
+        Credentials::Keyring {
+            service: "p4-mcp".to_string(),
+            user: None
+        }
+    );
+    let connection = P4Connection {
+        user: Some("alice".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(
+        config.login.credentials.provider(&connection).describe(),
+        "keyring entry p4-mcp/alice"
+    );
+    assert!(Config::parse("[login.credentials]\nprovider = \"argv\"\n").is_err());
+}
+
+#[test]
+fn test_output_chunks() {
+    use p4_mcp::mcp::truncation::chunk;
+
+    let text = "line one\nline two\nline three\n";
+    assert_eq!(chunk(text, 0, 0), (text, None));
+    assert_eq!(chunk(text, 0, 100), (text, None));
+    // Cut after the last newline that fits
+    assert_eq!(chunk(text, 0, 20), ("line one\nline two\n", Some(18)));
+    assert_eq!(chunk(text, 18, 20), ("line three\n", None));
+    // No newline in the second half: cut mid-line, on a character boundary
+    assert_eq!(chunk("ab\u{e9}cdef", 0, 3), ("ab", Some(2)));
+    assert_eq!(chunk("\u{e9}", 0, 1), ("\u{e9}", None));
+}
+
+#[test]
+fn test_severity_tagged_output() {
+    use p4_mcp::p4::severity::{exit_code, message, parse};
+
+    let report = parse(
+        "info: //depot/main/a.txt#3 - updating /ws/a.txt\n\
+         info1: 12 files\n\
+         warning: //depot/main/b.txt - file(s) up-to-date.\n\
+         error: //depot/main/c.txt - no such file(s).\n\
+         error: Some errors\n\
+         continue on a second line\n\
+         text: raw\n\
+         exit: 1\n",
+    );
+    assert_eq!(
+        report.output,
+        "//depot/main/a.txt#3 - updating /ws/a.txt\n... 12 files\nraw\n"
+    );
+    assert_eq!(
+        report.warnings,
+        vec!["//depot/main/b.txt - file(s) up-to-date."]
+    );
+    assert_eq!(
+        report.errors,
+        vec![
+            "//depot/main/c.txt - no such file(s).",
+            "Some errors\ncontinue on a second line"
+        ]
+    );
+    assert_eq!(report.exit_code, Some(1));
+    assert_eq!(exit_code("error: Unknown command.\nexit: 1\n"), Some(1));
+    assert_eq!(exit_code("info: Change 12345 created.\n"), None);
+
+    assert_eq!(
+        message("info: Change 12345 created."),
+        "Change 12345 created."
+    );
+    assert_eq!(
+        message("... serverAddress perforce:1666"),
+        "... serverAddress perforce:1666"
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_broker_responses() {
+    use p4_mcp::p4::broker::{Broker, Request};
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("broker.sh");
+    std::fs::write(
+        &script,
+        r#"while read -r line; do
+    case "$line" in
+        *'"-V"'*) printf '%s\n' '{"fallback": true}' ;;
+        *'"opened"'*) printf '%s\n' '{"stdout": "info: //depot/a.txt#1 - edit default change (text)\nexit: 0\n", "stderr": "", "status": 0}' ;;
+        *) printf '%s\n' '{"stdout": "error: Unknown command.\nexit: 1\n", "stderr": "", "status": 1}' ;;
+    esac
+done
+"#,
+    )
+    .unwrap();
+    let broker = Broker::new(vec!["sh".to_string(), script.display().to_string()]);
+    let request =
+        |args: &'static [&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+    let args = request(&["-s", "opened"]);
+    let output = broker
+        .run(&Request {
+            args: &args,
+            input: None,
+            cwd: Some(dir.path()),
+            env: [("P4CLIENT".to_string(), "alice-main".to_string())].into(),
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "info: //depot/a.txt#1 - edit default change (text)\nexit: 0\n"
+    );
+
+    // The same helper answers the next request
+    let args = request(&["-s", "frobnicate"]);
+    let output = broker
+        .run(&Request {
+            args: &args,
+            input: None,
+            cwd: None,
+            env: Default::default(),
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+
+    let args = request(&["-V"]);
+    let request = Request {
+        args: &args,
+        input: None,
+        cwd: None,
+        env: Default::default(),
+    };
+    assert!(broker.run(&request).await.unwrap().is_none());
+
+    // A helper that can't be started means running p4 directly
+    let missing = Broker::new(vec!["/nonexistent/p4_broker".to_string()]);
+    assert!(missing.run(&request).await.unwrap().is_none());
+}
+
+#[test]
+fn test_result_cache() {
+    use p4_mcp::p4::cache::{CacheKey, ResultCache};
+    use p4_mcp::Config;
+
+    let key = |dir: &str, args: &[&str]| CacheKey {
+        dir: Some(dir.into()),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+        input: None,
+    };
+
+    let cache = ResultCache::new(&CacheSettings::default());
+    assert!(cache.is_enabled());
+    cache.insert(
+        key("/ws", &["-ztag", "info"]),
+        "... userName alice\n".to_string(),
+    );
+    assert_eq!(
+        cache.get(&key("/ws", &["-ztag", "info"])).as_deref(),
+        Some("... userName alice\n")
+    );
+    // Another workspace or other flags are another query
+    assert_eq!(cache.get(&key("/other", &["-ztag", "info"])), None);
+    assert_eq!(cache.get(&key("/ws", &["-s", "info"])), None);
+    cache.invalidate();
+    assert_eq!(cache.get(&key("/ws", &["-ztag", "info"])), None);
+
+    let disabled = ResultCache::new(&CacheSettings { ttl_secs: 0 });
+    disabled.insert(key("/ws", &["info"]), "output".to_string());
+    assert_eq!(disabled.get(&key("/ws", &["info"])), None);
+
+    assert!(P4Command::Info.is_cacheable());
+    assert!(P4Command::Fstat {
+        paths: vec!["//depot/a.txt".to_string()]
+    }
+    .is_cacheable());
+    assert!(!P4Command::Opened { changelist: None }.is_cacheable());
+    assert!(P4Command::Edit {
+        files: vec!["a.txt".to_string()]
+    }
+    .is_mutating());
+    assert!(!P4Command::Dirs {
+        path: "//depot/*".to_string()
+    }
+    .is_mutating());
+
+    let config = Config::parse("[cache]\nttl_secs = 0\n").unwrap();
+    assert_eq!(config.cache.ttl_secs, 0);
+    assert_eq!(Config::default().cache.ttl_secs, 10);
+}
+
+#[test]
+fn test_file_lookups_read_paths_from_stdin() {
+    use p4_mcp::p4::ztag::{from_records, parse_ztag, WhereFile};
+
+    // Far more than fits on a Windows command line
+    let paths: Vec<String> = (0..5000)
+        .map(|i| format!("//depot/main/src/module{}/file{}.cpp", i / 100, i))
+        .collect();
+    let cmd = P4Command::Fstat {
+        paths: paths.clone(),
+    };
+    assert_eq!(cmd.to_command_args().1, vec!["-x", "-", "fstat"]);
+    let input = cmd.stdin_input().unwrap();
+    assert_eq!(input.lines().collect::<Vec<_>>(), paths);
+
+    let cmd = P4Command::Where {
+        paths: vec!["//depot/main/a b.txt".to_string()],
+    };
+    assert_eq!(cmd.to_command_args().1, vec!["-x", "-", "where"]);
+    assert_eq!(cmd.stdin_input().as_deref(), Some("//depot/main/a b.txt\n"));
+
+    let files: Vec<WhereFile> = from_records(parse_ztag(
+        "... depotFile //depot/main/a.txt\n... clientFile //alice-ws/main/a.txt\n\
+         ... path /home/alice/ws/main/a.txt\n\n\
+         ... depotFile //depot/main/build/out.o\n... clientFile //alice-ws/main/build/out.o\n\
+         ... path /home/alice/ws/main/build/out.o\n... unmap \n\n",
+    ))
+    .unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].path, "/home/alice/ws/main/a.txt");
+    assert_eq!(files[0].unmap, None);
+    assert!(files[1].unmap.is_some());
+}
+
+#[test]
+fn test_path_sandbox() {
+    use std::path::{Path, PathBuf};
+
+    let sandbox = PathSandbox {
+        depot: vec!["//depot/team-x/...".to_string()],
+        local: vec![PathBuf::from("/home/alice/ws/team-x")],
+    };
+    let ws = Path::new("/home/alice/ws/team-x/src");
+
+    for allowed in [
+        "//depot/team-x/...",
+        "//depot/team-x/src/main.c#head",
+        "//depot/team-x/...@2024/01/01,@now",
+        "main.c",
+        "../docs/...",
+        "/home/alice/ws/team-x/README",
+    ] {
+        assert!(sandbox.check(allowed, ws).is_ok(), "{} refused", allowed);
+    }
+    for refused in [
+        "//depot/...",
+        "//depot/team-xyz/a.c",
+        "//depot/team-x/../secret/...",
+        "//alice-ws/team-x/...",
+        "../../other/...",
+        "/etc/passwd",
+    ] {
+        assert!(sandbox.check(refused, ws).is_err(), "{} allowed", refused);
+    }
+
+    // Without local directories, local paths are refused
+    let depot_only = PathSandbox {
+        depot: sandbox.depot.clone(),
+        local: Vec::new(),
+    };
+    let error = depot_only.check("main.c", ws).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "Path main.c is outside the allowed local paths (none)"
+    );
+    assert!(PathSandbox::default().check("/etc/passwd", ws).is_ok());
+
+    let view = P4Command::SpecInput {
+        kind: SpecKind::Client,
+        spec: "Client:\talice-ws\n\nView:\n\t//depot/team-x/... //alice-ws/team-x/...\n\t-\"//depot/team-x/big files/...\" \"//alice-ws/team-x/big files/...\"\n".to_string(),
+    };
+    assert_eq!(
+        view.paths(),
+        vec!["//depot/team-x/...", "//depot/team-x/big files/..."]
+    );
+    let change = P4Command::SpecInput {
+        kind: SpecKind::Change,
+        spec: "Change:\tnew\n\nFiles:\n\t//depot/team-x/a.c\t# edit\n".to_string(),
+    };
+    assert_eq!(change.paths(), vec!["//depot/team-x/a.c"]);
+}
+
+#[test]
+fn test_path_sandbox_filters_files_of_changes() {
+    let sandbox = PathSandbox {
+        depot: vec!["//depot/team-x/...".to_string()],
+        local: Vec::new(),
+    };
+    let describe = P4Command::DescribeDiffs {
+        changelist: "12345".to_string(),
+    };
+    let output = "Change 12345 by alice@ws on 2024/01/15 12:30:45\n\n\tRework\n\n\
+                  Affected files ...\n\n\
+                  ... //depot/team-x/a.c#3 edit\n\
+                  ... //depot/secret/key.pem#2 edit\n\n\
+                  Differences ...\n\n\
+                  ==== //depot/team-x/a.c#3 (text) ====\n\n-old\n+new\n\n\
+                  ==== //depot/secret/key.pem#2 (text) ====\n\n-hunter1\n+hunter2\n";
+    let filtered = sandbox.filter_output(&describe, output.to_string());
+    assert!(filtered.contains("... //depot/team-x/a.c#3 edit\n"));
+    assert!(filtered.contains("+new\n"));
+    assert!(!filtered.contains("secret"), "{}", filtered);
+    assert!(!filtered.contains("hunter"), "{}", filtered);
+
+    // Tagged describes keep their jobs, and the files left are numbered from 0
+    let record = serde_json::json!({
+        "change": "12345",
+        "job0": "job000123",
+        "depotFile0": "//depot/secret/key.pem", "action0": "edit", "rev0": "2",
+        "depotFile1": "//depot/team-x/a.c", "action1": "add", "rev1": "1",
+    });
+    let records = sandbox.filter_records(&describe, vec![record.as_object().unwrap().clone()]);
+    assert_eq!(
+        serde_json::Value::Object(records[0].clone()),
+        serde_json::json!({
+            "change": "12345",
+            "job0": "job000123",
+            "depotFile0": "//depot/team-x/a.c", "action0": "add", "rev0": "1",
+        })
+    );
+
+    let opened = P4Command::Opened { changelist: None };
+    let output = "//depot/team-x/a.c#1 - edit default change (text)\n\
+                  //depot/secret/key.pem#2 - edit default change (text)\n";
+    assert_eq!(
+        sandbox.filter_output(&opened, output.to_string()),
+        "//depot/team-x/a.c#1 - edit default change (text)\n"
+    );
+    // Output of commands that are given their paths is left alone
+    let files = P4Command::Files {
+        path: "//depot/team-x/...".to_string(),
+        max: None,
+    };
+    assert_eq!(sandbox.filter_output(&files, output.to_string()), output);
+}
+
+#[test]
+fn test_audit_log_appends_json_lines() {
+    use p4_mcp::p4::audit::{AuditEntry, AuditLog};
+    use p4_mcp::Config;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let args = vec!["-s".to_string(), "opened".to_string()];
+    let entry = AuditEntry {
+        timestamp: "2024-05-01T12:00:00.000Z".to_string(),
+        request_id: Some("7"),
+        tool: Some("p4_opened"),
+        program: "p4".to_string(),
+        args: &args,
+        cwd: Some(dir.path()),
+        exit_status: Some(0),
+        error: None,
+        stdin_bytes: 0,
+        stdout_bytes: 64,
+        stderr_bytes: 0,
+        duration_ms: 12,
+    };
+    AuditLog::open(&path).unwrap().record(&entry);
+    // Opening the log again appends rather than truncating it
+    AuditLog::open(&path).unwrap().record(&AuditEntry {
+        request_id: None,
+        tool: None,
+        exit_status: None,
+        error: Some("p4 -s opened timed out after 5s".to_string()),
+        ..entry.clone()
+    });
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<serde_json::Value> = text
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["requestId"], "7");
+    assert_eq!(lines[0]["tool"], "p4_opened");
+    assert_eq!(lines[0]["args"], json!(["-s", "opened"]));
+    assert_eq!(lines[0]["exitStatus"], 0);
+    assert_eq!(lines[0]["stdoutBytes"], 64);
+    assert!(lines[0].get("error").is_none());
+    assert!(lines[1]["requestId"].is_null());
+    assert!(lines[1]["exitStatus"].is_null());
+    assert_eq!(lines[1]["error"], "p4 -s opened timed out after 5s");
+
+    assert!(AuditLog::open(&dir.path().join("missing").join("audit.jsonl")).is_err());
+    let config = Config::parse("audit_log = \"/var/log/p4-mcp/audit.jsonl\"\n").unwrap();
+    assert_eq!(
+        config.audit_log.as_deref(),
+        Some(std::path::Path::new("/var/log/p4-mcp/audit.jsonl"))
+    );
+}
+
+#[test]
+fn test_recording_replays_in_order() {
+    use p4_mcp::p4::recording::{Data, RecordedCommand, Recorder, Replayer};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.jsonl");
+    let opened = vec!["-s".to_string(), "opened".to_string()];
+    let change = vec!["-s".to_string(), "change".to_string(), "-i".to_string()];
+    let lines = [
+        json!({"args": opened, "status": 0, "stdout": "info: //depot/a.c#1 - edit default change (text)\n", "stderr": ""}),
+        json!({"args": opened, "status": 0, "stdout": "", "stderr": ""}),
+        json!({"args": change, "input": "Change: new\n", "status": 1, "stdout": "", "stderr": "error: no files\n"}),
+        json!({"args": ["-s", "print", "//depot/b.bin"], "status": 0, "stdout": [0, 159, 146, 150], "stderr": ""}),
+    ];
+    let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+    std::fs::write(&path, text).unwrap();
+
+    let replayer = Replayer::load(&path).unwrap();
+    // Responses come in the order they were recorded, then the last one repeats
+    let first = replayer.respond(&opened, None).unwrap();
+    assert!(first.status.success());
+    assert!(String::from_utf8_lossy(&first.stdout).contains("//depot/a.c#1"));
+    assert!(replayer.respond(&opened, None).unwrap().stdout.is_empty());
+    assert!(replayer.respond(&opened, None).unwrap().stdout.is_empty());
+
+    // Input is part of the match
+    let failed = replayer.respond(&change, Some(b"Change: new\n")).unwrap();
+    assert_eq!(failed.status.code(), Some(1));
+    assert_eq!(failed.stderr, b"error: no files\n");
+    let error = replayer.respond(&change, None).unwrap_err().to_string();
+    assert!(
+        error.contains("No response to p4 -s change -i"),
+        "{}",
+        error
+    );
+
+    // Output that isn't UTF-8 survives a round trip through the recorder
+    let print = vec![
+        "-s".to_string(),
+        "print".to_string(),
+        "//depot/b.bin".to_string(),
+    ];
+    let binary = replayer.respond(&print, None).unwrap();
+    assert_eq!(binary.stdout, vec![0, 159, 146, 150]);
+    let rerecorded = dir.path().join("rerecorded.jsonl");
+    Recorder::create(&rerecorded)
+        .unwrap()
+        .record(&print, None, Some(dir.path()), &binary);
+    let line = std::fs::read_to_string(&rerecorded).unwrap();
+    let command: RecordedCommand = serde_json::from_str(line.trim()).unwrap();
+    assert_eq!(command.stdout, Data::Bytes(vec![0, 159, 146, 150]));
+    assert_eq!(command.cwd.as_deref(), Some(dir.path()));
+    assert_eq!(
+        Replayer::load(&rerecorded)
+            .unwrap()
+            .respond(&print, None)
+            .unwrap()
+            .stdout,
+        binary.stdout
+    );
+
+    // Passwords given to p4 login aren't recorded
+    let login = vec!["login".to_string()];
+    Recorder::create(&rerecorded)
+        .unwrap()
+        .record(&login, Some(b"hunter2\n"), None, &failed);
+    let text = std::fs::read_to_string(&rerecorded).unwrap();
+    assert!(!text.contains("hunter2"));
+    let replayer = Replayer::load(&rerecorded).unwrap();
+    assert_eq!(
+        replayer
+            .respond(&login, Some(b"another password\n"))
+            .unwrap()
+            .status
+            .code(),
+        Some(1)
+    );
+
+    assert!(Replayer::load(&dir.path().join("missing.jsonl")).is_err());
+}
+
+#[test]
+fn test_mock_faults_follow_settings() {
+    use p4_mcp::p4::faults::{Fault, FaultInjector};
+    use std::time::Duration;
+
+    let config = p4_mcp::Config::parse(
+        r#"
+[mock_faults]
+error_rate = 0.5
+errors = ["expired_ticket", "connection_reset"]
+latency_ms = 20
+seed = 7
+
+[mock_faults.commands.submit]
+error_rate = 1.0
+errors = ["locked_file"]
+latency_ms = 500
+
+[mock_faults.commands.info]
+error_rate = 0.0
+"#,
+    )
+    .unwrap();
+    assert!(config.mock_faults.is_enabled());
+    assert!(!FaultInjection::default().is_enabled());
+
+    let injector = FaultInjector::new(config.mock_faults.clone());
+    assert_eq!(
+        injector.next("submit"),
+        (Duration::from_millis(500), Some(Fault::LockedFile))
+    );
+    assert_eq!(injector.next("info"), (Duration::from_millis(20), None));
+
+    let faults: Vec<Option<Fault>> = (0..200).map(|_| injector.next("sync").1).collect();
+    let failed = faults.iter().flatten().count();
+    assert!((50..150).contains(&failed), "{} of 200 failed", failed);
+    assert!(faults.contains(&Some(Fault::ExpiredTicket)));
+    assert!(faults.contains(&Some(Fault::ConnectionReset)));
+    assert!(!faults.contains(&Some(Fault::LockedFile)));
+
+    // The same seed makes the same choices
+    let again = FaultInjector::new(config.mock_faults);
+    again.next("submit");
+    again.next("info");
+    let repeated: Vec<Option<Fault>> = (0..200).map(|_| again.next("sync").1).collect();
+    assert_eq!(faults, repeated);
+
+    assert!(p4_mcp::Config::parse("[mock_faults]\nerrors = [\"disk_full\"]\n").is_err());
+    for rates in [
+        "[mock_faults]\nerror_rate = 1.5\n",
+        "[mock_faults]\nerror_rate = -0.1\n",
+        "[mock_faults.commands.sync]\nerror_rate = 2.0\n",
+    ] {
+        let error = p4_mcp::Config::parse(rates).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("not between 0 and 1"),
+            "{:#}",
+            error
+        );
+    }
+    assert!(Fault::ExpiredTicket
+        .message(None)
+        .contains("Your session has expired"));
+    assert!(Fault::LockedFile
+        .message(Some("//depot/main/a.c"))
+        .starts_with("//depot/main/a.c - "));
+}
+
+#[test]
+fn test_mock_users_opened_lines_and_conflicts() {
+    use p4_mcp::p4::mock_users::{covers, default_users, edit_conflicts};
+
+    let config = p4_mcp::Config::parse(
+        "[[mock_users]]\nuser = \"dave\"\nfiles = [\"//depot/a.c\"]\n\
+         locked = [\"//depot/b.c\"]\nexclusive = [\"//depot/art/logo.psd\"]\n",
+    )
+    .unwrap();
+    let users = config.mock_users.unwrap();
+    assert_eq!(users[0].owner(), "dave@dave-ws");
+    assert_eq!(
+        users[0].opened_lines(&[]),
+        vec![
+            "//depot/art/logo.psd#1 - edit default change (text+l) by dave@dave-ws *exclusive*",
+            "//depot/b.c#1 - edit default change (text) by dave@dave-ws *locked*",
+            "//depot/a.c#1 - edit default change (text) by dave@dave-ws",
+        ]
+    );
+    assert_eq!(
+        users[0]
+            .opened_lines(&["//depot/art/...".to_string()])
+            .len(),
+        1
+    );
+
+    let (errors, warnings) = edit_conflicts(
+        &users,
+        &["//depot/...".to_string(), "//depot/c.c".to_string()],
+    );
+    assert_eq!(
+        errors,
+        vec!["//depot/art/logo.psd - can't edit exclusive file already opened"]
+    );
+    assert_eq!(
+        warnings,
+        vec![
+            "//depot/a.c - also opened by dave@dave-ws",
+            "//depot/b.c - also opened by dave@dave-ws",
+        ]
+    );
+
+    assert!(covers("//depot/a.c#3", "//depot/a.c"));
+    assert!(!covers("//depot/a.cpp", "//depot/a.c"));
+    assert!(default_users()
+        .iter()
+        .any(|user| user.owner() == "alice@alice-ws" && !user.exclusive.is_empty()));
+    assert!(p4_mcp::Config::parse("mock_users = []\n")
+        .unwrap()
+        .mock_users
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_custom_backend_runs_commands() {
+    use p4_mcp::p4::backend::exit_status;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    /// Answers every command with one file and remembers what it ran; `p4 sync` runs
+    /// until it's cancelled.
+    #[derive(Default)]
+    struct Scripted {
+        runs: Mutex<Vec<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl P4Backend for Scripted {
+        async fn execute(&self, invocation: &Invocation<'_>) -> anyhow::Result<Output> {
+            self.runs.lock().unwrap().push(invocation.args.to_vec());
+            if invocation.args.iter().any(|arg| arg == "sync") {
+                std::future::pending::<()>().await;
+            }
+            Ok(Output {
+                status: exit_status(0),
+                stdout: b"info: //depot/a.c#1 - edit default change (text)\n\
+                          warning: //depot/b.c - file(s) not opened on this client.\n\
+                          exit: 0\n"
+                    .to_vec(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn program(&self) -> String {
+            "scripted".to_string()
+        }
+    }
+
+    let backend = Arc::new(Scripted::default());
+    let mut handler = P4Handler::new();
+    handler.set_mock_mode(MockMode::new(false));
+    handler.set_connection(P4Connection {
+        charset: Some("none".to_string()),
+        ..Default::default()
+    });
+    handler.set_backend(Some(backend.clone()));
+    assert_eq!(handler.backend().program(), "scripted");
+
+    // The handler still splits output by severity
+    let output = handler
+        .execute(P4Command::Opened { changelist: None })
+        .await
+        .unwrap();
+    assert_eq!(
+        output,
+        "//depot/a.c#1 - edit default change (text)\n\
+         //depot/b.c - file(s) not opened on this client.\n"
+    );
+    assert_eq!(
+        backend.runs.lock().unwrap().as_slice(),
+        [vec!["-s".to_string(), "opened".to_string()]]
+    );
+
+    // Cancelling a call stops the commands of the handlers sharing its token only
+    let mut other = handler.clone();
+    other.set_cancel_token(CancelToken::new());
+    let mut syncing = handler.clone();
+    let sync = tokio::spawn(async move {
+        syncing
+            .execute(P4Command::Sync {
+                path: "//depot/...".to_string(),
+                force: false,
+            })
+            .await
+    });
+    while backend.runs.lock().unwrap().len() < 2 {
+        tokio::task::yield_now().await;
+    }
+    handler.cancel();
+    let error = sync.await.unwrap().unwrap_err().to_string();
+    assert!(error.contains("cancelled"), "{}", error);
+    let edit = || P4Command::Edit {
+        files: vec!["//depot/a.c".to_string()],
+    };
+    let error = handler.execute(edit()).await.unwrap_err().to_string();
+    assert!(error.contains("cancelled"), "{}", error);
+    assert!(other.execute(edit()).await.is_ok());
+
+    // Mock mode takes precedence
+    handler.mock_mode().set(true);
+    assert_eq!(handler.backend().program(), "mock");
+    handler.set_backend(None);
+    handler.mock_mode().set(false);
+    assert_eq!(handler.backend().program(), "p4");
+
+    // The mock backend answers as p4 would
+    let mock = MockBackend::new();
+    let args = vec!["-s".to_string(), "opened".to_string()];
+    let output = mock
+        .execute(&Invocation {
+            command: Some(&P4Command::Opened { changelist: None }),
+            args: &args,
+            input: None,
+            cwd: None,
+            env: &[],
+            charset: None,
+        })
+        .await
+        .unwrap();
+    let report = severity::parse(&String::from_utf8(output.stdout).unwrap());
+    assert_eq!(report.exit_code, Some(0));
+    assert!(report.output.starts_with("Mock P4 Opened:\n"));
+}
+
+#[tokio::test]
+async fn test_relative_paths_resolve_against_the_client_root() {
+    use p4_mcp::p4::backend::exit_status;
+    use std::process::Output;
+    use std::sync::{Arc, Mutex};
+
+    /// A client rooted at `root`; remembers the commands it ran.
+    struct Client {
+        root: std::path::PathBuf,
+        runs: Mutex<Vec<Vec<String>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl P4Backend for Client {
+        async fn execute(&self, invocation: &Invocation<'_>) -> anyhow::Result<Output> {
+            self.runs.lock().unwrap().push(invocation.args.to_vec());
+            let stdout = if invocation.args.iter().any(|arg| arg == "info") {
+                format!(
+                    "... userName alice\n... clientName alice-ws\n... clientRoot {}\n\
+                     ... clientStream //streams/dev\n",
+                    self.root.display()
+                )
+            } else {
+                "exit: 0\n".to_string()
+            };
+            Ok(Output {
+                status: exit_status(0),
+                stdout: stdout.into_bytes(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn program(&self) -> String {
+            "client".to_string()
+        }
+    }
+
+    let root = tempfile::tempdir().unwrap();
+    let backend = Arc::new(Client {
+        root: root.path().to_path_buf(),
+        runs: Mutex::default(),
+    });
+    let mut handler = P4Handler::new();
+    handler.set_mock_mode(MockMode::new(false));
+    handler.set_connection(P4Connection {
+        charset: Some("none".to_string()),
+        ..Default::default()
+    });
+    handler.set_backend(Some(backend.clone()));
+
+    // The client found at startup is kept for resolving paths
+    let workspace = handler.detect_workspace().await.unwrap().unwrap();
+    assert_eq!(workspace.client, "alice-ws");
+    assert_eq!(workspace.stream.as_deref(), Some("//streams/dev"));
+    assert_eq!(workspace.local_root().as_deref(), Some(root.path()));
+    assert_eq!(handler.client_root().await.as_deref(), Some(root.path()));
+    let info_runs = || {
+        backend
+            .runs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|args| args.iter().any(|arg| arg == "info"))
+            .count()
+    };
+    assert_eq!(info_runs(), 1);
+
+    let edit = |file: &str| P4Command::Edit {
+        files: vec![file.to_string()],
+    };
+    let last_run = || backend.runs.lock().unwrap().last().unwrap().clone();
+    handler.execute(edit("src/a.c")).await.unwrap();
+    assert_eq!(
+        last_run().last().unwrap(),
+        &root.path().join("src/a.c").to_string_lossy()
+    );
+    handler.execute(edit("//depot/main/b.c")).await.unwrap();
+    assert_eq!(last_run().last().unwrap(), "//depot/main/b.c");
+
+    // A configured workspace root is where p4 runs, so paths are left relative to it
+    handler.set_workspace_root(Some(root.path().to_path_buf()));
+    handler.execute(edit("src/a.c")).await.unwrap();
+    assert_eq!(last_run().last().unwrap(), "src/a.c");
+}
+
+#[tokio::test]
+async fn test_concurrent_calls_share_the_process_limit() {
+    use p4_mcp::p4::backend::exit_status;
+    use std::process::Output;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Takes a while per command; remembers the most that ran at once.
+    #[derive(Default)]
+    struct Slow {
+        running: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl P4Backend for Slow {
+        async fn execute(&self, _invocation: &Invocation<'_>) -> anyhow::Result<Output> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(running, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(Output {
+                status: exit_status(0),
+                stdout: b"exit: 0\n".to_vec(),
+                stderr: Vec::new(),
+            })
+        }
+
+        fn program(&self) -> String {
+            "slow".to_string()
+        }
+    }
+
+    let backend = Arc::new(Slow::default());
+    let mut handler = P4Handler::new();
+    handler.set_mock_mode(MockMode::new(false));
+    handler.set_connection(P4Connection {
+        charset: Some("none".to_string()),
+        ..Default::default()
+    });
+    handler.set_backend(Some(backend.clone()));
+    handler.set_max_processes(2);
+
+    let calls: Vec<_> = (0..8)
+        .map(|i| {
+            let mut handler = handler.clone();
+            tokio::spawn(async move {
+                handler
+                    .execute(P4Command::Edit {
+                        files: vec![format!("//depot/main/file{}.c", i)],
+                    })
+                    .await
+            })
+        })
+        .collect();
+    for call in calls {
+        call.await.unwrap().unwrap();
+    }
+    assert_eq!(backend.peak.load(Ordering::SeqCst), 2);
+}
+#[test]
+fn test_typed_results_from_p4_output() {
+    let change = Changelist::from_spec(&Spec::parse(
+        "Change:\t12346\n\nClient:\tws\n\nUser:\talice\n\nStatus:\tpending\n\n\
+         Description:\n\tFix the build\n\n\
+         Jobs:\n\tjob000123\t# Broken build\n\n\
+         Files:\n\t//depot/main/a.c\t# edit\n\t//depot/main/b.c\t# add\n",
+    ));
+    assert_eq!(change.change, Some(12346));
+    assert_eq!(change.description, "Fix the build");
+    assert_eq!(change.jobs, ["job000123"]);
+    assert_eq!(
+        change.files[1],
+        ChangelistFile {
+            depot_file: "//depot/main/b.c".to_string(),
+            action: "add".to_string(),
+        }
+    );
+    assert_eq!(
+        change.depot_files(),
+        ["//depot/main/a.c", "//depot/main/b.c"]
+    );
+    let new = Changelist::from_spec(&Spec::parse("Change:\tnew\n\nStatus:\tnew\n"));
+    assert_eq!(new.change, None);
+
+    let client = ClientSpec::from_spec(&Spec::parse(
+        "Client:\tws\n\nOwner:\talice\n\nRoot:\t/home/alice/ws\n\n\
+         Options:\tnoallwrite clobber\n\n\
+         View:\n\t//depot/main/... //ws/main/...\n\
+         \t-//depot/main/gen/... //ws/main/gen/...\n\
+         \t\"//depot/main/my docs/...\" \"//ws/main/my docs/...\"\n",
+    ));
+    assert_eq!(client.options, ["noallwrite", "clobber"]);
+    assert_eq!(client.stream, None);
+    assert_eq!(client.view.len(), 3);
+    assert_eq!(client.view[1].depot_path, "-//depot/main/gen/...");
+    assert_eq!(client.view[2].client_path, "//ws/main/my docs/...");
+
+    let sync = SyncResult::parse(
+        "//depot/main/a.c#3 - updating /ws/main/a.c\n\
+         //depot/main/new.c#1 - added as /ws/main/new.c\n\
+         //depot/main/old.c#2 - deleted as /ws/main/old.c\n\
+         //depot/main/docs/... - file(s) up-to-date.\n",
+    );
+    assert_eq!(
+        sync.files[0],
+        SyncedFile {
+            depot_file: "//depot/main/a.c".to_string(),
+            rev: Some(3),
+            action: "updated".to_string(),
+            client_file: "/ws/main/a.c".to_string(),
+        }
+    );
+    assert_eq!(sync.files[2].action, "deleted");
+    assert_eq!(
+        sync.messages,
+        ["//depot/main/docs/... - file(s) up-to-date."]
+    );
+}
+
+#[tokio::test]
+async fn test_execute_streamed_yields_output_chunks() {
+    use futures::StreamExt;
+
+    let mut handler = P4Handler::new();
+    handler.set_mock_mode(MockMode::new(true));
+
+    let chunks: Vec<OutputChunk> = handler
+        .execute_streamed(P4Command::Sync {
+            path: "//depot/main/...".to_string(),
+            force: false,
+        })
+        .collect()
+        .await;
+    assert!(!chunks.is_empty());
+    assert!(chunks
+        .iter()
+        .all(|chunk| matches!(chunk, OutputChunk::Line(_))));
+
+    // alice has file3.h open exclusively, so p4 reports an error and the edit fails
+    let chunks: Vec<OutputChunk> = handler
+        .execute_streamed(P4Command::Edit {
+            files: vec!["//depot/main/file3.h".to_string()],
+        })
+        .collect()
+        .await;
+    assert!(chunks.contains(&OutputChunk::Error(
+        "//depot/main/file3.h - can't edit exclusive file already opened".to_string()
+    )));
+    assert!(matches!(chunks.last(), Some(OutputChunk::Failed(_))));
+
+    assert_eq!(
+        OutputChunk::from_line("warning: file(s) up-to-date."),
+        Some(OutputChunk::Warning("file(s) up-to-date.".to_string()))
+    );
+    assert_eq!(
+        OutputChunk::from_line("info1: head 3"),
+        Some(OutputChunk::Line("head 3".to_string()))
+    );
+    assert_eq!(OutputChunk::from_line("exit: 0"), None);
+}
+
+#[test]
+fn test_markdown_rendering() {
+    let changes = format::markdown(
+        "Change 12351 on 2024/01/16 by alice@alice-ws *pending* 'Fix the *build*'\n\
+         Change 12350 on 2024/01/15 by bob@bob-ws 'Add tests'\n",
+    );
+    assert_eq!(
+        changes,
+        "| Change | Date | User | Client | Status | Description |\n\
+         |---|---|---|---|---|---|\n\
+         | 12351 | 2024/01/16 | alice | alice-ws | pending | Fix the \\*build\\* |\n\
+         | 12350 | 2024/01/15 | bob | bob-ws |  | Add tests |\n"
+    );
+
+    let info = format::markdown("User name: testuser\nClient name: test-client\n");
+    assert_eq!(
+        info,
+        "| Field | Value |\n|---|---|\n| User name | testuser |\n| Client name | test-client |\n"
+    );
+
+    let opened = format::markdown("//depot/main/file_1.txt#1 - edit default change (text)\n");
+    assert_eq!(
+        opened,
+        "- `//depot/main/file_1.txt#1` edit default change (text)\n"
+    );
+
+    let diff =
+        format::markdown("==== //depot/main/a.c#3 - /ws/a.c ====\n@@ -1 +1 @@\n-old\n+```new```\n");
+    assert_eq!(
+        diff,
+        "#### `//depot/main/a.c`\n\n\
+         Revision #3, against `/ws/a.c`, language c\n\n\
+         ````diff\n@@ -1 +1 @@\n-old\n+```new```\n````\n"
+    );
+
+    assert_eq!(
+        format::markdown("{\"ok\": true}"),
+        "```json\n{\"ok\": true}\n```\n"
+    );
+    assert_eq!(
+        format::markdown("file(s) up-to-date."),
+        "file(s) up-to-date.\n"
+    );
+}
+
+#[test]
+fn test_diff_split_by_file() {
+    use p4_mcp::p4::diff::{language, split};
+
+    let described = split(
+        "Change 12347 by alice@alice-ws on 2024/01/14 *pending*\n\
+         \n\
+         \tShelved work\n\
+         \n\
+         Differences ...\n\
+         \n\
+         ==== //depot/main/src/app.rs#3 (text) ====\n\
+         \n\
+         @@ -1 +1 @@\n\
+         -old\n\
+         +new\n\
+         \n\
+         ==== //depot/main/art/logo.png#2 (binary+l) ====\n",
+    );
+    assert!(described.preamble.ends_with("Differences ..."));
+    assert_eq!(described.files.len(), 2);
+    let app = &described.files[0];
+    assert_eq!(app.path, "//depot/main/src/app.rs");
+    assert_eq!(app.revision.as_deref(), Some("#3"));
+    assert_eq!(app.file_type.as_deref(), Some("text"));
+    assert_eq!(app.body, "@@ -1 +1 @@\n-old\n+new");
+    assert_eq!(app.language(), Some("rust"));
+    assert_eq!(described.files[1].file_type.as_deref(), Some("binary+l"));
+    assert_eq!(described.files[1].body, "");
+
+    let diff2 = split(
+        "==== //depot/main/a.cpp#1 (text) - //depot/rel/a.cpp#4 (text) ==== content\n\
+         @@ -1 +1 @@\n-a\n+b\n",
+    );
+    assert_eq!(
+        diff2.files[0].against.as_deref(),
+        Some("//depot/rel/a.cpp#4 (text)")
+    );
+    assert_eq!(diff2.files[0].status.as_deref(), Some("content"));
+
+    // A unified header right after a `====` one belongs to the same file, while a
+    // removed `--` line followed by an added `++` one isn't a header at all
+    let unified = split(
+        "==== //depot/main/q.sql#2 - /ws/q.sql ====\n\
+         --- //depot/main/q.sql\t2024/01/15 12:30:45\n\
+         +++ /ws/q.sql\t2024/01/15 12:45:10\n\
+         @@ -1,2 +1,2 @@\n\
+         --- old comment\n\
+         +++ new comment\n\
+         --- //depot/main/b.py\t2024/01/15 12:30:45\n\
+         +++ //depot/main/b.py\t2024/01/15 12:45:10\n\
+         @@ -1 +1 @@\n\
+         -x\n\
+         +y\n",
+    );
+    assert_eq!(unified.files.len(), 2);
+    assert_eq!(unified.files[0].against.as_deref(), Some("/ws/q.sql"));
+    assert_eq!(
+        unified.files[0].body,
+        "@@ -1,2 +1,2 @@\n--- old comment\n+++ new comment"
+    );
+    assert_eq!(unified.files[1].path, "//depot/main/b.py");
+    assert_eq!(unified.files[1].against, None);
+
+    assert_eq!(language("//depot/main/CMakeLists.txt"), Some("cmake"));
+    assert_eq!(language("C:\\ws\\src\\Widget.H"), Some("cpp"));
+    assert_eq!(language("//depot/main/notes.txt"), None);
+    assert!(split("file(s) up-to-date.").files.is_empty());
+}
+
+#[test]
+fn test_metrics_latency_histogram() {
+    use p4_mcp::metrics::Metrics;
+    use std::time::Duration;
+
+    let metrics = Metrics::new();
+    metrics.record_p4_command("sync", true, Duration::from_millis(30));
+    metrics.record_p4_command("sync", false, Duration::from_secs(90));
+    let text = metrics.render();
+
+    // Buckets are cumulative, and a run slower than the last bucket only counts in +Inf
+    assert!(text
+        .contains("p4mcp_p4_command_duration_seconds_bucket{command=\"sync\",le=\"0.025\"} 0\n"));
+    assert!(
+        text.contains("p4mcp_p4_command_duration_seconds_bucket{command=\"sync\",le=\"0.05\"} 1\n")
+    );
+    assert!(
+        text.contains("p4mcp_p4_command_duration_seconds_bucket{command=\"sync\",le=\"60\"} 1\n")
+    );
+    assert!(
+        text.contains("p4mcp_p4_command_duration_seconds_bucket{command=\"sync\",le=\"+Inf\"} 2\n")
+    );
+    assert!(text.contains("p4mcp_p4_command_duration_seconds_count{command=\"sync\"} 2\n"));
+    assert!(text.contains("p4mcp_p4_commands_total{command=\"sync\",result=\"error\"} 1\n"));
+
+    let in_flight = metrics.request_started();
+    assert!(metrics.render().contains("p4mcp_requests_in_flight 1\n"));
+    drop(in_flight);
+    assert!(metrics.render().contains("p4mcp_requests_in_flight 0\n"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_long_file_lists_go_in_an_argfile() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let p4 = dir.path().join("p4");
+    std::fs::write(
+        &p4,
+        "#!/bin/sh\necho \"args: $*\"\nwhile [ $# -gt 0 ]; do \
+         if [ \"$1\" = -x ]; then cat \"$2\"; fi; shift; done\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&p4, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let backend = CliBackend::new(p4, None);
+    let run = |files: Vec<String>| {
+        let command = P4Command::Edit { files };
+        let args: Vec<String> = ["-s".to_string()]
+            .into_iter()
+            .chain(command.to_command_args().1)
+            .collect();
+        let backend = &backend;
+        async move {
+            let output = backend
+                .execute(&Invocation {
+                    command: Some(&command),
+                    args: &args,
+                    input: None,
+                    cwd: None,
+                    env: &[],
+                    charset: None,
+                })
+                .await
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        }
+    };
+
+    let output = run(vec!["a.c".to_string(), "b.c".to_string()]).await;
+    assert_eq!(output, "args: -s edit a.c b.c\n");
+
+    let files: Vec<String> = (0..=p4_mcp::p4::cli::ARGFILE_THRESHOLD)
+        .map(|i| format!("src/file{}.c", i))
+        .collect();
+    let output = run(files.clone()).await;
+    let mut lines = output.lines();
+    let args = lines.next().unwrap();
+    assert!(args.starts_with("args: -s -x "), "{}", args);
+    assert!(args.ends_with(" edit"), "{}", args);
+    assert_eq!(lines.collect::<Vec<_>>(), files);
+}
+
+#[test]
+fn test_schema_validation() {
+    use p4_mcp::mcp::validation::validate;
+
+    let schema = json!({
+        "type": "object",
+        "properties": {
+            "format": {"type": "string", "enum": ["json", "markdown"]},
+            "rev": {"type": ["integer", "null"]},
+            "a/b": {"type": "boolean"}
+        },
+        "required": ["format"]
+    });
+    assert!(validate(&schema, &json!({"format": "json", "rev": null})).is_ok());
+    assert!(validate(&schema, &json!({"format": "json", "rev": 3})).is_ok());
+
+    let error = validate(&schema, &json!({"format": "yaml"})).unwrap_err();
+    assert_eq!(error.pointer, "/format");
+    assert_eq!(
+        error.to_string(),
+        "/format: expected one of \"json\", \"markdown\", got \"yaml\""
+    );
+    let error = validate(&schema, &json!({"format": "json", "rev": 1.5})).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "/rev: expected integer or null, got number"
+    );
+    let error = validate(&schema, &json!({"format": "json", "a/b": 1})).unwrap_err();
+    assert_eq!(error.pointer, "/a~1b");
+    let error = validate(&schema, &json!([])).unwrap_err();
+    assert_eq!(error.to_string(), "expected object, got array");
+}
+
+#[test]
+fn test_file_contents_detection() {
+    let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+    assert!(matches!(
+        FileContents::detect(png, None),
+        FileContents::Image {
+            mime_type: "image/png",
+            ..
+        }
+    ));
+    let jpeg = vec![0xff, 0xd8, 0xff, 0xe0, 0, 0x10];
+    assert!(matches!(
+        FileContents::detect(jpeg, None),
+        FileContents::Image {
+            mime_type: "image/jpeg",
+            ..
+        }
+    ));
+    assert!(matches!(
+        FileContents::detect(b"GIF89a\x01\0".to_vec(), None),
+        FileContents::Image {
+            mime_type: "image/gif",
+            ..
+        }
+    ));
+    assert!(matches!(
+        FileContents::detect(b"RIFF\x24\0\0\0WEBPVP8 ".to_vec(), None),
+        FileContents::Image {
+            mime_type: "image/webp",
+            ..
+        }
+    ));
+
+    // A NUL byte makes any other file binary
+    assert_eq!(
+        FileContents::detect(b"MZ\x90\0\x03".to_vec(), None),
+        FileContents::Binary(b"MZ\x90\0\x03".to_vec())
+    );
+
+    // Text in another charset is decoded as it is for other output
+    assert_eq!(
+        FileContents::detect(b"caf\xe9\n".to_vec(), None),
+        FileContents::Text("caf\u{e9}\n".to_string())
+    );
+}
+
+#[test]
+fn test_workspace_templates_fill_in_client_specs() {
+    use p4_mcp::Config;
+
+    let config = Config::parse(
+        "[workspace_templates.main]
+         root = \"/home/{user}/ws/{client}\"
+         view = [\"//depot/main/... //{client}/...\", \"-//depot/main/docs/... //{client}/docs/...\"]
+         options = \"allwrite noclobber nocompress unlocked nomodtime rmdir\"
+
+         [workspace_templates.dev]
+         stream = \"//streams/dev\"
+         line_end = \"unix\"
+",
+    )
+    .unwrap();
+    let templates = config.workspace_templates;
+    let form = "Client:\talice-main\n\nOwner:\talice\n\nRoot:\t/tmp\n\nOptions:\tnoallwrite\n\n\
+                View:\n\t//depot/... //alice-main/...\n";
+
+    let mut spec = Spec::parse(form);
+    templates
+        .get("main")
+        .unwrap()
+        .apply(&mut spec, None)
+        .unwrap();
+    let client = ClientSpec::from_spec(&spec);
+    assert_eq!(client.root, "/home/alice/ws/alice-main");
+    assert_eq!(client.options[0], "allwrite");
+    assert_eq!(client.view.len(), 2);
+    assert_eq!(client.view[1].depot_path, "-//depot/main/docs/...");
+    assert_eq!(client.view[1].client_path, "//alice-main/docs/...");
+
+    // A stream client's view comes from the stream, and a root can be given per client
+    let mut spec = Spec::parse(form);
+    templates
+        .get("dev")
+        .unwrap()
+        .apply(&mut spec, Some("/data/{client}"))
+        .unwrap();
+    let client = ClientSpec::from_spec(&spec);
+    assert_eq!(client.root, "/data/alice-main");
+    assert_eq!(client.stream.as_deref(), Some("//streams/dev"));
+    assert_eq!(client.line_end, "unix");
+    assert!(client.view.is_empty());
+
+    let error = templates.get("release").unwrap_err().to_string();
+    assert!(error.ends_with("the templates are dev, main"), "{}", error);
+    assert!(WorkspaceTemplates::default()
+        .get("main")
+        .unwrap_err()
+        .to_string()
+        .starts_with("No workspace templates are configured"));
+
+    let both = WorkspaceTemplate {
+        stream: Some("//streams/dev".to_string()),
+        view: vec!["//depot/... //{client}/...".to_string()],
+        ..Default::default()
+    };
+    assert!(both.apply(&mut Spec::parse(form), None).is_err());
+}